@@ -0,0 +1,35 @@
+use crate::errors::Result;
+use crate::ShipAbiFiles;
+use libabieos_sys::ABIEOS;
+use serde::de::DeserializeOwned;
+
+/// A contract ABI from `resources/`, loaded into its own abieos context.
+pub(crate) struct EmbeddedAbi {
+    contract: &'static str,
+    abi: ABIEOS,
+}
+
+impl EmbeddedAbi {
+    /// loads `file` under the name `contract`
+    pub(crate) fn load(file: &str, contract: &'static str) -> Result<EmbeddedAbi> {
+        let abi_f = ShipAbiFiles::get(file).unwrap();
+        let abi_js = String::from_utf8(abi_f.as_ref().to_vec())?;
+        Ok(EmbeddedAbi {
+            contract,
+            abi: ABIEOS::new_with_abi(contract, &abi_js)?,
+        })
+    }
+
+    pub(crate) fn decode<T: DeserializeOwned>(&self, abi_type: &str, hex: &str) -> Result<T> {
+        let json = self
+            .abi
+            .hex_to_json(self.contract, abi_type, hex.as_bytes())?;
+        Ok(serde_json::from_str(&json)?)
+    }
+}
+
+impl Drop for EmbeddedAbi {
+    fn drop(&mut self) {
+        self.abi.destroy();
+    }
+}
@@ -0,0 +1,570 @@
+use crate::abi::EmbeddedAbi;
+use crate::errors::Result;
+use crate::sequence;
+use crate::shipper_types::{
+    Authority, GetBlocksResultV0Ex, KeyWeight, Permission, PermissionLevelWeight, PermissionLink,
+    TableRowTypes, WaitWeight,
+};
+use crate::state_store::StateStore;
+use crate::undo::{self, UndoLog};
+use crate::EOSIO_SYSTEM;
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+const AUTH_ACTIONS: [&str; 5] = [
+    "newaccount",
+    "updateauth",
+    "deleteauth",
+    "linkauth",
+    "unlinkauth",
+];
+
+/// What changed between two versions of an authority.
+///
+/// Entries are compared whole, so a key whose weight changed is both removed (old weight) and
+/// added (new weight).
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct AuthorityDiff {
+    /// (old, new) when the threshold changed
+    pub threshold: Option<(u32, u32)>,
+    pub keys_added: Vec<KeyWeight>,
+    pub keys_removed: Vec<KeyWeight>,
+    pub accounts_added: Vec<PermissionLevelWeight>,
+    pub accounts_removed: Vec<PermissionLevelWeight>,
+    pub waits_added: Vec<WaitWeight>,
+    pub waits_removed: Vec<WaitWeight>,
+}
+
+fn missing_from<T: Clone + PartialEq>(items: &[T], other: &[T]) -> Vec<T> {
+    items
+        .iter()
+        .filter(|i| !other.contains(i))
+        .cloned()
+        .collect()
+}
+
+impl AuthorityDiff {
+    /// a missing authority counts as empty, so a new permission has everything added
+    pub fn between(old: Option<&Authority>, new: Option<&Authority>) -> AuthorityDiff {
+        let empty = Authority {
+            threshold: 0,
+            keys: vec![],
+            accounts: vec![],
+            waits: vec![],
+        };
+        let old = old.unwrap_or(&empty);
+        let new = new.unwrap_or(&empty);
+        AuthorityDiff {
+            threshold: if old.threshold != new.threshold {
+                Some((old.threshold, new.threshold))
+            } else {
+                None
+            },
+            keys_added: missing_from(&new.keys, &old.keys),
+            keys_removed: missing_from(&old.keys, &new.keys),
+            accounts_added: missing_from(&new.accounts, &old.accounts),
+            accounts_removed: missing_from(&old.accounts, &new.accounts),
+            waits_added: missing_from(&new.waits, &old.waits),
+            waits_removed: missing_from(&old.waits, &new.waits),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == AuthorityDiff::default()
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PermissionChanged {
+    pub account: String,
+    pub permission: String,
+    /// `None` when the permission was created
+    pub old_authority: Option<Authority>,
+    /// `None` when the permission was deleted
+    pub new_authority: Option<Authority>,
+    pub diff: AuthorityDiff,
+    pub block: u32,
+    /// the transaction of the system action behind the change, if the traces have it
+    pub trx_id: Option<String>,
+}
+
+/// a `linkauth`/`unlinkauth`: which permission `account` needs to run `code::message_type`
+#[derive(Debug, Clone, Serialize)]
+pub struct PermissionLinkChanged {
+    pub account: String,
+    pub code: String,
+    pub message_type: String,
+    pub old_requirement: Option<String>,
+    pub new_requirement: Option<String>,
+    pub block: u32,
+    pub trx_id: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum AuditEvent {
+    PermissionChanged(PermissionChanged),
+    PermissionLinkChanged(PermissionLinkChanged),
+    /// a fork: the changes reported for blocks after `block` didn't happen
+    Undo {
+        block: u32,
+    },
+}
+
+/// the parts of the native auth actions we need, tagged like the token actions
+#[derive(Debug, Deserialize)]
+#[serde(tag = "action", rename_all = "lowercase")]
+enum AuthAction {
+    NewAccount {
+        name: String,
+        owner: Authority,
+        active: Authority,
+    },
+    UpdateAuth {
+        account: String,
+        permission: String,
+        auth: Authority,
+    },
+    DeleteAuth {
+        account: String,
+        permission: String,
+    },
+    LinkAuth {
+        account: String,
+        code: String,
+        #[serde(rename = "type")]
+        message_type: String,
+        requirement: String,
+    },
+    UnlinkAuth {
+        account: String,
+        code: String,
+        #[serde(rename = "type")]
+        message_type: String,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum AuditKey {
+    /// (account, permission)
+    Permission(String, String),
+    /// (account, code, message type)
+    Link(String, String, String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum AuditValue {
+    Authority(Authority),
+    Requirement(String),
+}
+
+fn authority(v: Option<AuditValue>) -> Option<Authority> {
+    match v {
+        Some(AuditValue::Authority(a)) => Some(a),
+        _ => None,
+    }
+}
+
+fn requirement(v: Option<AuditValue>) -> Option<String> {
+    match v {
+        Some(AuditValue::Requirement(r)) => Some(r),
+        _ => None,
+    }
+}
+
+/// the changes of one block, in the order they were first seen
+#[derive(Default)]
+struct BlockChanges {
+    changes: Vec<(AuditKey, Option<AuditValue>, Option<String>)>,
+    index: HashMap<AuditKey, usize>,
+}
+
+impl BlockChanges {
+    /// a later value for the same key wins; the transaction is kept unless a new one is known
+    fn note(&mut self, key: AuditKey, value: Option<AuditValue>, trx_id: Option<String>) {
+        match self.index.get(&key) {
+            Some(&i) => {
+                let change = &mut self.changes[i];
+                change.1 = value;
+                if trx_id.is_some() {
+                    change.2 = trx_id;
+                }
+            }
+            None => {
+                self.index.insert(key.clone(), self.changes.len());
+                self.changes.push((key, value, trx_id));
+            }
+        }
+    }
+}
+
+/// Audit trail of account permissions and permission links.
+///
+/// The `permission` and `permission_link` deltas say what each one looks like after a block,
+/// and the `newaccount`, `updateauth`, `deleteauth`, `linkauth` and `unlinkauth` actions in the
+/// traces say which transaction did it. With only traces fetched the actions alone drive the
+/// changes. The previous authority comes from what this audit has seen so far, so start it
+/// from the first block or seed it with `from_state`; otherwise the first change to each
+/// permission has no `old_authority`.
+///
+/// Forks roll back like `state_store::StateStore`.
+pub struct PermissionAudit {
+    abi: EmbeddedAbi,
+    rows: HashMap<AuditKey, AuditValue>,
+    undo: UndoLog<AuditKey, AuditValue>,
+}
+
+impl PermissionAudit {
+    pub fn new() -> Result<PermissionAudit> {
+        Ok(PermissionAudit {
+            abi: EmbeddedAbi::load("auth.abi.json", EOSIO_SYSTEM)?,
+            rows: HashMap::new(),
+            undo: UndoLog::new(),
+        })
+    }
+
+    /// starts from the permissions and links already in `state`. there is no undo log for
+    /// them, so forks below the state's head can't be rolled back.
+    pub fn from_state(state: &StateStore) -> Result<PermissionAudit> {
+        let mut audit = PermissionAudit::new()?;
+        for (_, row) in state.scan("permission", &[]) {
+            if let TableRowTypes::permission(Permission::permission_v0(p)) = row {
+                audit.rows.insert(
+                    AuditKey::Permission(p.owner.clone(), p.name.clone()),
+                    AuditValue::Authority(p.auth.clone()),
+                );
+            }
+        }
+        for (_, row) in state.scan("permission_link", &[]) {
+            if let TableRowTypes::permission_link(PermissionLink::permission_link_v0(l)) = row {
+                audit.rows.insert(
+                    AuditKey::Link(l.account.clone(), l.code.clone(), l.message_type.clone()),
+                    AuditValue::Requirement(l.required_permission.clone()),
+                );
+            }
+        }
+        audit.undo = UndoLog::starting_at(state.head_block_num());
+        Ok(audit)
+    }
+
+    fn actions(&self, block: &GetBlocksResultV0Ex, changes: &mut BlockChanges) -> Result<()> {
+        for e in sequence::actions(block)? {
+            if e.receiver != EOSIO_SYSTEM || e.act.account != EOSIO_SYSTEM {
+                continue;
+            }
+            let name = e.act.name.as_str();
+            if !AUTH_ACTIONS.contains(&name) {
+                continue;
+            }
+            let action = self
+                .abi
+                .decode::<serde_json::Value>(name, &e.act.data)
+                .and_then(|mut fields| {
+                    fields["action"] = serde_json::Value::from(name);
+                    Ok(serde_json::from_value::<AuthAction>(fields)?)
+                });
+            let action = match action {
+                Ok(action) => action,
+                Err(err) => {
+                    warn!("unable to decode eosio:{} in {}: {}", name, e.trx_id, err);
+                    continue;
+                }
+            };
+            let trx_id = Some(String::from(e.trx_id));
+            match action {
+                AuthAction::NewAccount {
+                    name,
+                    owner,
+                    active,
+                } => {
+                    changes.note(
+                        AuditKey::Permission(name.clone(), String::from("owner")),
+                        Some(AuditValue::Authority(owner)),
+                        trx_id.clone(),
+                    );
+                    changes.note(
+                        AuditKey::Permission(name, String::from("active")),
+                        Some(AuditValue::Authority(active)),
+                        trx_id,
+                    );
+                }
+                AuthAction::UpdateAuth {
+                    account,
+                    permission,
+                    auth,
+                } => changes.note(
+                    AuditKey::Permission(account, permission),
+                    Some(AuditValue::Authority(auth)),
+                    trx_id,
+                ),
+                AuthAction::DeleteAuth {
+                    account,
+                    permission,
+                } => changes.note(AuditKey::Permission(account, permission), None, trx_id),
+                AuthAction::LinkAuth {
+                    account,
+                    code,
+                    message_type,
+                    requirement,
+                } => changes.note(
+                    AuditKey::Link(account, code, message_type),
+                    Some(AuditValue::Requirement(requirement)),
+                    trx_id,
+                ),
+                AuthAction::UnlinkAuth {
+                    account,
+                    code,
+                    message_type,
+                } => changes.note(AuditKey::Link(account, code, message_type), None, trx_id),
+            }
+        }
+        Ok(())
+    }
+
+    /// the deltas have the final word on what each permission and link looks like
+    fn deltas(block: &GetBlocksResultV0Ex, changes: &mut BlockChanges) {
+        for delta in &block.deltas {
+            for row in &delta.rows {
+                match &row.data {
+                    TableRowTypes::permission(Permission::permission_v0(p)) => changes.note(
+                        AuditKey::Permission(p.owner.clone(), p.name.clone()),
+                        if row.present {
+                            Some(AuditValue::Authority(p.auth.clone()))
+                        } else {
+                            None
+                        },
+                        None,
+                    ),
+                    TableRowTypes::permission_link(PermissionLink::permission_link_v0(l)) => {
+                        changes.note(
+                            AuditKey::Link(
+                                l.account.clone(),
+                                l.code.clone(),
+                                l.message_type.clone(),
+                            ),
+                            if row.present {
+                                Some(AuditValue::Requirement(l.required_permission.clone()))
+                            } else {
+                                None
+                            },
+                            None,
+                        )
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    /// applies the block and returns every permission and link it changed
+    pub fn apply_block(&mut self, block: &GetBlocksResultV0Ex) -> Result<Vec<AuditEvent>> {
+        let block_num = match &block.this_block {
+            Some(bp) => bp.block_num,
+            None => return Ok(vec![]),
+        };
+        let mut events = vec![];
+        if let Some(fork) = self.undo.fork_point(block_num) {
+            info!("audit: fork at {}, rolling back to {}", block_num, fork);
+            self.rollback_to(fork)?;
+            events.push(AuditEvent::Undo { block: fork });
+        }
+        let mut changes = BlockChanges::default();
+        self.actions(block, &mut changes)?;
+        PermissionAudit::deltas(block, &mut changes);
+
+        let mut entries = vec![];
+        for (key, value, trx_id) in changes.changes {
+            let previous = self.rows.get(&key).cloned();
+            if previous == value {
+                continue;
+            }
+            match &value {
+                Some(v) => self.rows.insert(key.clone(), v.clone()),
+                None => self.rows.remove(&key),
+            };
+            entries.push((key.clone(), previous.clone()));
+            events.push(match key {
+                AuditKey::Permission(account, permission) => {
+                    let old_authority = authority(previous);
+                    let new_authority = authority(value);
+                    AuditEvent::PermissionChanged(PermissionChanged {
+                        diff: AuthorityDiff::between(
+                            old_authority.as_ref(),
+                            new_authority.as_ref(),
+                        ),
+                        account,
+                        permission,
+                        old_authority,
+                        new_authority,
+                        block: block_num,
+                        trx_id,
+                    })
+                }
+                AuditKey::Link(account, code, message_type) => {
+                    AuditEvent::PermissionLinkChanged(PermissionLinkChanged {
+                        account,
+                        code,
+                        message_type,
+                        old_requirement: requirement(previous),
+                        new_requirement: requirement(value),
+                        block: block_num,
+                        trx_id,
+                    })
+                }
+            });
+        }
+        self.undo.push(block_num, entries);
+        self.undo
+            .set_irreversible(block.last_irreversible.block_num);
+        Ok(events)
+    }
+
+    /// undo every block above `block_num`
+    pub fn rollback_to(&mut self, block_num: u32) -> Result<()> {
+        let rows = &mut self.rows;
+        self.undo.rollback_to(block_num, |key, previous| {
+            undo::restore(rows, key, previous)
+        })
+    }
+
+    pub fn permission(&self, account: &str, permission: &str) -> Option<&Authority> {
+        let key = AuditKey::Permission(String::from(account), String::from(permission));
+        match self.rows.get(&key) {
+            Some(AuditValue::Authority(a)) => Some(a),
+            _ => None,
+        }
+    }
+
+    /// the permission `account` has linked to `code::message_type`, if any
+    pub fn link(&self, account: &str, code: &str, message_type: &str) -> Option<&str> {
+        let key = AuditKey::Link(
+            String::from(account),
+            String::from(code),
+            String::from(message_type),
+        );
+        match self.rows.get(&key) {
+            Some(AuditValue::Requirement(r)) => Some(r),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shipper_types::tests::block;
+    use crate::shipper_types::{PermissionLinkV0, TableDeltaEx, TableRowEx};
+
+    fn link(message_type: &str, requirement: &str, present: bool) -> TableDeltaEx {
+        TableDeltaEx {
+            name: String::from("permission_link"),
+            rows: vec![TableRowEx {
+                present,
+                data: TableRowTypes::permission_link(PermissionLink::permission_link_v0(
+                    PermissionLinkV0 {
+                        account: String::from("alice"),
+                        code: String::from("eosio.token"),
+                        message_type: String::from(message_type),
+                        required_permission: String::from(requirement),
+                    },
+                )),
+            }],
+        }
+    }
+
+    fn key(key: &str, weight: u16) -> KeyWeight {
+        KeyWeight {
+            key: String::from(key),
+            weight,
+        }
+    }
+
+    fn changed_links(events: &[AuditEvent]) -> Vec<(Option<&str>, Option<&str>)> {
+        events
+            .iter()
+            .filter_map(|e| match e {
+                AuditEvent::PermissionLinkChanged(l) => {
+                    Some((l.old_requirement.as_deref(), l.new_requirement.as_deref()))
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn diff_compares_entries_whole() {
+        let old = Authority {
+            threshold: 1,
+            keys: vec![key("K1", 1), key("K2", 1)],
+            accounts: vec![],
+            waits: vec![],
+        };
+        let new = Authority {
+            threshold: 2,
+            keys: vec![key("K1", 1), key("K2", 2)],
+            accounts: vec![],
+            waits: vec![],
+        };
+        let diff = AuthorityDiff::between(Some(&old), Some(&new));
+        assert_eq!(diff.threshold, Some((1, 2)));
+        assert_eq!(diff.keys_added, vec![key("K2", 2)]);
+        assert_eq!(diff.keys_removed, vec![key("K2", 1)]);
+        assert!(AuthorityDiff::between(Some(&old), Some(&old)).is_empty());
+
+        let created = AuthorityDiff::between(None, Some(&old));
+        assert_eq!(created.threshold, Some((0, 1)));
+        assert_eq!(created.keys_added.len(), 2);
+    }
+
+    #[test]
+    fn fork_reports_undo_and_rolls_back() {
+        let mut audit = PermissionAudit::new().unwrap();
+        let events = audit
+            .apply_block(&block(1, 0, vec![link("transfer", "active", true)]))
+            .unwrap();
+        assert_eq!(changed_links(&events), vec![(None, Some("active"))]);
+        let events = audit
+            .apply_block(&block(2, 0, vec![link("transfer", "xfer", true)]))
+            .unwrap();
+        assert_eq!(changed_links(&events), vec![(Some("active"), Some("xfer"))]);
+        // the same row again changes nothing
+        let events = audit
+            .apply_block(&block(3, 0, vec![link("transfer", "xfer", true)]))
+            .unwrap();
+        assert!(events.is_empty());
+
+        let events = audit
+            .apply_block(&block(2, 0, vec![link("transfer", "", false)]))
+            .unwrap();
+        match events.first() {
+            Some(AuditEvent::Undo { block }) => assert_eq!(*block, 1),
+            other => panic!("expected an undo event, got {:?}", other),
+        }
+        // the old requirement is the one from block 1, not the forked out block 2
+        assert_eq!(changed_links(&events), vec![(Some("active"), None)]);
+        assert_eq!(audit.link("alice", "eosio.token", "transfer"), None);
+    }
+
+    #[test]
+    fn irreversible_blocks_cant_be_rolled_back() {
+        let mut audit = PermissionAudit::new().unwrap();
+        audit
+            .apply_block(&block(1, 0, vec![link("transfer", "active", true)]))
+            .unwrap();
+        audit
+            .apply_block(&block(2, 1, vec![link("issue", "owner", true)]))
+            .unwrap();
+        assert!(audit.rollback_to(0).is_err());
+        assert!(audit
+            .apply_block(&block(1, 1, vec![link("transfer", "x", true)]))
+            .is_err());
+
+        audit.rollback_to(1).unwrap();
+        assert_eq!(
+            audit.link("alice", "eosio.token", "transfer"),
+            Some("active")
+        );
+        assert_eq!(audit.link("alice", "eosio.token", "issue"), None);
+    }
+}
@@ -0,0 +1,518 @@
+//! `eosio-ship`, a command line reader for state history endpoints
+use clap::{crate_version, App, AppSettings, Arg, ArgMatches, SubCommand};
+use eosio_shipper::checkpoint::FileCheckpoint;
+use eosio_shipper::client::{server_status, RangePolicy, ShipClient, ShipClientConfig};
+use eosio_shipper::errors::Result;
+use eosio_shipper::filter::{ActionFilter, DeltaFilter, EventFilter};
+use eosio_shipper::health::{BlockRange, HealthReport};
+use eosio_shipper::keepalive::KeepaliveConfig;
+use eosio_shipper::shipper_types::GetStatusResponseV0;
+use eosio_shipper::sinks::ndjson::{Compression, Granularity, NdjsonSink, Rotation};
+use eosio_shipper::sinks::BlockSink;
+use log::*;
+use std::str::FromStr;
+
+mod serve;
+
+const DEFAULT_ENDPOINT: &str = "ws://127.0.0.1:9999";
+/// blocks between checkpoint saves
+const CHECKPOINT_EVERY: u32 = 100;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Mode {
+    /// a range of blocks, up to the head by default
+    Dump,
+    /// from the head (or the checkpoint) on, forever
+    Follow,
+    /// irreversible history, from the oldest block the server keeps up to the LIB
+    Replay,
+}
+
+/// a block on the command line, `-N` meaning N blocks before the head
+#[derive(Debug, Clone, Copy)]
+enum BlockArg {
+    Absolute(u32),
+    FromHead(u32),
+}
+
+impl BlockArg {
+    fn resolve(self, head: u32) -> u32 {
+        match self {
+            BlockArg::Absolute(n) => n,
+            BlockArg::FromHead(n) => head.saturating_sub(n),
+        }
+    }
+}
+
+impl FromStr for BlockArg {
+    type Err = eosio_shipper::errors::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let parsed = match s.strip_prefix('-') {
+            Some(n) => n.parse().map(BlockArg::FromHead),
+            None => s.parse().map(BlockArg::Absolute),
+        };
+        parsed.map_err(|_| format!("invalid block '{}'", s).into())
+    }
+}
+
+fn endpoint_arg() -> Arg<'static, 'static> {
+    Arg::with_name("endpoint")
+        .short("e")
+        .long("endpoint")
+        .value_name("URL")
+        .takes_value(true)
+        .multiple(true)
+        .number_of_values(1)
+        .default_value(DEFAULT_ENDPOINT)
+        .help("state history endpoint, repeat it to fail over between several")
+}
+
+fn start_arg() -> Arg<'static, 'static> {
+    Arg::with_name("start")
+        .long("start")
+        .value_name("BLOCK")
+        .takes_value(true)
+        .allow_hyphen_values(true)
+        .help("first block, or -N for N blocks before the head")
+}
+
+fn end_arg() -> Arg<'static, 'static> {
+    Arg::with_name("end")
+        .long("end")
+        .value_name("BLOCK")
+        .takes_value(true)
+        .allow_hyphen_values(true)
+        .help("block to stop before, or -N for N blocks before the head")
+}
+
+fn fetch_arg() -> Arg<'static, 'static> {
+    Arg::with_name("fetch")
+        .long("fetch")
+        .value_name("PARTS")
+        .takes_value(true)
+        .use_delimiter(true)
+        .possible_values(&["block", "traces", "deltas", "finality"])
+        .default_value("block,traces,deltas")
+        .help("what to fetch for each block")
+}
+
+/// the flags shared by dump, follow and replay
+fn stream_args() -> Vec<Arg<'static, 'static>> {
+    vec![
+        endpoint_arg(),
+        Arg::with_name("chain-id")
+            .long("chain-id")
+            .value_name("ID")
+            .takes_value(true)
+            .help("refuse endpoints on another chain [default: the first endpoint's]"),
+        start_arg(),
+        end_arg(),
+        Arg::with_name("window")
+            .long("window")
+            .value_name("BLOCKS")
+            .takes_value(true)
+            .default_value("150")
+            .help("blocks the server may send ahead of our acknowledgements"),
+        fetch_arg(),
+        Arg::with_name("range-policy")
+            .long("range-policy")
+            .takes_value(true)
+            .possible_values(&["fail", "clamp", "allow"])
+            .default_value("fail")
+            .help("missing traces or deltas: stop, skip those blocks, or write them anyway"),
+        Arg::with_name("max-decompressed")
+            .long("max-decompressed")
+            .value_name("BYTES")
+            .takes_value(true)
+            .help("largest a compressed transaction may inflate to [default: 4 MiB]"),
+        Arg::with_name("irreversible")
+            .long("irreversible")
+            .help("only irreversible blocks"),
+        Arg::with_name("action")
+            .long("action")
+            .value_name("FILTER")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .help("account:name[@receiver] of actions to output with --format event"),
+        Arg::with_name("delta")
+            .long("delta")
+            .value_name("FILTER")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .help("table_type[:code[:table]] of delta rows to output with --format event"),
+        Arg::with_name("format")
+            .long("format")
+            .takes_value(true)
+            .possible_values(&["block", "action", "delta", "event"])
+            .help("what each line holds [default: block, or event with filters]"),
+        Arg::with_name("output")
+            .short("o")
+            .long("output")
+            .value_name("DIR")
+            .takes_value(true)
+            .default_value("-")
+            .help("directory to write rotated files to, - for stdout"),
+        Arg::with_name("prefix")
+            .long("prefix")
+            .takes_value(true)
+            .default_value("ship")
+            .help("file name prefix in the output directory"),
+        Arg::with_name("compress")
+            .long("compress")
+            .takes_value(true)
+            .possible_values(&["none", "gzip", "zstd"])
+            .default_value("none"),
+        Arg::with_name("rotate-blocks")
+            .long("rotate-blocks")
+            .value_name("BLOCKS")
+            .takes_value(true)
+            .help("start a new file after this many blocks"),
+        Arg::with_name("checkpoint")
+            .long("checkpoint")
+            .value_name("FILE")
+            .takes_value(true)
+            .help("resume after the block in FILE instead of --start, and keep it up to date"),
+    ]
+}
+
+fn app() -> App<'static, 'static> {
+    let app = App::new("eosio-ship")
+        .version(crate_version!())
+        .about("Reads blocks, traces and deltas from an EOSIO state history endpoint")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .subcommand(
+            SubCommand::with_name("status")
+                .about("reports on each endpoint, and whether it has the blocks from --start on")
+                .arg(endpoint_arg())
+                .arg(start_arg())
+                .arg(end_arg())
+                .arg(fetch_arg())
+                .arg(
+                    Arg::with_name("json")
+                        .long("json")
+                        .help("print JSON instead of a table"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("dump")
+                .about("writes a range of blocks, up to the head by default")
+                .args(&stream_args()),
+        )
+        .subcommand(
+            SubCommand::with_name("follow")
+                .about("writes blocks from the head (or the checkpoint) on, until stopped")
+                .args(&stream_args()),
+        )
+        .subcommand(
+            SubCommand::with_name("replay")
+                .about("writes irreversible history, from the oldest block the server keeps")
+                .args(&stream_args()),
+        )
+        .subcommand(
+            SubCommand::with_name("serve")
+                .about("runs a mock state history server with a few empty blocks")
+                .arg(
+                    Arg::with_name("listen")
+                        .long("listen")
+                        .value_name("ADDR")
+                        .takes_value(true)
+                        .default_value("0.0.0.0:9999"),
+                ),
+        );
+    #[cfg(feature = "metrics-server")]
+    let app = app.arg(
+        Arg::with_name("metrics")
+            .long("metrics")
+            .value_name("ADDR")
+            .takes_value(true)
+            .global(true)
+            .help("serve Prometheus metrics on ADDR/metrics"),
+    );
+    app
+}
+
+fn endpoints(m: &ArgMatches) -> Vec<String> {
+    m.values_of("endpoint")
+        .map(|v| v.map(String::from).collect())
+        .unwrap_or_default()
+}
+
+fn parsed<T: FromStr>(m: &ArgMatches, name: &str) -> Result<Option<T>> {
+    match m.value_of(name) {
+        Some(s) => match s.parse() {
+            Ok(v) => Ok(Some(v)),
+            Err(_) => Err(format!("invalid --{} '{}'", name, s).into()),
+        },
+        None => Ok(None),
+    }
+}
+
+fn fetching(m: &ArgMatches, part: &str) -> bool {
+    m.values_of("fetch")
+        .map_or(false, |mut v| v.any(|p| p == part))
+}
+
+fn filters<T: FromStr<Err = eosio_shipper::errors::Error>>(
+    m: &ArgMatches,
+    name: &str,
+) -> Result<Vec<T>> {
+    m.values_of(name)
+        .map(|v| v.map(T::from_str).collect())
+        .unwrap_or_else(|| Ok(vec![]))
+}
+
+/// the status of the first endpoint that answers
+async fn first_status(endpoints: &[String]) -> Result<GetStatusResponseV0> {
+    let keepalive = KeepaliveConfig::default();
+    let mut last_error = None;
+    for url in endpoints {
+        match server_status(url, &keepalive).await {
+            Ok(server) => return Ok(server.status),
+            Err(e) => {
+                warn!("{} unusable: {}", url, e);
+                last_error = Some(e);
+            }
+        }
+    }
+    Err(last_error.unwrap_or_else(|| "no endpoint given".into()))
+}
+
+async fn status(m: &ArgMatches<'_>) -> Result<()> {
+    let keepalive = KeepaliveConfig::default();
+    let start = parsed::<BlockArg>(m, "start")?;
+    let end = parsed::<BlockArg>(m, "end")?;
+    let mut complete = true;
+    for url in endpoints(m) {
+        let server = match server_status(&url, &keepalive).await {
+            Ok(server) => server,
+            Err(e) => {
+                eprintln!("{}: {}", url, e);
+                complete = false;
+                continue;
+            }
+        };
+        let head = server.status.head.block_num;
+        let requested = start
+            .map(|s| BlockRange::new(s.resolve(head), end.map_or(head + 1, |e| e.resolve(head))));
+        let report = HealthReport::new(
+            &url,
+            server,
+            requested,
+            fetching(m, "traces"),
+            fetching(m, "deltas"),
+            fetching(m, "finality"),
+        );
+        if m.is_present("json") {
+            println!("{}", serde_json::to_string_pretty(&report)?);
+        } else {
+            println!("{}", report);
+        }
+        complete &= report.is_complete();
+    }
+    if complete {
+        Ok(())
+    } else {
+        Err("not every endpoint has everything requested".into())
+    }
+}
+
+/// the first block the server still has everything we fetch for
+fn oldest_block(status: &GetStatusResponseV0, traces: bool, deltas: bool) -> u32 {
+    let mut block = 1;
+    if traces {
+        block = block.max(status.trace_begin_block);
+    }
+    if deltas {
+        block = block.max(status.chain_state_begin_block);
+    }
+    block
+}
+
+/// fails on file options given without an output directory, before anything connects
+fn check_output(m: &ArgMatches) -> Result<()> {
+    let to_stdout = matches!(m.value_of("output"), Some("-") | None);
+    if to_stdout && (m.value_of("compress") != Some("none") || m.is_present("rotate-blocks")) {
+        return Err("--compress and --rotate-blocks need --output DIR".into());
+    }
+    Ok(())
+}
+
+fn sink(m: &ArgMatches) -> Result<NdjsonSink> {
+    let filter = EventFilter {
+        actions: filters::<ActionFilter>(m, "action")?,
+        deltas: filters::<DeltaFilter>(m, "delta")?,
+    };
+    let filtered = !filter.actions.is_empty() || !filter.deltas.is_empty();
+    let granularity = match m.value_of("format") {
+        Some("action") => Granularity::Action,
+        Some("delta") => Granularity::Delta,
+        Some("event") => Granularity::Event,
+        Some(_) => Granularity::Block,
+        None if filtered => Granularity::Event,
+        None => Granularity::Block,
+    };
+    if filtered && granularity != Granularity::Event {
+        return Err("--action and --delta only apply to --format event".into());
+    }
+    // no filters at all means every action and delta row
+    let filter = if filtered {
+        filter
+    } else {
+        EventFilter {
+            actions: vec![ActionFilter::default()],
+            deltas: vec![DeltaFilter::default()],
+        }
+    };
+    let compression = match m.value_of("compress") {
+        Some("gzip") => Compression::Gzip,
+        #[cfg(feature = "zstd")]
+        Some("zstd") => Compression::Zstd,
+        #[cfg(not(feature = "zstd"))]
+        Some("zstd") => return Err("zstd output needs the zstd feature".into()),
+        _ => Compression::None,
+    };
+    let sink = match m.value_of("output") {
+        Some("-") | None => NdjsonSink::stdout(granularity),
+        Some(dir) => NdjsonSink::new(
+            dir,
+            m.value_of("prefix").unwrap_or("ship"),
+            granularity,
+            compression,
+            Rotation {
+                max_blocks: parsed(m, "rotate-blocks")?,
+                max_bytes: None,
+            },
+        )?,
+    };
+    Ok(sink.with_filter(filter))
+}
+
+/// pushes out what the sink holds, then records `block_num` as done
+fn save(sink: &mut NdjsonSink, checkpoint: &Option<FileCheckpoint>, block_num: u32) -> Result<()> {
+    sink.flush()?;
+    if let Some(c) = checkpoint {
+        c.save(block_num)?;
+    }
+    Ok(())
+}
+
+async fn stream(mode: Mode, m: &ArgMatches<'_>) -> Result<()> {
+    check_output(m)?;
+    let endpoints = endpoints(m);
+    let status = first_status(&endpoints).await?;
+    let head = status.head.block_num;
+
+    let checkpoint = m.value_of("checkpoint").map(FileCheckpoint::new);
+    let resume = match &checkpoint {
+        Some(c) => c.load()?.map(|n| n + 1),
+        None => None,
+    };
+    let start = match (resume, parsed::<BlockArg>(m, "start")?) {
+        (Some(n), _) => n,
+        (None, Some(b)) => b.resolve(head),
+        (None, None) => match mode {
+            Mode::Follow => head,
+            Mode::Replay => oldest_block(&status, fetching(m, "traces"), fetching(m, "deltas")),
+            Mode::Dump => return Err("dump needs --start or an existing --checkpoint".into()),
+        },
+    };
+    let end = match parsed::<BlockArg>(m, "end")? {
+        Some(b) => b.resolve(head),
+        None => match mode {
+            Mode::Dump => head + 1,
+            Mode::Follow => u32::MAX,
+            Mode::Replay => status.last_irreversible.block_num + 1,
+        },
+    };
+    if start >= end {
+        info!("nothing to do, {} is not before {}", start, end);
+        return Ok(());
+    }
+
+    let chain_id = match m.value_of("chain-id") {
+        Some(id) => String::from(id),
+        None => status.chain_id.clone().unwrap_or_default(),
+    };
+    let mut config = ShipClientConfig::new(endpoints, &chain_id, start);
+    config.end_block = end;
+    config.max_messages_in_flight = parsed(m, "window")?.unwrap_or(150);
+    config.irreversible_only = mode == Mode::Replay || m.is_present("irreversible");
+    config.fetch_block = fetching(m, "block");
+    config.fetch_traces = fetching(m, "traces");
+    config.fetch_deltas = fetching(m, "deltas");
+    config.fetch_finality_data = fetching(m, "finality");
+    config.range_policy = match m.value_of("range-policy") {
+        Some("clamp") => RangePolicy::Clamp,
+        Some("allow") => RangePolicy::Allow,
+        _ => RangePolicy::Fail,
+    };
+    if let Some(max) = parsed(m, "max-decompressed")? {
+        config.max_decompressed_size = max;
+    }
+
+    let mut sink = sink(m)?;
+    let mut client = ShipClient::new(config);
+    info!("reading blocks {} to {}", start, end);
+    // a reversible block may still be forked out, so the checkpoint stays at or below the LIB
+    // and a restart writes those blocks again rather than missing their replacements
+    let mut done = None;
+    let mut since_save = 0;
+    while let Some(block) = client.next_block().await? {
+        let block_num = match &block.this_block {
+            Some(bp) => bp.block_num,
+            None => continue,
+        };
+        sink.handle_block(&block)?;
+        let safe = block_num.min(block.last_irreversible.block_num);
+        done = Some(safe);
+        since_save += 1;
+        if since_save >= CHECKPOINT_EVERY {
+            save(&mut sink, &checkpoint, safe)?;
+            since_save = 0;
+        }
+    }
+    if let Some(block_num) = done {
+        save(&mut sink, &checkpoint, block_num)?;
+    }
+    sink.close()
+}
+
+async fn run(matches: &ArgMatches<'_>) -> Result<()> {
+    #[cfg(feature = "metrics-server")]
+    {
+        if let Some(addr) = matches.subcommand().1.and_then(|m| m.value_of("metrics")) {
+            let addr = addr
+                .parse()
+                .map_err(|_| format!("invalid --metrics '{}'", addr))?;
+            tokio::spawn(async move {
+                if let Err(e) = eosio_shipper::metrics::serve(addr).await {
+                    error!("metrics server failed: {}", e);
+                }
+            });
+        }
+    }
+    match matches.subcommand() {
+        ("status", Some(m)) => status(m).await,
+        ("dump", Some(m)) => stream(Mode::Dump, m).await,
+        ("follow", Some(m)) => stream(Mode::Follow, m).await,
+        ("replay", Some(m)) => stream(Mode::Replay, m).await,
+        ("serve", Some(m)) => serve::serve(m.value_of("listen").unwrap_or("0.0.0.0:9999")).await,
+        _ => unreachable!("clap requires a subcommand"),
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    env_logger::init();
+    let matches = app().get_matches();
+    if let Err(e) = run(&matches).await {
+        eprintln!("error: {}", e);
+        for cause in e.iter().skip(1) {
+            eprintln!("caused by: {}", cause);
+        }
+        std::process::exit(1);
+    }
+}
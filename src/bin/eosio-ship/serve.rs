@@ -0,0 +1,223 @@
+//! a mock state history server for trying out clients without a nodeos
+use eosio_shipper::errors::Result;
+use eosio_shipper::shipper_types::{
+    BlockHeader, BlockPosition, GetBlocksResultV1, GetStatusResponseV0, MaybePackedBlock,
+    ShipRequests, ShipResults, SignedBlock, SignedBlockHeader, SignedBlockV1,
+};
+use eosio_shipper::{ShipAbiFiles, EOSIO_SYSTEM};
+use futures_util::{SinkExt, StreamExt};
+use libabieos_sys::ABIEOS;
+use log::*;
+use std::net::SocketAddr;
+use tokio::net::{TcpListener, TcpStream};
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::{accept_async, WebSocketStream};
+
+const HEAD: u32 = 10;
+const LAST_IRREVERSIBLE: u32 = 8;
+
+fn gen_block_id(block_num: u32) -> String {
+    format!(
+        "{:0>56}{:0>8x}",
+        "00a7a475a5fce4a49cc43d7131e1a86efeeac498703e38319aad0759", block_num
+    )
+}
+
+fn position(block_num: u32) -> BlockPosition {
+    BlockPosition {
+        block_num,
+        block_id: gen_block_id(block_num),
+    }
+}
+
+fn status_response() -> Result<String> {
+    let gsr = GetStatusResponseV0 {
+        head: position(HEAD),
+        last_irreversible: position(LAST_IRREVERSIBLE),
+        trace_begin_block: 1,
+        trace_end_block: HEAD + 1,
+        chain_state_begin_block: 1,
+        chain_state_end_block: HEAD + 1,
+        chain_id: Some(
+            "00a7a47738ccf44cd09f38a24aed9d95c0d650d29dd23670ffaa75c483c92b44".to_string(),
+        ),
+        finality_data_begin_block: None,
+        finality_data_end_block: None,
+    };
+    Ok(serde_json::to_string(&ShipResults::get_status_result_v0(
+        gsr,
+    ))?)
+}
+
+/// what the client asked for in its last `get_blocks_request_v0`
+#[derive(Default)]
+struct Request {
+    next_block: u32,
+    end_block: u32,
+    fetch_block: bool,
+    fetch_traces: bool,
+    fetch_deltas: bool,
+}
+
+fn gen_block(shipper_abi: &ABIEOS, block_num: u32, request: &Request) -> Result<String> {
+    let signed_block = SignedBlockV1 {
+        signed_header: SignedBlockHeader {
+            header: BlockHeader {
+                timestamp: "2018-06-01T12:00:00.000".to_string(),
+                producer: "ship_serv".to_string(),
+                confirmed: 0,
+                previous: if block_num <= 1 {
+                    "0000000000000000000000000000000000000000000000000000000000000000".to_string()
+                } else {
+                    gen_block_id(block_num - 1)
+                },
+                transaction_mroot:
+                    "0000000000000000000000000000000000000000000000000000000000000000".to_string(),
+                action_mroot: "747d103e24c96deb1beebc13eb31f7c2188126946c8677dfd1691af9f9c03ab1"
+                    .to_string(),
+                schedule_version: 0,
+                new_producers: None,
+                header_extensions: vec![],
+            },
+            producer_signature:
+                "SIG_K1_111111111111111111111111111111111111111111111111111111111111111116uk5ne"
+                    .to_string(),
+        },
+        prune_state: 0,
+        transactions: vec![],
+        block_extensions: vec![],
+    };
+    let traces = if request.fetch_traces {
+        Some(shipper_abi.json_to_hex(EOSIO_SYSTEM, "transaction_trace[]", "[]")?)
+    } else {
+        None
+    };
+    let deltas = if request.fetch_deltas {
+        Some(shipper_abi.json_to_hex(EOSIO_SYSTEM, "table_delta[]", "[]")?)
+    } else {
+        None
+    };
+    let gbr = GetBlocksResultV1 {
+        head: position(HEAD),
+        last_irreversible: position(LAST_IRREVERSIBLE.min(block_num)),
+        this_block: Some(position(block_num)),
+        prev_block: if block_num <= 1 {
+            None
+        } else {
+            Some(position(block_num - 1))
+        },
+        block: if request.fetch_block {
+            Some(MaybePackedBlock::Block(SignedBlock::signed_block_v1(
+                signed_block,
+            )))
+        } else {
+            None
+        },
+        traces,
+        deltas,
+        finality_data: None,
+    };
+    Ok(serde_json::to_string(&ShipResults::get_blocks_result_v1(
+        gbr,
+    ))?)
+}
+
+async fn send_json(
+    ws_stream: &mut WebSocketStream<TcpStream>,
+    shipper_abi: &ABIEOS,
+    json: &str,
+) -> Result<()> {
+    let bin = shipper_abi.json_to_bin(EOSIO_SYSTEM, "result", json)?;
+    ws_stream.send(Message::Binary(bin)).await?;
+    Ok(())
+}
+
+/// sends up to `count` blocks of the current request, never past the head
+async fn send_blocks(
+    ws_stream: &mut WebSocketStream<TcpStream>,
+    shipper_abi: &ABIEOS,
+    request: &mut Request,
+    count: u32,
+) -> Result<()> {
+    let end = request.end_block.min(HEAD + 1);
+    for _ in 0..count {
+        if request.next_block >= end {
+            break;
+        }
+        let json = gen_block(shipper_abi, request.next_block, request)?;
+        send_json(ws_stream, shipper_abi, &json).await?;
+        debug!("sent block {}", request.next_block);
+        request.next_block += 1;
+    }
+    Ok(())
+}
+
+async fn respond(ws_stream: &mut WebSocketStream<TcpStream>, shipper_abi: &ABIEOS) -> Result<()> {
+    let mut request = Request::default();
+    while let Some(msg) = ws_stream.next().await {
+        let msg = match msg? {
+            Message::Binary(data) => data,
+            Message::Close(_) => break,
+            _ => continue,
+        };
+        let sr = ShipRequests::from_bin(shipper_abi, &msg)?;
+        debug!("{:?}", sr);
+        match sr {
+            ShipRequests::get_status_request_v0(_) => {
+                send_json(ws_stream, shipper_abi, &status_response()?).await?;
+            }
+            ShipRequests::get_blocks_request_v0(br) => {
+                request = Request {
+                    next_block: br.start_block_num.max(1),
+                    end_block: br.end_block_num,
+                    fetch_block: br.fetch_block,
+                    fetch_traces: br.fetch_traces,
+                    fetch_deltas: br.fetch_deltas,
+                };
+                send_blocks(
+                    ws_stream,
+                    shipper_abi,
+                    &mut request,
+                    br.max_messages_in_flight,
+                )
+                .await?;
+            }
+            ShipRequests::get_blocks_ack_request_v0(ar) => {
+                send_blocks(ws_stream, shipper_abi, &mut request, ar.num_messages).await?;
+            }
+            ShipRequests::get_blocks_request_v1(br) => {
+                // the embedded ABI doesn't offer v1, so a client shouldn't send it
+                warn!("unsupported {:?}", br);
+            }
+            ShipRequests::quit => break,
+        }
+    }
+    Ok(())
+}
+
+async fn accept_connection(peer: SocketAddr, stream: TcpStream) -> Result<()> {
+    let ship_abi_f = ShipAbiFiles::get("shipper.abi.json").ok_or("shipper.abi.json missing")?;
+    let ship_abi_js = String::from_utf8(ship_abi_f.as_ref().to_vec())?;
+
+    let mut ws_stream = accept_async(stream).await?;
+    info!("new connection from {}", peer);
+    ws_stream.send(Message::Text(ship_abi_js.clone())).await?;
+
+    let shipper_abi = ABIEOS::new_with_abi(EOSIO_SYSTEM, &ship_abi_js)?;
+    let result = respond(&mut ws_stream, &shipper_abi).await;
+    shipper_abi.destroy();
+    result
+}
+
+/// Serves a chain of empty blocks 1 to `HEAD` on `listen`, one connection at a time.
+pub async fn serve(listen: &str) -> Result<()> {
+    let mut listener = TcpListener::bind(listen).await?;
+    info!("listening on {}", listen);
+    loop {
+        let (socket, peer) = listener.accept().await?;
+        if let Err(e) = accept_connection(peer, socket).await {
+            warn!("{}: {}", peer, e);
+        }
+        info!("{} disconnected", peer);
+    }
+}
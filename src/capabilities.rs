@@ -0,0 +1,167 @@
+use crate::errors::{ErrorKind, Result};
+use crate::shipper_types::abi_variant_types;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// result variants `ShipResults` can decode
+const KNOWN_RESULTS: [&str; 5] = [
+    "get_status_result_v0",
+    "get_blocks_result_v0",
+    "get_blocks_result_v1",
+    "get_status_result_v1",
+    "get_blocks_result_v2",
+];
+
+/// What a state history server can do, read from the ABI it sends on connect.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ServerCapabilities {
+    /// the ABI's `version`, eg `eosio::abi/1.1`
+    #[serde(default)]
+    pub abi_version: String,
+    /// the types of the `request` variant, eg `get_blocks_request_v1`
+    pub requests: Vec<String>,
+    /// the types of the `result` variant
+    pub results: Vec<String>,
+    /// table types that can show up in deltas, eg `contract_row`
+    pub tables: Vec<String>,
+    /// action traces can be `action_trace_v1`, which carries `return_value`
+    pub action_trace_v1: bool,
+    /// packed transactions can carry prunable data (signatures and context free data)
+    pub prunable_data: bool,
+}
+
+fn find<'a>(abi: &'a Value, section: &str, name: &str) -> Option<&'a Value> {
+    abi[section]
+        .as_array()
+        .and_then(|a| a.iter().find(|v| v["name"] == name))
+}
+
+impl ServerCapabilities {
+    pub fn from_abi(abi_json: &str) -> Result<ServerCapabilities> {
+        let abi: Value = serde_json::from_str(abi_json)?;
+        let tables = match abi["tables"].as_array() {
+            Some(a) => a
+                .iter()
+                .filter_map(|t| t["type"].as_str().map(String::from))
+                .collect(),
+            None => vec![],
+        };
+        Ok(ServerCapabilities {
+            abi_version: String::from(abi["version"].as_str().unwrap_or_default()),
+            requests: abi_variant_types(abi_json, "request")?,
+            results: abi_variant_types(abi_json, "result")?,
+            tables,
+            action_trace_v1: abi_variant_types(abi_json, "action_trace")?
+                .iter()
+                .any(|t| t == "action_trace_v1"),
+            prunable_data: find(&abi, "variants", "prunable_data_type").is_some()
+                || find(&abi, "structs", "prunable_data").is_some(),
+        })
+    }
+
+    pub fn supports_request(&self, name: &str) -> bool {
+        self.requests.iter().any(|r| r == name)
+    }
+
+    pub fn supports_result(&self, name: &str) -> bool {
+        self.results.iter().any(|r| r == name)
+    }
+
+    pub fn has_table(&self, name: &str) -> bool {
+        self.tables.iter().any(|t| t == name)
+    }
+
+    /// whether `get_blocks_request_v1` (and so `fetch_finality_data`) can be used
+    pub fn blocks_request_v1(&self) -> bool {
+        self.supports_request("get_blocks_request_v1")
+    }
+
+    /// fails with `UnsupportedServer` when the server is missing a request we need or can send
+    /// a result we can't decode
+    pub fn check(&self) -> Result<()> {
+        for required in &["get_status_request_v0", "get_blocks_ack_request_v0"] {
+            if !self.supports_request(required) {
+                return Err(ErrorKind::UnsupportedServer(format!("no {}", required)).into());
+            }
+        }
+        if !self.supports_request("get_blocks_request_v0") && !self.blocks_request_v1() {
+            return Err(ErrorKind::UnsupportedServer(String::from("no get_blocks_request")).into());
+        }
+        if let Some(r) = self
+            .results
+            .iter()
+            .find(|r| !KNOWN_RESULTS.contains(&r.as_str()))
+        {
+            return Err(ErrorKind::UnsupportedServer(format!("unknown result {}", r)).into());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ShipAbiFiles;
+
+    fn bundled_abi() -> Value {
+        let abi_f = ShipAbiFiles::get("shipper.abi.json").unwrap();
+        serde_json::from_slice(abi_f.as_ref()).unwrap()
+    }
+
+    /// the types of the variant `name`, to edit
+    fn variant<'a>(abi: &'a mut Value, name: &str) -> &'a mut Vec<Value> {
+        abi["variants"]
+            .as_array_mut()
+            .unwrap()
+            .iter_mut()
+            .find(|v| v["name"] == name)
+            .unwrap()["types"]
+            .as_array_mut()
+            .unwrap()
+    }
+
+    fn unsupported(abi: &Value) -> String {
+        let capabilities = ServerCapabilities::from_abi(&abi.to_string()).unwrap();
+        match capabilities.check().unwrap_err().kind() {
+            ErrorKind::UnsupportedServer(why) => why.clone(),
+            e => panic!("unexpected {:?}", e),
+        }
+    }
+
+    #[test]
+    fn reads_the_bundled_abi() {
+        let abi = bundled_abi();
+        let capabilities = ServerCapabilities::from_abi(&abi.to_string()).unwrap();
+        capabilities.check().unwrap();
+        assert_eq!(capabilities.abi_version, "eosio::abi/1.1");
+        assert!(capabilities.supports_request("get_blocks_request_v0"));
+        assert!(!capabilities.blocks_request_v1());
+        assert!(capabilities.supports_result("get_blocks_result_v1"));
+        assert!(capabilities.has_table("contract_row"));
+        assert!(capabilities.action_trace_v1);
+    }
+
+    #[test]
+    fn refuses_results_it_cant_decode() {
+        let mut abi = bundled_abi();
+        variant(&mut abi, "result").push(Value::from("get_blocks_result_v9"));
+        assert_eq!(unsupported(&abi), "unknown result get_blocks_result_v9");
+    }
+
+    #[test]
+    fn needs_a_blocks_request() {
+        let mut abi = bundled_abi();
+        variant(&mut abi, "request").retain(|t| t != "get_blocks_request_v0");
+        assert_eq!(unsupported(&abi), "no get_blocks_request");
+
+        // v1 alone will do
+        variant(&mut abi, "request").push(Value::from("get_blocks_request_v1"));
+        let capabilities = ServerCapabilities::from_abi(&abi.to_string()).unwrap();
+        capabilities.check().unwrap();
+        assert!(capabilities.blocks_request_v1());
+        assert!(!capabilities.supports_request("get_blocks_request_v0"));
+
+        variant(&mut abi, "request").retain(|t| t != "get_blocks_ack_request_v0");
+        assert_eq!(unsupported(&abi), "no get_blocks_ack_request_v0");
+    }
+}
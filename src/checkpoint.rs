@@ -0,0 +1,76 @@
+use crate::errors::Result;
+use std::fs;
+use std::path::PathBuf;
+
+/// The last block a consumer has finished with, kept in a small text file.
+///
+/// Writes go to a temporary file which is then renamed over the old one, so a crash never
+/// leaves a half written checkpoint behind.
+#[derive(Debug, Clone)]
+pub struct FileCheckpoint {
+    path: PathBuf,
+}
+
+impl FileCheckpoint {
+    pub fn new(path: &str) -> FileCheckpoint {
+        FileCheckpoint {
+            path: PathBuf::from(path),
+        }
+    }
+
+    pub fn load(&self) -> Result<Option<u32>> {
+        if !self.path.exists() {
+            return Ok(None);
+        }
+        let s = fs::read_to_string(&self.path)?;
+        match s.trim().parse::<u32>() {
+            Ok(n) => Ok(Some(n)),
+            Err(_) => Err(format!("invalid checkpoint in {:?}: {}", self.path, s).into()),
+        }
+    }
+
+    pub fn save(&self, block_num: u32) -> Result<()> {
+        let tmp = self.path.with_extension("tmp");
+        fs::write(&tmp, format!("{}\n", block_num))?;
+        fs::rename(&tmp, &self.path)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> String {
+        let dir = std::env::temp_dir().join(format!("ship-checkpoint-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        dir.join(name).to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn round_trip() {
+        let path = temp_path("round_trip");
+        let _ = fs::remove_file(&path);
+        let checkpoint = FileCheckpoint::new(&path);
+        assert_eq!(checkpoint.load().unwrap(), None);
+
+        checkpoint.save(42).unwrap();
+        assert_eq!(checkpoint.load().unwrap(), Some(42));
+        checkpoint.save(u32::MAX).unwrap();
+        assert_eq!(FileCheckpoint::new(&path).load().unwrap(), Some(u32::MAX));
+        // the temporary file is renamed away
+        assert!(!PathBuf::from(&path).with_extension("tmp").exists());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn rejects_garbage() {
+        let path = temp_path("garbage");
+        fs::write(&path, "not a block\n").unwrap();
+        assert!(FileCheckpoint::new(&path).load().is_err());
+        // surrounding whitespace is fine
+        fs::write(&path, "  7\n").unwrap();
+        assert_eq!(FileCheckpoint::new(&path).load().unwrap(), Some(7));
+        fs::remove_file(&path).unwrap();
+    }
+}
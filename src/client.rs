@@ -0,0 +1,602 @@
+use crate::capabilities::ServerCapabilities;
+use crate::errors::{Error, ErrorKind, Result};
+use crate::health::{check_ranges, Availability, BlockRange};
+use crate::keepalive::{KeepaliveConfig, Watchdog, POLL};
+use crate::metrics;
+use crate::shipper_types::{
+    BlockParts, BlockPosition, GetBlocksACKRequestV0, GetBlocksRequestV1, GetBlocksResultV0Ex,
+    GetStatusRequestV0, GetStatusResponseV0, Incomplete, ShipRequests, ShipResultsEx,
+    DEFAULT_MAX_DECOMPRESSED_SIZE,
+};
+use crate::EOSIO_SYSTEM;
+use futures_util::future::join_all;
+use futures_util::sink::Sink;
+use futures_util::stream::Stream;
+use futures_util::{SinkExt, StreamExt};
+use libabieos_sys::ABIEOS;
+use std::collections::VecDeque;
+use std::pin::Pin;
+use std::time::{Duration, Instant};
+use tokio_tungstenite::connect_async;
+use tokio_tungstenite::tungstenite::error::Error as WsError;
+use tokio_tungstenite::tungstenite::Message;
+use tracing::{info, warn};
+use url::Url;
+
+type WsSink = Pin<Box<dyn Sink<Message, Error = WsError> + Send>>;
+type WsStream = Pin<Box<dyn Stream<Item = std::result::Result<Message, WsError>> + Send>>;
+
+enum Received {
+    Data(Vec<u8>),
+    Closed,
+    /// blocks are overdue, find out whether the head has moved on
+    WantsHead,
+}
+
+/// one websocket to one nodeos, with the ABI it sent when we connected
+struct Connection {
+    url: String,
+    sink: WsSink,
+    stream: WsStream,
+    shipper_abi: ABIEOS,
+    capabilities: ServerCapabilities,
+    watchdog: Watchdog,
+}
+
+impl Connection {
+    async fn open(url: &str, keepalive: &KeepaliveConfig) -> Result<Connection> {
+        let (socket, _) = connect_async(Url::parse(url).map_err(|e| e.to_string())?).await?;
+        let (sink, mut stream) = socket.split();
+        let abi_text = match stream.next().await {
+            Some(msg) => msg?.into_text()?,
+            None => return Err(ErrorKind::ExpectedABI.into()),
+        };
+        let capabilities = ServerCapabilities::from_abi(&abi_text)?;
+        capabilities.check()?;
+        let shipper_abi = ABIEOS::new_with_abi(EOSIO_SYSTEM, &abi_text)
+            .map_err(|e| Error::with_chain(e, "parsing shipper abi"))?;
+        Ok(Connection {
+            url: String::from(url),
+            sink: Box::pin(sink),
+            stream: Box::pin(stream),
+            shipper_abi,
+            capabilities,
+            watchdog: Watchdog::new(keepalive.clone()),
+        })
+    }
+
+    async fn send(&mut self, request: ShipRequests) -> Result<()> {
+        let bin = request.to_bin(&self.shipper_abi)?;
+        self.sink.send(Message::Binary(bin)).await?;
+        Ok(())
+    }
+
+    /// the next binary message, pinging while the socket is quiet. fails with `NoPong` or
+    /// `Stalled` from the watchdog.
+    async fn recv(&mut self) -> Result<Received> {
+        loop {
+            let msg = match tokio::time::timeout(POLL, self.stream.next()).await {
+                Ok(Some(msg)) => msg?,
+                Ok(None) => return Ok(Received::Closed),
+                Err(_) => {
+                    if let Some(ping) = self.watchdog.check()? {
+                        self.sink.send(ping).await?;
+                    }
+                    if self.watchdog.wants_head() {
+                        return Ok(Received::WantsHead);
+                    }
+                    continue;
+                }
+            };
+            self.watchdog.heard();
+            match msg {
+                Message::Binary(data) => {
+                    metrics::BYTES_RECEIVED.inc_by(data.len() as i64);
+                    return Ok(Received::Data(data));
+                }
+                Message::Close(_) => return Ok(Received::Closed),
+                _ => {}
+            }
+        }
+    }
+
+    async fn status(&mut self) -> Result<GetStatusResponseV0> {
+        self.send(ShipRequests::get_status_request_v0(GetStatusRequestV0 {}))
+            .await?;
+        loop {
+            let data = match self.recv().await? {
+                Received::Data(data) => data,
+                Received::Closed => return Err(format!("{} closed the socket", self.url).into()),
+                Received::WantsHead => continue,
+            };
+            if let ShipResultsEx::Status(status) =
+                ShipResultsEx::from_bin(&self.shipper_abi, &data)?
+            {
+                return Ok(status);
+            }
+        }
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.shipper_abi.destroy();
+    }
+}
+
+/// what to do with an endpoint that doesn't have traces or deltas for all the requested blocks
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RangePolicy {
+    /// don't use it. `RangeUnavailable` when no endpoint has them.
+    Fail,
+    /// Start from the first block it has everything for, skipping the blocks before. Only the
+    /// start moves: one whose data stops short of the requested end (or of its head) is
+    /// refused like under `Fail`.
+    Clamp,
+    /// ask anyway. the blocks that come back without them are marked `incomplete`.
+    Allow,
+}
+
+#[derive(Debug, Clone)]
+pub struct ShipClientConfig {
+    /// state history endpoints, eg `ws://127.0.0.1:8080`
+    pub endpoints: Vec<String>,
+    /// every endpoint has to report this chain id
+    pub chain_id: String,
+    pub start_block: u32,
+    /// exclusive, `u32::MAX` to follow the chain
+    pub end_block: u32,
+    pub max_messages_in_flight: u32,
+    pub irreversible_only: bool,
+    pub fetch_block: bool,
+    pub fetch_traces: bool,
+    pub fetch_deltas: bool,
+    /// Spring finality data, used when the server has `get_blocks_request_v1`
+    pub fetch_finality_data: bool,
+    /// wait between rounds of connection attempts when no endpoint is usable
+    pub retry_interval: Duration,
+    pub keepalive: KeepaliveConfig,
+    /// return `Stalled` to the caller instead of failing over
+    pub fail_on_stall: bool,
+    pub range_policy: RangePolicy,
+    /// a packed transaction inflating past this many bytes isn't decoded, see
+    /// `GetBlocksResultV0Ex::transaction_errors`
+    pub max_decompressed_size: usize,
+}
+
+impl ShipClientConfig {
+    /// follow the chain from `start_block`, fetching blocks, traces and deltas
+    pub fn new(endpoints: Vec<String>, chain_id: &str, start_block: u32) -> ShipClientConfig {
+        ShipClientConfig {
+            endpoints,
+            chain_id: String::from(chain_id),
+            start_block,
+            end_block: u32::MAX,
+            max_messages_in_flight: 150,
+            irreversible_only: false,
+            fetch_block: true,
+            fetch_traces: true,
+            fetch_deltas: true,
+            fetch_finality_data: false,
+            retry_interval: Duration::from_secs(5),
+            keepalive: KeepaliveConfig::default(),
+            fail_on_stall: false,
+            range_policy: RangePolicy::Fail,
+            max_decompressed_size: DEFAULT_MAX_DECOMPRESSED_SIZE,
+        }
+    }
+}
+
+/// Reads blocks from the best of several state history endpoints.
+///
+/// On connecting, every endpoint is asked for its status. Endpoints on another chain are
+/// refused, and of the rest the one with the highest head is used. When it disconnects the
+/// client picks again and resumes after the last block it delivered, passing the reversible
+/// blocks it has delivered as `have_positions` so the new node restarts from the first block
+/// where the two disagree. A block at or below one already delivered is a fork and should go
+/// through something like `BlockSink::handle_block`.
+///
+/// A quiet socket is pinged, and one that doesn't answer is treated as a disconnect. When blocks
+/// stop arriving while the head (as reported by the stream, or by asking the other endpoints)
+/// has moved on, the connection is stalled and the client fails over, or returns `Stalled` if
+/// `fail_on_stall` is set.
+///
+/// Before asking for blocks, the endpoint's status is checked for traces and deltas (as
+/// fetched) from the next block on, and `range_policy` decides what happens when some are
+/// missing. Blocks past the head don't count, they just haven't been produced yet.
+pub struct ShipClient {
+    config: ShipClientConfig,
+    conn: Option<Connection>,
+    status: Option<GetStatusResponseV0>,
+    /// reversible blocks delivered so far, oldest first
+    delivered: VecDeque<BlockPosition>,
+    next_block: u32,
+}
+
+impl ShipClient {
+    pub fn new(config: ShipClientConfig) -> ShipClient {
+        let next_block = config.start_block;
+        ShipClient {
+            config,
+            conn: None,
+            status: None,
+            delivered: VecDeque::new(),
+            next_block,
+        }
+    }
+
+    /// the endpoint currently streaming, if any
+    pub fn endpoint(&self) -> Option<&str> {
+        self.conn.as_ref().map(|c| c.url.as_str())
+    }
+
+    /// what the current endpoint's ABI offers
+    pub fn capabilities(&self) -> Option<&ServerCapabilities> {
+        self.conn.as_ref().map(|c| &c.capabilities)
+    }
+
+    /// status of the current endpoint when it was selected
+    pub fn status(&self) -> Option<&GetStatusResponseV0> {
+        self.status.as_ref()
+    }
+
+    /// the next block, `None` once `end_block` is reached
+    pub async fn next_block(&mut self) -> Result<Option<GetBlocksResultV0Ex>> {
+        loop {
+            if self.next_block >= self.config.end_block {
+                return Ok(None);
+            }
+            if self.conn.is_none() {
+                self.connect().await?;
+            }
+            let conn = self.conn.as_mut().unwrap();
+            let data = match conn.recv().await {
+                Ok(Received::Data(data)) => data,
+                Ok(Received::Closed) => {
+                    warn!("{} closed the socket", conn.url);
+                    self.disconnect();
+                    continue;
+                }
+                Ok(Received::WantsHead) => {
+                    if let Some(head) = self.best_head().await {
+                        if let Some(conn) = self.conn.as_mut() {
+                            conn.watchdog.saw_head(head);
+                        }
+                    }
+                    continue;
+                }
+                Err(e) => {
+                    warn!("{} failed: {}", conn.url, e);
+                    let stalled = match e.kind() {
+                        ErrorKind::Stalled(..) => true,
+                        _ => false,
+                    };
+                    if stalled {
+                        metrics::STALLS.inc();
+                    }
+                    self.disconnect();
+                    if stalled && self.config.fail_on_stall {
+                        return Err(e);
+                    }
+                    continue;
+                }
+            };
+            let result = ShipResultsEx::from_bin_with_limit(
+                &conn.shipper_abi,
+                &data,
+                self.config.max_decompressed_size,
+            )?;
+            let mut block = match result {
+                ShipResultsEx::BlockResult(block) => block,
+                ShipResultsEx::Status(_) => continue,
+            };
+            let ack =
+                ShipRequests::get_blocks_ack_request_v0(GetBlocksACKRequestV0 { num_messages: 1 });
+            if let Err(e) = conn.send(ack).await {
+                warn!("{} failed: {}", conn.url, e);
+                self.disconnect();
+            }
+            let bp = match &block.this_block {
+                Some(bp) => bp.clone(),
+                None => continue,
+            };
+            metrics::observe_block(&block);
+            block.incomplete = Incomplete::check(self.requested_parts(), block.received);
+            if block.incomplete.is_some() {
+                metrics::INCOMPLETE_BLOCKS.inc();
+            }
+            if let Some(conn) = self.conn.as_mut() {
+                conn.watchdog.block(block.head.block_num, bp.block_num);
+            }
+            if bp.block_num < self.next_block {
+                info!(
+                    "fork at {}, {} blocks replaced",
+                    bp.block_num,
+                    self.next_block - bp.block_num
+                );
+                metrics::FORKS.inc();
+                self.delivered.retain(|p| p.block_num < bp.block_num);
+            }
+            let lib = block.last_irreversible.block_num;
+            self.delivered.push_back(bp.clone());
+            while self.delivered.front().map_or(false, |p| p.block_num < lib) {
+                self.delivered.pop_front();
+            }
+            self.next_block = bp.block_num + 1;
+            return Ok(Some(block));
+        }
+    }
+
+    /// what the current connection was asked to send
+    fn requested_parts(&self) -> BlockParts {
+        let v1 = self
+            .conn
+            .as_ref()
+            .map_or(false, |c| c.capabilities.blocks_request_v1());
+        BlockParts {
+            block: self.config.fetch_block,
+            traces: self.config.fetch_traces,
+            deltas: self.config.fetch_deltas,
+            finality_data: self.config.fetch_finality_data && v1,
+        }
+    }
+
+    /// where a server with `status` can start under the range policy, or why it can't
+    fn usable_from(
+        &self,
+        status: &GetStatusResponseV0,
+        capabilities: &ServerCapabilities,
+    ) -> std::result::Result<u32, String> {
+        let requested = BlockRange::new(self.next_block, self.config.end_block);
+        let checks = check_ranges(
+            status,
+            requested,
+            self.config.fetch_traces,
+            self.config.fetch_deltas,
+            self.config.fetch_finality_data && capabilities.blocks_request_v1(),
+        );
+        let mut start = self.next_block;
+        for check in checks {
+            match (self.config.range_policy, check.availability) {
+                (_, Availability::Full) | (RangePolicy::Allow, _) => {}
+                (RangePolicy::Clamp, Availability::Partial)
+                    if check.available.end >= check.requested.end =>
+                {
+                    start = start.max(check.available.begin)
+                }
+                _ => return Err(check.warning().unwrap_or_default()),
+            }
+        }
+        Ok(start)
+    }
+
+    /// highest head any endpoint reports within the pong timeout. The endpoints are asked all
+    /// at once, so one that doesn't answer costs the timeout once rather than per endpoint.
+    async fn best_head(&self) -> Option<u32> {
+        let probes = self.config.endpoints.iter().map(|url| {
+            tokio::time::timeout(
+                self.config.keepalive.pong_timeout,
+                probe(url, &self.config.chain_id, &self.config.keepalive),
+            )
+        });
+        join_all(probes)
+            .await
+            .into_iter()
+            .filter_map(|probed| match probed {
+                Ok(Ok((_, status))) => Some(status.head.block_num),
+                _ => None,
+            })
+            .max()
+    }
+
+    fn disconnect(&mut self) {
+        self.conn = None;
+        self.status = None;
+        metrics::RECONNECTS.inc();
+    }
+
+    /// connects to the usable endpoint with the highest head and asks it for blocks
+    async fn connect(&mut self) -> Result<()> {
+        loop {
+            let mut best: Option<(Connection, GetStatusResponseV0, u32)> = None;
+            let mut mismatch: Option<Error> = None;
+            let mut unavailable: Option<Error> = None;
+            for url in &self.config.endpoints {
+                let probed = probe(url, &self.config.chain_id, &self.config.keepalive)
+                    .await
+                    .and_then(|(conn, status)| {
+                        match self.usable_from(&status, &conn.capabilities) {
+                            Ok(start) => Ok((conn, status, start)),
+                            Err(reason) => {
+                                Err(ErrorKind::RangeUnavailable(String::from(url), reason).into())
+                            }
+                        }
+                    });
+                match probed {
+                    Ok((conn, status, start)) => {
+                        info!("{} head {}", url, status.head.block_num);
+                        let better = match &best {
+                            Some((_, b, _)) => status.head.block_num > b.head.block_num,
+                            None => true,
+                        };
+                        if better {
+                            best = Some((conn, status, start));
+                        }
+                    }
+                    Err(e) => {
+                        warn!("{} unusable: {}", url, e);
+                        match e.kind() {
+                            ErrorKind::ChainIdMismatch(..) => mismatch = Some(e),
+                            ErrorKind::RangeUnavailable(..) => unavailable = Some(e),
+                            _ => {}
+                        }
+                    }
+                }
+            }
+            match best {
+                Some((mut conn, status, start)) => {
+                    if start > self.next_block {
+                        warn!(
+                            "{} doesn't have everything before {}, skipping blocks {} to {}",
+                            conn.url,
+                            start,
+                            self.next_block,
+                            start - 1
+                        );
+                        self.next_block = start;
+                    }
+                    if self.next_block >= self.config.end_block {
+                        return Err(ErrorKind::RangeUnavailable(
+                            conn.url.clone(),
+                            format!("nothing left before {}", self.config.end_block),
+                        )
+                        .into());
+                    }
+                    info!("streaming from {} at block {}", conn.url, self.next_block);
+                    conn.watchdog
+                        .requested_blocks(self.next_block, self.config.end_block);
+                    let request = GetBlocksRequestV1 {
+                        start_block_num: self.next_block,
+                        end_block_num: self.config.end_block,
+                        max_messages_in_flight: self.config.max_messages_in_flight,
+                        have_positions: self.delivered.iter().cloned().collect(),
+                        irreversible_only: self.config.irreversible_only,
+                        fetch_block: self.config.fetch_block,
+                        fetch_traces: self.config.fetch_traces,
+                        fetch_deltas: self.config.fetch_deltas,
+                        fetch_finality_data: self.config.fetch_finality_data,
+                    };
+                    let request = if conn.capabilities.blocks_request_v1() {
+                        ShipRequests::get_blocks_request_v1(request)
+                    } else {
+                        if request.fetch_finality_data {
+                            warn!("{} can't send finality data", conn.url);
+                        }
+                        ShipRequests::get_blocks_request_v0(request.to_v0())
+                    };
+                    conn.send(request).await?;
+                    self.conn = Some(conn);
+                    self.status = Some(status);
+                    return Ok(());
+                }
+                None => {
+                    // a node on the wrong chain, or one that has pruned what we want, is a
+                    // configuration error rather than an outage
+                    if let Some(e) = mismatch.or(unavailable) {
+                        return Err(e);
+                    }
+                    warn!(
+                        "no usable endpoint, retrying in {:?}",
+                        self.config.retry_interval
+                    );
+                    tokio::time::delay_for(self.config.retry_interval).await;
+                }
+            }
+        }
+    }
+}
+
+/// a server's status, what its ABI offers, and how long the status took to come back
+#[derive(Debug, Clone)]
+pub struct ServerStatus {
+    pub capabilities: ServerCapabilities,
+    pub status: GetStatusResponseV0,
+    /// from sending `get_status_request_v0` to decoding the answer
+    pub round_trip: Duration,
+}
+
+/// asks `url` for its status, whatever chain it is on
+pub async fn server_status(url: &str, keepalive: &KeepaliveConfig) -> Result<ServerStatus> {
+    let mut conn = Connection::open(url, keepalive).await?;
+    let sent = Instant::now();
+    let status = conn.status().await?;
+    Ok(ServerStatus {
+        capabilities: conn.capabilities.clone(),
+        status,
+        round_trip: sent.elapsed(),
+    })
+}
+
+/// connects to `url` and checks it serves `chain_id`
+async fn probe(
+    url: &str,
+    chain_id: &str,
+    keepalive: &KeepaliveConfig,
+) -> Result<(Connection, GetStatusResponseV0)> {
+    let mut conn = Connection::open(url, keepalive).await?;
+    let status = conn.status().await?;
+    let actual = status.chain_id.clone().unwrap_or_default();
+    if !actual.eq_ignore_ascii_case(chain_id) {
+        return Err(
+            ErrorKind::ChainIdMismatch(String::from(url), String::from(chain_id), actual).into(),
+        );
+    }
+    Ok((conn, status))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn status(head: u32, begin: u32, end: u32) -> GetStatusResponseV0 {
+        let position = |n: u32| json!({ "block_num": n, "block_id": "" });
+        serde_json::from_value(json!({
+            "head": position(head),
+            "last_irreversible": position(head),
+            "trace_begin_block": begin,
+            "trace_end_block": end,
+            "chain_state_begin_block": begin,
+            "chain_state_end_block": end,
+            "chain_id": null,
+        }))
+        .unwrap()
+    }
+
+    fn client(policy: RangePolicy, start: u32, end: u32) -> ShipClient {
+        let mut config = ShipClientConfig::new(vec![], "", start);
+        config.end_block = end;
+        config.range_policy = policy;
+        ShipClient::new(config)
+    }
+
+    #[test]
+    fn clamp_moves_the_start_but_not_the_end() {
+        let capabilities = ServerCapabilities::default();
+        let clamp = client(RangePolicy::Clamp, 10, 100);
+        assert_eq!(
+            clamp.usable_from(&status(200, 1, 201), &capabilities),
+            Ok(10)
+        );
+        assert_eq!(
+            clamp.usable_from(&status(200, 50, 201), &capabilities),
+            Ok(50)
+        );
+        // the history stops before the requested end
+        assert!(clamp
+            .usable_from(&status(200, 50, 80), &capabilities)
+            .is_err());
+        assert!(clamp
+            .usable_from(&status(200, 1, 80), &capabilities)
+            .is_err());
+        // following the chain, blocks past the head don't count
+        let follow = client(RangePolicy::Clamp, 10, u32::MAX);
+        assert_eq!(
+            follow.usable_from(&status(200, 50, 201), &capabilities),
+            Ok(50)
+        );
+        assert!(follow
+            .usable_from(&status(200, 50, 150), &capabilities)
+            .is_err());
+
+        let fail = client(RangePolicy::Fail, 10, 100);
+        assert!(fail
+            .usable_from(&status(200, 50, 201), &capabilities)
+            .is_err());
+        let allow = client(RangePolicy::Allow, 10, 100);
+        assert_eq!(
+            allow.usable_from(&status(200, 50, 80), &capabilities),
+            Ok(10)
+        );
+    }
+}
@@ -0,0 +1,706 @@
+use crate::errors::{ErrorKind, Result};
+use crate::shipper_types::{GetBlocksResultV0Ex, DEFAULT_MAX_DECOMPRESSED_SIZE};
+use crate::sinks::BlockSink;
+use arrow::array::{
+    Array, ArrayRef, BooleanArray, PrimitiveArrayOps, StringArray, UInt32Array, UInt64Array,
+    UInt8Array,
+};
+use arrow::datatypes::{DataType, Field, Schema, SchemaRef};
+use arrow::record_batch::RecordBatch;
+use log::*;
+use parquet::arrow::schema::arrow_to_parquet_schema;
+use parquet::column::writer::ColumnWriter;
+use parquet::data_type::ByteArray;
+use parquet::file::properties::WriterProperties;
+use parquet::file::writer::{FileWriter, SerializedFileWriter};
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::sync::Arc;
+
+pub fn block_schema() -> SchemaRef {
+    Arc::new(Schema::new(vec![
+        Field::new("block_num", DataType::UInt32, false),
+        Field::new("block_id", DataType::Utf8, false),
+        Field::new("previous", DataType::Utf8, false),
+        Field::new("timestamp", DataType::Utf8, false),
+        Field::new("producer", DataType::Utf8, false),
+        Field::new("confirmed", DataType::UInt32, false),
+        Field::new("schedule_version", DataType::UInt32, false),
+        Field::new("transaction_count", DataType::UInt32, false),
+        Field::new("last_irreversible", DataType::UInt32, false),
+    ]))
+}
+
+pub fn transaction_schema() -> SchemaRef {
+    Arc::new(Schema::new(vec![
+        Field::new("block_num", DataType::UInt32, false),
+        Field::new("seq", DataType::UInt32, false),
+        Field::new("trx_id", DataType::Utf8, false),
+        Field::new("status", DataType::UInt8, false),
+        Field::new("cpu_usage_us", DataType::UInt32, false),
+        Field::new("net_usage_words", DataType::UInt32, false),
+    ]))
+}
+
+pub fn action_schema() -> SchemaRef {
+    Arc::new(Schema::new(vec![
+        Field::new("block_num", DataType::UInt32, false),
+        Field::new("trx_id", DataType::Utf8, false),
+        Field::new("action_ordinal", DataType::UInt32, false),
+        Field::new("creator_action_ordinal", DataType::UInt32, false),
+        Field::new("receiver", DataType::Utf8, false),
+        Field::new("account", DataType::Utf8, false),
+        Field::new("name", DataType::Utf8, false),
+        Field::new("data", DataType::Utf8, false),
+        Field::new("global_sequence", DataType::UInt64, true),
+        Field::new("recv_sequence", DataType::UInt64, true),
+        Field::new("except", DataType::Utf8, true),
+    ]))
+}
+
+pub fn delta_schema() -> SchemaRef {
+    Arc::new(Schema::new(vec![
+        Field::new("block_num", DataType::UInt32, false),
+        Field::new("table", DataType::Utf8, false),
+        Field::new("present", DataType::Boolean, false),
+        Field::new("data", DataType::Utf8, false),
+    ]))
+}
+
+#[derive(Debug, Clone)]
+struct BlockRow {
+    block_num: u32,
+    block_id: String,
+    previous: String,
+    timestamp: String,
+    producer: String,
+    confirmed: u32,
+    schedule_version: u32,
+    transaction_count: u32,
+    last_irreversible: u32,
+}
+
+#[derive(Debug, Clone)]
+struct TransactionRow {
+    block_num: u32,
+    seq: u32,
+    trx_id: String,
+    status: u8,
+    cpu_usage_us: u32,
+    net_usage_words: u32,
+}
+
+#[derive(Debug, Clone)]
+struct ActionRow {
+    block_num: u32,
+    trx_id: String,
+    action_ordinal: u32,
+    creator_action_ordinal: u32,
+    receiver: String,
+    account: String,
+    name: String,
+    data: String,
+    global_sequence: Option<u64>,
+    recv_sequence: Option<u64>,
+    except: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+struct DeltaRow {
+    block_num: u32,
+    table: String,
+    present: bool,
+    data: String,
+}
+
+pub struct RecordBatches {
+    pub blocks: RecordBatch,
+    pub transactions: RecordBatch,
+    pub actions: RecordBatch,
+    pub deltas: RecordBatch,
+}
+
+/// rows collected from decoded blocks, waiting to become record batches
+#[derive(Debug, Default)]
+pub struct ColumnBuffers {
+    blocks: Vec<BlockRow>,
+    transactions: Vec<TransactionRow>,
+    actions: Vec<ActionRow>,
+    deltas: Vec<DeltaRow>,
+}
+
+impl ColumnBuffers {
+    pub fn new() -> ColumnBuffers {
+        ColumnBuffers::default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+            && self.transactions.is_empty()
+            && self.actions.is_empty()
+            && self.deltas.is_empty()
+    }
+
+    /// Fails when a packed transaction doesn't inflate within `max_decompressed_size` bytes,
+    /// as its id can't be worked out.
+    pub fn push_block(
+        &mut self,
+        block: &GetBlocksResultV0Ex,
+        max_decompressed_size: usize,
+    ) -> Result<()> {
+        let this_block = match &block.this_block {
+            Some(bp) => bp,
+            None => return Ok(()),
+        };
+        let block_num = this_block.block_num;
+        if let Some(sb) = &block.block {
+            let header = &sb.signed_header().header;
+            let receipts = sb
+                .receipts(max_decompressed_size)
+                .into_iter()
+                .map(|(receipt, trx_id)| Ok((receipt, trx_id?)))
+                .collect::<Result<Vec<_>>>()?;
+            self.blocks.push(BlockRow {
+                block_num,
+                block_id: this_block.block_id.clone(),
+                previous: header.previous.clone(),
+                timestamp: header.timestamp.clone(),
+                producer: header.producer.clone(),
+                confirmed: header.confirmed as u32,
+                schedule_version: header.schedule_version,
+                transaction_count: receipts.len() as u32,
+                last_irreversible: block.last_irreversible.block_num,
+            });
+            for (seq, (receipt, trx_id)) in receipts.into_iter().enumerate() {
+                self.transactions.push(TransactionRow {
+                    block_num,
+                    seq: seq as u32,
+                    trx_id,
+                    status: receipt.status,
+                    cpu_usage_us: receipt.cpu_usage_us,
+                    net_usage_words: receipt.net_usage_words,
+                });
+            }
+        }
+        for trace in &block.traces {
+            let tt = trace.transaction_trace();
+            for at in &tt.action_traces {
+                let act = at.act();
+                let receipt = at.receipt();
+                self.actions.push(ActionRow {
+                    block_num,
+                    trx_id: tt.id.clone(),
+                    action_ordinal: at.action_ordinal(),
+                    creator_action_ordinal: at.creator_action_ordinal(),
+                    receiver: String::from(at.receiver()),
+                    account: act.account.clone(),
+                    name: act.name.clone(),
+                    data: act.data.clone(),
+                    global_sequence: receipt.and_then(|r| r.global_sequence.parse::<u64>().ok()),
+                    recv_sequence: receipt.and_then(|r| r.recv_sequence.parse::<u64>().ok()),
+                    except: at.except().cloned(),
+                });
+            }
+        }
+        for delta in &block.deltas {
+            for row in &delta.rows {
+                self.deltas.push(DeltaRow {
+                    block_num,
+                    table: delta.name.clone(),
+                    present: row.present,
+                    data: serde_json::to_string(&row.data)?,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// drops rows for blocks above `block_num`
+    pub fn truncate_above(&mut self, block_num: u32) {
+        self.blocks.retain(|r| r.block_num <= block_num);
+        self.transactions.retain(|r| r.block_num <= block_num);
+        self.actions.retain(|r| r.block_num <= block_num);
+        self.deltas.retain(|r| r.block_num <= block_num);
+    }
+
+    pub fn to_record_batches(&self) -> Result<RecordBatches> {
+        let b = &self.blocks;
+        let blocks = RecordBatch::try_new(
+            block_schema(),
+            vec![
+                Arc::new(UInt32Array::from(
+                    b.iter().map(|r| r.block_num).collect::<Vec<_>>(),
+                )) as ArrayRef,
+                Arc::new(StringArray::from(
+                    b.iter().map(|r| r.block_id.as_str()).collect::<Vec<_>>(),
+                )),
+                Arc::new(StringArray::from(
+                    b.iter().map(|r| r.previous.as_str()).collect::<Vec<_>>(),
+                )),
+                Arc::new(StringArray::from(
+                    b.iter().map(|r| r.timestamp.as_str()).collect::<Vec<_>>(),
+                )),
+                Arc::new(StringArray::from(
+                    b.iter().map(|r| r.producer.as_str()).collect::<Vec<_>>(),
+                )),
+                Arc::new(UInt32Array::from(
+                    b.iter().map(|r| r.confirmed).collect::<Vec<_>>(),
+                )),
+                Arc::new(UInt32Array::from(
+                    b.iter().map(|r| r.schedule_version).collect::<Vec<_>>(),
+                )),
+                Arc::new(UInt32Array::from(
+                    b.iter().map(|r| r.transaction_count).collect::<Vec<_>>(),
+                )),
+                Arc::new(UInt32Array::from(
+                    b.iter().map(|r| r.last_irreversible).collect::<Vec<_>>(),
+                )),
+            ],
+        )?;
+
+        let t = &self.transactions;
+        let transactions = RecordBatch::try_new(
+            transaction_schema(),
+            vec![
+                Arc::new(UInt32Array::from(
+                    t.iter().map(|r| r.block_num).collect::<Vec<_>>(),
+                )) as ArrayRef,
+                Arc::new(UInt32Array::from(
+                    t.iter().map(|r| r.seq).collect::<Vec<_>>(),
+                )),
+                Arc::new(StringArray::from(
+                    t.iter().map(|r| r.trx_id.as_str()).collect::<Vec<_>>(),
+                )),
+                Arc::new(UInt8Array::from(
+                    t.iter().map(|r| r.status).collect::<Vec<_>>(),
+                )),
+                Arc::new(UInt32Array::from(
+                    t.iter().map(|r| r.cpu_usage_us).collect::<Vec<_>>(),
+                )),
+                Arc::new(UInt32Array::from(
+                    t.iter().map(|r| r.net_usage_words).collect::<Vec<_>>(),
+                )),
+            ],
+        )?;
+
+        let a = &self.actions;
+        let actions = RecordBatch::try_new(
+            action_schema(),
+            vec![
+                Arc::new(UInt32Array::from(
+                    a.iter().map(|r| r.block_num).collect::<Vec<_>>(),
+                )) as ArrayRef,
+                Arc::new(StringArray::from(
+                    a.iter().map(|r| r.trx_id.as_str()).collect::<Vec<_>>(),
+                )),
+                Arc::new(UInt32Array::from(
+                    a.iter().map(|r| r.action_ordinal).collect::<Vec<_>>(),
+                )),
+                Arc::new(UInt32Array::from(
+                    a.iter()
+                        .map(|r| r.creator_action_ordinal)
+                        .collect::<Vec<_>>(),
+                )),
+                Arc::new(StringArray::from(
+                    a.iter().map(|r| r.receiver.as_str()).collect::<Vec<_>>(),
+                )),
+                Arc::new(StringArray::from(
+                    a.iter().map(|r| r.account.as_str()).collect::<Vec<_>>(),
+                )),
+                Arc::new(StringArray::from(
+                    a.iter().map(|r| r.name.as_str()).collect::<Vec<_>>(),
+                )),
+                Arc::new(StringArray::from(
+                    a.iter().map(|r| r.data.as_str()).collect::<Vec<_>>(),
+                )),
+                Arc::new(UInt64Array::from(
+                    a.iter().map(|r| r.global_sequence).collect::<Vec<_>>(),
+                )),
+                Arc::new(UInt64Array::from(
+                    a.iter().map(|r| r.recv_sequence).collect::<Vec<_>>(),
+                )),
+                Arc::new(StringArray::from(
+                    a.iter().map(|r| r.except.as_deref()).collect::<Vec<_>>(),
+                )),
+            ],
+        )?;
+
+        let d = &self.deltas;
+        let deltas = RecordBatch::try_new(
+            delta_schema(),
+            vec![
+                Arc::new(UInt32Array::from(
+                    d.iter().map(|r| r.block_num).collect::<Vec<_>>(),
+                )) as ArrayRef,
+                Arc::new(StringArray::from(
+                    d.iter().map(|r| r.table.as_str()).collect::<Vec<_>>(),
+                )),
+                Arc::new(BooleanArray::from(
+                    d.iter().map(|r| r.present).collect::<Vec<_>>(),
+                )),
+                Arc::new(StringArray::from(
+                    d.iter().map(|r| r.data.as_str()).collect::<Vec<_>>(),
+                )),
+            ],
+        )?;
+
+        Ok(RecordBatches {
+            blocks,
+            transactions,
+            actions,
+            deltas,
+        })
+    }
+}
+
+/// converts a batch of blocks into one record batch per schema
+pub fn to_record_batches(
+    blocks: &[GetBlocksResultV0Ex],
+    max_decompressed_size: usize,
+) -> Result<RecordBatches> {
+    let mut buffers = ColumnBuffers::new();
+    for block in blocks {
+        buffers.push_block(block, max_decompressed_size)?;
+    }
+    buffers.to_record_batches()
+}
+
+/// the values of a column that aren't null, with the definition levels when it is nullable
+fn values<T, A: Array + 'static>(
+    array: &ArrayRef,
+    nullable: bool,
+    value: impl Fn(&A, usize) -> T,
+) -> Result<(Vec<T>, Option<Vec<i16>>)> {
+    let a = array
+        .as_any()
+        .downcast_ref::<A>()
+        .ok_or("parquet: column doesn't match its schema")?;
+    let values = (0..a.len())
+        .filter(|&i| !a.is_null(i))
+        .map(|i| value(a, i))
+        .collect();
+    let levels = if nullable {
+        Some(
+            (0..a.len())
+                .map(|i| if a.is_null(i) { 0 } else { 1 })
+                .collect(),
+        )
+    } else {
+        None
+    };
+    Ok((values, levels))
+}
+
+fn write_column(writer: &mut ColumnWriter, array: &ArrayRef, nullable: bool) -> Result<()> {
+    match writer {
+        ColumnWriter::Int32ColumnWriter(w) => {
+            let (v, levels) = match array.data_type() {
+                DataType::UInt8 => values(array, nullable, |a: &UInt8Array, i| a.value(i) as i32)?,
+                _ => values(array, nullable, |a: &UInt32Array, i| a.value(i) as i32)?,
+            };
+            w.write_batch(&v, levels.as_deref(), None)?;
+        }
+        ColumnWriter::Int64ColumnWriter(w) => {
+            let (v, levels) = values(array, nullable, |a: &UInt64Array, i| a.value(i) as i64)?;
+            w.write_batch(&v, levels.as_deref(), None)?;
+        }
+        ColumnWriter::ByteArrayColumnWriter(w) => {
+            let (v, levels) = values(array, nullable, |a: &StringArray, i| {
+                ByteArray::from(a.value(i))
+            })?;
+            w.write_batch(&v, levels.as_deref(), None)?;
+        }
+        ColumnWriter::BoolColumnWriter(w) => {
+            let (v, levels) = values(array, nullable, |a: &BooleanArray, i| a.value(i))?;
+            w.write_batch(&v, levels.as_deref(), None)?;
+        }
+        _ => return Err("parquet: unsupported column type".into()),
+    }
+    Ok(())
+}
+
+/// `batch` as a parquet file with one row group. parquet 2.0 has no arrow writer, so the
+/// columns go through its column writers.
+fn write_parquet(path: &Path, batch: &RecordBatch) -> Result<()> {
+    let schema = batch.schema();
+    let descriptor = arrow_to_parquet_schema(&schema)?;
+    let mut writer = SerializedFileWriter::new(
+        File::create(path)?,
+        Rc::new(descriptor.root_schema().clone()),
+        // parquet 2.0's dictionary hashing reads misaligned memory, plain encoding avoids it
+        Rc::new(
+            WriterProperties::builder()
+                .set_dictionary_enabled(false)
+                .build(),
+        ),
+    )?;
+    let mut row_group = writer.next_row_group()?;
+    for (array, field) in batch.columns().iter().zip(schema.fields()) {
+        let mut column = row_group
+            .next_column()?
+            .ok_or("parquet: fewer columns than the schema")?;
+        write_column(&mut column, array, field.is_nullable())?;
+        row_group.close_column(column)?;
+    }
+    writer.close_row_group(row_group)?;
+    writer.close()?;
+    Ok(())
+}
+
+/// Writes Parquet files partitioned by block range.
+///
+/// Blocks `n * partition_size .. (n + 1) * partition_size` end up in
+/// `<dir>/<blocks|transactions|actions|deltas>/<kind>-<first>-<last>.parquet`, written when the
+/// stream moves past the partition or on `flush`. Written files are never rewritten, so a fork
+/// below the last written block fails with `RollbackUnavailable`.
+pub struct ParquetSink {
+    dir: PathBuf,
+    partition_size: u32,
+    buffers: ColumnBuffers,
+    partition: Option<u32>,
+    first_block: u32,
+    last_block: Option<u32>,
+    /// the last block in a written file
+    committed: Option<u32>,
+    max_decompressed_size: usize,
+}
+
+impl ParquetSink {
+    pub fn new(dir: &str, partition_size: u32) -> Result<ParquetSink> {
+        let dir = PathBuf::from(dir);
+        for kind in ["blocks", "transactions", "actions", "deltas"].iter() {
+            fs::create_dir_all(dir.join(kind))?;
+        }
+        Ok(ParquetSink {
+            dir,
+            partition_size: partition_size.max(1),
+            buffers: ColumnBuffers::new(),
+            partition: None,
+            first_block: 0,
+            last_block: None,
+            committed: None,
+            max_decompressed_size: DEFAULT_MAX_DECOMPRESSED_SIZE,
+        })
+    }
+
+    /// how far a packed transaction may inflate while working out its id
+    pub fn with_max_decompressed_size(mut self, max_decompressed_size: usize) -> ParquetSink {
+        self.max_decompressed_size = max_decompressed_size;
+        self
+    }
+
+    fn write_partition(&mut self) -> Result<()> {
+        let last = match self.last_block {
+            Some(l) => l,
+            None => return Ok(()),
+        };
+        if self.buffers.is_empty() {
+            return Ok(());
+        }
+        let batches = self.buffers.to_record_batches()?;
+        for (kind, batch) in [
+            ("blocks", &batches.blocks),
+            ("transactions", &batches.transactions),
+            ("actions", &batches.actions),
+            ("deltas", &batches.deltas),
+        ]
+        .iter()
+        {
+            let path = self.dir.join(kind).join(format!(
+                "{}-{:010}-{:010}.parquet",
+                kind, self.first_block, last
+            ));
+            write_parquet(&path, batch)?;
+            debug!("parquet: wrote {:?}", path);
+        }
+        self.buffers = ColumnBuffers::new();
+        self.committed = Some(last);
+        Ok(())
+    }
+}
+
+impl BlockSink for ParquetSink {
+    fn write_block(&mut self, block: &GetBlocksResultV0Ex) -> Result<()> {
+        if let Some(bp) = &block.this_block {
+            let partition = bp.block_num / self.partition_size;
+            if self.partition != Some(partition) {
+                self.write_partition()?;
+                self.partition = Some(partition);
+                self.first_block = bp.block_num;
+            }
+            self.buffers.push_block(block, self.max_decompressed_size)?;
+            self.last_block = Some(bp.block_num);
+        }
+        Ok(())
+    }
+
+    /// only blocks still buffered can be undone, parquet files already written are left alone
+    fn undo_to(&mut self, block_num: u32) -> Result<()> {
+        if let Some(committed) = self.committed {
+            if block_num < committed {
+                warn!(
+                    "parquet: fork to {} is before block {}, already written",
+                    block_num, committed
+                );
+                return Err(ErrorKind::RollbackUnavailable(block_num).into());
+            }
+        }
+        self.buffers.truncate_above(block_num);
+        self.last_block = Some(block_num);
+        Ok(())
+    }
+
+    fn last_committed_block(&mut self) -> Result<Option<u32>> {
+        Ok(self.committed)
+    }
+
+    /// Forks are looked for against the last block buffered rather than the last one
+    /// committed, so a fork inside the open partition drops the rows it replaces.
+    fn handle_block(&mut self, block: &GetBlocksResultV0Ex) -> Result<()> {
+        if let Some(bp) = &block.this_block {
+            if self.last_block.map_or(false, |last| bp.block_num <= last) {
+                self.undo_to(bp.block_num.saturating_sub(1))?;
+            }
+            self.write_block(block)?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.write_partition()?;
+        self.partition = None;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shipper_types::tests::{block, trace};
+    use parquet::arrow::{ArrowReader, ParquetFileArrowReader};
+    use parquet::file::reader::SerializedFileReader;
+    use std::convert::TryFrom;
+
+    fn dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("ship-parquet-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn test_block(block_num: u32, trx_id: &str) -> GetBlocksResultV0Ex {
+        let mut b = block(block_num, 0, vec![]);
+        b.traces = vec![trace(
+            trx_id,
+            &[
+                ("eosio.token", "transfer", Some(block_num as u64)),
+                ("alice", "transfer", None),
+            ],
+        )];
+        b
+    }
+
+    /// the actions files in `dir`, by name
+    fn action_files(dir: &Path) -> Vec<String> {
+        let mut names: Vec<String> = fs::read_dir(dir.join("actions"))
+            .unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        names
+    }
+
+    fn read(path: &Path) -> RecordBatch {
+        let file = SerializedFileReader::try_from(File::open(path).unwrap()).unwrap();
+        let mut reader = ParquetFileArrowReader::new(Rc::new(file));
+        reader
+            .get_record_reader(1024)
+            .unwrap()
+            .next()
+            .unwrap()
+            .unwrap()
+    }
+
+    fn column<A: Array + 'static>(batch: &RecordBatch, i: usize) -> &A {
+        batch.column(i).as_any().downcast_ref::<A>().unwrap()
+    }
+
+    #[test]
+    fn partitions_are_written_and_read_back() {
+        let dir = dir("write");
+        let mut sink = ParquetSink::new(dir.to_str().unwrap(), 10).unwrap();
+        for block_num in 8..12 {
+            sink.handle_block(&test_block(block_num, &format!("t{}", block_num)))
+                .unwrap();
+        }
+        // moving into the partition of 10 wrote the one of 8 and 9
+        assert_eq!(
+            action_files(&dir),
+            vec!["actions-0000000008-0000000009.parquet"]
+        );
+        assert_eq!(sink.last_committed_block().unwrap(), Some(9));
+        sink.flush().unwrap();
+        assert_eq!(sink.last_committed_block().unwrap(), Some(11));
+
+        let batch = read(
+            &dir.join("actions")
+                .join("actions-0000000010-0000000011.parquet"),
+        );
+        assert_eq!(batch.num_rows(), 4);
+        assert_eq!(column::<UInt32Array>(&batch, 0).value(0), 10);
+        assert_eq!(column::<StringArray>(&batch, 1).value(3), "t11");
+        let global_sequence = column::<UInt64Array>(&batch, 8);
+        assert_eq!(global_sequence.value(0), 10);
+        assert!(global_sequence.is_null(1));
+        assert!(column::<StringArray>(&batch, 10).is_null(0));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn a_fork_in_the_open_partition_replaces_its_rows() {
+        let dir = dir("fork");
+        let mut sink = ParquetSink::new(dir.to_str().unwrap(), 100).unwrap();
+        for block_num in 101..=110 {
+            sink.handle_block(&test_block(block_num, "old")).unwrap();
+        }
+        assert_eq!(sink.last_committed_block().unwrap(), None);
+        sink.handle_block(&test_block(105, "new")).unwrap();
+        sink.flush().unwrap();
+        assert_eq!(sink.last_committed_block().unwrap(), Some(105));
+
+        assert_eq!(
+            action_files(&dir),
+            vec!["actions-0000000101-0000000105.parquet"]
+        );
+        let batch = read(
+            &dir.join("actions")
+                .join("actions-0000000101-0000000105.parquet"),
+        );
+        let blocks = column::<UInt32Array>(&batch, 0);
+        let trx_ids = column::<StringArray>(&batch, 1);
+        let rows: Vec<(u32, &str)> = (0..batch.num_rows())
+            .map(|i| (blocks.value(i), trx_ids.value(i)))
+            .collect();
+        assert_eq!(rows.len(), 10);
+        assert_eq!(rows[7], (104, "old"));
+        assert_eq!(rows[8], (105, "new"));
+        assert_eq!(rows[9], (105, "new"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn written_blocks_cant_be_undone() {
+        let dir = dir("undo");
+        let mut sink = ParquetSink::new(dir.to_str().unwrap(), 10).unwrap();
+        for block_num in 5..13 {
+            sink.handle_block(&test_block(block_num, "t")).unwrap();
+        }
+        assert_eq!(sink.last_committed_block().unwrap(), Some(9));
+        let err = sink.handle_block(&test_block(8, "t")).unwrap_err();
+        assert!(matches!(err.kind(), ErrorKind::RollbackUnavailable(7)));
+        // just above what was written is still fine
+        sink.handle_block(&test_block(10, "t")).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
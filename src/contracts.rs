@@ -0,0 +1,408 @@
+use crate::abi::EmbeddedAbi;
+use crate::errors::Result;
+use crate::shipper_types::{
+    Account, AccountMetadata, Checksum256, Code, CodeV0, GetBlocksResultV0Ex, TableRowTypes,
+};
+use crate::state_store::StateStore;
+use libabieos_sys::hex_to_bin;
+use log::*;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// the ABI describing binary ABIs (`abi_def`) is loaded under this name
+const ABI_DEF_CONTRACT: &str = "eosio.abi";
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ContractDeployed {
+    pub account: String,
+    /// sha256 of the WASM
+    pub code_hash: String,
+    pub vm_type: u8,
+    pub vm_version: u8,
+    pub previous_code_hash: Option<String>,
+    pub block: u32,
+}
+
+/// `setcode` with empty code
+#[derive(Debug, Clone, Serialize)]
+pub struct ContractCleared {
+    pub account: String,
+    pub previous_code_hash: Option<String>,
+    pub block: u32,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct AbiUpdated {
+    pub account: String,
+    /// sha256 of the binary ABI, `None` when it was cleared
+    pub abi_hash: Option<String>,
+    pub previous_abi_hash: Option<String>,
+    /// the ABI as JSON, `None` when it was cleared or doesn't decode
+    pub abi: Option<serde_json::Value>,
+    pub block: u32,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum ContractEvent {
+    ContractDeployed(ContractDeployed),
+    ContractCleared(ContractCleared),
+    AbiUpdated(AbiUpdated),
+    /// a fork: the changes reported for blocks after `block` didn't happen
+    Undo {
+        block: u32,
+    },
+}
+
+/// per account, the value set at each block where it changed
+type History = HashMap<String, BTreeMap<u32, Option<String>>>;
+
+fn value_at<'a>(history: &'a History, account: &str, block_num: u32) -> Option<&'a str> {
+    history
+        .get(account)
+        .and_then(|h| h.range(..=block_num).next_back())
+        .and_then(|(_, v)| v.as_deref())
+}
+
+fn current<'a>(history: &'a History, account: &str) -> Option<&'a str> {
+    value_at(history, account, u32::MAX)
+}
+
+fn truncate(history: &mut History, block_num: u32) {
+    for h in history.values_mut() {
+        let _ = h.split_off(&block_num.saturating_add(1));
+    }
+    history.retain(|_, h| !h.is_empty());
+}
+
+fn sha256_hex(bin: &[u8]) -> String {
+    let mut value = [0u8; 32];
+    value.copy_from_slice(&Sha256::digest(bin));
+    Checksum256 { value }.to_string()
+}
+
+/// writes `bytes` to `path` unless it is already there. the content decides the name, so an
+/// existing file already has the right bytes.
+fn write_once(path: &Path, bytes: &[u8]) -> Result<()> {
+    if path.exists() {
+        return Ok(());
+    }
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, bytes)?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+/// Tracks which code and ABI each account runs, from the `account_metadata`, `account` and
+/// `code` deltas (needs `fetch_deltas`).
+///
+/// Every change is kept, so `code_hash_at` can say which code an account ran at any block
+/// since the tracker started. A fork drops the history above the fork point. Deployments made
+/// before the first block seen are unknown unless the tracker is seeded with `from_state`.
+///
+/// With `store_in`, WASM is written as `wasm/<code_hash>.wasm` and ABIs as
+/// `abi/<abi_hash>.json`, so each distinct contract is only written once.
+pub struct ContractTracker {
+    abi_def: EmbeddedAbi,
+    store: Option<PathBuf>,
+    code: History,
+    abis: History,
+    head: Option<u32>,
+}
+
+impl ContractTracker {
+    pub fn new() -> Result<ContractTracker> {
+        Ok(ContractTracker {
+            abi_def: EmbeddedAbi::load("abi.abi.json", ABI_DEF_CONTRACT)?,
+            store: None,
+            code: HashMap::new(),
+            abis: HashMap::new(),
+            head: None,
+        })
+    }
+
+    /// the code and ABI every account in `state` has at its head
+    pub fn from_state(state: &StateStore) -> Result<ContractTracker> {
+        let mut tracker = ContractTracker::new()?;
+        let block_num = state.head_block_num().unwrap_or(0);
+        for (_, row) in state.scan("account_metadata", &[]) {
+            if let TableRowTypes::account_metadata(AccountMetadata::account_metadata_v0(m)) = row {
+                if let Some(c) = &m.code {
+                    tracker
+                        .code
+                        .entry(m.name.clone())
+                        .or_insert_with(BTreeMap::new)
+                        .insert(block_num, Some(c.code_hash.clone()));
+                }
+            }
+        }
+        for (_, row) in state.scan("account", &[]) {
+            if let TableRowTypes::account(Account::account_v0(a)) = row {
+                if !a.abi.is_empty() {
+                    tracker
+                        .abis
+                        .entry(a.name.clone())
+                        .or_insert_with(BTreeMap::new)
+                        .insert(block_num, Some(sha256_hex(&hex_to_bin(&a.abi))));
+                }
+            }
+        }
+        tracker.head = state.head_block_num();
+        Ok(tracker)
+    }
+
+    /// write WASM and ABI files under `dir`
+    pub fn store_in(mut self, dir: &str) -> Result<ContractTracker> {
+        let dir = PathBuf::from(dir);
+        fs::create_dir_all(dir.join("wasm"))?;
+        fs::create_dir_all(dir.join("abi"))?;
+        self.store = Some(dir);
+        Ok(self)
+    }
+
+    fn store_wasm(&self, code: &CodeV0) -> Result<()> {
+        let dir = match &self.store {
+            Some(dir) => dir,
+            None => return Ok(()),
+        };
+        let path = dir.join("wasm").join(format!("{}.wasm", code.code_hash));
+        if path.exists() {
+            return Ok(());
+        }
+        let wasm = hex_to_bin(&code.code);
+        let hash = sha256_hex(&wasm);
+        if hash != code.code_hash {
+            warn!(
+                "code {} hashes to {}, storing it anyway",
+                code.code_hash, hash
+            );
+        }
+        write_once(&path, &wasm)
+    }
+
+    fn store_abi(&self, abi_hash: &str, abi: &serde_json::Value) -> Result<()> {
+        match &self.store {
+            Some(dir) => write_once(
+                &dir.join("abi").join(format!("{}.json", abi_hash)),
+                &serde_json::to_vec_pretty(abi)?,
+            ),
+            None => Ok(()),
+        }
+    }
+
+    /// applies the block and returns the deployments it made
+    pub fn apply_block(&mut self, block: &GetBlocksResultV0Ex) -> Result<Vec<ContractEvent>> {
+        let block_num = match &block.this_block {
+            Some(bp) => bp.block_num,
+            None => return Ok(vec![]),
+        };
+        let mut events = vec![];
+        if let Some(head) = self.head {
+            if block_num <= head {
+                let fork = block_num.saturating_sub(1);
+                info!("contracts: fork at {}, rolling back to {}", block_num, fork);
+                self.rollback_to(fork);
+                events.push(ContractEvent::Undo { block: fork });
+            }
+        }
+        for delta in &block.deltas {
+            for row in delta.rows.iter().filter(|r| r.present) {
+                match &row.data {
+                    TableRowTypes::code(Code::code_v0(c)) => self.store_wasm(c)?,
+                    TableRowTypes::account_metadata(AccountMetadata::account_metadata_v0(m)) => {
+                        let code_hash = m.code.as_ref().map(|c| c.code_hash.clone());
+                        let previous_code_hash = current(&self.code, &m.name).map(String::from);
+                        if code_hash == previous_code_hash {
+                            continue;
+                        }
+                        self.code
+                            .entry(m.name.clone())
+                            .or_insert_with(BTreeMap::new)
+                            .insert(block_num, code_hash);
+                        events.push(match &m.code {
+                            Some(c) => ContractEvent::ContractDeployed(ContractDeployed {
+                                account: m.name.clone(),
+                                code_hash: c.code_hash.clone(),
+                                vm_type: c.vm_type,
+                                vm_version: c.vm_version,
+                                previous_code_hash,
+                                block: block_num,
+                            }),
+                            None => ContractEvent::ContractCleared(ContractCleared {
+                                account: m.name.clone(),
+                                previous_code_hash,
+                                block: block_num,
+                            }),
+                        });
+                    }
+                    TableRowTypes::account(Account::account_v0(a)) => {
+                        let bin = hex_to_bin(&a.abi);
+                        let abi_hash = if bin.is_empty() {
+                            None
+                        } else {
+                            Some(sha256_hex(&bin))
+                        };
+                        let previous_abi_hash = current(&self.abis, &a.name).map(String::from);
+                        if abi_hash == previous_abi_hash {
+                            continue;
+                        }
+                        let abi = match &abi_hash {
+                            Some(hash) => match self.abi_def.decode("abi_def", &a.abi) {
+                                Ok(abi) => {
+                                    self.store_abi(hash, &abi)?;
+                                    Some(abi)
+                                }
+                                Err(e) => {
+                                    warn!("unable to decode the ABI of {}: {}", a.name, e);
+                                    None
+                                }
+                            },
+                            None => None,
+                        };
+                        self.abis
+                            .entry(a.name.clone())
+                            .or_insert_with(BTreeMap::new)
+                            .insert(block_num, abi_hash.clone());
+                        events.push(ContractEvent::AbiUpdated(AbiUpdated {
+                            account: a.name.clone(),
+                            abi_hash,
+                            previous_abi_hash,
+                            abi,
+                            block: block_num,
+                        }));
+                    }
+                    _ => {}
+                }
+            }
+        }
+        self.head = Some(block_num);
+        Ok(events)
+    }
+
+    /// forget every change above `block_num`
+    pub fn rollback_to(&mut self, block_num: u32) {
+        truncate(&mut self.code, block_num);
+        truncate(&mut self.abis, block_num);
+        self.head = match self.head {
+            Some(h) if h > block_num => Some(block_num),
+            h => h,
+        };
+    }
+
+    pub fn code_hash(&self, account: &str) -> Option<&str> {
+        current(&self.code, account)
+    }
+
+    /// the code hash `account` ran at `block_num`
+    pub fn code_hash_at(&self, account: &str, block_num: u32) -> Option<&str> {
+        value_at(&self.code, account, block_num)
+    }
+
+    pub fn abi_hash(&self, account: &str) -> Option<&str> {
+        current(&self.abis, account)
+    }
+
+    pub fn abi_hash_at(&self, account: &str, block_num: u32) -> Option<&str> {
+        value_at(&self.abis, account, block_num)
+    }
+
+    /// where the WASM for `code_hash` is stored, if it has been
+    pub fn wasm_path(&self, code_hash: &str) -> Option<PathBuf> {
+        self.store
+            .as_ref()
+            .map(|dir| dir.join("wasm").join(format!("{}.wasm", code_hash)))
+            .filter(|p| p.exists())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shipper_types::tests::block;
+    use crate::shipper_types::{AccountMetadataV0, CodeID, TableDeltaEx, TableRowEx};
+    use chrono::Utc;
+
+    fn setcode(account: &str, code_hash: Option<&str>) -> TableDeltaEx {
+        TableDeltaEx {
+            name: String::from("account_metadata"),
+            rows: vec![TableRowEx {
+                present: true,
+                data: TableRowTypes::account_metadata(AccountMetadata::account_metadata_v0(
+                    AccountMetadataV0 {
+                        name: String::from(account),
+                        privileged: false,
+                        last_code_update: Utc::now(),
+                        code: code_hash.map(|h| CodeID {
+                            vm_type: 0,
+                            vm_version: 0,
+                            code_hash: String::from(h),
+                        }),
+                    },
+                )),
+            }],
+        }
+    }
+
+    #[test]
+    fn keeps_the_code_history() {
+        let mut tracker = ContractTracker::new().unwrap();
+        let events = tracker
+            .apply_block(&block(1, 0, vec![setcode("alice", Some("c1"))]))
+            .unwrap();
+        match events.as_slice() {
+            [ContractEvent::ContractDeployed(d)] => {
+                assert_eq!(
+                    (d.code_hash.as_str(), d.previous_code_hash.as_deref()),
+                    ("c1", None)
+                )
+            }
+            other => panic!("expected a deployment, got {:?}", other),
+        }
+        // the same code again isn't a deployment
+        assert!(tracker
+            .apply_block(&block(2, 0, vec![setcode("alice", Some("c1"))]))
+            .unwrap()
+            .is_empty());
+        let events = tracker
+            .apply_block(&block(3, 0, vec![setcode("alice", None)]))
+            .unwrap();
+        match events.as_slice() {
+            [ContractEvent::ContractCleared(c)] => {
+                assert_eq!(c.previous_code_hash.as_deref(), Some("c1"))
+            }
+            other => panic!("expected a cleared contract, got {:?}", other),
+        }
+        assert_eq!(tracker.code_hash("alice"), None);
+        assert_eq!(tracker.code_hash_at("alice", 2), Some("c1"));
+        assert_eq!(tracker.code_hash_at("alice", 0), None);
+    }
+
+    #[test]
+    fn fork_reports_undo_and_drops_later_history() {
+        let mut tracker = ContractTracker::new().unwrap();
+        tracker
+            .apply_block(&block(1, 0, vec![setcode("alice", Some("c1"))]))
+            .unwrap();
+        tracker
+            .apply_block(&block(2, 0, vec![setcode("alice", Some("c2"))]))
+            .unwrap();
+
+        let events = tracker
+            .apply_block(&block(2, 0, vec![setcode("alice", Some("c3"))]))
+            .unwrap();
+        match events.as_slice() {
+            [ContractEvent::Undo { block: 1 }, ContractEvent::ContractDeployed(d)] => {
+                assert_eq!(d.previous_code_hash.as_deref(), Some("c1"));
+                assert_eq!(d.code_hash, "c3");
+            }
+            other => panic!("expected an undo and a deployment, got {:?}", other),
+        }
+        assert_eq!(tracker.code_hash_at("alice", 2), Some("c3"));
+
+        tracker.rollback_to(0);
+        assert_eq!(tracker.code_hash("alice"), None);
+    }
+}
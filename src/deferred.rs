@@ -0,0 +1,413 @@
+use crate::abi::EmbeddedAbi;
+use crate::errors::Result;
+use crate::shipper_types::{
+    Action, GeneratedTransaction, GeneratedTransactionV0, GetBlocksResultV0Ex, TableRowTypes,
+    Transaction, TransactionTraceV0,
+};
+use crate::undo::{self, UndoLog};
+use crate::EOSIO_SYSTEM;
+use log::*;
+use serde::Serialize;
+use std::collections::HashMap;
+
+// transaction_receipt_header::status_enum
+const EXECUTED: u8 = 0;
+const SOFT_FAIL: u8 = 1;
+const HARD_FAIL: u8 = 2;
+const DELAYED: u8 = 3;
+const EXPIRED: u8 = 4;
+
+/// where a deferred transaction came from
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Creator {
+    pub trx_id: String,
+    /// the action that called `send_deferred`, `None` for a transaction sent with a delay
+    pub action_ordinal: Option<u32>,
+    pub global_sequence: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DeferredTrx {
+    /// empty for a transaction sent with a delay
+    pub sender: String,
+    pub sender_id: String,
+    pub payer: String,
+    pub trx_id: String,
+    pub delay_sec: u32,
+    pub expiration: Option<String>,
+    pub actions: Vec<Action>,
+    /// `None` when it was created before the tracker started
+    pub created_block: Option<u32>,
+    pub created_by: Option<Creator>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum DeferredEvent {
+    Created {
+        trx: DeferredTrx,
+        block: u32,
+    },
+    /// `send_deferred` with `replace_existing` over a transaction that hadn't run yet
+    Replaced {
+        trx: DeferredTrx,
+        replaced_trx_id: String,
+        block: u32,
+    },
+    /// ran, `status` is executed or soft_fail. the trace is the one with `trx.trx_id`
+    Executed {
+        trx: DeferredTrx,
+        status: u8,
+        block: u32,
+    },
+    /// ran and failed; `onerror_trx_id` is set when the sender's `onerror` handled it
+    Failed {
+        trx: DeferredTrx,
+        except: Option<String>,
+        onerror_trx_id: Option<String>,
+        block: u32,
+    },
+    Expired {
+        trx: DeferredTrx,
+        block: u32,
+    },
+    /// removed without running, by `cancel_deferred` or `canceldelay`
+    Cancelled {
+        trx: DeferredTrx,
+        block: u32,
+    },
+    /// a fork: the events reported for blocks after `block` didn't happen
+    Undo {
+        block: u32,
+    },
+}
+
+/// (sender, sender_id)
+type GenKey = (String, String);
+
+/// how a deferred transaction left the table, from the traces of the block that removed it
+enum Outcome<'a> {
+    Ran(&'a TransactionTraceV0),
+    OnError {
+        failed: &'a TransactionTraceV0,
+        onerror_trx_id: &'a str,
+    },
+    Gone,
+}
+
+fn outcome<'a>(block: &'a GetBlocksResultV0Ex, trx_id: &str) -> Outcome<'a> {
+    for trace in &block.traces {
+        let tt = trace.transaction_trace();
+        if tt.scheduled && tt.id == trx_id {
+            return Outcome::Ran(tt);
+        }
+        if let Some(failed) = &tt.failed_dtrx_trace {
+            let failed = failed.transaction_trace();
+            if failed.id == trx_id {
+                return Outcome::OnError {
+                    failed,
+                    onerror_trx_id: &tt.id,
+                };
+            }
+        }
+    }
+    Outcome::Gone
+}
+
+/// Best effort at the action that created a deferred transaction, since traces don't record
+/// `send_deferred`. A transaction sent with a delay has a `delayed` trace of its own.
+/// Otherwise it is the first action run by the sender that billed the payer more RAM, or
+/// failing that the first action run by the sender.
+fn creator(block: &GetBlocksResultV0Ex, gtrx: &GeneratedTransactionV0) -> Option<Creator> {
+    let mut by_sender = None;
+    for trace in &block.traces {
+        let tt = trace.transaction_trace();
+        if tt.id == gtrx.trx_id {
+            if tt.status == DELAYED {
+                return Some(Creator {
+                    trx_id: tt.id.clone(),
+                    action_ordinal: None,
+                    global_sequence: None,
+                });
+            }
+            continue;
+        }
+        for at in &tt.action_traces {
+            let receipt = match at.receipt() {
+                Some(r) if at.receiver() == gtrx.sender => r,
+                _ => continue,
+            };
+            let c = Creator {
+                trx_id: tt.id.clone(),
+                action_ordinal: Some(at.action_ordinal()),
+                global_sequence: receipt.global_sequence.parse::<u64>().ok(),
+            };
+            let billed_payer = at.account_ram_deltas().iter().any(|d| {
+                d.account == gtrx.payer && d.delta.parse::<i64>().map_or(false, |n| n > 0)
+            });
+            if billed_payer {
+                return Some(c);
+            }
+            if by_sender.is_none() {
+                by_sender = Some(c);
+            }
+        }
+    }
+    by_sender
+}
+
+/// Follows deferred transactions from the `generated_transaction` delta that creates them to
+/// the one that removes them (needs `fetch_deltas` and `fetch_traces`).
+///
+/// The block that removes one tells what happened: a `scheduled` trace with its id means it
+/// ran (or expired), an `onerror` trace carrying it as `failed_dtrx_trace` means it failed,
+/// and no trace at all means it was cancelled. Forks roll back like
+/// `state_store::StateStore`.
+pub struct DeferredTracker {
+    abi: EmbeddedAbi,
+    pending: HashMap<GenKey, DeferredTrx>,
+    undo: UndoLog<GenKey, DeferredTrx>,
+}
+
+impl DeferredTracker {
+    pub fn new() -> Result<DeferredTracker> {
+        Ok(DeferredTracker {
+            abi: EmbeddedAbi::load("shipper.abi.json", EOSIO_SYSTEM)?,
+            pending: HashMap::new(),
+            undo: UndoLog::new(),
+        })
+    }
+
+    fn deferred_trx(&self, gtrx: &GeneratedTransactionV0) -> DeferredTrx {
+        let (delay_sec, expiration, actions) = match self
+            .abi
+            .decode::<Transaction>("transaction", &gtrx.packed_trx)
+        {
+            Ok(t) => (
+                t.header.delay_sec,
+                Some(t.header.expiration.format("%Y-%m-%dT%H:%M:%S").to_string()),
+                t.actions,
+            ),
+            Err(e) => {
+                warn!("unable to decode deferred {}: {}", gtrx.trx_id, e);
+                (0, None, vec![])
+            }
+        };
+        DeferredTrx {
+            sender: gtrx.sender.clone(),
+            sender_id: gtrx.sender_id.clone(),
+            payer: gtrx.payer.clone(),
+            trx_id: gtrx.trx_id.clone(),
+            delay_sec,
+            expiration,
+            actions,
+            created_block: None,
+            created_by: None,
+        }
+    }
+
+    /// applies the block and returns the lifecycle events it caused
+    pub fn apply_block(&mut self, block: &GetBlocksResultV0Ex) -> Result<Vec<DeferredEvent>> {
+        let block_num = match &block.this_block {
+            Some(bp) => bp.block_num,
+            None => return Ok(vec![]),
+        };
+        let mut events = vec![];
+        if let Some(fork) = self.undo.fork_point(block_num) {
+            info!("deferred: fork at {}, rolling back to {}", block_num, fork);
+            self.rollback_to(fork)?;
+            events.push(DeferredEvent::Undo { block: fork });
+        }
+        let mut entries = vec![];
+        for delta in &block.deltas {
+            for row in &delta.rows {
+                let gtrx = match &row.data {
+                    TableRowTypes::generated_transaction(
+                        GeneratedTransaction::generated_transaction_v0(g),
+                    ) => g,
+                    _ => continue,
+                };
+                let key = (gtrx.sender.clone(), gtrx.sender_id.clone());
+                if row.present {
+                    let mut trx = self.deferred_trx(gtrx);
+                    trx.created_block = Some(block_num);
+                    trx.created_by = creator(block, gtrx);
+                    let previous = self.pending.insert(key.clone(), trx.clone());
+                    events.push(match &previous {
+                        Some(p) if p.trx_id != trx.trx_id => DeferredEvent::Replaced {
+                            replaced_trx_id: p.trx_id.clone(),
+                            trx,
+                            block: block_num,
+                        },
+                        _ => DeferredEvent::Created {
+                            trx,
+                            block: block_num,
+                        },
+                    });
+                    entries.push((key, previous));
+                } else {
+                    let previous = self.pending.remove(&key);
+                    let trx = match &previous {
+                        Some(p) => p.clone(),
+                        None => self.deferred_trx(gtrx),
+                    };
+                    entries.push((key, previous));
+                    events.push(match outcome(block, &gtrx.trx_id) {
+                        Outcome::Ran(tt) if tt.status == EXPIRED => DeferredEvent::Expired {
+                            trx,
+                            block: block_num,
+                        },
+                        Outcome::Ran(tt) if tt.status == EXECUTED || tt.status == SOFT_FAIL => {
+                            DeferredEvent::Executed {
+                                trx,
+                                status: tt.status,
+                                block: block_num,
+                            }
+                        }
+                        Outcome::Ran(tt) => {
+                            if tt.status != HARD_FAIL {
+                                debug!("deferred {} ended with status {}", tt.id, tt.status);
+                            }
+                            DeferredEvent::Failed {
+                                trx,
+                                except: tt.except.clone(),
+                                onerror_trx_id: None,
+                                block: block_num,
+                            }
+                        }
+                        Outcome::OnError {
+                            failed,
+                            onerror_trx_id,
+                        } => DeferredEvent::Failed {
+                            trx,
+                            except: failed.except.clone(),
+                            onerror_trx_id: Some(String::from(onerror_trx_id)),
+                            block: block_num,
+                        },
+                        Outcome::Gone => DeferredEvent::Cancelled {
+                            trx,
+                            block: block_num,
+                        },
+                    });
+                }
+            }
+        }
+        self.undo.push(block_num, entries);
+        self.undo
+            .set_irreversible(block.last_irreversible.block_num);
+        Ok(events)
+    }
+
+    /// undo every block above `block_num`
+    pub fn rollback_to(&mut self, block_num: u32) -> Result<()> {
+        let pending = &mut self.pending;
+        self.undo.rollback_to(block_num, |key, previous| {
+            undo::restore(pending, key, previous)
+        })
+    }
+
+    /// deferred transactions created since the tracker started that haven't left yet
+    pub fn pending(&self) -> impl Iterator<Item = &DeferredTrx> {
+        self.pending.values()
+    }
+
+    pub fn get(&self, sender: &str, sender_id: &str) -> Option<&DeferredTrx> {
+        self.pending
+            .get(&(String::from(sender), String::from(sender_id)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shipper_types::tests::block;
+    use crate::shipper_types::{TableDeltaEx, TableRowEx};
+
+    fn generated(sender_id: &str, trx_id: &str, present: bool) -> TableDeltaEx {
+        TableDeltaEx {
+            name: String::from("generated_transaction"),
+            rows: vec![TableRowEx {
+                present,
+                data: TableRowTypes::generated_transaction(
+                    GeneratedTransaction::generated_transaction_v0(GeneratedTransactionV0 {
+                        sender: String::from("alice"),
+                        sender_id: String::from(sender_id),
+                        payer: String::from("alice"),
+                        trx_id: String::from(trx_id),
+                        packed_trx: String::new(),
+                    }),
+                ),
+            }],
+        }
+    }
+
+    /// (event, block, trx_id) of each event
+    fn summary(events: &[DeferredEvent]) -> Vec<(&'static str, u32, &str)> {
+        events
+            .iter()
+            .map(|e| match e {
+                DeferredEvent::Created { trx, block } => ("created", *block, trx.trx_id.as_str()),
+                DeferredEvent::Replaced { trx, block, .. } => {
+                    ("replaced", *block, trx.trx_id.as_str())
+                }
+                DeferredEvent::Executed { trx, block, .. } => {
+                    ("executed", *block, trx.trx_id.as_str())
+                }
+                DeferredEvent::Failed { trx, block, .. } => ("failed", *block, trx.trx_id.as_str()),
+                DeferredEvent::Expired { trx, block } => ("expired", *block, trx.trx_id.as_str()),
+                DeferredEvent::Cancelled { trx, block } => {
+                    ("cancelled", *block, trx.trx_id.as_str())
+                }
+                DeferredEvent::Undo { block } => ("undo", *block, ""),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn follows_a_deferred_transaction_across_a_fork() {
+        let mut tracker = DeferredTracker::new().unwrap();
+        let events = tracker
+            .apply_block(&block(1, 0, vec![generated("1", "aa", true)]))
+            .unwrap();
+        assert_eq!(summary(&events), vec![("created", 1, "aa")]);
+        assert_eq!(tracker.get("alice", "1").unwrap().created_block, Some(1));
+
+        let events = tracker
+            .apply_block(&block(2, 0, vec![generated("1", "bb", true)]))
+            .unwrap();
+        assert_eq!(summary(&events), vec![("replaced", 2, "bb")]);
+        // no trace removed it, so it was cancelled
+        let events = tracker
+            .apply_block(&block(3, 0, vec![generated("1", "bb", false)]))
+            .unwrap();
+        assert_eq!(summary(&events), vec![("cancelled", 3, "bb")]);
+        assert_eq!(tracker.pending().count(), 0);
+
+        // another block 2 cancels the original instead
+        let events = tracker
+            .apply_block(&block(2, 0, vec![generated("1", "aa", false)]))
+            .unwrap();
+        assert_eq!(
+            summary(&events),
+            vec![("undo", 1, ""), ("cancelled", 2, "aa")]
+        );
+        tracker.rollback_to(1).unwrap();
+        assert_eq!(tracker.get("alice", "1").unwrap().trx_id, "aa");
+    }
+
+    #[test]
+    fn irreversible_blocks_cant_be_rolled_back() {
+        let mut tracker = DeferredTracker::new().unwrap();
+        tracker
+            .apply_block(&block(1, 0, vec![generated("1", "aa", true)]))
+            .unwrap();
+        tracker
+            .apply_block(&block(2, 1, vec![generated("2", "bb", true)]))
+            .unwrap();
+        assert!(tracker.rollback_to(0).is_err());
+        assert!(tracker.get("alice", "1").is_some());
+
+        tracker.rollback_to(1).unwrap();
+        assert!(tracker.get("alice", "2").is_none());
+    }
+}
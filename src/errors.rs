@@ -26,11 +26,60 @@ error_chain! {
         Tungtentie(tokio_tungstenite::tungstenite::error::Error);
         LibABIEOS(libabieos_sys::errors::Error);
         SerdeJson(serde_json::error::Error);
+        Io(std::io::Error);
+        Sqlite(rusqlite::Error) #[cfg(feature = "sqlite")];
+        Postgres(postgres::Error) #[cfg(feature = "postgres")];
+        Arrow(arrow::error::ArrowError) #[cfg(feature = "arrow")];
+        Parquet(parquet::errors::ParquetError) #[cfg(feature = "arrow")];
+        Reqwest(reqwest::Error) #[cfg(feature = "webhook")];
+        Kafka(rdkafka::error::KafkaError) #[cfg(feature = "kafka")];
+        Hyper(hyper::Error) #[cfg(feature = "metrics-server")];
     }
     errors {
         ExpectedABI{
             description("expected shipper ABI")
             display("expected shipper ABI")
         }
+        InvalidFilter(f: String) {
+            description("invalid filter")
+            display("invalid filter '{}'", f)
+        }
+        UnknownTable(t: String) {
+            description("table type not described by the ABI")
+            display("table type '{}' not described by the ABI", t)
+        }
+        UnsupportedServer(reason: String) {
+            description("state history server not supported")
+            display("state history server not supported: {}", reason)
+        }
+        ChainIdMismatch(url: String, expected: String, actual: String) {
+            description("endpoint is on another chain")
+            display("{} serves chain '{}', expected '{}'", url, actual, expected)
+        }
+        Decode(abi_type: String, block_num: Option<u32>) {
+            description("unable to decode state history data")
+            display("unable to decode '{}' in block {}", abi_type,
+                block_num.map_or(String::from("?"), |n| n.to_string()))
+        }
+        DecompressedTooLarge(limit: usize) {
+            description("compressed data inflates past the limit")
+            display("compressed data inflates past the {} byte limit", limit)
+        }
+        NoPong(secs: u64) {
+            description("no pong received")
+            display("no pong received within {}s", secs)
+        }
+        Stalled(block_num: u32, secs: u64) {
+            description("block stream stalled")
+            display("no block after {} for {}s while the head moved on", block_num, secs)
+        }
+        RangeUnavailable(url: String, reason: String) {
+            description("endpoint doesn't have the requested blocks")
+            display("{} can't serve the requested blocks: {}", url, reason)
+        }
+        RollbackUnavailable(block_num: u32) {
+            description("no undo information to roll back to block")
+            display("no undo information to roll back to block {}", block_num)
+        }
     }
 }
@@ -0,0 +1,420 @@
+use crate::errors::{ErrorKind, Result};
+use crate::shipper_types::{
+    ActionTraceVariant, ContractIndex128, ContractIndex256, ContractIndex64, ContractIndexDouble,
+    ContractIndexLongDouble, ContractRow, ContractTable, GetBlocksResultV0Ex, TableRowTypes,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::str::FromStr;
+
+fn part(s: &str) -> Option<String> {
+    if s.is_empty() || s == "*" {
+        None
+    } else {
+        Some(String::from(s))
+    }
+}
+
+fn matches(want: &Option<String>, have: &str) -> bool {
+    match want {
+        Some(w) => w == have,
+        None => true,
+    }
+}
+
+/// matches action traces on `account:name[@receiver]`, `*` or an empty part matching anything
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ActionFilter {
+    pub account: Option<String>,
+    pub name: Option<String>,
+    pub receiver: Option<String>,
+}
+
+impl ActionFilter {
+    pub fn matches(&self, at: &ActionTraceVariant) -> bool {
+        let act = at.act();
+        matches(&self.account, &act.account)
+            && matches(&self.name, &act.name)
+            && matches(&self.receiver, at.receiver())
+    }
+}
+
+impl FromStr for ActionFilter {
+    type Err = crate::errors::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (action, receiver) = match s.find('@') {
+            Some(i) => (&s[..i], part(&s[i + 1..])),
+            None => (s, None),
+        };
+        let mut parts = action.split(':');
+        let account = part(parts.next().unwrap_or(""));
+        let name = part(parts.next().unwrap_or(""));
+        if parts.next().is_some() {
+            return Err(ErrorKind::InvalidFilter(String::from(s)).into());
+        }
+        Ok(ActionFilter {
+            account,
+            name,
+            receiver,
+        })
+    }
+}
+
+/// matches table delta rows on `table_type[:code[:table]]`, eg `contract_row:eosio.token:accounts`.
+/// code and table only apply to the contract_* row types.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DeltaFilter {
+    pub name: Option<String>,
+    pub code: Option<String>,
+    pub table: Option<String>,
+}
+
+impl DeltaFilter {
+    pub fn matches(&self, name: &str, row: &TableRowTypes) -> bool {
+        if !matches(&self.name, name) {
+            return false;
+        }
+        if self.code.is_none() && self.table.is_none() {
+            return true;
+        }
+        match contract_table_of(row) {
+            Some((code, table)) => matches(&self.code, code) && matches(&self.table, table),
+            None => false,
+        }
+    }
+}
+
+impl FromStr for DeltaFilter {
+    type Err = crate::errors::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut parts = s.split(':');
+        let name = part(parts.next().unwrap_or(""));
+        let code = part(parts.next().unwrap_or(""));
+        let table = part(parts.next().unwrap_or(""));
+        if parts.next().is_some() {
+            return Err(ErrorKind::InvalidFilter(String::from(s)).into());
+        }
+        Ok(DeltaFilter { name, code, table })
+    }
+}
+
+/// (code, table) of the contract_* row types
+pub fn contract_table_of(row: &TableRowTypes) -> Option<(&str, &str)> {
+    match row {
+        TableRowTypes::contract_table(ContractTable::contract_table_v0(r)) => {
+            Some((&r.code, &r.table))
+        }
+        TableRowTypes::contract_row(ContractRow::contract_row_v0(r)) => Some((&r.code, &r.table)),
+        TableRowTypes::contract_index64(ContractIndex64::contract_index64_v0(r)) => {
+            Some((&r.code, &r.table))
+        }
+        TableRowTypes::contract_index128(ContractIndex128::contract_index128_v0(r)) => {
+            Some((&r.code, &r.table))
+        }
+        TableRowTypes::contract_index256(ContractIndex256::contract_index256_v0(r)) => {
+            Some((&r.code, &r.table))
+        }
+        TableRowTypes::contract_index_double(ContractIndexDouble::contract_index_double_v0(r)) => {
+            Some((&r.code, &r.table))
+        }
+        TableRowTypes::contract_index_long_double(
+            ContractIndexLongDouble::contract_index_long_double_v0(r),
+        ) => Some((&r.code, &r.table)),
+        _ => None,
+    }
+}
+
+/// a set of action and delta filters. an event matches if any filter matches it.
+#[derive(Debug, Clone, Default)]
+pub struct EventFilter {
+    pub actions: Vec<ActionFilter>,
+    pub deltas: Vec<DeltaFilter>,
+}
+
+impl EventFilter {
+    /// every matched action trace and delta row of the block
+    pub fn events(&self, block: &GetBlocksResultV0Ex) -> Result<Vec<Event>> {
+        let (block_num, block_id) = match &block.this_block {
+            Some(bp) => (bp.block_num, bp.block_id.clone()),
+            None => return Ok(vec![]),
+        };
+        let mut events = vec![];
+        if !self.actions.is_empty() {
+            for trace in &block.traces {
+                let tt = trace.transaction_trace();
+                for at in &tt.action_traces {
+                    if self.actions.iter().any(|f| f.matches(at)) {
+                        events.push(Event {
+                            kind: EventKind::Action,
+                            block_num,
+                            block_id: block_id.clone(),
+                            trx_id: Some(tt.id.clone()),
+                            name: format!("{}:{}", at.act().account, at.act().name),
+                            key: String::from(at.receiver()),
+                            data: serde_json::to_value(at)?,
+                        });
+                    }
+                }
+            }
+        }
+        if !self.deltas.is_empty() {
+            for delta in &block.deltas {
+                for row in &delta.rows {
+                    if self
+                        .deltas
+                        .iter()
+                        .any(|f| f.matches(&delta.name, &row.data))
+                    {
+                        let key = match contract_table_of(&row.data) {
+                            Some((code, _)) => String::from(code),
+                            None => delta.name.clone(),
+                        };
+                        events.push(Event {
+                            kind: if row.present {
+                                EventKind::Delta
+                            } else {
+                                EventKind::DeltaRemoved
+                            },
+                            block_num,
+                            block_id: block_id.clone(),
+                            trx_id: None,
+                            name: delta.name.clone(),
+                            key,
+                            data: serde_json::to_value(&row.data)?,
+                        });
+                    }
+                }
+            }
+        }
+        Ok(events)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EventKind {
+    Action,
+    Delta,
+    DeltaRemoved,
+    /// everything after `block_num` has been forked out
+    Undo,
+}
+
+/// an action trace or delta row picked out of a block, ready to be sent somewhere
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Event {
+    pub kind: EventKind,
+    pub block_num: u32,
+    pub block_id: String,
+    pub trx_id: Option<String>,
+    /// `account:name` for actions, the table type for deltas
+    pub name: String,
+    /// receiver for actions, contract (or table type) for deltas
+    pub key: String,
+    pub data: Value,
+}
+
+impl Event {
+    pub fn undo(block_num: u32) -> Event {
+        Event {
+            kind: EventKind::Undo,
+            block_num,
+            block_id: String::new(),
+            trx_id: None,
+            name: String::from("undo"),
+            key: String::new(),
+            data: Value::Null,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shipper_types::tests::{block, trace};
+    use crate::shipper_types::{
+        AccountMetadata, AccountMetadataV0, ContractRowV0, TableDeltaEx, TableRowEx,
+    };
+    use chrono::Utc;
+
+    fn contract_row(code: &str, table: &str, present: bool) -> TableRowEx {
+        TableRowEx {
+            present,
+            data: TableRowTypes::contract_row(ContractRow::contract_row_v0(ContractRowV0 {
+                code: String::from(code),
+                scope: String::from("alice"),
+                table: String::from(table),
+                primary_key: String::from("1"),
+                payer: String::from("alice"),
+                value: String::new(),
+            })),
+        }
+    }
+
+    fn account_metadata() -> TableRowEx {
+        TableRowEx {
+            present: true,
+            data: TableRowTypes::account_metadata(AccountMetadata::account_metadata_v0(
+                AccountMetadataV0 {
+                    name: String::from("alice"),
+                    privileged: false,
+                    last_code_update: Utc::now(),
+                    code: None,
+                },
+            )),
+        }
+    }
+
+    fn test_block() -> GetBlocksResultV0Ex {
+        let mut b = block(
+            10,
+            9,
+            vec![
+                TableDeltaEx {
+                    name: String::from("contract_row"),
+                    rows: vec![
+                        contract_row("eosio.token", "accounts", true),
+                        contract_row("eosio.token", "stat", true),
+                        contract_row("other", "accounts", false),
+                    ],
+                },
+                TableDeltaEx {
+                    name: String::from("account_metadata"),
+                    rows: vec![account_metadata()],
+                },
+            ],
+        );
+        b.traces = vec![trace(
+            "t1",
+            &[
+                ("eosio.token", "transfer", Some(1)),
+                ("alice", "transfer", Some(2)),
+            ],
+        )];
+        b
+    }
+
+    fn action_filter(s: &str) -> ActionFilter {
+        s.parse().unwrap()
+    }
+
+    fn delta_filter(s: &str) -> DeltaFilter {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn parses_action_filters() {
+        assert_eq!(
+            action_filter("eosio.token:transfer@alice"),
+            ActionFilter {
+                account: Some(String::from("eosio.token")),
+                name: Some(String::from("transfer")),
+                receiver: Some(String::from("alice")),
+            }
+        );
+        assert_eq!(
+            action_filter("eosio.token"),
+            ActionFilter {
+                account: Some(String::from("eosio.token")),
+                ..ActionFilter::default()
+            }
+        );
+        assert_eq!(
+            action_filter(":transfer"),
+            ActionFilter {
+                name: Some(String::from("transfer")),
+                ..ActionFilter::default()
+            }
+        );
+        assert_eq!(action_filter("*:*@*"), ActionFilter::default());
+        assert!("a:b:c".parse::<ActionFilter>().is_err());
+    }
+
+    #[test]
+    fn parses_delta_filters() {
+        assert_eq!(
+            delta_filter("contract_row:eosio.token:accounts"),
+            DeltaFilter {
+                name: Some(String::from("contract_row")),
+                code: Some(String::from("eosio.token")),
+                table: Some(String::from("accounts")),
+            }
+        );
+        assert_eq!(
+            delta_filter("contract_row::accounts"),
+            DeltaFilter {
+                name: Some(String::from("contract_row")),
+                table: Some(String::from("accounts")),
+                ..DeltaFilter::default()
+            }
+        );
+        assert!("a:b:c:d".parse::<DeltaFilter>().is_err());
+    }
+
+    #[test]
+    fn matches_delta_rows() {
+        let accounts = contract_row("eosio.token", "accounts", true).data;
+        let metadata = account_metadata().data;
+        assert!(
+            delta_filter("contract_row:eosio.token:accounts").matches("contract_row", &accounts)
+        );
+        assert!(!delta_filter("contract_row:eosio.token:stat").matches("contract_row", &accounts));
+        assert!(delta_filter("*:eosio.token").matches("contract_row", &accounts));
+        assert!(delta_filter("account_metadata").matches("account_metadata", &metadata));
+        // code and table only apply to contract rows
+        assert!(!delta_filter("account_metadata:alice").matches("account_metadata", &metadata));
+    }
+
+    #[test]
+    fn picks_events_out_of_a_block() {
+        let filter = EventFilter {
+            actions: vec![action_filter("eosio.token:transfer@eosio.token")],
+            deltas: vec![
+                delta_filter("contract_row::accounts"),
+                delta_filter("account_metadata"),
+            ],
+        };
+        let events = filter.events(&test_block()).unwrap();
+        let summary: Vec<(EventKind, &str, &str)> = events
+            .iter()
+            .map(|e| (e.kind, e.name.as_str(), e.key.as_str()))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (EventKind::Action, "eosio.token:transfer", "eosio.token"),
+                (EventKind::Delta, "contract_row", "eosio.token"),
+                (EventKind::DeltaRemoved, "contract_row", "other"),
+                (EventKind::Delta, "account_metadata", "account_metadata"),
+            ]
+        );
+        assert!(events.iter().all(|e| e.block_num == 10));
+        assert_eq!(events[0].trx_id.as_deref(), Some("t1"));
+        assert_eq!(events[1].trx_id, None);
+
+        assert!(EventFilter::default()
+            .events(&test_block())
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn events_serialize_with_snake_case_kinds() {
+        let json = serde_json::to_value(Event::undo(7)).unwrap();
+        assert_eq!(json["kind"], "undo");
+        assert_eq!(json["block_num"], 7);
+        let removed: Event = serde_json::from_value(serde_json::json!({
+            "kind": "delta_removed",
+            "block_num": 1,
+            "block_id": "",
+            "trx_id": null,
+            "name": "contract_row",
+            "key": "eosio.token",
+            "data": null,
+        }))
+        .unwrap();
+        assert_eq!(removed.kind, EventKind::DeltaRemoved);
+    }
+}
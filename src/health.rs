@@ -0,0 +1,406 @@
+use crate::capabilities::ServerCapabilities;
+use crate::client::ServerStatus;
+use crate::shipper_types::GetStatusResponseV0;
+use serde::Serialize;
+use std::fmt;
+
+/// blocks `begin` up to but not including `end`, like the ranges in a status
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct BlockRange {
+    pub begin: u32,
+    pub end: u32,
+}
+
+impl BlockRange {
+    pub fn new(begin: u32, end: u32) -> BlockRange {
+        BlockRange { begin, end }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.begin >= self.end
+    }
+
+    /// the blocks in both
+    pub fn intersect(&self, other: &BlockRange) -> BlockRange {
+        BlockRange {
+            begin: self.begin.max(other.begin),
+            end: self.end.min(other.end),
+        }
+    }
+}
+
+impl fmt::Display for BlockRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_empty() {
+            write!(f, "none")
+        } else {
+            write!(f, "{} to {}", self.begin, self.end - 1)
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Availability {
+    Full,
+    Partial,
+    None,
+}
+
+/// whether the server has one kind of data for the requested blocks
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct RangeCheck {
+    /// `traces`, `deltas` or `finality_data`
+    pub data: &'static str,
+    pub requested: BlockRange,
+    pub available: BlockRange,
+    pub availability: Availability,
+}
+
+impl RangeCheck {
+    fn new(data: &'static str, requested: BlockRange, available: BlockRange) -> RangeCheck {
+        let covered = requested.intersect(&available);
+        let availability = if requested.is_empty() || covered == requested {
+            Availability::Full
+        } else if covered.is_empty() {
+            Availability::None
+        } else {
+            Availability::Partial
+        };
+        RangeCheck {
+            data,
+            requested,
+            available,
+            availability,
+        }
+    }
+
+    /// what an operator should know, `None` when everything is there
+    pub fn warning(&self) -> Option<String> {
+        if self.availability == Availability::Full {
+            return None;
+        }
+        if self.available.is_empty() {
+            return Some(format!("the server has no {}", self.data));
+        }
+        if self.available.begin > self.requested.begin && self.available.end >= self.requested.end {
+            return Some(format!(
+                "{} before block {} have been pruned or were never recorded, requested from {}",
+                self.data, self.available.begin, self.requested.begin
+            ));
+        }
+        Some(format!(
+            "{} are only available for blocks {}, requested {}",
+            self.data, self.available, self.requested
+        ))
+    }
+}
+
+/// Checks the server has what a request for `requested` needs. Blocks past the head haven't
+/// been produced yet and aren't counted, so a request that follows the chain passes.
+pub fn check_ranges(
+    status: &GetStatusResponseV0,
+    requested: BlockRange,
+    traces: bool,
+    deltas: bool,
+    finality_data: bool,
+) -> Vec<RangeCheck> {
+    let requested = BlockRange {
+        begin: requested.begin,
+        end: requested.end.min(status.head.block_num.saturating_add(1)),
+    };
+    let mut checks = vec![];
+    if traces {
+        checks.push(RangeCheck::new(
+            "traces",
+            requested,
+            BlockRange::new(status.trace_begin_block, status.trace_end_block),
+        ));
+    }
+    if deltas {
+        checks.push(RangeCheck::new(
+            "deltas",
+            requested,
+            BlockRange::new(status.chain_state_begin_block, status.chain_state_end_block),
+        ));
+    }
+    if finality_data {
+        checks.push(RangeCheck::new(
+            "finality_data",
+            requested,
+            BlockRange::new(
+                status.finality_data_begin_block.unwrap_or(0),
+                status.finality_data_end_block.unwrap_or(0),
+            ),
+        ));
+    }
+    checks
+}
+
+/// Everything worth knowing about one endpoint, see `eosio-ship status`.
+///
+/// `Display` gives a table for people; serialize it for scripts.
+#[derive(Debug, Clone, Serialize)]
+pub struct HealthReport {
+    pub endpoint: String,
+    pub status: GetStatusResponseV0,
+    /// blocks between the head and the last irreversible block
+    pub irreversible_lag: u32,
+    pub capabilities: ServerCapabilities,
+    pub round_trip_ms: f64,
+    /// the requested blocks against what the server has, empty when nothing was requested
+    pub ranges: Vec<RangeCheck>,
+    pub warnings: Vec<String>,
+}
+
+impl HealthReport {
+    /// the report for `server`, checked against `requested` for the data that will be fetched
+    pub fn new(
+        endpoint: &str,
+        server: ServerStatus,
+        requested: Option<BlockRange>,
+        traces: bool,
+        deltas: bool,
+        finality_data: bool,
+    ) -> HealthReport {
+        let status = server.status;
+        let ranges = match requested {
+            Some(r) => check_ranges(&status, r, traces, deltas, finality_data),
+            None => vec![],
+        };
+        let mut warnings: Vec<String> = ranges.iter().filter_map(|r| r.warning()).collect();
+        if status.chain_id.is_none() {
+            warnings.push(String::from("the server doesn't report a chain id"));
+        }
+        if finality_data && !server.capabilities.blocks_request_v1() {
+            warnings.push(String::from("the server can't send finality data"));
+        }
+        HealthReport {
+            endpoint: String::from(endpoint),
+            irreversible_lag: status
+                .head
+                .block_num
+                .saturating_sub(status.last_irreversible.block_num),
+            status,
+            capabilities: server.capabilities,
+            round_trip_ms: server.round_trip.as_secs_f64() * 1000.0,
+            ranges,
+            warnings,
+        }
+    }
+
+    /// every requested block has everything that will be fetched
+    pub fn is_complete(&self) -> bool {
+        self.ranges
+            .iter()
+            .all(|r| r.availability == Availability::Full)
+    }
+}
+
+fn yes_no(b: bool) -> &'static str {
+    if b {
+        "yes"
+    } else {
+        "no"
+    }
+}
+
+impl fmt::Display for HealthReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = &self.status;
+        let c = &self.capabilities;
+        writeln!(f, "{:<20}{}", "endpoint", self.endpoint)?;
+        writeln!(
+            f,
+            "{:<20}{}",
+            "chain id",
+            s.chain_id.as_deref().unwrap_or("-")
+        )?;
+        writeln!(f, "{:<20}{} {}", "head", s.head.block_num, s.head.block_id)?;
+        writeln!(
+            f,
+            "{:<20}{} {} ({} behind)",
+            "last irreversible",
+            s.last_irreversible.block_num,
+            s.last_irreversible.block_id,
+            self.irreversible_lag
+        )?;
+        writeln!(
+            f,
+            "{:<20}{}",
+            "traces",
+            BlockRange::new(s.trace_begin_block, s.trace_end_block)
+        )?;
+        writeln!(
+            f,
+            "{:<20}{}",
+            "chain state",
+            BlockRange::new(s.chain_state_begin_block, s.chain_state_end_block)
+        )?;
+        if let (Some(begin), Some(end)) = (s.finality_data_begin_block, s.finality_data_end_block) {
+            writeln!(f, "{:<20}{}", "finality data", BlockRange::new(begin, end))?;
+        }
+        writeln!(f, "{:<20}{}", "abi version", c.abi_version)?;
+        writeln!(f, "{:<20}{}", "requests", c.requests.join(", "))?;
+        writeln!(f, "{:<20}{}", "results", c.results.join(", "))?;
+        writeln!(f, "{:<20}{}", "tables", c.tables.len())?;
+        writeln!(f, "{:<20}{}", "action_trace_v1", yes_no(c.action_trace_v1))?;
+        writeln!(f, "{:<20}{}", "prunable data", yes_no(c.prunable_data))?;
+        writeln!(f, "{:<20}{:.1} ms", "round trip", self.round_trip_ms)?;
+        for r in &self.ranges {
+            writeln!(
+                f,
+                "{:<20}{}: {:?}",
+                format!("requested {}", r.data),
+                r.requested,
+                r.availability
+            )?;
+        }
+        for w in &self.warnings {
+            writeln!(f, "warning: {}", w)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shipper_types::BlockPosition;
+    use std::time::Duration;
+
+    fn position(block_num: u32) -> BlockPosition {
+        BlockPosition {
+            block_num,
+            block_id: String::new(),
+        }
+    }
+
+    /// head at 1000, irreversible at 900, traces and deltas for `blocks`
+    fn status(blocks: BlockRange, finality_data: Option<BlockRange>) -> GetStatusResponseV0 {
+        GetStatusResponseV0 {
+            head: position(1000),
+            last_irreversible: position(900),
+            trace_begin_block: blocks.begin,
+            trace_end_block: blocks.end,
+            chain_state_begin_block: blocks.begin,
+            chain_state_end_block: blocks.end,
+            chain_id: Some(String::from("aca376f2")),
+            finality_data_begin_block: finality_data.map(|r| r.begin),
+            finality_data_end_block: finality_data.map(|r| r.end),
+        }
+    }
+
+    fn traces(available: BlockRange, requested: BlockRange) -> RangeCheck {
+        check_ranges(&status(available, None), requested, true, false, false).remove(0)
+    }
+
+    #[test]
+    fn availability_of_the_requested_blocks() {
+        let available = BlockRange::new(100, 1001);
+        let check = |begin, end| traces(available, BlockRange::new(begin, end)).availability;
+        assert_eq!(check(100, 1001), Availability::Full);
+        assert_eq!(check(500, 600), Availability::Full);
+        assert_eq!(check(50, 600), Availability::Partial);
+        assert_eq!(check(10, 100), Availability::None);
+        assert_eq!(check(100, 100), Availability::Full);
+    }
+
+    #[test]
+    fn blocks_past_the_head_arent_requested_yet() {
+        let check = traces(BlockRange::new(100, 1001), BlockRange::new(500, u32::MAX));
+        assert_eq!(check.requested, BlockRange::new(500, 1001));
+        assert_eq!(check.availability, Availability::Full);
+        assert_eq!(check.warning(), None);
+
+        // the server is behind its own head
+        let check = traces(BlockRange::new(100, 990), BlockRange::new(500, u32::MAX));
+        assert_eq!(check.availability, Availability::Partial);
+    }
+
+    #[test]
+    fn warnings_tell_pruned_from_short_ranges() {
+        let pruned = traces(BlockRange::new(100, 1001), BlockRange::new(50, 600));
+        assert_eq!(
+            pruned.warning().unwrap(),
+            "traces before block 100 have been pruned or were never recorded, requested from 50"
+        );
+
+        let short = traces(BlockRange::new(100, 500), BlockRange::new(200, 600));
+        assert_eq!(
+            short.warning().unwrap(),
+            "traces are only available for blocks 100 to 499, requested 200 to 599"
+        );
+        // missing at both ends isn't only pruning
+        let both = traces(BlockRange::new(100, 500), BlockRange::new(50, 600));
+        assert!(both
+            .warning()
+            .unwrap()
+            .contains("only available for blocks 100 to 499"));
+    }
+
+    #[test]
+    fn empty_ranges_and_missing_finality_data() {
+        let empty = traces(BlockRange::new(0, 0), BlockRange::new(50, 600));
+        assert_eq!(empty.availability, Availability::None);
+        assert_eq!(empty.warning().unwrap(), "the server has no traces");
+
+        let checks = check_ranges(
+            &status(BlockRange::new(100, 1001), None),
+            BlockRange::new(500, 600),
+            true,
+            true,
+            true,
+        );
+        let data: Vec<&str> = checks.iter().map(|c| c.data).collect();
+        assert_eq!(data, vec!["traces", "deltas", "finality_data"]);
+        assert_eq!(checks[2].availability, Availability::None);
+        assert_eq!(
+            checks[2].warning().unwrap(),
+            "the server has no finality_data"
+        );
+
+        let checks = check_ranges(
+            &status(BlockRange::new(100, 1001), Some(BlockRange::new(550, 1001))),
+            BlockRange::new(500, 600),
+            false,
+            false,
+            true,
+        );
+        assert_eq!(checks[0].availability, Availability::Partial);
+    }
+
+    fn report(status: GetStatusResponseV0, requested: Option<BlockRange>) -> HealthReport {
+        let server = ServerStatus {
+            capabilities: ServerCapabilities::default(),
+            status,
+            round_trip: Duration::from_millis(3),
+        };
+        HealthReport::new("ws://localhost:8080", server, requested, true, true, false)
+    }
+
+    #[test]
+    fn complete_only_when_every_range_is_full() {
+        let available = BlockRange::new(100, 1001);
+        let full = report(status(available, None), Some(BlockRange::new(200, 300)));
+        assert!(full.is_complete());
+        assert!(full.warnings.is_empty());
+        assert_eq!(full.irreversible_lag, 100);
+
+        let partial = report(status(available, None), Some(BlockRange::new(50, 300)));
+        assert!(!partial.is_complete());
+        // one for traces, one for deltas
+        assert_eq!(partial.warnings.len(), 2);
+
+        // nothing requested, nothing to miss
+        let mut no_chain_id = status(BlockRange::new(0, 0), None);
+        no_chain_id.chain_id = None;
+        let report = report(no_chain_id, None);
+        assert!(report.ranges.is_empty());
+        assert!(report.is_complete());
+        assert_eq!(
+            report.warnings,
+            vec!["the server doesn't report a chain id"]
+        );
+    }
+}
@@ -0,0 +1,241 @@
+use crate::errors::{ErrorKind, Result};
+use std::time::{Duration, Instant};
+use tokio_tungstenite::tungstenite::Message;
+
+/// how often the socket is checked while waiting for a message
+pub(crate) const POLL: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone)]
+pub struct KeepaliveConfig {
+    /// ping after this long without hearing anything, `None` to never ping
+    pub ping_interval: Option<Duration>,
+    /// the connection is dead if a ping gets no answer in this time
+    pub pong_timeout: Duration,
+    /// a `get_blocks` request is stalled when nothing arrives for this long while the head is
+    /// ahead of the last block received. `None` turns the check off.
+    pub stall_timeout: Option<Duration>,
+}
+
+impl Default for KeepaliveConfig {
+    fn default() -> KeepaliveConfig {
+        KeepaliveConfig {
+            ping_interval: Some(Duration::from_secs(15)),
+            pong_timeout: Duration::from_secs(10),
+            stall_timeout: Some(Duration::from_secs(30)),
+        }
+    }
+}
+
+/// Watches one connection for silence.
+///
+/// Feed it everything received, the block positions and each `get_blocks` request sent, then
+/// call `check` whenever `POLL` passes. It hands back a ping to send when one is due, and
+/// fails with `NoPong` or `Stalled`.
+pub(crate) struct Watchdog {
+    config: KeepaliveConfig,
+    now: Box<dyn Fn() -> Instant + Send>,
+    last_heard: Instant,
+    ping_sent: Option<Instant>,
+    last_block_at: Instant,
+    /// (head, last block received) from the last block result
+    head: u32,
+    last_block: u32,
+    end_block: u32,
+    awaiting_blocks: bool,
+    last_probe: Option<Instant>,
+}
+
+impl Watchdog {
+    pub(crate) fn new(config: KeepaliveConfig) -> Watchdog {
+        Watchdog::with_clock(config, Box::new(Instant::now))
+    }
+
+    /// a watchdog that reads the time from `now`
+    fn with_clock(config: KeepaliveConfig, now: Box<dyn Fn() -> Instant + Send>) -> Watchdog {
+        let start = now();
+        Watchdog {
+            config,
+            now,
+            last_heard: start,
+            ping_sent: None,
+            last_block_at: start,
+            head: 0,
+            last_block: 0,
+            end_block: 0,
+            awaiting_blocks: false,
+            last_probe: None,
+        }
+    }
+
+    pub(crate) fn heard(&mut self) {
+        self.last_heard = (self.now)();
+        self.ping_sent = None;
+    }
+
+    pub(crate) fn requested_blocks(&mut self, start_block: u32, end_block: u32) {
+        self.awaiting_blocks = start_block < end_block;
+        self.end_block = end_block;
+        self.last_block_at = (self.now)();
+    }
+
+    pub(crate) fn block(&mut self, head: u32, block_num: u32) {
+        self.head = head;
+        self.last_block = block_num;
+        self.last_block_at = (self.now)();
+        if block_num.saturating_add(1) >= self.end_block {
+            self.awaiting_blocks = false;
+        }
+    }
+
+    /// a head seen some other way, eg from another endpoint
+    pub(crate) fn saw_head(&mut self, head: u32) {
+        self.head = self.head.max(head);
+    }
+
+    fn elapsed(&self, since: Instant) -> Duration {
+        (self.now)().saturating_duration_since(since)
+    }
+
+    fn overdue(&self) -> bool {
+        match self.config.stall_timeout {
+            Some(t) => self.awaiting_blocks && self.elapsed(self.last_block_at) >= t,
+            None => false,
+        }
+    }
+
+    /// blocks are overdue but nothing says the head has moved past the last one, so it is
+    /// worth asking elsewhere. true at most once per `stall_timeout`.
+    pub(crate) fn wants_head(&mut self) -> bool {
+        if !self.overdue() || self.head > self.last_block {
+            return false;
+        }
+        let t = self.config.stall_timeout.unwrap_or(POLL);
+        if self.last_probe.map_or(false, |p| self.elapsed(p) < t) {
+            return false;
+        }
+        self.last_probe = Some((self.now)());
+        true
+    }
+
+    pub(crate) fn check(&mut self) -> Result<Option<Message>> {
+        if let Some(sent) = self.ping_sent {
+            if self.elapsed(sent) >= self.config.pong_timeout {
+                return Err(ErrorKind::NoPong(self.config.pong_timeout.as_secs()).into());
+            }
+        } else if let Some(interval) = self.config.ping_interval {
+            if self.elapsed(self.last_heard) >= interval {
+                self.ping_sent = Some((self.now)());
+                return Ok(Some(Message::Ping(vec![])));
+            }
+        }
+        if self.overdue() && self.head > self.last_block {
+            return Err(ErrorKind::Stalled(
+                self.last_block,
+                self.elapsed(self.last_block_at).as_secs(),
+            )
+            .into());
+        }
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    type Clock = Arc<Mutex<Instant>>;
+
+    fn watchdog(ping_interval: Option<u64>, stall_timeout: Option<u64>) -> (Watchdog, Clock) {
+        let clock = Arc::new(Mutex::new(Instant::now()));
+        let config = KeepaliveConfig {
+            ping_interval: ping_interval.map(Duration::from_secs),
+            pong_timeout: Duration::from_secs(10),
+            stall_timeout: stall_timeout.map(Duration::from_secs),
+        };
+        let now = clock.clone();
+        let watchdog = Watchdog::with_clock(config, Box::new(move || *now.lock().unwrap()));
+        (watchdog, clock)
+    }
+
+    fn advance(clock: &Clock, secs: u64) {
+        *clock.lock().unwrap() += Duration::from_secs(secs);
+    }
+
+    fn pinged(watchdog: &mut Watchdog) -> bool {
+        match watchdog.check().unwrap() {
+            Some(Message::Ping(_)) => true,
+            Some(m) => panic!("unexpected {:?}", m),
+            None => false,
+        }
+    }
+
+    #[test]
+    fn pings_when_quiet_and_fails_without_a_pong() {
+        let (mut watchdog, clock) = watchdog(Some(15), None);
+        assert!(!pinged(&mut watchdog));
+        advance(&clock, 14);
+        assert!(!pinged(&mut watchdog));
+        advance(&clock, 1);
+        assert!(pinged(&mut watchdog));
+        // one ping at a time
+        advance(&clock, 9);
+        assert!(!pinged(&mut watchdog));
+
+        // the answer starts the interval again
+        watchdog.heard();
+        advance(&clock, 14);
+        assert!(!pinged(&mut watchdog));
+        advance(&clock, 1);
+        assert!(pinged(&mut watchdog));
+        advance(&clock, 10);
+        let err = watchdog.check().unwrap_err();
+        assert!(matches!(err.kind(), ErrorKind::NoPong(10)));
+    }
+
+    #[test]
+    fn stalls_while_the_head_is_ahead() {
+        let (mut watchdog, clock) = watchdog(None, Some(30));
+        // nothing requested, so nothing is overdue
+        advance(&clock, 60);
+        assert!(!pinged(&mut watchdog));
+
+        watchdog.requested_blocks(100, 200);
+        advance(&clock, 20);
+        watchdog.block(150, 100);
+        advance(&clock, 29);
+        assert!(!pinged(&mut watchdog));
+        advance(&clock, 1);
+        let err = watchdog.check().unwrap_err();
+        assert!(matches!(err.kind(), ErrorKind::Stalled(100, 30)));
+    }
+
+    #[test]
+    fn waits_at_the_head_and_asks_for_it_once() {
+        let (mut watchdog, clock) = watchdog(None, Some(30));
+        watchdog.requested_blocks(100, 200);
+        watchdog.block(120, 120);
+        advance(&clock, 30);
+        // the chain may just be slow, find out from elsewhere
+        assert!(!pinged(&mut watchdog));
+        assert!(watchdog.wants_head());
+        assert!(!watchdog.wants_head());
+
+        watchdog.saw_head(125);
+        let err = watchdog.check().unwrap_err();
+        assert!(matches!(err.kind(), ErrorKind::Stalled(120, 30)));
+    }
+
+    #[test]
+    fn finished_requests_dont_stall() {
+        let (mut watchdog, clock) = watchdog(None, Some(30));
+        watchdog.requested_blocks(100, 200);
+        watchdog.block(300, 199);
+        advance(&clock, 60);
+        assert!(!pinged(&mut watchdog));
+
+        watchdog.requested_blocks(5, 5);
+        advance(&clock, 60);
+        assert!(!pinged(&mut watchdog));
+    }
+}
@@ -1,6 +1,9 @@
-use futures_channel::mpsc::{UnboundedReceiver, UnboundedSender};
-use futures_util::{future, pin_mut, SinkExt, StreamExt};
-//use log::*;
+use futures_channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
+use futures_channel::oneshot;
+use futures_util::future::{self, Either};
+use futures_util::{pin_mut, SinkExt, StreamExt};
+use std::cell::RefCell;
+use tracing::{info, warn};
 //use std::io::prelude::*;
 use rust_embed::RustEmbed;
 use tokio_tungstenite::connect_async;
@@ -12,9 +15,31 @@ use url::Url;
 use errors::{Error, ErrorKind, Result};
 #[macro_use]
 extern crate lazy_static;
+mod abi;
+pub mod audit;
+pub mod capabilities;
+pub mod checkpoint;
+pub mod client;
+#[cfg(feature = "arrow")]
+pub mod columnar;
+pub mod contracts;
+pub mod deferred;
 pub mod errors;
+pub mod filter;
+pub mod health;
+pub mod keepalive;
+pub mod metrics;
+pub mod producers;
+pub mod resources;
+pub mod sequence;
 pub mod shipper_types;
+pub mod sinks;
+pub mod state_store;
+pub mod token;
+pub mod undo;
 
+use crate::capabilities::ServerCapabilities;
+use crate::keepalive::{KeepaliveConfig, Watchdog};
 use crate::shipper_types::{ShipRequests, ShipResultsEx};
 use libabieos_sys::{AbiFiles, ABIEOS};
 
@@ -26,8 +51,35 @@ pub const EOSIO_SYSTEM: &str = "eosio";
 
 pub async fn get_sink_stream(
     server_url: &str,
-    mut in_tx: UnboundedReceiver<ShipRequests>,
+    in_tx: UnboundedReceiver<ShipRequests>,
     out_rx: UnboundedSender<ShipResultsEx>,
+) -> Result<()> {
+    get_sink_stream_with_keepalive(server_url, in_tx, out_rx, KeepaliveConfig::default()).await
+}
+
+/// `get_sink_stream`, pinging the server when the socket is quiet. Returns `NoPong` when a ping
+/// goes unanswered, and `Stalled` when an outstanding `get_blocks_request_v0` stops delivering
+/// while the head reported in the last block is still ahead of it.
+pub async fn get_sink_stream_with_keepalive(
+    server_url: &str,
+    in_tx: UnboundedReceiver<ShipRequests>,
+    out_rx: UnboundedSender<ShipResultsEx>,
+    keepalive: KeepaliveConfig,
+) -> Result<()> {
+    let (capabilities, _) = oneshot::channel();
+    get_sink_stream_with_capabilities(server_url, in_tx, out_rx, keepalive, capabilities).await
+}
+
+/// `get_sink_stream_with_keepalive`, sending the server's capabilities on `capabilities_tx` once
+/// its ABI has been read, before any result. Servers we can't talk to are refused with
+/// `UnsupportedServer` and nothing is sent. Block requests are sent as the version the server
+/// has: v1 as v0 without `fetch_finality_data`, and v0 as v1 to servers that only have v1.
+pub async fn get_sink_stream_with_capabilities(
+    server_url: &str,
+    in_tx: UnboundedReceiver<ShipRequests>,
+    out_rx: UnboundedSender<ShipResultsEx>,
+    keepalive: KeepaliveConfig,
+    capabilities_tx: oneshot::Sender<ServerCapabilities>,
 ) -> Result<()> {
     let r = connect_async(Url::parse(server_url).expect("Can't connect to server")).await?;
     let socket = r.0;
@@ -51,52 +103,116 @@ pub async fn get_sink_stream(
                 Error::with_chain(e, "parsing shipper abi")
             })?;
 
+            let capabilities = ServerCapabilities::from_abi(&msg_text)
+                .and_then(|c| c.check().map(|_| c))
+                .map_err(|e| {
+                    shipper_abi.destroy();
+                    abi.destroy();
+                    e
+                })?;
+            let blocks_v0 = capabilities.supports_request("get_blocks_request_v0");
+            let blocks_v1 = capabilities.blocks_request_v1();
+            // nobody may be listening
+            let _ = capabilities_tx.send(capabilities);
+            let watchdog = RefCell::new(Watchdog::new(keepalive));
+            let (ping_tx, ping_rx) = unbounded::<Message>();
             let out_loop = async {
+                let mut last_block: Option<u32> = None;
                 loop {
-                    let data = stream
-                        .next()
-                        .await
-                        .unwrap()
-                        .expect("get_status_request_v0 Response error")
-                        .into_data();
+                    let msg =
+                        match tokio::time::timeout(crate::keepalive::POLL, stream.next()).await {
+                            Ok(Some(msg)) => msg?,
+                            Ok(None) => break,
+                            Err(_) => {
+                                if let Some(ping) = watchdog.borrow_mut().check()? {
+                                    let _ = ping_tx.unbounded_send(ping);
+                                }
+                                continue;
+                            }
+                        };
+                    watchdog.borrow_mut().heard();
+                    let data = match msg {
+                        Message::Binary(data) => data,
+                        Message::Close(_) => break,
+                        _ => continue,
+                    };
+                    metrics::BYTES_RECEIVED.inc_by(data.len() as i64);
 
-                    let r = ShipResultsEx::from_bin(&shipper_abi, &data).unwrap();
+                    let r = ShipResultsEx::from_bin(&shipper_abi, &data)?;
+                    if let ShipResultsEx::BlockResult(br) = &r {
+                        if let Some(bp) = &br.this_block {
+                            if last_block.map_or(false, |n| bp.block_num <= n) {
+                                metrics::FORKS.inc();
+                            }
+                            last_block = Some(bp.block_num);
+                            watchdog.borrow_mut().block(br.head.block_num, bp.block_num);
+                        }
+                        metrics::observe_block(br);
+                    }
 
-                    out_rx.unbounded_send(r).expect("Didn't send");
+                    // counted before it's sent so a fast consumer can't take the depth below 0
+                    metrics::result_queued();
+                    if out_rx.unbounded_send(r).is_err() {
+                        // nobody is listening any more
+                        metrics::result_taken();
+                        break;
+                    }
                 }
+                info!("socket closed");
+                Ok::<(), Error>(())
             };
             let in_loop = async {
-                loop {
-                    let data: ShipRequests = in_tx.next().await.unwrap();
-
-                    match data {
-                        ShipRequests::get_status_request_v0(r) => {
-                            let req = r.to_bin(&shipper_abi).unwrap();
-                            let msg = Message::Binary(req);
-                            sink.send(msg).await.expect("Didn't send");
+                // pings from the out_loop go out alongside the requests
+                let mut outgoing = futures_util::stream::select(
+                    in_tx.map(Either::Left),
+                    ping_rx.map(Either::Right),
+                );
+                while let Some(data) = outgoing.next().await {
+                    let data = match data {
+                        Either::Left(data) => data,
+                        Either::Right(ping) => {
+                            sink.send(ping).await?;
+                            continue;
                         }
-                        ShipRequests::get_blocks_request_v0(br) => {
-                            let req = br.to_bin(&shipper_abi).unwrap();
-                            let msg = Message::Binary(req);
-                            sink.send(msg).await.expect("Didn't send");
-                        }
-                        ShipRequests::get_blocks_ack_request_v0(ar) => {
-                            let req = ar.to_bin(&shipper_abi).unwrap();
-                            let msg = Message::Binary(req);
-                            sink.send(msg).await.expect("Didn't send");
+                    };
+                    if let ShipRequests::quit = data {
+                        info!("quit requested, closing the socket");
+                        sink.close().await?;
+                        break;
+                    }
+                    let data = match data {
+                        ShipRequests::get_blocks_request_v1(r) if !blocks_v1 => {
+                            warn!("server has no get_blocks_request_v1, sending v0");
+                            ShipRequests::get_blocks_request_v0(r.to_v0())
                         }
-                        ShipRequests::quit => {
-                            eprintln!("QUIT");
-                            &sink.close();
-                            break;
+                        ShipRequests::get_blocks_request_v0(r) if !blocks_v0 => {
+                            ShipRequests::get_blocks_request_v1(r.to_v1())
                         }
+                        data => data,
+                    };
+                    match &data {
+                        ShipRequests::get_blocks_request_v0(r) => watchdog
+                            .borrow_mut()
+                            .requested_blocks(r.start_block_num, r.end_block_num),
+                        ShipRequests::get_blocks_request_v1(r) => watchdog
+                            .borrow_mut()
+                            .requested_blocks(r.start_block_num, r.end_block_num),
+                        _ => {}
                     }
+                    let req = data.to_bin(&shipper_abi)?;
+                    sink.send(Message::Binary(req)).await?;
                 }
+                Ok::<(), Error>(())
             };
             pin_mut!(in_loop, out_loop);
-            future::join(in_loop, out_loop).await;
-
-            Ok(())
+            // whichever side finishes first (quit, socket closed, or an error) ends the stream
+            let r = match future::select(in_loop, out_loop).await {
+                Either::Left((r, _)) => r,
+                Either::Right((r, _)) => r,
+            };
+            shipper_abi.destroy();
+            abi.destroy();
+            r
         }
         None => {
             abi.destroy();
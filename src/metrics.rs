@@ -0,0 +1,295 @@
+//! Prometheus metrics for the stream. Without the `metrics` feature they are no-ops and
+//! prometheus isn't a dependency.
+use crate::shipper_types::{GetBlocksResultV0Ex, ShipResultsEx};
+use futures_channel::mpsc::UnboundedReceiver;
+use futures_util::stream::Stream;
+#[cfg(not(feature = "metrics"))]
+use noop::{Histogram, HistogramVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec};
+#[cfg(feature = "metrics")]
+use prometheus::{
+    register_histogram, register_histogram_vec, register_int_counter, register_int_counter_vec,
+    register_int_gauge, register_int_gauge_vec, Histogram, HistogramVec, IntCounter, IntCounterVec,
+    IntGauge, IntGaugeVec,
+};
+use std::pin::Pin;
+use std::sync::Mutex;
+use std::task::{Context, Poll};
+use std::time::Instant;
+
+/// stand-ins with the same methods as the prometheus types, that do nothing
+#[cfg(not(feature = "metrics"))]
+mod noop {
+    #[derive(Debug, Clone, Copy, Default)]
+    pub struct Metric;
+
+    impl Metric {
+        pub fn inc(&self) {}
+        pub fn inc_by(&self, _v: i64) {}
+        pub fn dec(&self) {}
+        pub fn set(&self, _v: i64) {}
+        pub fn observe(&self, _v: f64) {}
+        pub fn with_label_values(&self, _labels: &[&str]) -> Metric {
+            Metric
+        }
+        pub fn start_timer(&self) -> Metric {
+            Metric
+        }
+        pub fn observe_duration(self) {}
+    }
+
+    pub type IntCounter = Metric;
+    pub type IntCounterVec = Metric;
+    pub type IntGauge = Metric;
+    pub type IntGaugeVec = Metric;
+    pub type Histogram = Metric;
+    pub type HistogramVec = Metric;
+}
+
+#[cfg(not(feature = "metrics"))]
+macro_rules! noop_metric {
+    ($($arg:tt)*) => {
+        Ok::<noop::Metric, ()>(noop::Metric)
+    };
+}
+#[cfg(not(feature = "metrics"))]
+use {
+    noop_metric as register_histogram, noop_metric as register_histogram_vec,
+    noop_metric as register_int_counter, noop_metric as register_int_counter_vec,
+    noop_metric as register_int_gauge, noop_metric as register_int_gauge_vec,
+};
+
+lazy_static! {
+    pub static ref HEAD_LAG: IntGauge = register_int_gauge!(
+        "ship_head_lag_blocks",
+        "blocks between the chain head and the last block received"
+    )
+    .unwrap();
+    pub static ref IRREVERSIBLE_LAG: IntGauge = register_int_gauge!(
+        "ship_irreversible_lag_blocks",
+        "blocks between the last block received and the last irreversible block"
+    )
+    .unwrap();
+    pub static ref BLOCK_NUM: IntGauge =
+        register_int_gauge!("ship_block_num", "number of the last block received").unwrap();
+    pub static ref BLOCKS: IntCounter =
+        register_int_counter!("ship_blocks_total", "blocks received").unwrap();
+    pub static ref BLOCKS_PER_SECOND: IntGauge = register_int_gauge!(
+        "ship_blocks_per_second",
+        "blocks received over the last measuring window"
+    )
+    .unwrap();
+    pub static ref DECODE_SECONDS: HistogramVec = register_histogram_vec!(
+        "ship_decode_seconds",
+        "time spent decoding a result, by stage",
+        &["stage"],
+        vec![0.0001, 0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0]
+    )
+    .unwrap();
+    pub static ref BYTES_RECEIVED: IntCounter = register_int_counter!(
+        "ship_bytes_received_total",
+        "bytes received from the state history socket"
+    )
+    .unwrap();
+    pub static ref RECONNECTS: IntCounter = register_int_counter!(
+        "ship_reconnects_total",
+        "reconnects to a state history endpoint"
+    )
+    .unwrap();
+    pub static ref STALLS: IntCounter = register_int_counter!(
+        "ship_stalls_total",
+        "get_blocks requests that stopped delivering while the head moved on"
+    )
+    .unwrap();
+    pub static ref FORKS: IntCounter = register_int_counter!(
+        "ship_forks_total",
+        "times a block at or below the previous one was received"
+    )
+    .unwrap();
+    pub static ref INCOMPLETE_BLOCKS: IntCounter = register_int_counter!(
+        "ship_incomplete_blocks_total",
+        "blocks received without traces, deltas or the block that were asked for"
+    )
+    .unwrap();
+    pub static ref TRANSACTION_DECODE_ERRORS: IntCounter = register_int_counter!(
+        "ship_transaction_decode_errors_total",
+        "packed transactions that couldn't be inflated or decoded"
+    )
+    .unwrap();
+    pub static ref QUEUE_DEPTH: IntGauge = register_int_gauge!(
+        "ship_queue_depth",
+        "decoded results waiting for the consumer"
+    )
+    .unwrap();
+    pub static ref PRODUCER_BLOCKS: IntCounterVec = register_int_counter_vec!(
+        "ship_producer_blocks_total",
+        "blocks produced, by producer",
+        &["producer"]
+    )
+    .unwrap();
+    pub static ref PRODUCER_MISSED_SLOTS: IntCounterVec = register_int_counter_vec!(
+        "ship_producer_missed_slots_total",
+        "block slots left empty, by the producer scheduled for them",
+        &["producer"]
+    )
+    .unwrap();
+    pub static ref PRODUCER_DRIFT_MS: IntGaugeVec = register_int_gauge_vec!(
+        "ship_producer_block_drift_ms",
+        "time between the block timestamp and receiving it, for the producer's last block",
+        &["producer"]
+    )
+    .unwrap();
+    pub static ref SCHEDULE_VERSION: IntGauge = register_int_gauge!(
+        "ship_producer_schedule_version",
+        "version of the active producer schedule"
+    )
+    .unwrap();
+    pub static ref BLOCK_TRANSACTIONS: Histogram = register_histogram!(
+        "ship_block_transactions",
+        "transactions per block",
+        vec![0.0, 1.0, 5.0, 10.0, 50.0, 100.0, 500.0, 1000.0, 5000.0]
+    )
+    .unwrap();
+    pub static ref BLOCK_CPU_US: Histogram = register_histogram!(
+        "ship_block_cpu_us",
+        "billed CPU per block in microseconds",
+        vec![0.0, 1000.0, 5000.0, 10000.0, 50000.0, 100000.0, 150000.0, 200000.0]
+    )
+    .unwrap();
+    static ref RATE: Mutex<(Instant, u64)> = Mutex::new((Instant::now(), 0));
+}
+
+/// times `f` into `ship_decode_seconds{stage=...}`
+pub fn time_stage<T, F: FnOnce() -> T>(stage: &str, f: F) -> T {
+    let timer = DECODE_SECONDS.with_label_values(&[stage]).start_timer();
+    let r = f();
+    timer.observe_duration();
+    r
+}
+
+/// updates the lag, block and rate metrics from a received block
+pub fn observe_block(block: &GetBlocksResultV0Ex) {
+    let this_block = match &block.this_block {
+        Some(bp) => bp.block_num as i64,
+        None => return,
+    };
+    HEAD_LAG.set(block.head.block_num as i64 - this_block);
+    IRREVERSIBLE_LAG.set(this_block - block.last_irreversible.block_num as i64);
+    BLOCK_NUM.set(this_block);
+    BLOCKS.inc();
+
+    let mut rate = RATE.lock().unwrap();
+    rate.1 += 1;
+    let elapsed = rate.0.elapsed().as_secs_f64();
+    if elapsed >= 1.0 {
+        BLOCKS_PER_SECOND.set((rate.1 as f64 / elapsed).round() as i64);
+        *rate = (Instant::now(), 0);
+    }
+}
+
+/// `get_sink_stream` calls this for every result it puts on the channel
+pub fn result_queued() {
+    QUEUE_DEPTH.inc();
+}
+
+/// consumers of `get_sink_stream` call this for every result they take off the channel, so
+/// `ship_queue_depth` shows how far behind they are. `QueuedResults` does it for them.
+pub fn result_taken() {
+    QUEUE_DEPTH.dec();
+}
+
+/// the receiving end of `get_sink_stream`, calling `result_taken` for every result it yields
+pub struct QueuedResults(UnboundedReceiver<ShipResultsEx>);
+
+impl QueuedResults {
+    pub fn new(results: UnboundedReceiver<ShipResultsEx>) -> QueuedResults {
+        QueuedResults(results)
+    }
+}
+
+impl Stream for QueuedResults {
+    type Item = ShipResultsEx;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<ShipResultsEx>> {
+        let next = Pin::new(&mut self.0).poll_next(cx);
+        if let Poll::Ready(Some(_)) = next {
+            result_taken();
+        }
+        next
+    }
+}
+
+/// the default registry in the Prometheus text format, empty without the `metrics` feature
+#[cfg(not(feature = "metrics"))]
+pub fn render() -> String {
+    String::new()
+}
+
+/// the default registry in the Prometheus text format
+#[cfg(feature = "metrics")]
+pub fn render() -> String {
+    use prometheus::Encoder;
+    let encoder = prometheus::TextEncoder::new();
+    let mut buffer = vec![];
+    encoder
+        .encode(&prometheus::gather(), &mut buffer)
+        .unwrap_or_default();
+    String::from_utf8(buffer).unwrap_or_default()
+}
+
+/// Serves `render()` on `GET /metrics` until the future is dropped.
+#[cfg(feature = "metrics-server")]
+pub async fn serve(addr: std::net::SocketAddr) -> crate::errors::Result<()> {
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Body, Request, Response, Server, StatusCode};
+    use std::convert::Infallible;
+
+    async fn handle(req: Request<Body>) -> std::result::Result<Response<Body>, Infallible> {
+        let response = if req.uri().path() == "/metrics" {
+            Response::builder()
+                .header("Content-Type", "text/plain; version=0.0.4")
+                .body(Body::from(render()))
+        } else {
+            Response::builder()
+                .status(StatusCode::NOT_FOUND)
+                .body(Body::empty())
+        };
+        Ok(response.unwrap())
+    }
+
+    let make_svc = make_service_fn(|_| async { Ok::<_, Infallible>(service_fn(handle)) });
+    Server::bind(&addr).serve(make_svc).await?;
+    Ok(())
+}
+
+#[cfg(all(test, feature = "metrics"))]
+mod tests {
+    use super::*;
+    use crate::shipper_types::tests::block;
+    use futures_channel::mpsc::unbounded;
+    use futures_util::{FutureExt, StreamExt};
+
+    #[test]
+    fn queued_results_are_taken_off_the_depth() {
+        let (tx, rx) = unbounded();
+        let mut results = QueuedResults::new(rx);
+        let depth = QUEUE_DEPTH.get();
+        for block_num in 1..=3 {
+            result_queued();
+            tx.unbounded_send(ShipResultsEx::BlockResult(block(block_num, 0, vec![])))
+                .unwrap();
+        }
+        assert_eq!(QUEUE_DEPTH.get(), depth + 3);
+
+        assert!(results.next().now_or_never().unwrap().is_some());
+        assert_eq!(QUEUE_DEPTH.get(), depth + 2);
+        assert!(results.next().now_or_never().unwrap().is_some());
+        assert!(results.next().now_or_never().unwrap().is_some());
+        assert_eq!(QUEUE_DEPTH.get(), depth);
+
+        // nothing waiting and the sender closed: neither changes the depth
+        assert!(results.next().now_or_never().is_none());
+        drop(tx);
+        assert!(results.next().now_or_never().unwrap().is_none());
+        assert_eq!(QUEUE_DEPTH.get(), depth);
+    }
+}
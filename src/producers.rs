@@ -0,0 +1,468 @@
+use crate::abi::EmbeddedAbi;
+use crate::errors::Result;
+use crate::metrics;
+use crate::resources::{timestamp_millis, timestamp_slot};
+use crate::shipper_types::{GetBlocksResultV0Ex, ProducerSchedule};
+use crate::EOSIO_SYSTEM;
+use chrono::Utc;
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// `config::producer_repetitions`, consecutive blocks each producer gets
+const PRODUCER_REPETITIONS: u32 = 12;
+/// `producer_schedule_change_extension`, how WTMSIG blocks propose a new schedule
+const PRODUCER_SCHEDULE_CHANGE_EXTENSION: u16 = 1;
+/// two days of slots. a longer gap is counted but not attributed slot by slot.
+const MAX_ATTRIBUTED_GAP: u32 = 2 * 24 * 60 * 60 * 2;
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Schedule {
+    pub version: u32,
+    pub producers: Vec<String>,
+}
+
+impl Schedule {
+    /// the producer scheduled for block slot `slot`, like nodeos' `get_scheduled_producer`
+    pub fn producer_at(&self, slot: u32) -> Option<&str> {
+        if self.producers.is_empty() {
+            return None;
+        }
+        let round = self.producers.len() as u32 * PRODUCER_REPETITIONS;
+        let index = (slot % round) / PRODUCER_REPETITIONS;
+        Some(&self.producers[index as usize])
+    }
+}
+
+impl From<&ProducerSchedule> for Schedule {
+    fn from(ps: &ProducerSchedule) -> Schedule {
+        Schedule {
+            version: ps.version,
+            producers: ps
+                .producers
+                .iter()
+                .map(|p| p.producer_name.clone())
+                .collect(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct ProducerAuthority {
+    producer_name: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct ProducerAuthoritySchedule {
+    version: u32,
+    producers: Vec<ProducerAuthority>,
+}
+
+/// a schedule showing up in a block header (`activated: false`), or becoming the active one
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ScheduleChange {
+    pub block: u32,
+    pub schedule: Schedule,
+    pub activated: bool,
+}
+
+/// an empty slot before a block, and who should have filled it (if the schedule is known)
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct MissedSlot {
+    pub slot: u32,
+    pub producer: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct BlockProduction {
+    pub block: u32,
+    pub producer: String,
+    pub timestamp: String,
+    pub slot: u32,
+    pub confirmed: u16,
+    pub schedule_version: u32,
+    /// empty slots between the previous block and this one
+    pub missed_slots: u64,
+    /// the same slots one by one, left out when there are more than two days of them
+    pub missed: Vec<MissedSlot>,
+    /// when the block was received less its timestamp. during a replay this is its age.
+    pub drift_ms: i64,
+    pub transactions: u64,
+    pub cpu_usage_us: u64,
+    pub net_usage_words: u64,
+    pub schedule_changes: Vec<ScheduleChange>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct ProducerSummary {
+    pub blocks: u64,
+    pub missed_slots: u64,
+    pub transactions: u64,
+    pub cpu_usage_us: u64,
+    pub max_drift_ms: i64,
+    pub total_drift_ms: i64,
+}
+
+impl ProducerSummary {
+    pub fn average_drift_ms(&self) -> Option<i64> {
+        if self.blocks == 0 {
+            None
+        } else {
+            Some(self.total_drift_ms / self.blocks as i64)
+        }
+    }
+}
+
+/// block production over a period, see `ProducerStats::take_summary`
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ProductionSummary {
+    pub first_block: Option<u32>,
+    pub last_block: Option<u32>,
+    pub blocks: u64,
+    pub missed_slots: u64,
+    /// missed while the active schedule was unknown
+    pub unattributed_missed_slots: u64,
+    pub transactions: u64,
+    pub cpu_usage_us: u64,
+    pub schedule_changes: Vec<ScheduleChange>,
+    pub producers: BTreeMap<String, ProducerSummary>,
+}
+
+/// Per producer block counts, missed slots, drift, and transactions and CPU per block, from
+/// the block headers (needs `fetch_block`). Everything also goes to the `ship_producer_*` and
+/// `ship_block_*` metrics.
+///
+/// Missed slots need the active schedule. It is learnt when a new schedule (`new_producers`,
+/// or the schedule change extension on WTMSIG chains) becomes active, which happens when
+/// blocks start carrying its `schedule_version`. Give it the current one with `with_schedule`
+/// to attribute misses from the start. Blocks received again after a fork are counted again.
+pub struct ProducerStats {
+    abi: EmbeddedAbi,
+    active: Option<Schedule>,
+    pending: Option<Schedule>,
+    /// (block, slot) of the last block
+    last: Option<(u32, u32)>,
+    summary: ProductionSummary,
+}
+
+impl ProducerStats {
+    pub fn new() -> Result<ProducerStats> {
+        Ok(ProducerStats {
+            abi: EmbeddedAbi::load("shipper.abi.json", EOSIO_SYSTEM)?,
+            active: None,
+            pending: None,
+            last: None,
+            summary: ProductionSummary::default(),
+        })
+    }
+
+    /// the schedule active at the first block given to `apply_block`
+    pub fn with_schedule(mut self, schedule: Schedule) -> ProducerStats {
+        metrics::SCHEDULE_VERSION.set(schedule.version as i64);
+        self.active = Some(schedule);
+        self
+    }
+
+    pub fn active_schedule(&self) -> Option<&Schedule> {
+        self.active.as_ref()
+    }
+
+    /// proposed in a header, waiting to become active
+    pub fn pending_schedule(&self) -> Option<&Schedule> {
+        self.pending.as_ref()
+    }
+
+    /// the schedule a header proposes, in either form
+    fn proposed(&self, block: &GetBlocksResultV0Ex) -> Option<Schedule> {
+        let header = &block.block.as_ref()?.signed_header().header;
+        if let Some(ps) = &header.new_producers {
+            return Some(Schedule::from(ps));
+        }
+        let ext = header
+            .header_extensions
+            .iter()
+            .find(|e| e.r#type == PRODUCER_SCHEDULE_CHANGE_EXTENSION)?;
+        match self
+            .abi
+            .decode::<ProducerAuthoritySchedule>("producer_authority_schedule", &ext.data)
+        {
+            Ok(s) => Some(Schedule {
+                version: s.version,
+                producers: s.producers.into_iter().map(|p| p.producer_name).collect(),
+            }),
+            Err(e) => {
+                warn!("unable to decode a producer schedule change: {}", e);
+                None
+            }
+        }
+    }
+
+    /// the production of one block, `None` when the result has no block
+    pub fn apply_block(&mut self, block: &GetBlocksResultV0Ex) -> Result<Option<BlockProduction>> {
+        let (block_num, sb) = match (&block.this_block, &block.block) {
+            (Some(bp), Some(sb)) => (bp.block_num, sb),
+            _ => return Ok(None),
+        };
+        let header = &sb.signed_header().header;
+        let slot = timestamp_slot(&header.timestamp)
+            .ok_or_else(|| format!("invalid block timestamp '{}'", header.timestamp))?;
+
+        let missed_from = match self.last {
+            // after a fork the gap to the previous block means nothing
+            Some((last_block, last_slot)) if last_block < block_num => last_slot + 1,
+            _ => slot,
+        };
+        // a slot at or before the last one (a clock that went back) misses nothing
+        let missed_slots = slot.saturating_sub(missed_from);
+        let mut missed = vec![];
+        let mut unattributed = 0;
+        if missed_slots > MAX_ATTRIBUTED_GAP {
+            warn!(
+                "{} empty slots before block {}, not attributing them",
+                missed_slots, block_num
+            );
+            unattributed = missed_slots as u64;
+        } else {
+            // the empty slots all came before this block, so the schedule that was active
+            // until now is the one they belonged to, even when this block activates another
+            for s in missed_from..slot {
+                let producer = self
+                    .active
+                    .as_ref()
+                    .and_then(|a| a.producer_at(s))
+                    .map(String::from);
+                match &producer {
+                    Some(p) => metrics::PRODUCER_MISSED_SLOTS
+                        .with_label_values(&[p.as_str()])
+                        .inc(),
+                    None => unattributed += 1,
+                }
+                missed.push(MissedSlot { slot: s, producer });
+            }
+        }
+        self.last = Some((block_num, slot));
+
+        let mut schedule_changes = vec![];
+        if let Some(pending) = &self.pending {
+            if header.schedule_version >= pending.version {
+                let schedule = self.pending.take().unwrap();
+                info!(
+                    "producer schedule v{} active at {}",
+                    schedule.version, block_num
+                );
+                metrics::SCHEDULE_VERSION.set(schedule.version as i64);
+                schedule_changes.push(ScheduleChange {
+                    block: block_num,
+                    schedule: schedule.clone(),
+                    activated: true,
+                });
+                self.active = Some(schedule);
+            }
+        }
+        if let Some(schedule) = self.proposed(block) {
+            info!(
+                "producer schedule v{} proposed in {}",
+                schedule.version, block_num
+            );
+            schedule_changes.push(ScheduleChange {
+                block: block_num,
+                schedule: schedule.clone(),
+                activated: false,
+            });
+            self.pending = Some(schedule);
+        }
+
+        let drift_ms = timestamp_millis(&header.timestamp)
+            .map(|t| Utc::now().timestamp_millis() - t)
+            .unwrap_or(0);
+        let receipts = sb.receipt_headers();
+        let cpu_usage_us: u64 = receipts.iter().map(|r| r.cpu_usage_us as u64).sum();
+        let net_usage_words: u64 = receipts.iter().map(|r| r.net_usage_words as u64).sum();
+        let production = BlockProduction {
+            block: block_num,
+            producer: header.producer.clone(),
+            timestamp: header.timestamp.clone(),
+            slot,
+            confirmed: header.confirmed,
+            schedule_version: header.schedule_version,
+            missed_slots: missed_slots as u64,
+            missed,
+            drift_ms,
+            transactions: receipts.len() as u64,
+            cpu_usage_us,
+            net_usage_words,
+            schedule_changes,
+        };
+
+        metrics::PRODUCER_BLOCKS
+            .with_label_values(&[production.producer.as_str()])
+            .inc();
+        metrics::PRODUCER_DRIFT_MS
+            .with_label_values(&[production.producer.as_str()])
+            .set(drift_ms);
+        metrics::BLOCK_TRANSACTIONS.observe(production.transactions as f64);
+        metrics::BLOCK_CPU_US.observe(cpu_usage_us as f64);
+
+        let summary = &mut self.summary;
+        summary.first_block.get_or_insert(block_num);
+        summary.last_block = Some(block_num);
+        summary.blocks += 1;
+        summary.missed_slots += production.missed_slots;
+        summary.unattributed_missed_slots += unattributed;
+        summary.transactions += production.transactions;
+        summary.cpu_usage_us += cpu_usage_us;
+        summary
+            .schedule_changes
+            .extend(production.schedule_changes.iter().cloned());
+        for m in &production.missed {
+            if let Some(p) = &m.producer {
+                summary.producers.entry(p.clone()).or_default().missed_slots += 1;
+            }
+        }
+        let ps = summary
+            .producers
+            .entry(production.producer.clone())
+            .or_default();
+        ps.blocks += 1;
+        ps.transactions += production.transactions;
+        ps.cpu_usage_us += cpu_usage_us;
+        ps.max_drift_ms = ps.max_drift_ms.max(drift_ms);
+        ps.total_drift_ms += drift_ms;
+
+        Ok(Some(production))
+    }
+
+    /// everything since the last `take_summary`
+    pub fn summary(&self) -> &ProductionSummary {
+        &self.summary
+    }
+
+    /// hands back the summary and starts a new period
+    pub fn take_summary(&mut self) -> ProductionSummary {
+        std::mem::take(&mut self.summary)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shipper_types::tests::block;
+    use serde_json::json;
+
+    fn schedule(producers: &[&str]) -> Schedule {
+        Schedule {
+            version: 1,
+            producers: producers.iter().map(|p| String::from(*p)).collect(),
+        }
+    }
+
+    #[test]
+    fn producer_at_gives_each_producer_twelve_slots() {
+        let s = schedule(&["alice", "bob", "carol"]);
+        assert_eq!(s.producer_at(0), Some("alice"));
+        assert_eq!(s.producer_at(11), Some("alice"));
+        assert_eq!(s.producer_at(12), Some("bob"));
+        assert_eq!(s.producer_at(35), Some("carol"));
+        // the next round
+        assert_eq!(s.producer_at(36), Some("alice"));
+        assert_eq!(s.producer_at(36 * 1000 + 24), Some("carol"));
+        assert_eq!(s.producer_at(u32::MAX), Some("alice"));
+    }
+
+    #[test]
+    fn producer_at_without_producers() {
+        assert_eq!(schedule(&[]).producer_at(7), None);
+        assert_eq!(schedule(&["solo"]).producer_at(12345), Some("solo"));
+    }
+
+    /// block `block_num` in `slot`, maybe proposing `new_producers` as schedule v2
+    fn produced(
+        block_num: u32,
+        slot: u32,
+        producer: &str,
+        schedule_version: u32,
+        new_producers: &[&str],
+    ) -> GetBlocksResultV0Ex {
+        let mut b = block(block_num, 0, vec![]);
+        let ms = slot as i64 * 500;
+        let timestamp = format!(
+            "2000-01-01T{:02}:{:02}:{:02}.{:03}",
+            ms / 3_600_000,
+            ms / 60_000 % 60,
+            ms / 1000 % 60,
+            ms % 1000
+        );
+        let new_producers = if new_producers.is_empty() {
+            serde_json::Value::Null
+        } else {
+            json!({
+                "version": 2,
+                "producers": new_producers
+                    .iter()
+                    .map(|p| json!({ "producer_name": p, "block_signing_key": "" }))
+                    .collect::<Vec<_>>(),
+            })
+        };
+        b.block = Some(
+            serde_json::from_value(json!({ "signed_block_v0": {
+                "timestamp": timestamp,
+                "producer": producer,
+                "confirmed": 0,
+                "previous": "",
+                "transaction_mroot": "",
+                "action_mroot": "",
+                "schedule_version": schedule_version,
+                "new_producers": new_producers,
+                "header_extensions": [],
+                "producer_signature": "",
+                "transactions": [],
+                "block_extensions": [],
+            }}))
+            .unwrap(),
+        );
+        b
+    }
+
+    #[test]
+    fn missed_slots_belong_to_the_schedule_before_the_switch() {
+        let mut stats = ProducerStats::new()
+            .unwrap()
+            .with_schedule(schedule(&["alice", "bob"]));
+        stats
+            .apply_block(&produced(1, 0, "alice", 1, &["carol", "dave"]))
+            .unwrap();
+        assert_eq!(stats.pending_schedule().map(|s| s.version), Some(2));
+
+        // slots 1 to 35 are empty, and this block switches to v2
+        let p = stats
+            .apply_block(&produced(2, 36, "carol", 2, &[]))
+            .unwrap()
+            .unwrap();
+        assert_eq!(p.missed_slots, 35);
+        let producer = |slot: u32| p.missed[slot as usize - 1].producer.as_deref();
+        assert_eq!(producer(1), Some("alice"));
+        assert_eq!(producer(12), Some("bob"));
+        assert_eq!(producer(24), Some("alice"));
+        assert_eq!(stats.active_schedule().map(|s| s.version), Some(2));
+        assert!(p.schedule_changes[0].activated);
+
+        let summary = stats.summary();
+        assert_eq!(summary.producers["alice"].missed_slots, 23);
+        assert_eq!(summary.producers["bob"].missed_slots, 12);
+        assert!(!summary.producers.contains_key("dave"));
+    }
+
+    #[test]
+    fn a_slot_going_backwards_misses_nothing() {
+        let mut stats = ProducerStats::new().unwrap();
+        stats
+            .apply_block(&produced(1, 10, "alice", 0, &[]))
+            .unwrap();
+        let p = stats
+            .apply_block(&produced(2, 5, "alice", 0, &[]))
+            .unwrap()
+            .unwrap();
+        assert_eq!(p.missed_slots, 0);
+        assert!(p.missed.is_empty());
+    }
+}
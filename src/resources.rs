@@ -0,0 +1,443 @@
+use crate::errors::Result;
+use crate::shipper_types::{
+    GetBlocksResultV0Ex, ResourceLimits, ResourceUsage, SignedBlock, TableRowTypes,
+    UsageAccumulator,
+};
+use crate::state_store::StateStore;
+use chrono::NaiveDateTime;
+use serde::Serialize;
+use std::collections::BTreeSet;
+
+/// `config::rate_limiting_precision`, the fixed point scale of `value_ex`
+const RATE_LIMITING_PRECISION: u128 = 1_000_000;
+/// nodeos' default account usage window, 24h of block slots
+const DEFAULT_WINDOW: u32 = 172_800;
+/// block timestamps count 500ms slots from 2000-01-01
+const BLOCK_TIMESTAMP_EPOCH_MS: i64 = 946_684_800_000;
+const BLOCK_INTERVAL_MS: i64 = 500;
+
+fn parse_u64(s: &str) -> Result<u64> {
+    s.parse::<u64>()
+        .map_err(|_| format!("invalid resource value '{}'", s).into())
+}
+
+fn parse_i64(s: &str) -> Result<i64> {
+    s.parse::<i64>()
+        .map_err(|_| format!("invalid resource value '{}'", s).into())
+}
+
+fn divide_ceil(num: u128, den: u128) -> u128 {
+    let q = num / den;
+    if num % den > 0 {
+        q + 1
+    } else {
+        q
+    }
+}
+
+fn clamp(n: u128) -> u64 {
+    n.min(u64::MAX as u128) as u64
+}
+
+/// nodeos' `exponential_moving_average_accumulator`, with the u64 strings parsed
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct Accumulator {
+    /// the block slot (accounts) or block number (chain averages) of the last update
+    pub last_ordinal: u32,
+    /// the average, scaled by the rate limiting precision
+    pub value_ex: u64,
+    pub consumed: u64,
+}
+
+impl Accumulator {
+    pub fn from_row(row: &UsageAccumulator) -> Result<Accumulator> {
+        match row {
+            UsageAccumulator::usage_accumulator_v0(a) => Ok(Accumulator {
+                last_ordinal: a.last_ordinal,
+                value_ex: parse_u64(&a.value_ex)?,
+                consumed: parse_u64(&a.consumed)?,
+            }),
+        }
+    }
+
+    pub fn average(&self) -> u64 {
+        clamp(divide_ceil(self.value_ex as u128, RATE_LIMITING_PRECISION))
+    }
+
+    /// the accumulator as it would be at `ordinal` with nothing added, which is how nodeos
+    /// looks at usage before checking a limit
+    pub fn decayed(&self, ordinal: u32, window: u32) -> Accumulator {
+        if ordinal <= self.last_ordinal {
+            return *self;
+        }
+        let elapsed = ordinal - self.last_ordinal;
+        let value_ex = if elapsed < window {
+            clamp(self.value_ex as u128 * (window - elapsed) as u128 / window as u128)
+        } else {
+            0
+        };
+        let mut decayed = Accumulator {
+            last_ordinal: ordinal,
+            value_ex,
+            consumed: 0,
+        };
+        decayed.consumed = decayed.average();
+        decayed
+    }
+
+    /// units (us of CPU, bytes of NET) used over `window`
+    pub fn used_in_window(&self, window: u32) -> u64 {
+        clamp(divide_ceil(
+            self.value_ex as u128 * window as u128,
+            RATE_LIMITING_PRECISION,
+        ))
+    }
+}
+
+/// CPU (us) or NET (bytes) for one account, like nodeos' `account_resource_limit`.
+/// `available` and `max` are `None` when the account is unlimited.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct ResourceLimit {
+    pub used: u64,
+    pub available: Option<u64>,
+    pub max: Option<u64>,
+}
+
+impl ResourceLimit {
+    /// how much of the allowance is used, `None` when unlimited
+    pub fn used_fraction(&self) -> Option<f64> {
+        match self.max {
+            Some(0) => Some(1.0),
+            Some(max) => Some(self.used as f64 / max as f64),
+            None => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct RamUsage {
+    pub used: u64,
+    /// `None` when the account is unlimited
+    pub quota: Option<u64>,
+}
+
+impl RamUsage {
+    pub fn available(&self) -> Option<u64> {
+        self.quota.map(|q| q.saturating_sub(self.used))
+    }
+}
+
+/// the resources of one account as of a block
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct AccountUsage {
+    pub account: String,
+    pub block: u32,
+    /// the block slot usage was decayed to
+    pub slot: u32,
+    pub cpu: ResourceLimit,
+    pub net: ResourceLimit,
+    pub ram: RamUsage,
+    pub cpu_weight: i64,
+    pub net_weight: i64,
+}
+
+/// the chain wide totals and the virtual limits the elastic resource model hands out
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ChainLimits {
+    pub block: u32,
+    pub virtual_cpu_limit: u64,
+    pub virtual_net_limit: u64,
+    pub total_cpu_weight: u64,
+    pub total_net_weight: u64,
+    pub total_ram_bytes: u64,
+    pub average_block_cpu: u64,
+    pub average_block_net: u64,
+}
+
+/// what one block changed: the chain limits if they moved, and every account whose usage or
+/// limits were touched
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ResourceSamples {
+    pub chain: Option<ChainLimits>,
+    pub accounts: Vec<AccountUsage>,
+}
+
+/// milliseconds since the unix epoch of a block timestamp, eg `2020-06-01T12:00:00.500`
+pub fn timestamp_millis(timestamp: &str) -> Option<i64> {
+    NaiveDateTime::parse_from_str(timestamp, "%Y-%m-%dT%H:%M:%S%.f")
+        .ok()
+        .map(|t| t.timestamp_millis())
+}
+
+/// the 500ms slot of a block timestamp
+pub fn timestamp_slot(timestamp: &str) -> Option<u32> {
+    let ms = timestamp_millis(timestamp)? - BLOCK_TIMESTAMP_EPOCH_MS;
+    if ms < 0 {
+        return None;
+    }
+    Some((ms / BLOCK_INTERVAL_MS) as u32)
+}
+
+/// The block's slot, from its header (`fetch_block`) or else from the newest account usage
+/// update in its deltas, which nodeos stamps with the slot of the block.
+pub fn block_slot(block: &GetBlocksResultV0Ex) -> Option<u32> {
+    let header = match &block.block {
+        Some(SignedBlock::signed_block_v0(b)) => Some(&b.signed_header.header),
+        Some(SignedBlock::signed_block_v1(b)) => Some(&b.signed_header.header),
+        None => None,
+    };
+    if let Some(slot) = header.and_then(|h| timestamp_slot(&h.timestamp)) {
+        return Some(slot);
+    }
+    block
+        .deltas
+        .iter()
+        .flat_map(|d| d.rows.iter())
+        .filter_map(|r| match &r.data {
+            TableRowTypes::resource_usage(ResourceUsage::resource_usage_v0(u)) => {
+                Accumulator::from_row(&u.cpu_usage)
+                    .ok()
+                    .map(|a| a.last_ordinal)
+            }
+            _ => None,
+        })
+        .max()
+}
+
+pub fn chain_limits(state: &StateStore) -> Result<Option<ChainLimits>> {
+    let s = match state.resource_limits_state() {
+        Some(s) => s,
+        None => return Ok(None),
+    };
+    Ok(Some(ChainLimits {
+        block: state.head_block_num().unwrap_or(0),
+        virtual_cpu_limit: parse_u64(&s.virtual_cpu_limit)?,
+        virtual_net_limit: parse_u64(&s.virtual_net_limit)?,
+        total_cpu_weight: parse_u64(&s.total_cpu_weight)?,
+        total_net_weight: parse_u64(&s.total_net_weight)?,
+        total_ram_bytes: parse_u64(&s.total_ram_bytes)?,
+        average_block_cpu: Accumulator::from_row(&s.average_block_cpu_usage)?.average(),
+        average_block_net: Accumulator::from_row(&s.average_block_net_usage)?.average(),
+    }))
+}
+
+/// nodeos' `get_account_cpu_limit_ex`/`get_account_net_limit_ex`: the account's share of the
+/// virtual limit over the window, less what it used in it
+fn limit(
+    weight: i64,
+    total_weight: u64,
+    virtual_limit: u64,
+    usage: &Accumulator,
+    slot: u32,
+    window: u32,
+) -> ResourceLimit {
+    let used = usage.decayed(slot, window).used_in_window(window);
+    if weight < 0 || total_weight == 0 {
+        return ResourceLimit {
+            used,
+            available: None,
+            max: None,
+        };
+    }
+    let capacity = virtual_limit as u128 * window as u128;
+    let max = clamp(capacity * weight as u128 / total_weight as u128);
+    ResourceLimit {
+        used,
+        available: Some(max.saturating_sub(used)),
+        max: Some(max),
+    }
+}
+
+/// Effective CPU, NET and RAM for `account` at block slot `slot`, computed from the state the
+/// way nodeos does it (`resource_usage`, `resource_limits`, `resource_limits_state` and
+/// `resource_limits_config` must be in the deltas).
+///
+/// Greylisting is a local nodeos setting and isn't taken into account.
+pub fn account_usage(state: &StateStore, account: &str, slot: u32) -> Result<Option<AccountUsage>> {
+    let usage = match state.resource_usage(account) {
+        Some(u) => u,
+        None => return Ok(None),
+    };
+    let (cpu_weight, net_weight, ram_bytes) = match state.resource_limits(account) {
+        Some(l) => (
+            parse_i64(&l.cpu_weight)?,
+            parse_i64(&l.net_weight)?,
+            parse_i64(&l.ram_bytes)?,
+        ),
+        None => (-1, -1, -1),
+    };
+    let (cpu_window, net_window) = match state.resource_limits_config() {
+        Some(c) => (
+            c.account_cpu_usage_average_window,
+            c.account_net_usage_average_window,
+        ),
+        None => (DEFAULT_WINDOW, DEFAULT_WINDOW),
+    };
+    let chain = chain_limits(state)?;
+    let (total_cpu_weight, total_net_weight, virtual_cpu_limit, virtual_net_limit) = match &chain {
+        Some(c) => (
+            c.total_cpu_weight,
+            c.total_net_weight,
+            c.virtual_cpu_limit,
+            c.virtual_net_limit,
+        ),
+        None => (0, 0, 0, 0),
+    };
+    let cpu_usage = Accumulator::from_row(&usage.cpu_usage)?;
+    let net_usage = Accumulator::from_row(&usage.net_usage)?;
+    Ok(Some(AccountUsage {
+        account: usage.owner.clone(),
+        block: state.head_block_num().unwrap_or(0),
+        slot,
+        cpu: limit(
+            cpu_weight,
+            total_cpu_weight,
+            virtual_cpu_limit,
+            &cpu_usage,
+            slot,
+            cpu_window,
+        ),
+        net: limit(
+            net_weight,
+            total_net_weight,
+            virtual_net_limit,
+            &net_usage,
+            slot,
+            net_window,
+        ),
+        ram: RamUsage {
+            used: parse_u64(&usage.ram_usage)?,
+            quota: if ram_bytes < 0 {
+                None
+            } else {
+                Some(ram_bytes as u64)
+            },
+        },
+        cpu_weight,
+        net_weight,
+    }))
+}
+
+/// One point of the CPU/NET/RAM time series for each account `block` touched, plus the chain
+/// limits when they changed.
+///
+/// Call it after `state.apply_block(block)`.
+pub fn samples(state: &StateStore, block: &GetBlocksResultV0Ex) -> Result<ResourceSamples> {
+    let mut accounts = BTreeSet::new();
+    let mut chain_changed = false;
+    for delta in &block.deltas {
+        for row in &delta.rows {
+            match &row.data {
+                TableRowTypes::resource_usage(ResourceUsage::resource_usage_v0(u)) => {
+                    accounts.insert(u.owner.as_str());
+                }
+                TableRowTypes::resource_limits(ResourceLimits::resource_limits_v0(l)) => {
+                    accounts.insert(l.owner.as_str());
+                }
+                TableRowTypes::resource_limits_state(_)
+                | TableRowTypes::resource_limits_config(_) => chain_changed = true,
+                _ => {}
+            }
+        }
+    }
+    let slot = block_slot(block);
+    let mut samples = ResourceSamples {
+        chain: if chain_changed {
+            chain_limits(state)?
+        } else {
+            None
+        },
+        accounts: Vec::with_capacity(accounts.len()),
+    };
+    for account in accounts {
+        // without a slot, look at the usage as of its own last update
+        let slot = match (slot, state.resource_usage(account)) {
+            (Some(slot), _) => slot,
+            (None, Some(u)) => Accumulator::from_row(&u.cpu_usage)?.last_ordinal,
+            (None, None) => continue,
+        };
+        if let Some(usage) = account_usage(state, account, slot)? {
+            samples.accounts.push(usage);
+        }
+    }
+    Ok(samples)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn accumulator(last_ordinal: u32, value_ex: u64) -> Accumulator {
+        Accumulator {
+            last_ordinal,
+            value_ex,
+            consumed: 0,
+        }
+    }
+
+    // expected values worked out with nodeos' `exponential_moving_average_accumulator::add`
+    // for a zero `units`: value_ex * (window - elapsed) / window, then consumed = average()
+
+    #[test]
+    fn decays_like_nodeos() {
+        let a = accumulator(1000, 1_000_000_000);
+        assert_eq!(a.average(), 1000);
+
+        let half = a.decayed(1000 + DEFAULT_WINDOW / 2, DEFAULT_WINDOW);
+        assert_eq!(half.last_ordinal, 87_400);
+        assert_eq!(half.value_ex, 500_000_000);
+        assert_eq!(half.consumed, 500);
+
+        // integer division rounds the decayed value down, average() rounds up
+        let b = accumulator(0, 1_234_567).decayed(1, 3);
+        assert_eq!(b.value_ex, 823_044);
+        assert_eq!(b.consumed, 1);
+
+        // a full window or more leaves nothing
+        assert_eq!(a.decayed(1000 + DEFAULT_WINDOW, DEFAULT_WINDOW).value_ex, 0);
+        assert_eq!(a.decayed(u32::MAX, DEFAULT_WINDOW).consumed, 0);
+    }
+
+    #[test]
+    fn no_decay_at_or_before_the_last_update() {
+        let a = Accumulator {
+            last_ordinal: 50,
+            value_ex: 7,
+            consumed: 3,
+        };
+        assert_eq!(a.decayed(50, 10), a);
+        assert_eq!(a.decayed(10, 10), a);
+    }
+
+    #[test]
+    fn used_in_window_rounds_up() {
+        // get_account_cpu_limit_ex: ceil(value_ex * window / precision)
+        assert_eq!(accumulator(0, 1).used_in_window(DEFAULT_WINDOW), 1);
+        assert_eq!(
+            accumulator(0, 2_000_000).used_in_window(DEFAULT_WINDOW),
+            2 * DEFAULT_WINDOW as u64
+        );
+        assert_eq!(accumulator(0, u64::MAX).used_in_window(u32::MAX), u64::MAX);
+    }
+
+    #[test]
+    fn limit_shares_the_virtual_limit_by_weight() {
+        let usage = accumulator(100, 1_000_000);
+        let l = limit(25, 100, 1000, &usage, 100, 10);
+        assert_eq!(l.max, Some(2500));
+        assert_eq!(l.used, 10);
+        assert_eq!(l.available, Some(2490));
+
+        let unlimited = limit(-1, 100, 1000, &usage, 100, 10);
+        assert_eq!((unlimited.available, unlimited.max), (None, None));
+        assert_eq!(unlimited.used_fraction(), None);
+    }
+
+    #[test]
+    fn block_timestamps_to_slots() {
+        assert_eq!(timestamp_slot("2000-01-01T00:00:00.000"), Some(0));
+        assert_eq!(timestamp_slot("2000-01-01T00:00:01.500"), Some(3));
+        assert_eq!(timestamp_slot("1999-12-31T23:59:59.500"), None);
+        assert_eq!(timestamp_slot("yesterday"), None);
+    }
+}
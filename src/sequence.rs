@@ -0,0 +1,209 @@
+use crate::errors::Result;
+use crate::shipper_types::{Action, GetBlocksResultV0Ex};
+use std::collections::BTreeMap;
+
+fn parse_sequence(s: &str) -> Result<u64> {
+    s.parse::<u64>()
+        .map_err(|_| format!("invalid action sequence '{}'", s).into())
+}
+
+/// one executed action, in the order the chain ran it
+#[derive(Debug, Clone)]
+pub struct ActionExecution<'a> {
+    pub block_num: u32,
+    pub trx_id: &'a str,
+    pub action_ordinal: u32,
+    pub global_sequence: u64,
+    pub recv_sequence: u64,
+    pub receiver: &'a str,
+    pub act: &'a Action,
+}
+
+/// Every action trace of the block that has a receipt, ordered by `global_sequence`.
+///
+/// Action traces without a receipt didn't execute and have no sequence, so they are left out.
+/// Needs the block's traces (`fetch_traces`).
+pub fn actions(block: &GetBlocksResultV0Ex) -> Result<std::vec::IntoIter<ActionExecution<'_>>> {
+    let block_num = match &block.this_block {
+        Some(bp) => bp.block_num,
+        None => return Ok(vec![].into_iter()),
+    };
+    let mut executions = vec![];
+    for trace in &block.traces {
+        let tt = trace.transaction_trace();
+        for at in &tt.action_traces {
+            if let Some(receipt) = at.receipt() {
+                executions.push(ActionExecution {
+                    block_num,
+                    trx_id: &tt.id,
+                    action_ordinal: at.action_ordinal(),
+                    global_sequence: parse_sequence(&receipt.global_sequence)?,
+                    recv_sequence: parse_sequence(&receipt.recv_sequence)?,
+                    receiver: at.receiver(),
+                    act: at.act(),
+                });
+            }
+        }
+    }
+    executions.sort_by_key(|e| e.global_sequence);
+    Ok(executions.into_iter())
+}
+
+/// global sequences `first..=last` are missing before block `block_num`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SequenceGap {
+    pub block_num: u32,
+    pub first: u64,
+    pub last: u64,
+}
+
+/// Checks that `global_sequence` has no holes across consecutive blocks.
+///
+/// Every block has at least the `onblock` action, so a hole means traces are missing, either
+/// inside a block or between two of them. Forks are followed: a block at or below one already
+/// checked picks up from the block before it.
+#[derive(Debug, Default)]
+pub struct SequenceValidator {
+    /// last global sequence of each recent block
+    last_sequence: BTreeMap<u32, u64>,
+}
+
+impl SequenceValidator {
+    pub fn new() -> SequenceValidator {
+        SequenceValidator::default()
+    }
+
+    /// the gaps leading up to and inside `block`
+    pub fn check(&mut self, block: &GetBlocksResultV0Ex) -> Result<Vec<SequenceGap>> {
+        let block_num = match &block.this_block {
+            Some(bp) => bp.block_num,
+            None => return Ok(vec![]),
+        };
+        // a fork replaces everything from block_num up
+        let _ = self.last_sequence.split_off(&block_num);
+        let mut expected = self.last_sequence.values().next_back().map(|s| s + 1);
+        let mut gaps = vec![];
+        let mut last = None;
+        for e in actions(block)? {
+            if let Some(x) = expected {
+                if e.global_sequence > x {
+                    gaps.push(SequenceGap {
+                        block_num,
+                        first: x,
+                        last: e.global_sequence - 1,
+                    });
+                }
+            }
+            expected = Some(e.global_sequence + 1);
+            last = Some(e.global_sequence);
+        }
+        if let Some(last) = last {
+            self.last_sequence.insert(block_num, last);
+        }
+        // keep what a fork back to the last irreversible block needs
+        let lib = block.last_irreversible.block_num;
+        while self.last_sequence.len() > 1
+            && self
+                .last_sequence
+                .keys()
+                .nth(1)
+                .map_or(false, |&n| n <= lib)
+        {
+            let first = *self.last_sequence.keys().next().unwrap();
+            self.last_sequence.remove(&first);
+        }
+        Ok(gaps)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shipper_types::tests::{block, trace};
+
+    fn block_with(
+        block_num: u32,
+        last_irreversible: u32,
+        sequences: &[Option<u64>],
+    ) -> GetBlocksResultV0Ex {
+        let mut b = block(block_num, last_irreversible, vec![]);
+        let actions: Vec<(&str, &str, Option<u64>)> = sequences
+            .iter()
+            .map(|gs| ("eosio", "onblock", *gs))
+            .collect();
+        b.traces = vec![trace("t1", &actions)];
+        b
+    }
+
+    #[test]
+    fn actions_come_in_global_sequence_order() {
+        let b = block_with(5, 0, &[Some(12), None, Some(10), Some(11)]);
+        let executions: Vec<_> = actions(&b).unwrap().collect();
+        let sequences: Vec<u64> = executions.iter().map(|e| e.global_sequence).collect();
+        // the one without a receipt didn't run
+        assert_eq!(sequences, vec![10, 11, 12]);
+        assert_eq!(executions[0].action_ordinal, 3);
+        assert_eq!(executions[0].block_num, 5);
+        assert_eq!(executions[0].trx_id, "t1");
+    }
+
+    #[test]
+    fn gaps_across_blocks_and_forks() {
+        let mut validator = SequenceValidator::new();
+        assert_eq!(
+            validator
+                .check(&block_with(1, 0, &[Some(1), Some(2)]))
+                .unwrap(),
+            vec![]
+        );
+        assert_eq!(
+            validator.check(&block_with(2, 0, &[Some(3)])).unwrap(),
+            vec![]
+        );
+        assert_eq!(
+            validator
+                .check(&block_with(3, 0, &[Some(6), Some(8)]))
+                .unwrap(),
+            vec![
+                SequenceGap {
+                    block_num: 3,
+                    first: 4,
+                    last: 5,
+                },
+                SequenceGap {
+                    block_num: 3,
+                    first: 7,
+                    last: 7,
+                },
+            ]
+        );
+        // block 2 again picks up after block 1
+        assert_eq!(
+            validator.check(&block_with(2, 0, &[Some(3)])).unwrap(),
+            vec![]
+        );
+        assert_eq!(
+            validator.check(&block_with(3, 0, &[Some(4)])).unwrap(),
+            vec![]
+        );
+    }
+
+    #[test]
+    fn keeps_what_a_fork_to_the_irreversible_block_needs() {
+        let mut validator = SequenceValidator::new();
+        for n in 1..=4 {
+            validator
+                .check(&block_with(n, 3, &[Some(n as u64)]))
+                .unwrap();
+        }
+        // block 3 is irreversible, block 4 can still be replaced
+        assert_eq!(
+            validator.last_sequence.keys().collect::<Vec<_>>(),
+            vec![&3, &4]
+        );
+        assert_eq!(
+            validator.check(&block_with(4, 3, &[Some(4)])).unwrap(),
+            vec![]
+        );
+    }
+}
@@ -2,16 +2,19 @@
 use std::collections::HashSet;
 
 use serde::ser::SerializeTuple;
-use serde::{Deserialize, Serialize, Serializer};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 // source from work done by @lucas3fonseca and @leordev
 // plan is to move to their work once it is public
-use crate::errors::Result;
+use crate::errors::{ErrorKind, Result, ResultExt};
+use crate::metrics;
 use chrono::{DateTime, Utc};
 use flate2::read::ZlibDecoder;
 use libabieos_sys::{eosio_datetime_format, hex_to_bin, ABIEOS};
-use log::*;
+use sha2::{Digest, Sha256};
 use std::fmt;
 use std::io::prelude::*;
+use std::str::FromStr;
+use tracing::{debug, debug_span, field, info_span, warn, Span};
 
 lazy_static! {
     static ref ROWTYPES: HashSet<String> = vec![
@@ -27,10 +30,10 @@ lazy_static! {
         String::from("contract_index_long_double"),
         // key_value
         // global_property
-        // generated_transaction
+        String::from("generated_transaction"),
         // protocol_state
-        // permission
-        // permission_link
+        String::from("permission"),
+        String::from("permission_link"),
         String::from("resource_limits"),
         String::from("resource_usage"),
         String::from("resource_limits_state"),
@@ -73,9 +76,27 @@ pub enum ShipRequests {
     get_status_request_v0(GetStatusRequestV0),
     get_blocks_request_v0(GetBlocksRequestV0),
     get_blocks_ack_request_v0(GetBlocksACKRequestV0),
+    /// Leap/Spring, only sent when the server ABI has it
+    get_blocks_request_v1(GetBlocksRequestV1),
     quit,
 }
 
+/// the types of variant `name` in an ABI, eg the `request` variant of the ABI nodeos sends
+pub fn abi_variant_types(abi_json: &str, name: &str) -> Result<Vec<String>> {
+    let abi: serde_json::Value = serde_json::from_str(abi_json)?;
+    let types = abi["variants"]
+        .as_array()
+        .and_then(|vs| vs.iter().find(|v| v["name"] == name))
+        .and_then(|v| v["types"].as_array());
+    match types {
+        Some(types) => Ok(types
+            .iter()
+            .filter_map(|t| t.as_str().map(String::from))
+            .collect()),
+        None => Ok(vec![]),
+    }
+}
+
 impl ShipRequests {
     pub fn from_bin(shipper_abi: &ABIEOS, bin: &[u8]) -> Result<ShipRequests> {
         let mut s: String = String::from("");
@@ -88,6 +109,16 @@ impl ShipRequests {
         let sr: ShipRequests = serde_json::from_str(&json)?;
         Ok(sr)
     }
+
+    pub fn to_bin(&self, shipper_abi: &ABIEOS) -> Result<Vec<u8>> {
+        match self {
+            ShipRequests::get_status_request_v0(r) => r.to_bin(shipper_abi),
+            ShipRequests::get_blocks_request_v0(r) => r.to_bin(shipper_abi),
+            ShipRequests::get_blocks_ack_request_v0(r) => r.to_bin(shipper_abi),
+            ShipRequests::get_blocks_request_v1(r) => r.to_bin(shipper_abi),
+            ShipRequests::quit => Err("quit is not sent to the server".into()),
+        }
+    }
 }
 
 impl Serialize for ShipRequests {
@@ -109,6 +140,10 @@ impl Serialize for ShipRequests {
                 m.serialize_element("get_blocks_ack_request_v0")?;
                 m.serialize_element(k)?;
             }
+            ShipRequests::get_blocks_request_v1(k) => {
+                m.serialize_element("get_blocks_request_v1")?;
+                m.serialize_element(k)?;
+            }
             ShipRequests::quit => {
                 m.serialize_element("quit")?;
                 m.serialize_element(&{})?;
@@ -154,6 +189,58 @@ impl GetBlocksRequestV0 {
         let trx = shipper_abi.json_to_bin("eosio", "request", &json);
         Ok(trx?)
     }
+
+    /// the same request for servers that only have v1, without finality data
+    pub fn to_v1(self) -> GetBlocksRequestV1 {
+        GetBlocksRequestV1 {
+            start_block_num: self.start_block_num,
+            end_block_num: self.end_block_num,
+            max_messages_in_flight: self.max_messages_in_flight,
+            have_positions: self.have_positions,
+            irreversible_only: self.irreversible_only,
+            fetch_block: self.fetch_block,
+            fetch_traces: self.fetch_traces,
+            fetch_deltas: self.fetch_deltas,
+            fetch_finality_data: false,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct GetBlocksRequestV1 {
+    pub start_block_num: u32,
+    pub end_block_num: u32,
+    pub max_messages_in_flight: u32,
+    pub have_positions: Vec<BlockPosition>,
+    pub irreversible_only: bool,
+    pub fetch_block: bool,
+    pub fetch_traces: bool,
+    pub fetch_deltas: bool,
+    pub fetch_finality_data: bool,
+}
+
+impl GetBlocksRequestV1 {
+    pub fn to_bin(&self, shipper_abi: &ABIEOS) -> Result<Vec<u8>> {
+        let _json = String::from(serde_json::to_string(&self)?);
+        let json: String =
+            String::from("[\"get_blocks_request_v1\",") + &_json + &String::from("]");
+        let trx = shipper_abi.json_to_bin("eosio", "request", &json);
+        Ok(trx?)
+    }
+
+    /// the same request for servers without v1, dropping `fetch_finality_data`
+    pub fn to_v0(self) -> GetBlocksRequestV0 {
+        GetBlocksRequestV0 {
+            start_block_num: self.start_block_num,
+            end_block_num: self.end_block_num,
+            max_messages_in_flight: self.max_messages_in_flight,
+            have_positions: self.have_positions,
+            irreversible_only: self.irreversible_only,
+            fetch_block: self.fetch_block,
+            fetch_traces: self.fetch_traces,
+            fetch_deltas: self.fetch_deltas,
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -171,7 +258,7 @@ impl GetBlocksACKRequestV0 {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct BlockPosition {
     pub block_num: u32,
     pub block_id: String,
@@ -184,6 +271,10 @@ pub enum ShipResults {
     get_status_result_v0(GetStatusResponseV0),
     get_blocks_result_v0(GetBlocksResultV0),
     get_blocks_result_v1(GetBlocksResultV1),
+    /// Leap/Spring. the extra finality range fields are on `GetStatusResponseV0`
+    get_status_result_v1(GetStatusResponseV0),
+    /// Leap/Spring. same shape as v1 here, with a packed block and finality data
+    get_blocks_result_v2(GetBlocksResultV1),
 }
 
 impl Serialize for ShipResults {
@@ -205,6 +296,14 @@ impl Serialize for ShipResults {
                 m.serialize_element("get_blocks_result_v1")?;
                 m.serialize_element(k)?;
             }
+            ShipResults::get_status_result_v1(k) => {
+                m.serialize_element("get_status_result_v1")?;
+                m.serialize_element(k)?;
+            }
+            ShipResults::get_blocks_result_v2(k) => {
+                m.serialize_element("get_blocks_result_v2")?;
+                m.serialize_element(k)?;
+            }
         }
         m.end()
     }
@@ -216,99 +315,206 @@ pub enum ShipResultsEx {
     BlockResult(GetBlocksResultV0Ex),
 }
 
+/// runs one decode step in its own span, timing it into the `ship_decode_seconds` metric
+fn decode_stage<T, F: FnOnce() -> T>(stage: &'static str, f: F) -> T {
+    let span = debug_span!("decode", stage);
+    let _enter = span.enter();
+    metrics::time_stage(stage, f)
+}
+
+fn record_block(span: &Span, this_block: &Option<BlockPosition>) -> Option<u32> {
+    let bp = this_block.as_ref()?;
+    span.record("block_num", &bp.block_num);
+    span.record("block_id", &bp.block_id.as_str());
+    Some(bp.block_num)
+}
+
 impl ShipResultsEx {
     pub fn from_bin(shipper_abi: &ABIEOS, bin: &[u8]) -> Result<ShipResultsEx> {
+        ShipResultsEx::from_bin_with_limit(shipper_abi, bin, DEFAULT_MAX_DECOMPRESSED_SIZE)
+    }
+
+    /// `from_bin`, refusing to inflate a packed transaction past `max_decompressed_size` bytes
+    pub fn from_bin_with_limit(
+        shipper_abi: &ABIEOS,
+        bin: &[u8],
+        max_decompressed_size: usize,
+    ) -> Result<ShipResultsEx> {
+        let span = info_span!(
+            "ship_result",
+            bytes = bin.len(),
+            block_num = field::Empty,
+            block_id = field::Empty
+        );
+        let _enter = span.enter();
         let mut s: String = String::from("");
         for b in bin {
             let hex = format!("{:02x}", b);
             s += hex.as_str();
         }
-        let json = shipper_abi.hex_to_json("eosio", "result", s.as_bytes())?;
+        let json = decode_stage("result", || {
+            shipper_abi.hex_to_json("eosio", "result", s.as_bytes())
+        })
+        .chain_err(|| ErrorKind::Decode(String::from("result"), None))?;
         debug!("{}", json);
-        let sr: ShipResults = serde_json::from_str(&json)?;
+        let sr: ShipResults = serde_json::from_str(&json)
+            .chain_err(|| ErrorKind::Decode(String::from("result"), None))?;
         match sr {
             ShipResults::get_blocks_result_v0(br) => {
-                let traces = match br.traces {
-                    None => vec![],
-                    Some(t) => ShipResultsEx::convert_traces(shipper_abi, &t.as_bytes()).unwrap(),
-                };
-                let deltas = match br.deltas {
-                    None => vec![],
-                    Some(t) => ShipResultsEx::convert_deltas(shipper_abi, &t.as_bytes()).unwrap(),
-                };
-                let (block, trans) = match br.block {
-                    None => (None, vec![]),
-                    Some(t) => {
-                        let sb: SignedBlock =
-                            ShipResultsEx::convert_block_v0(shipper_abi, &t.as_bytes()).unwrap();
-                        let v_ot: Vec<Option<Transaction>> = sb.get_trx(shipper_abi);
-                        (Some(sb), v_ot)
-                    }
-                };
-
-                let br_ex = GetBlocksResultV0Ex {
+                let br = GetBlocksResultV1 {
                     head: br.head,
                     last_irreversible: br.last_irreversible,
                     this_block: br.this_block,
                     prev_block: br.prev_block,
-                    block: block,
-                    traces: traces,
-                    deltas: deltas,
-                    transactions: trans,
+                    block: br.block.map(MaybePackedBlock::Packed),
+                    traces: br.traces,
+                    deltas: br.deltas,
+                    finality_data: None,
                 };
+                ShipResultsEx::convert_blocks_result(shipper_abi, &span, br, max_decompressed_size)
+            }
+            ShipResults::get_blocks_result_v1(br) | ShipResults::get_blocks_result_v2(br) => {
+                ShipResultsEx::convert_blocks_result(shipper_abi, &span, br, max_decompressed_size)
+            }
+            ShipResults::get_status_result_v0(sr) | ShipResults::get_status_result_v1(sr) => {
+                Ok(ShipResultsEx::Status(sr))
+            } //_ => Err("Invalid response to block response".into()),
+        }
+    }
 
-                Ok(ShipResultsEx::BlockResult(br_ex))
+    /// every get_blocks_result version comes through here as the widest shape
+    fn convert_blocks_result(
+        shipper_abi: &ABIEOS,
+        span: &Span,
+        br: GetBlocksResultV1,
+        max_decompressed_size: usize,
+    ) -> Result<ShipResultsEx> {
+        let block_num = record_block(span, &br.this_block);
+        let received = BlockParts {
+            block: br.block.is_some(),
+            traces: br.traces.is_some(),
+            deltas: br.deltas.is_some(),
+            finality_data: br.finality_data.is_some(),
+        };
+        let traces = match br.traces {
+            None => vec![],
+            Some(t) => decode_stage("convert_traces", || {
+                ShipResultsEx::convert_traces(shipper_abi, block_num, &t.as_bytes())
+            })?,
+        };
+        let deltas = match br.deltas {
+            None => vec![],
+            Some(t) => decode_stage("convert_deltas", || {
+                ShipResultsEx::convert_deltas(shipper_abi, block_num, &t.as_bytes())
+            })?,
+        };
+        let block = match br.block {
+            None => None,
+            Some(MaybePackedBlock::Block(sb)) => Some(sb),
+            Some(MaybePackedBlock::Packed(t)) => Some(decode_stage("convert_block", || {
+                ShipResultsEx::convert_block_v0(shipper_abi, block_num, &t.as_bytes())
+            })?),
+        };
+        let trx = match &block {
+            None => vec![],
+            Some(sb) => decode_stage("get_trx", || sb.get_trx(shipper_abi, max_decompressed_size)),
+        };
+        // a transaction that can't be decoded leaves a `None`, the rest of the block is kept
+        let mut trans: Vec<Option<Transaction>> = Vec::with_capacity(trx.len());
+        let mut transaction_errors = vec![];
+        for (index, t) in trx.into_iter().enumerate() {
+            match t {
+                Ok(t) => trans.push(t),
+                Err(e) => {
+                    let error: Vec<String> = e.iter().map(|e| e.to_string()).collect();
+                    let error = error.join(": ");
+                    warn!(
+                        "transaction {} in block {} not decoded: {}",
+                        index,
+                        block_num.map_or(String::from("?"), |n| n.to_string()),
+                        error
+                    );
+                    metrics::TRANSACTION_DECODE_ERRORS.inc();
+                    transaction_errors.push(TransactionError { index, error });
+                    trans.push(None);
+                }
             }
-            ShipResults::get_blocks_result_v1(br) => {
-                let traces = match br.traces {
-                    None => vec![],
-                    Some(t) => ShipResultsEx::convert_traces(shipper_abi, &t.as_bytes()).unwrap(),
-                };
-                let deltas = match br.deltas {
-                    None => vec![],
-                    Some(t) => ShipResultsEx::convert_deltas(shipper_abi, &t.as_bytes()).unwrap(),
-                };
-                let (block, trans) = match br.block {
-                    None => (None, vec![]),
-                    Some(t) => {
-                        let v_ot: Vec<Option<Transaction>> = t.get_trx(shipper_abi);
-                        (Some(t), v_ot)
-                    }
-                };
+        }
+        let finality_data = match br.finality_data {
+            None => None,
+            Some(t) => decode_stage("convert_finality_data", || {
+                ShipResultsEx::convert_finality_data(shipper_abi, block_num, &t.as_bytes())
+            })?,
+        };
 
-                let br_ex = GetBlocksResultV0Ex {
-                    head: br.head,
-                    last_irreversible: br.last_irreversible,
-                    this_block: br.this_block,
-                    prev_block: br.prev_block,
-                    block: block,
-                    traces: traces,
-                    deltas: deltas,
-                    transactions: trans,
-                };
+        let br_ex = GetBlocksResultV0Ex {
+            head: br.head,
+            last_irreversible: br.last_irreversible,
+            this_block: br.this_block,
+            prev_block: br.prev_block,
+            block: block,
+            traces: traces,
+            deltas: deltas,
+            transactions: trans,
+            transaction_errors,
+            finality_data: finality_data,
+            received,
+            incomplete: None,
+        };
 
-                Ok(ShipResultsEx::BlockResult(br_ex))
-            }
-            ShipResults::get_status_result_v0(sr) => Ok(ShipResultsEx::Status(sr)),
-            //_ => Err("Invalid response to block response".into()),
+        Ok(ShipResultsEx::BlockResult(br_ex))
+    }
+
+    fn convert_finality_data(
+        shipper_abi: &ABIEOS,
+        block_num: Option<u32>,
+        finality_hex: &[u8],
+    ) -> Result<Option<serde_json::Value>> {
+        if finality_hex.len() == 0 {
+            Ok(None)
+        } else {
+            let abi_type = "finality_data";
+            let json = shipper_abi
+                .hex_to_json("eosio", abi_type, finality_hex)
+                .chain_err(|| ErrorKind::Decode(String::from(abi_type), block_num))?;
+            let finality_data: serde_json::Value = serde_json::from_str(&json)
+                .chain_err(|| ErrorKind::Decode(String::from(abi_type), block_num))?;
+            Ok(Some(finality_data))
         }
     }
-    fn convert_traces(shipper_abi: &ABIEOS, trace_hex: &[u8]) -> Result<Vec<Traces>> {
+
+    fn convert_traces(
+        shipper_abi: &ABIEOS,
+        block_num: Option<u32>,
+        trace_hex: &[u8],
+    ) -> Result<Vec<Traces>> {
         if trace_hex.len() == 0 {
             Ok(vec![])
         } else {
-            let json = shipper_abi.hex_to_json("eosio", "transaction_trace[]", trace_hex)?;
-            let trace_v: Vec<Traces> = serde_json::from_str(&json)?;
+            let abi_type = "transaction_trace[]";
+            let json = shipper_abi
+                .hex_to_json("eosio", abi_type, trace_hex)
+                .chain_err(|| ErrorKind::Decode(String::from(abi_type), block_num))?;
+            let trace_v: Vec<Traces> = serde_json::from_str(&json)
+                .chain_err(|| ErrorKind::Decode(String::from(abi_type), block_num))?;
             Ok(trace_v)
         }
     }
 
-    fn convert_deltas(shipper_abi: &ABIEOS, delta_hex: &[u8]) -> Result<Vec<TableDeltaEx>> {
+    fn convert_deltas(
+        shipper_abi: &ABIEOS,
+        block_num: Option<u32>,
+        delta_hex: &[u8],
+    ) -> Result<Vec<TableDeltaEx>> {
         if delta_hex.len() == 0 {
             Ok(vec![])
         } else {
-            let json = shipper_abi.hex_to_json("eosio", "table_delta[]", delta_hex)?;
-            let deltas: Vec<TableDeltas> = serde_json::from_str(&json)?;
+            let abi_type = "table_delta[]";
+            let json = shipper_abi
+                .hex_to_json("eosio", abi_type, delta_hex)
+                .chain_err(|| ErrorKind::Decode(String::from(abi_type), block_num))?;
+            let deltas: Vec<TableDeltas> = serde_json::from_str(&json)
+                .chain_err(|| ErrorKind::Decode(String::from(abi_type), block_num))?;
             let mut delta_ex: Vec<TableDeltaEx> = Vec::with_capacity(deltas.len());
             for delta in deltas {
                 match delta {
@@ -317,11 +523,12 @@ impl ShipResultsEx {
                         let mut row_ex: Vec<TableRowEx> = Vec::with_capacity(td0.rows.len());
                         for row in td0.rows {
                             if ROWTYPES.contains(&name) {
-                                // println!("{}",name);
-                                let _json =
-                                    shipper_abi.hex_to_json("eosio", &name, row.data.as_bytes())?;
+                                let _json = shipper_abi
+                                    .hex_to_json("eosio", &name, row.data.as_bytes())
+                                    .chain_err(|| ErrorKind::Decode(name.clone(), block_num))?;
                                 let json = format!("{{\"{}\":{}}}", &name, _json);
-                                let r: TableRowTypes = serde_json::from_str(&json)?;
+                                let r: TableRowTypes = serde_json::from_str(&json)
+                                    .chain_err(|| ErrorKind::Decode(name.clone(), block_num))?;
                                 row_ex.push(TableRowEx {
                                     present: row.present,
                                     data: r,
@@ -343,14 +550,22 @@ impl ShipResultsEx {
     }
 
     // v0 only has a signed_block_v0 .. v1 contains a variant here
-    fn convert_block_v0(shipper_abi: &ABIEOS, block_hex: &[u8]) -> Result<SignedBlock> {
-        let json = shipper_abi.hex_to_json("eosio", "signed_block", block_hex)?;
-        let signed_block: SignedBlockV0 = serde_json::from_str(&json)?;
+    fn convert_block_v0(
+        shipper_abi: &ABIEOS,
+        block_num: Option<u32>,
+        block_hex: &[u8],
+    ) -> Result<SignedBlock> {
+        let abi_type = "signed_block";
+        let json = shipper_abi
+            .hex_to_json("eosio", abi_type, block_hex)
+            .chain_err(|| ErrorKind::Decode(String::from(abi_type), block_num))?;
+        let signed_block: SignedBlockV0 = serde_json::from_str(&json)
+            .chain_err(|| ErrorKind::Decode(String::from(abi_type), block_num))?;
         Ok(SignedBlock::signed_block_v0(signed_block))
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct GetStatusResponseV0 {
     pub head: BlockPosition,
     pub last_irreversible: BlockPosition,
@@ -359,6 +574,12 @@ pub struct GetStatusResponseV0 {
     pub chain_state_begin_block: u32,
     pub chain_state_end_block: u32,
     pub chain_id: Option<String>,
+    /// get_status_result_v1 only
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub finality_data_begin_block: Option<u32>,
+    /// get_status_result_v1 only
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub finality_data_end_block: Option<u32>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -372,18 +593,30 @@ pub struct GetBlocksResultV0 {
     pub deltas: Option<String>,
 }
 
+/// `block` of a get_blocks_result_v1. EOSIO 2.1 sends a signed_block_variant, Leap/Spring send
+/// the packed bytes of a signed_block.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum MaybePackedBlock {
+    Block(SignedBlock),
+    Packed(String),
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct GetBlocksResultV1 {
     pub head: BlockPosition,
     pub last_irreversible: BlockPosition,
     pub this_block: Option<BlockPosition>,
     pub prev_block: Option<BlockPosition>,
-    pub block: Option<SignedBlock>,
+    pub block: Option<MaybePackedBlock>,
     pub traces: Option<String>,
     pub deltas: Option<String>,
+    /// Leap/Spring only
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub finality_data: Option<String>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct GetBlocksResultV0Ex {
     pub head: BlockPosition,
     pub last_irreversible: BlockPosition,
@@ -393,6 +626,63 @@ pub struct GetBlocksResultV0Ex {
     pub traces: Vec<Traces>,
     pub deltas: Vec<TableDeltaEx>,
     pub transactions: Vec<Option<Transaction>>,
+    /// the packed transactions that couldn't be decoded, their entries in `transactions` are
+    /// `None`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub transaction_errors: Vec<TransactionError>,
+    /// decoded finality_data, sent by Spring when asked for with `fetch_finality_data`
+    pub finality_data: Option<serde_json::Value>,
+    /// the parts the server sent. the others are empty because they weren't there, not
+    /// because the block had none.
+    #[serde(skip)]
+    pub received: BlockParts,
+    /// set by `client::ShipClient` when parts it asked for didn't come back
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub incomplete: Option<Incomplete>,
+}
+
+/// a packed transaction in the block that couldn't be inflated or decoded
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TransactionError {
+    /// position in the block's transactions
+    pub index: usize,
+    pub error: String,
+}
+
+/// the optional parts of a get_blocks result
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct BlockParts {
+    pub block: bool,
+    pub traces: bool,
+    pub deltas: bool,
+    pub finality_data: bool,
+}
+
+/// A block that came back without some of what was requested, typically because it is
+/// outside the server's `trace_begin_block..trace_end_block` or
+/// `chain_state_begin_block..chain_state_end_block`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Incomplete {
+    pub requested: BlockParts,
+    pub received: BlockParts,
+}
+
+impl Incomplete {
+    /// `None` when everything requested was received
+    pub fn check(requested: BlockParts, received: BlockParts) -> Option<Incomplete> {
+        let missing = (requested.block && !received.block)
+            || (requested.traces && !received.traces)
+            || (requested.deltas && !received.deltas)
+            || (requested.finality_data && !received.finality_data);
+        if missing {
+            Some(Incomplete {
+                requested,
+                received,
+            })
+        } else {
+            None
+        }
+    }
 }
 
 #[allow(non_camel_case_types)]
@@ -409,7 +699,7 @@ impl Serialize for Traces {
         let mut m = serializer.serialize_tuple(2)?;
         match self {
             Traces::transaction_trace_v0(k) => {
-                m.serialize_element("get_status_result_v0")?;
+                m.serialize_element("transaction_trace_v0")?;
                 m.serialize_element(k)?;
             }
         }
@@ -417,6 +707,14 @@ impl Serialize for Traces {
     }
 }
 
+impl Traces {
+    pub fn transaction_trace(&self) -> &TransactionTraceV0 {
+        match self {
+            Traces::transaction_trace_v0(t) => t,
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct TransactionTraceV0 {
     pub id: String,
@@ -471,8 +769,23 @@ pub struct PartialTransactionV0 {
     pub max_cpu_usage_ms: u8,
     pub delay_sec: u32,
     pub transaction_extensions: Vec<Extension>,
-    pub signatures: Vec<String>,
-    //    pub context_free_data: Vec<String>,
+    pub signatures: Vec<Signature>,
+    /// hex
+    pub context_free_data: Vec<String>,
+}
+
+impl PartialTransactionV0 {
+    pub fn prunable(&self) -> PrunableContent {
+        PrunableContent {
+            signatures: self.signatures.clone(),
+            context_free_data: self
+                .context_free_data
+                .iter()
+                .map(|data| ContextFreeSegment::Data(data.clone()))
+                .collect(),
+            pruned_digest: None,
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -488,6 +801,26 @@ pub struct PartialTransactionV1 {
     pub prunable_data: Option<PrunableData>,
 }
 
+impl PartialTransactionV1 {
+    /// traces don't say how the transaction was compressed, so `packed_context_segments` is
+    /// taken to be uncompressed and there is nothing to inflate
+    pub fn prunable(&self) -> Result<Option<PrunableContent>> {
+        self.prunable_data
+            .as_ref()
+            .map(|p| p.content(0, DEFAULT_MAX_DECOMPRESSED_SIZE))
+            .transpose()
+    }
+}
+
+impl PartialTransactionVariant {
+    pub fn prunable(&self) -> Result<Option<PrunableContent>> {
+        match self {
+            PartialTransactionVariant::partial_transaction_v0(p) => Ok(Some(p.prunable())),
+            PartialTransactionVariant::partial_transaction_v1(p) => p.prunable(),
+        }
+    }
+}
+
 #[allow(non_camel_case_types)]
 #[derive(Debug, Deserialize)]
 pub enum ActionTraceVariant {
@@ -515,6 +848,98 @@ impl Serialize for ActionTraceVariant {
     }
 }
 
+impl ActionTraceVariant {
+    pub fn action_ordinal(&self) -> u32 {
+        match self {
+            ActionTraceVariant::action_trace_v0(a) => a.action_ordinal,
+            ActionTraceVariant::action_trace_v1(a) => a.action_ordinal,
+        }
+    }
+
+    pub fn creator_action_ordinal(&self) -> u32 {
+        match self {
+            ActionTraceVariant::action_trace_v0(a) => a.creator_action_ordinal,
+            ActionTraceVariant::action_trace_v1(a) => a.creator_action_ordinal,
+        }
+    }
+
+    pub fn receiver(&self) -> &str {
+        match self {
+            ActionTraceVariant::action_trace_v0(a) => &a.receiver,
+            ActionTraceVariant::action_trace_v1(a) => &a.receiver,
+        }
+    }
+
+    pub fn act(&self) -> &Action {
+        match self {
+            ActionTraceVariant::action_trace_v0(a) => &a.act,
+            ActionTraceVariant::action_trace_v1(a) => &a.act,
+        }
+    }
+
+    pub fn receipt(&self) -> Option<&ActionReceiptV0> {
+        let r = match self {
+            ActionTraceVariant::action_trace_v0(a) => &a.receipt,
+            ActionTraceVariant::action_trace_v1(a) => &a.receipt,
+        };
+        match r {
+            Some(ActionReceiptVariant::action_receipt_v0(r0)) => Some(r0),
+            None => None,
+        }
+    }
+
+    pub fn context_free(&self) -> bool {
+        match self {
+            ActionTraceVariant::action_trace_v0(a) => a.context_free,
+            ActionTraceVariant::action_trace_v1(a) => a.context_free,
+        }
+    }
+
+    pub fn elapsed(&self) -> &str {
+        match self {
+            ActionTraceVariant::action_trace_v0(a) => &a.elapsed,
+            ActionTraceVariant::action_trace_v1(a) => &a.elapsed,
+        }
+    }
+
+    pub fn console(&self) -> &str {
+        match self {
+            ActionTraceVariant::action_trace_v0(a) => &a.console,
+            ActionTraceVariant::action_trace_v1(a) => &a.console,
+        }
+    }
+
+    pub fn account_ram_deltas(&self) -> &[AccountDelta] {
+        match self {
+            ActionTraceVariant::action_trace_v0(a) => &a.account_ram_deltas,
+            ActionTraceVariant::action_trace_v1(a) => &a.account_ram_deltas,
+        }
+    }
+
+    pub fn except(&self) -> Option<&String> {
+        match self {
+            ActionTraceVariant::action_trace_v0(a) => a.except.as_ref(),
+            ActionTraceVariant::action_trace_v1(a) => a.except.as_ref(),
+        }
+    }
+
+    /// the eosio_assert_code or other error code the action failed with
+    pub fn error_code(&self) -> Option<u64> {
+        match self {
+            ActionTraceVariant::action_trace_v0(a) => a.error_code,
+            ActionTraceVariant::action_trace_v1(a) => a.error_code,
+        }
+    }
+
+    /// only action_trace_v1 carries a return value
+    pub fn return_value(&self) -> Option<&str> {
+        match self {
+            ActionTraceVariant::action_trace_v0(_) => None,
+            ActionTraceVariant::action_trace_v1(a) => Some(&a.return_value),
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ActionTraceV0 {
     pub action_ordinal: u32,
@@ -600,7 +1025,7 @@ pub struct AccountDelta {
     pub delta: String,
 }
 
-#[derive(Deserialize, Serialize, Debug, Clone)]
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
 pub struct PermissionLevel {
     pub actor: String,
     pub permission: String,
@@ -646,16 +1071,16 @@ impl Serialize for TableDeltas {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct ProducerKey {
-    pub name: String,
-    pub public_key: String,
+    pub producer_name: String,
+    pub block_signing_key: String,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct ProducerSchedule {
     pub version: u32,
-    pub producer: Vec<ProducerKey>,
+    pub producers: Vec<ProducerKey>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -766,46 +1191,39 @@ pub struct TransactionID {
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct PackedTransactionV0 {
-    pub signatures: Vec<String>,
+    pub signatures: Vec<Signature>,
     pub compression: u8,
     pub packed_context_free_data: String,
     pub packed_trx: String,
 }
 
 impl PackedTransactionV0 {
-    fn convert_trx(&self, shipper_abi: &ABIEOS) -> Option<Transaction> {
-        if self.packed_trx.len() != 0 {
-            match self.compression {
-                0 => {
-                    let json = shipper_abi
-                        .hex_to_json("eosio", "transaction", self.packed_trx.as_bytes())
-                        .unwrap();
-                    let trace_v: Transaction = serde_json::from_str(&json).unwrap();
-                    //self.transaction= Some(trace_v);
-                    Some(trace_v)
-                }
-                1 => {
-                    let bin_compressed_trx = hex_to_bin(&self.packed_trx);
-                    let mut d = ZlibDecoder::new(bin_compressed_trx.as_slice());
-                    let mut buffer = Vec::new();
-                    d.read_to_end(&mut buffer).unwrap();
-                    let json = shipper_abi
-                        .bin_to_json("eosio", "transaction", &buffer)
-                        .unwrap();
-                    let trace_v: Transaction = serde_json::from_str(&json).unwrap();
-                    Some(trace_v)
-                }
-                _ => {
-                    error!(
-                        "Invalid compression level of {}. Skipped (PackedTransactionV0)",
-                        self.compression
-                    );
-                    None
-                }
-            }
-        } else {
-            None
-        }
+    /// fails when `packed_context_free_data` inflates past `max_decompressed_size` bytes
+    pub fn prunable(&self, max_decompressed_size: usize) -> Result<PrunableContent> {
+        Ok(PrunableContent {
+            signatures: self.signatures.clone(),
+            context_free_data: packed_segments(
+                &self.packed_context_free_data,
+                self.compression,
+                max_decompressed_size,
+            )?,
+            pruned_digest: None,
+        })
+    }
+
+    /// the transaction in `packed_trx`, `None` when there is none. Fails when it doesn't
+    /// decode or inflates past `max_decompressed_size` bytes.
+    pub fn convert_trx(
+        &self,
+        shipper_abi: &ABIEOS,
+        max_decompressed_size: usize,
+    ) -> Result<Option<Transaction>> {
+        unpack_trx(
+            shipper_abi,
+            self.compression,
+            &self.packed_trx,
+            max_decompressed_size,
+        )
     }
 }
 
@@ -817,38 +1235,25 @@ pub struct PackedTransactionV1 {
 }
 
 impl PackedTransactionV1 {
-    fn convert_trx(&self, shipper_abi: &ABIEOS) -> Option<Transaction> {
-        if self.packed_trx.len() != 0 {
-            match self.compression {
-                0 => {
-                    let json = shipper_abi
-                        .hex_to_json("eosio", "transaction", self.packed_trx.as_bytes())
-                        .unwrap();
-                    let trace_v: Transaction = serde_json::from_str(&json).unwrap();
-                    Some(trace_v)
-                }
-                1 => {
-                    let bin_compressed_trx = hex_to_bin(&self.packed_trx);
-                    let mut d = ZlibDecoder::new(bin_compressed_trx.as_slice());
-                    let mut buffer = Vec::new();
-                    d.read_to_end(&mut buffer).unwrap();
-                    let json = shipper_abi
-                        .bin_to_json("eosio", "transaction", &buffer)
-                        .unwrap();
-                    let trace_v: Transaction = serde_json::from_str(&json).unwrap();
-                    Some(trace_v)
-                }
-                _ => {
-                    error!(
-                        "Invalid compression level of {}. Skipped (PackedTransactionV1)",
-                        self.compression
-                    );
-                    None
-                }
-            }
-        } else {
-            None
-        }
+    /// fails when `packed_context_segments` inflates past `max_decompressed_size` bytes
+    pub fn prunable(&self, max_decompressed_size: usize) -> Result<PrunableContent> {
+        self.prunable_data
+            .content(self.compression, max_decompressed_size)
+    }
+
+    /// the transaction in `packed_trx`, `None` when there is none. Fails when it doesn't
+    /// decode or inflates past `max_decompressed_size` bytes.
+    pub fn convert_trx(
+        &self,
+        shipper_abi: &ABIEOS,
+        max_decompressed_size: usize,
+    ) -> Result<Option<Transaction>> {
+        unpack_trx(
+            shipper_abi,
+            self.compression,
+            &self.packed_trx,
+            max_decompressed_size,
+        )
     }
 }
 
@@ -891,26 +1296,28 @@ impl Serialize for PrunableData {
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct PrunableDataFullLegacy {
-    pub signatures: Vec<String>,
+    pub signatures: Vec<Signature>,
     pub packed_context_segments: String,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct PrunableDataFull {
-    pub signatures: Vec<String>,
+    pub signatures: Vec<Signature>,
     pub context_free_segments: Vec<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct PrunableDataPartial {
-    pub signatures: Vec<String>,
+    pub signatures: Vec<Signature>,
     pub context_free_segments: Vec<ContextFreeSegmentType>,
 }
 
 #[allow(non_camel_case_types)]
 #[derive(Debug, Deserialize, Clone)]
 pub enum ContextFreeSegmentType {
+    /// the digest of a segment that was pruned
     signature(String),
+    /// hex
     bytes(String),
 }
 
@@ -939,6 +1346,277 @@ pub struct PrunableDataNone {
     pub prunable_digest: String,
 }
 
+impl PrunableData {
+    /// The signatures and context free data, whatever form they came in. `compression` is the
+    /// packed transaction's, which `packed_context_segments` is also compressed with, and it may
+    /// inflate to no more than `max_decompressed_size` bytes.
+    pub fn content(
+        &self,
+        compression: u8,
+        max_decompressed_size: usize,
+    ) -> Result<PrunableContent> {
+        Ok(match self {
+            PrunableData::prunable_data_full_legacy(p) => PrunableContent {
+                signatures: p.signatures.clone(),
+                context_free_data: packed_segments(
+                    &p.packed_context_segments,
+                    compression,
+                    max_decompressed_size,
+                )?,
+                pruned_digest: None,
+            },
+            PrunableData::prunable_data_none(p) => PrunableContent {
+                signatures: vec![],
+                context_free_data: vec![],
+                pruned_digest: Some(p.prunable_digest.clone()),
+            },
+            PrunableData::prunable_data_partial(p) => PrunableContent {
+                signatures: p.signatures.clone(),
+                context_free_data: p
+                    .context_free_segments
+                    .iter()
+                    .map(|s| match s {
+                        ContextFreeSegmentType::signature(digest) => {
+                            ContextFreeSegment::Pruned(digest.clone())
+                        }
+                        ContextFreeSegmentType::bytes(data) => {
+                            ContextFreeSegment::Data(data.clone())
+                        }
+                    })
+                    .collect(),
+                pruned_digest: None,
+            },
+            PrunableData::prunable_data_full(p) => PrunableContent {
+                signatures: p.signatures.clone(),
+                context_free_data: p
+                    .context_free_segments
+                    .iter()
+                    .map(|data| ContextFreeSegment::Data(data.clone()))
+                    .collect(),
+                pruned_digest: None,
+            },
+        })
+    }
+}
+
+/// the key type of a signature, from its `SIG_<type>_` prefix
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum SignatureType {
+    K1,
+    R1,
+    WA,
+    Other(String),
+    /// not in the `SIG_<type>_` form at all, `data` holds the string as it came
+    Unknown,
+}
+
+impl fmt::Display for SignatureType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SignatureType::K1 => write!(f, "K1"),
+            SignatureType::R1 => write!(f, "R1"),
+            SignatureType::WA => write!(f, "WA"),
+            SignatureType::Other(t) => write!(f, "{}", t),
+            SignatureType::Unknown => write!(f, "unknown"),
+        }
+    }
+}
+
+/// A signature in the `SIG_<type>_<base58>` form nodeos writes, which is also how it
+/// serializes. `data` is the base58 part, checksum included. One that isn't in that form
+/// deserializes as `SignatureType::Unknown` rather than failing the block it is in.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Signature {
+    pub key_type: SignatureType,
+    pub data: String,
+}
+
+impl FromStr for Signature {
+    type Err = crate::errors::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || format!("invalid signature '{}'", s);
+        let rest = s.strip_prefix("SIG_").ok_or_else(invalid)?;
+        let i = rest.find('_').ok_or_else(invalid)?;
+        let key_type = match &rest[..i] {
+            "K1" => SignatureType::K1,
+            "R1" => SignatureType::R1,
+            "WA" => SignatureType::WA,
+            t => SignatureType::Other(String::from(t)),
+        };
+        Ok(Signature {
+            key_type,
+            data: String::from(&rest[i + 1..]),
+        })
+    }
+}
+
+impl fmt::Display for Signature {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.key_type {
+            SignatureType::Unknown => write!(f, "{}", self.data),
+            _ => write!(f, "SIG_{}_{}", self.key_type, self.data),
+        }
+    }
+}
+
+impl Serialize for Signature {
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Signature {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        Ok(s.parse().unwrap_or_else(|e| {
+            warn!("keeping signature as it came: {}", e);
+            Signature {
+                key_type: SignatureType::Unknown,
+                data: s,
+            }
+        }))
+    }
+}
+
+/// one segment of context free data, or the digest left in its place when it was pruned
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ContextFreeSegment {
+    /// hex
+    Data(String),
+    Pruned(String),
+}
+
+/// A transaction's signatures and context free data, from any of the forms they come in:
+/// `packed_context_free_data`, the prunable data variants, or a partial transaction.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PrunableContent {
+    /// empty when they have been pruned
+    pub signatures: Vec<Signature>,
+    pub context_free_data: Vec<ContextFreeSegment>,
+    /// `prunable_data_none`: everything was pruned and this digest is all that is left
+    pub pruned_digest: Option<String>,
+}
+
+impl PrunableContent {
+    /// whether any of it has been pruned
+    pub fn is_pruned(&self) -> bool {
+        self.pruned_digest.is_some()
+            || self
+                .context_free_data
+                .iter()
+                .any(|s| matches!(s, ContextFreeSegment::Pruned(_)))
+    }
+}
+
+fn to_hex(bin: &[u8]) -> String {
+    let mut hex = String::with_capacity(bin.len() * 2);
+    for b in bin {
+        hex += &format!("{:02x}", b);
+    }
+    hex
+}
+
+/// How far a packed transaction or its context free data may inflate. Past this it is taken
+/// to be a decompression bomb rather than a transaction.
+pub const DEFAULT_MAX_DECOMPRESSED_SIZE: usize = 4 * 1024 * 1024;
+
+/// the bytes of `hex`, inflated when `compression` is zlib
+fn unpack(hex: &str, compression: u8, max_decompressed_size: usize) -> Result<Vec<u8>> {
+    // hex_to_bin panics on anything but pairs of hex digits
+    if hex.len() % 2 != 0 || !hex.bytes().all(|c| c.is_ascii_hexdigit()) {
+        return Err(ErrorKind::Decode(String::from("hex"), None).into());
+    }
+    let bin = hex_to_bin(hex);
+    match compression {
+        0 => Ok(bin),
+        1 => {
+            let mut buffer = Vec::new();
+            // one byte over is enough to know it is too big
+            ZlibDecoder::new(bin.as_slice())
+                .take(max_decompressed_size as u64 + 1)
+                .read_to_end(&mut buffer)?;
+            if buffer.len() > max_decompressed_size {
+                return Err(ErrorKind::DecompressedTooLarge(max_decompressed_size).into());
+            }
+            Ok(buffer)
+        }
+        c => Err(format!("unknown compression {}", c).into()),
+    }
+}
+
+/// the transaction packed in `packed_trx`, `None` when it is empty
+fn unpack_trx(
+    shipper_abi: &ABIEOS,
+    compression: u8,
+    packed_trx: &str,
+    max_decompressed_size: usize,
+) -> Result<Option<Transaction>> {
+    if packed_trx.is_empty() {
+        return Ok(None);
+    }
+    let bin = unpack(packed_trx, compression, max_decompressed_size)?;
+    let json = shipper_abi
+        .bin_to_json("eosio", "transaction", &bin)
+        .chain_err(|| ErrorKind::Decode(String::from("transaction"), None))?;
+    let transaction: Transaction = serde_json::from_str(&json)
+        .chain_err(|| ErrorKind::Decode(String::from("transaction"), None))?;
+    Ok(Some(transaction))
+}
+
+fn read_varuint32(bin: &[u8], pos: &mut usize) -> Result<u32> {
+    let mut value: u32 = 0;
+    for shift in (0..35).step_by(7) {
+        let b = *bin.get(*pos).ok_or("varuint32 runs past the end")?;
+        *pos += 1;
+        value |= ((b & 0x7f) as u32) << shift;
+        if b & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err("varuint32 is too long".into())
+}
+
+/// `bytes[]` packed as a varuint32 count followed by each entry's varuint32 length and bytes
+fn unpack_bytes_vec(bin: &[u8]) -> Result<Vec<Vec<u8>>> {
+    let mut pos = 0;
+    let count = read_varuint32(bin, &mut pos)?;
+    let mut entries = vec![];
+    for _ in 0..count {
+        let len = read_varuint32(bin, &mut pos)? as usize;
+        let entry = bin
+            .get(pos..pos.saturating_add(len))
+            .ok_or("context free data runs past the end")?;
+        entries.push(entry.to_vec());
+        pos += len;
+    }
+    Ok(entries)
+}
+
+/// context free data packed (and maybe compressed) into one `bytes`, inflated no further
+/// than `max_decompressed_size`
+fn packed_segments(
+    hex: &str,
+    compression: u8,
+    max_decompressed_size: usize,
+) -> Result<Vec<ContextFreeSegment>> {
+    if hex.is_empty() {
+        return Ok(vec![]);
+    }
+    let bin = unpack(hex, compression, max_decompressed_size)?;
+    Ok(unpack_bytes_vec(&bin)?
+        .iter()
+        .map(|s| ContextFreeSegment::Data(to_hex(s)))
+        .collect())
+}
+
 #[allow(non_camel_case_types)]
 #[derive(Debug, Deserialize)]
 pub enum SignedBlock {
@@ -967,22 +1645,111 @@ impl Serialize for SignedBlock {
 }
 
 impl SignedBlock {
-    pub fn get_trx(&self, shipper_abi: &ABIEOS) -> Vec<Option<Transaction>> {
-        let mut vo_t: Vec<Option<Transaction>> = vec![];
+    pub fn signed_header(&self) -> &SignedBlockHeader {
+        match self {
+            SignedBlock::signed_block_v0(k) => &k.signed_header,
+            SignedBlock::signed_block_v1(k) => &k.signed_header,
+        }
+    }
+
+    pub fn receipt_headers(&self) -> Vec<&TransactionReceiptHeader> {
+        match self {
+            SignedBlock::signed_block_v0(k) => k.transactions.iter().map(|t| &t.header).collect(),
+            SignedBlock::signed_block_v1(k) => k.transactions.iter().map(|t| &t.header).collect(),
+        }
+    }
+
+    /// receipt header and transaction id of each transaction in the block.
+    /// packed transactions don't carry their id, so it is the sha256 of the inflated packed_trx,
+    /// an error when that doesn't inflate within `max_decompressed_size` bytes. One that fails
+    /// doesn't affect the others.
+    pub fn receipts(
+        &self,
+        max_decompressed_size: usize,
+    ) -> Vec<(&TransactionReceiptHeader, Result<String>)> {
+        match self {
+            SignedBlock::signed_block_v0(k) => k
+                .transactions
+                .iter()
+                .map(|t| {
+                    let id = match &t.trx {
+                        TransactionVariantV0::transaction_id(tid) => Ok(tid.transaction_id.clone()),
+                        TransactionVariantV0::packed_transaction(pt) => {
+                            packed_trx_id(pt.compression, &pt.packed_trx, max_decompressed_size)
+                        }
+                        TransactionVariantV0::packed_transaction_v0(pt) => {
+                            packed_trx_id(pt.compression, &pt.packed_trx, max_decompressed_size)
+                        }
+                    };
+                    (&t.header, id)
+                })
+                .collect(),
+            SignedBlock::signed_block_v1(k) => k
+                .transactions
+                .iter()
+                .map(|t| {
+                    let id = match &t.trx {
+                        TransactionVariantV1::transaction_id(tid) => Ok(tid.transaction_id.clone()),
+                        TransactionVariantV1::packed_transaction_v1(pt) => {
+                            packed_trx_id(pt.compression, &pt.packed_trx, max_decompressed_size)
+                        }
+                    };
+                    (&t.header, id)
+                })
+                .collect(),
+        }
+    }
+
+    /// signatures and context free data of each transaction, `None` for a receipt that only
+    /// has the transaction id. Context free data may inflate to `max_decompressed_size` bytes.
+    pub fn prunable(&self, max_decompressed_size: usize) -> Vec<Result<Option<PrunableContent>>> {
+        match self {
+            SignedBlock::signed_block_v0(k) => k
+                .transactions
+                .iter()
+                .map(|t| match &t.trx {
+                    TransactionVariantV0::transaction_id(_) => Ok(None),
+                    TransactionVariantV0::packed_transaction(pt)
+                    | TransactionVariantV0::packed_transaction_v0(pt) => {
+                        pt.prunable(max_decompressed_size).map(Some)
+                    }
+                })
+                .collect(),
+            SignedBlock::signed_block_v1(k) => k
+                .transactions
+                .iter()
+                .map(|t| match &t.trx {
+                    TransactionVariantV1::transaction_id(_) => Ok(None),
+                    TransactionVariantV1::packed_transaction_v1(pt) => {
+                        pt.prunable(max_decompressed_size).map(Some)
+                    }
+                })
+                .collect(),
+        }
+    }
+
+    /// each transaction in the block, `None` for a receipt that only has the transaction id.
+    /// One that fails to decode doesn't affect the others.
+    pub fn get_trx(
+        &self,
+        shipper_abi: &ABIEOS,
+        max_decompressed_size: usize,
+    ) -> Vec<Result<Option<Transaction>>> {
+        let mut vo_t: Vec<Result<Option<Transaction>>> = vec![];
         match self {
             SignedBlock::signed_block_v0(k) => {
                 //  let mut kt = k.transactions.clone();
                 for t in &k.transactions {
                     match &t.trx {
                         TransactionVariantV0::transaction_id(_) => {
-                            vo_t.push(None);
+                            vo_t.push(Ok(None));
                         }
                         TransactionVariantV0::packed_transaction(pt) => {
-                            let transaction = pt.convert_trx(shipper_abi);
+                            let transaction = pt.convert_trx(shipper_abi, max_decompressed_size);
                             vo_t.push(transaction);
                         }
                         TransactionVariantV0::packed_transaction_v0(pt) => {
-                            let transaction = pt.convert_trx(shipper_abi);
+                            let transaction = pt.convert_trx(shipper_abi, max_decompressed_size);
                             vo_t.push(transaction);
                         }
                     }
@@ -992,9 +1759,9 @@ impl SignedBlock {
                 // let mut kt = k.transactions.clone();
                 for t in &k.transactions {
                     match &t.trx {
-                        TransactionVariantV1::transaction_id(_tt) => vo_t.push(None),
+                        TransactionVariantV1::transaction_id(_tt) => vo_t.push(Ok(None)),
                         TransactionVariantV1::packed_transaction_v1(pt) => {
-                            let transaction = pt.convert_trx(shipper_abi);
+                            let transaction = pt.convert_trx(shipper_abi, max_decompressed_size);
                             vo_t.push(transaction);
                         }
                     }
@@ -1005,6 +1772,19 @@ impl SignedBlock {
     }
 }
 
+/// the sha256 of the inflated `packed_trx`. Fails when it doesn't inflate, hashing the
+/// compressed bytes would give an id no other node knows.
+fn packed_trx_id(
+    compression: u8,
+    packed_trx: &str,
+    max_decompressed_size: usize,
+) -> Result<String> {
+    let bin = unpack(packed_trx, compression, max_decompressed_size)?;
+    let mut value = [0u8; 32];
+    value.copy_from_slice(&Sha256::digest(&bin));
+    Ok(Checksum256 { value }.to_string())
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct BlockHeader {
     pub timestamp: String,
@@ -1042,20 +1822,20 @@ pub struct SignedBlockV1 {
     pub block_extensions: Vec<Extension>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct TableDeltaEx {
     pub name: String,
     pub rows: Vec<TableRowEx>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct TableRowEx {
     pub present: bool,
     pub data: TableRowTypes,
 }
 
 #[allow(non_camel_case_types)]
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Clone)]
 pub enum TableRowTypes {
     account(Account),
     account_metadata(AccountMetadata),
@@ -1069,6 +1849,11 @@ pub enum TableRowTypes {
     // TODO float 128 it accepts the string.. but no idea next step
     contract_index_long_double(ContractIndexLongDouble),
 
+    generated_transaction(GeneratedTransaction),
+
+    permission(Permission),
+    permission_link(PermissionLink),
+
     resource_limits(ResourceLimits),
     resource_usage(ResourceUsage),
     resource_limits_state(ResourceLimitsState),
@@ -1124,6 +1909,18 @@ impl Serialize for TableRowTypes {
                 m.serialize_element("contract_index_long_double")?;
                 m.serialize_element(k)?;
             }
+            TableRowTypes::generated_transaction(k) => {
+                m.serialize_element("generated_transaction")?;
+                m.serialize_element(k)?;
+            }
+            TableRowTypes::permission(k) => {
+                m.serialize_element("permission")?;
+                m.serialize_element(k)?;
+            }
+            TableRowTypes::permission_link(k) => {
+                m.serialize_element("permission_link")?;
+                m.serialize_element(k)?;
+            }
             TableRowTypes::resource_limits(k) => {
                 m.serialize_element("resource_limits")?;
                 m.serialize_element(k)?;
@@ -1147,7 +1944,7 @@ impl Serialize for TableRowTypes {
 }
 
 #[allow(non_camel_case_types)]
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Clone)]
 pub enum ContractTable {
     contract_table_v0(ContractTableV0),
 }
@@ -1168,7 +1965,7 @@ impl Serialize for ContractTable {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct ContractTableV0 {
     pub code: String,
     pub scope: String,
@@ -1177,7 +1974,7 @@ pub struct ContractTableV0 {
 }
 
 #[allow(non_camel_case_types)]
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Clone)]
 pub enum ContractRow {
     contract_row_v0(ContractRowV0),
 }
@@ -1198,7 +1995,7 @@ impl Serialize for ContractRow {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct ContractRowV0 {
     pub code: String,
     pub scope: String,
@@ -1209,7 +2006,7 @@ pub struct ContractRowV0 {
 }
 
 #[allow(non_camel_case_types)]
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Clone)]
 pub enum ContractIndex64 {
     contract_index64_v0(ContractIndex64V0),
 }
@@ -1230,7 +2027,7 @@ impl Serialize for ContractIndex64 {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct ContractIndex64V0 {
     pub code: String,
     pub scope: String,
@@ -1241,7 +2038,7 @@ pub struct ContractIndex64V0 {
 }
 
 #[allow(non_camel_case_types)]
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Clone)]
 pub enum ContractIndex128 {
     contract_index128_v0(ContractIndex128V0),
 }
@@ -1262,7 +2059,7 @@ impl Serialize for ContractIndex128 {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct ContractIndex128V0 {
     pub code: String,
     pub scope: String,
@@ -1273,7 +2070,7 @@ pub struct ContractIndex128V0 {
 }
 
 #[allow(non_camel_case_types)]
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Clone)]
 pub enum ContractIndex256 {
     contract_index256_v0(ContractIndex256V0),
 }
@@ -1294,7 +2091,7 @@ impl Serialize for ContractIndex256 {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct ContractIndex256V0 {
     pub code: String,
     pub scope: String,
@@ -1305,7 +2102,7 @@ pub struct ContractIndex256V0 {
 }
 
 #[allow(non_camel_case_types)]
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Clone)]
 pub enum ContractIndexDouble {
     contract_index_double_v0(ContractIndexDoubleV0),
 }
@@ -1326,7 +2123,7 @@ impl Serialize for ContractIndexDouble {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct ContractIndexDoubleV0 {
     pub code: String,
     pub scope: String,
@@ -1337,7 +2134,7 @@ pub struct ContractIndexDoubleV0 {
 }
 
 #[allow(non_camel_case_types)]
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Clone)]
 pub enum ContractIndexLongDouble {
     contract_index_long_double_v0(ContractIndexLongDoubleV0),
 }
@@ -1358,7 +2155,7 @@ impl Serialize for ContractIndexLongDouble {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct ContractIndexLongDoubleV0 {
     pub code: String,
     pub scope: String,
@@ -1370,7 +2167,7 @@ pub struct ContractIndexLongDoubleV0 {
 }
 
 #[allow(non_camel_case_types)]
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Clone)]
 pub enum Code {
     code_v0(CodeV0),
 }
@@ -1391,7 +2188,7 @@ impl Serialize for Code {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct CodeV0 {
     pub vm_type: u8,
     pub vm_version: u8,
@@ -1400,7 +2197,7 @@ pub struct CodeV0 {
 }
 
 #[allow(non_camel_case_types)]
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Clone)]
 pub enum AccountMetadata {
     account_metadata_v0(AccountMetadataV0),
 }
@@ -1421,14 +2218,14 @@ impl Serialize for AccountMetadata {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct CodeID {
     pub vm_type: u8,
     pub vm_version: u8,
     pub code_hash: String,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct AccountMetadataV0 {
     pub name: String,
     pub privileged: bool,
@@ -1438,7 +2235,7 @@ pub struct AccountMetadataV0 {
 }
 
 #[allow(non_camel_case_types)]
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Clone)]
 pub enum Account {
     account_v0(AccountV0),
 }
@@ -1459,7 +2256,7 @@ impl Serialize for Account {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct AccountV0 {
     pub name: String,
     #[serde(with = "eosio_datetime_format")]
@@ -1468,7 +2265,7 @@ pub struct AccountV0 {
 }
 
 #[allow(non_camel_case_types)]
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Clone)]
 pub enum ResourceUsage {
     resource_usage_v0(ResourceUsageV0),
 }
@@ -1490,7 +2287,7 @@ impl Serialize for ResourceUsage {
 }
 
 #[allow(non_camel_case_types)]
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Clone)]
 pub enum UsageAccumulator {
     usage_accumulator_v0(UsageAccumulatorV0),
 }
@@ -1511,7 +2308,7 @@ impl Serialize for UsageAccumulator {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct UsageAccumulatorV0 {
     pub last_ordinal: u32,
     pub value_ex: String,
@@ -1519,7 +2316,7 @@ pub struct UsageAccumulatorV0 {
     pub consumed: String, // u64
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct ResourceUsageV0 {
     pub owner: String,
     pub net_usage: UsageAccumulator,
@@ -1528,7 +2325,7 @@ pub struct ResourceUsageV0 {
 }
 
 #[allow(non_camel_case_types)]
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Clone)]
 pub enum ResourceLimits {
     resource_limits_v0(ResourceLimitsV0),
 }
@@ -1549,7 +2346,7 @@ impl Serialize for ResourceLimits {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct ResourceLimitsV0 {
     pub owner: String,
     pub net_weight: String,
@@ -1560,7 +2357,7 @@ pub struct ResourceLimitsV0 {
 }
 
 #[allow(non_camel_case_types)]
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Clone)]
 pub enum ResourceLimitsState {
     resource_limits_state_v0(ResourceLimitsStateV0),
 }
@@ -1581,7 +2378,7 @@ impl Serialize for ResourceLimitsState {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct ResourceLimitsStateV0 {
     pub average_block_net_usage: UsageAccumulator,
     pub average_block_cpu_usage: UsageAccumulator,
@@ -1598,7 +2395,7 @@ pub struct ResourceLimitsStateV0 {
 }
 
 #[allow(non_camel_case_types)]
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Clone)]
 pub enum ResourceLimitsConfig {
     resource_limits_config_v0(ResourceLimitsConfigV0),
 }
@@ -1620,7 +2417,7 @@ impl Serialize for ResourceLimitsConfig {
 }
 
 #[allow(non_camel_case_types)]
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Clone)]
 pub enum ElasticLimitParameters {
     elastic_limit_parameters_v0(ElasticLimitParametersV0),
 }
@@ -1641,7 +2438,7 @@ impl Serialize for ElasticLimitParameters {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct ElasticLimitParametersV0 {
     pub target: String,
     //u64
@@ -1656,7 +2453,7 @@ pub struct ElasticLimitParametersV0 {
 }
 
 #[allow(non_camel_case_types)]
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Clone)]
 pub enum ResourceLimitsRatio {
     resource_limits_ratio_v0(ResourceLimitsRatioV0),
 }
@@ -1677,17 +2474,330 @@ impl Serialize for ResourceLimitsRatio {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct ResourceLimitsRatioV0 {
     pub numerator: String,
     //u64
     pub denominator: String, //u64
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct ResourceLimitsConfigV0 {
     pub cpu_limit_parameters: ElasticLimitParameters,
     pub net_limit_parameters: ElasticLimitParameters,
     pub account_cpu_usage_average_window: u32,
     pub account_net_usage_average_window: u32,
 }
+
+#[allow(non_camel_case_types)]
+#[derive(Debug, Deserialize, Clone)]
+pub enum GeneratedTransaction {
+    generated_transaction_v0(GeneratedTransactionV0),
+}
+
+impl Serialize for GeneratedTransaction {
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut m = serializer.serialize_tuple(2)?;
+        match self {
+            GeneratedTransaction::generated_transaction_v0(k) => {
+                m.serialize_element("generated_transaction_v0")?;
+                m.serialize_element(k)?;
+            }
+        }
+        m.end()
+    }
+}
+
+/// a deferred transaction waiting to run, from `send_deferred` or a transaction with a delay
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct GeneratedTransactionV0 {
+    pub sender: String,
+    pub sender_id: String, // u128
+    pub payer: String,
+    pub trx_id: String,
+    pub packed_trx: String,
+}
+
+#[allow(non_camel_case_types)]
+#[derive(Debug, Deserialize, Clone)]
+pub enum Permission {
+    permission_v0(PermissionV0),
+}
+
+impl Serialize for Permission {
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut m = serializer.serialize_tuple(2)?;
+        match self {
+            Permission::permission_v0(k) => {
+                m.serialize_element("permission_v0")?;
+                m.serialize_element(k)?;
+            }
+        }
+        m.end()
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct KeyWeight {
+    pub key: String,
+    pub weight: u16,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct PermissionLevelWeight {
+    pub permission: PermissionLevel,
+    pub weight: u16,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct WaitWeight {
+    pub wait_sec: u32,
+    pub weight: u16,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Authority {
+    pub threshold: u32,
+    pub keys: Vec<KeyWeight>,
+    pub accounts: Vec<PermissionLevelWeight>,
+    pub waits: Vec<WaitWeight>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct PermissionV0 {
+    pub owner: String,
+    pub name: String,
+    pub parent: String,
+    #[serde(with = "eosio_datetime_format")]
+    pub last_updated: DateTime<Utc>,
+    pub auth: Authority,
+}
+
+#[allow(non_camel_case_types)]
+#[derive(Debug, Deserialize, Clone)]
+pub enum PermissionLink {
+    permission_link_v0(PermissionLinkV0),
+}
+
+impl Serialize for PermissionLink {
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut m = serializer.serialize_tuple(2)?;
+        match self {
+            PermissionLink::permission_link_v0(k) => {
+                m.serialize_element("permission_link_v0")?;
+                m.serialize_element(k)?;
+            }
+        }
+        m.end()
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct PermissionLinkV0 {
+    pub account: String,
+    pub code: String,
+    pub message_type: String,
+    pub required_permission: String,
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use flate2::write::ZlibEncoder;
+    use flate2::Compression;
+    use serde_json::json;
+
+    /// a block with nothing but `deltas`, for the trackers' tests
+    pub(crate) fn block(
+        block_num: u32,
+        last_irreversible: u32,
+        deltas: Vec<TableDeltaEx>,
+    ) -> GetBlocksResultV0Ex {
+        let position = |n: u32| json!({ "block_num": n, "block_id": format!("{:064x}", n) });
+        let mut block: GetBlocksResultV0Ex = serde_json::from_value(json!({
+            "head": position(block_num),
+            "last_irreversible": position(last_irreversible),
+            "this_block": position(block_num),
+            "prev_block": null,
+            "block": null,
+            "traces": [],
+            "deltas": [],
+            "transactions": [],
+            "finality_data": null,
+        }))
+        .unwrap();
+        block.deltas = deltas;
+        block
+    }
+
+    /// a transaction trace of `(receiver, action, global sequence)` executions, with no receipt
+    /// when the global sequence is `None`
+    pub(crate) fn trace(id: &str, actions: &[(&str, &str, Option<u64>)]) -> Traces {
+        let action_traces: Vec<serde_json::Value> = actions
+            .iter()
+            .enumerate()
+            .map(|(i, (receiver, name, global_sequence))| {
+                let receipt = global_sequence.map(|gs| {
+                    json!({ "action_receipt_v0": {
+                        "receiver": receiver,
+                        "act_digest": "",
+                        "global_sequence": gs.to_string(),
+                        "recv_sequence": "1",
+                        "auth_sequence": [],
+                        "code_sequence": 0,
+                        "abi_sequence": 0,
+                    }})
+                });
+                json!({ "action_trace_v0": {
+                    "action_ordinal": i + 1,
+                    "creator_action_ordinal": 0,
+                    "receipt": receipt,
+                    "receiver": receiver,
+                    "act": { "account": receiver, "name": name, "authorization": [], "data": "" },
+                    "context_free": false,
+                    "elapsed": "0",
+                    "console": "",
+                    "account_ram_deltas": [],
+                    "except": null,
+                    "error_code": null,
+                }})
+            })
+            .collect();
+        serde_json::from_value(json!({ "transaction_trace_v0": {
+            "id": id,
+            "status": 0,
+            "cpu_usage_us": 0,
+            "net_usage_words": 0,
+            "elapsed": "0",
+            "net_usage": "0",
+            "scheduled": false,
+            "action_traces": action_traces,
+            "account_ram_delta": null,
+            "except": null,
+            "error_code": null,
+            "failed_dtrx_trace": null,
+            "partial": null,
+        }}))
+        .unwrap()
+    }
+
+    fn parts(block: bool, traces: bool, deltas: bool) -> BlockParts {
+        BlockParts {
+            block,
+            traces,
+            deltas,
+            finality_data: false,
+        }
+    }
+
+    #[test]
+    fn unpack_bytes_vec_reads_each_entry() {
+        // two entries, the second one 130 bytes long so its length takes two bytes
+        let mut bin = vec![2, 3, 1, 2, 3, 0x82, 0x01];
+        bin.extend(vec![7u8; 130]);
+        let entries = unpack_bytes_vec(&bin).unwrap();
+        assert_eq!(entries, vec![vec![1, 2, 3], vec![7u8; 130]]);
+
+        assert_eq!(unpack_bytes_vec(&[0]).unwrap(), Vec::<Vec<u8>>::new());
+        // an entry longer than what is left, and a count with nothing after it
+        assert!(unpack_bytes_vec(&[1, 4, 1, 2]).is_err());
+        assert!(unpack_bytes_vec(&[1]).is_err());
+        assert!(unpack_bytes_vec(&[]).is_err());
+        // a varuint32 that never ends
+        assert!(unpack_bytes_vec(&[0xff, 0xff, 0xff, 0xff, 0xff, 0x01]).is_err());
+    }
+
+    #[test]
+    fn unpack_stops_at_the_limit() {
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&[0u8; 1000]).unwrap();
+        let hex = to_hex(&encoder.finish().unwrap());
+
+        assert_eq!(unpack(&hex, 1, 1000).unwrap(), vec![0u8; 1000]);
+        let err = unpack(&hex, 1, 999).unwrap_err();
+        assert!(matches!(err.kind(), ErrorKind::DecompressedTooLarge(999)));
+        assert_eq!(unpack("0102", 0, 1).unwrap(), vec![1, 2]);
+        assert!(unpack("0102", 2, 1000).is_err());
+    }
+
+    #[test]
+    fn unpack_refuses_bad_hex() {
+        for hex in &["010", "0g", "zz", "é1", "01 2"] {
+            for compression in 0..=1 {
+                let err = unpack(hex, compression, 1000).unwrap_err();
+                assert!(matches!(err.kind(), ErrorKind::Decode(t, None) if t == "hex"));
+            }
+        }
+        assert!(unpack("", 0, 1000).unwrap().is_empty());
+        assert_eq!(unpack("0aFf", 0, 1000).unwrap(), vec![0x0a, 0xff]);
+    }
+
+    #[test]
+    fn packed_trx_id_hashes_the_inflated_bytes() {
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(b"transaction").unwrap();
+        let compressed = to_hex(&encoder.finish().unwrap());
+        let plain = to_hex(b"transaction");
+
+        let id = packed_trx_id(0, &plain, 1000).unwrap();
+        assert_eq!(packed_trx_id(1, &compressed, 1000).unwrap(), id);
+        assert_eq!(id.len(), 64);
+        // no id from bytes that don't inflate
+        assert!(packed_trx_id(1, &plain, 1000).is_err());
+        assert!(packed_trx_id(2, &plain, 1000).is_err());
+        let err = packed_trx_id(1, &compressed, 5).unwrap_err();
+        assert!(matches!(err.kind(), ErrorKind::DecompressedTooLarge(5)));
+    }
+
+    #[test]
+    fn signatures_keep_what_doesnt_parse() {
+        let sigs: Vec<Signature> =
+            serde_json::from_value(json!(["SIG_K1_abc", "SIG_X9_d_e", "odd"])).unwrap();
+        assert_eq!(sigs[0].key_type, SignatureType::K1);
+        assert_eq!(sigs[0].data, "abc");
+        assert_eq!(sigs[1].key_type, SignatureType::Other(String::from("X9")));
+        assert_eq!(sigs[1].data, "d_e");
+        assert_eq!(sigs[2].key_type, SignatureType::Unknown);
+        assert!("odd".parse::<Signature>().is_err());
+        // all of them serialize back the way they came
+        assert_eq!(
+            serde_json::to_value(&sigs).unwrap(),
+            json!(["SIG_K1_abc", "SIG_X9_d_e", "odd"])
+        );
+    }
+
+    #[test]
+    fn incomplete_only_when_something_requested_is_missing() {
+        let all = parts(true, true, true);
+        assert_eq!(Incomplete::check(all, all), None);
+        // more than requested is fine
+        assert_eq!(Incomplete::check(parts(true, false, false), all), None);
+        assert_eq!(
+            Incomplete::check(parts(false, false, false), parts(false, false, false)),
+            None
+        );
+
+        let received = parts(true, false, true);
+        assert_eq!(
+            Incomplete::check(all, received),
+            Some(Incomplete {
+                requested: all,
+                received,
+            })
+        );
+        let finality = BlockParts {
+            finality_data: true,
+            ..parts(false, false, false)
+        };
+        assert!(Incomplete::check(finality, all).is_some());
+    }
+}
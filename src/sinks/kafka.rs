@@ -0,0 +1,463 @@
+use crate::checkpoint::FileCheckpoint;
+use crate::errors::Result;
+use crate::filter::{Event, EventFilter};
+use crate::shipper_types::GetBlocksResultV0Ex;
+use crate::sinks::BlockSink;
+use log::*;
+use rdkafka::config::ClientConfig;
+use rdkafka::error::KafkaError;
+use rdkafka::producer::{BaseProducer, BaseRecord, DeliveryResult, Producer, ProducerContext};
+use rdkafka::types::RDKafkaErrorCode;
+use rdkafka::ClientContext;
+use serde_json::json;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+/// what one message on the default topic holds
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MessageUnit {
+    /// keyed by producer
+    Block,
+    /// keyed by the account of the first action
+    Transaction,
+    /// keyed by receiver
+    Action,
+}
+
+/// events matching `filter` are published to `topic`, keyed by receiver (actions) or contract
+/// (deltas)
+#[derive(Debug, Clone)]
+pub struct TopicRoute {
+    pub topic: String,
+    pub filter: EventFilter,
+}
+
+#[derive(Debug, Clone)]
+pub struct KafkaConfig {
+    /// bootstrap.servers, eg `localhost:9092`
+    pub brokers: String,
+    pub unit: MessageUnit,
+    /// gets every block, transaction or action (per `unit`) when set
+    pub default_topic: Option<String>,
+    pub routes: Vec<TopicRoute>,
+    pub checkpoint: FileCheckpoint,
+    /// blocks between checkpoint commits
+    pub commit_interval: u32,
+    pub ack_timeout: Duration,
+}
+
+/// a message ready to go out. without a partition the key picks one.
+#[derive(Debug, Clone, PartialEq)]
+struct Message {
+    topic: String,
+    partition: Option<i32>,
+    key: Option<String>,
+    payload: String,
+}
+
+impl Message {
+    fn keyed(topic: &str, key: &str, payload: String) -> Message {
+        Message {
+            topic: String::from(topic),
+            partition: None,
+            key: Some(String::from(key)),
+            payload,
+        }
+    }
+}
+
+/// every message `block` makes, on the default topic and on the routes
+fn block_messages(config: &KafkaConfig, block: &GetBlocksResultV0Ex) -> Result<Vec<Message>> {
+    let block_num = match &block.this_block {
+        Some(bp) => bp.block_num,
+        None => return Ok(vec![]),
+    };
+    let mut messages = vec![];
+    if let Some(topic) = &config.default_topic {
+        match config.unit {
+            MessageUnit::Block => {
+                let producer = match &block.block {
+                    Some(sb) => sb.signed_header().header.producer.clone(),
+                    None => String::new(),
+                };
+                messages.push(Message::keyed(
+                    topic,
+                    &producer,
+                    serde_json::to_string(block)?,
+                ));
+            }
+            MessageUnit::Transaction => {
+                for trace in &block.traces {
+                    let tt = trace.transaction_trace();
+                    let key = match tt.action_traces.first() {
+                        Some(at) => at.act().account.clone(),
+                        None => String::new(),
+                    };
+                    let payload = json!({ "block_num": block_num, "transaction_trace": trace });
+                    messages.push(Message::keyed(topic, &key, payload.to_string()));
+                }
+            }
+            MessageUnit::Action => {
+                let all = EventFilter {
+                    actions: vec![Default::default()],
+                    deltas: vec![],
+                };
+                for event in all.events(block)? {
+                    messages.push(Message::keyed(
+                        topic,
+                        &event.key,
+                        serde_json::to_string(&event)?,
+                    ));
+                }
+            }
+        }
+    }
+    for route in &config.routes {
+        for event in route.filter.events(block)? {
+            messages.push(Message::keyed(
+                &route.topic,
+                &event.key,
+                serde_json::to_string(&event)?,
+            ));
+        }
+    }
+    Ok(messages)
+}
+
+/// The undo event on every partition of every topic. The events it retracts were spread over
+/// the partitions by their keys, so each partition's consumers need to see it.
+fn undo_messages(block_num: u32, partitions: &[(String, i32)]) -> Result<Vec<Message>> {
+    let payload = serde_json::to_string(&Event::undo(block_num))?;
+    Ok(partitions
+        .iter()
+        .flat_map(|(topic, count)| {
+            let payload = &payload;
+            (0..*count).map(move |partition| Message {
+                topic: topic.clone(),
+                partition: Some(partition),
+                key: None,
+                payload: payload.clone(),
+            })
+        })
+        .collect())
+}
+
+/// counts failed deliveries. The messages that failed are gone, so the count is never reset:
+/// once one fails the checkpoint can't move again and the sink has to be restarted from it.
+#[derive(Default)]
+struct DeliveryTracker {
+    failed: AtomicUsize,
+}
+
+impl DeliveryTracker {
+    /// whether the checkpoint can move past everything sent so far
+    fn check(&self, in_flight: i32) -> Result<()> {
+        let failed = self.failed.load(Ordering::SeqCst);
+        if in_flight > 0 || failed > 0 {
+            return Err(format!(
+                "kafka: {} messages unacknowledged, {} failed; checkpoint not moved",
+                in_flight, failed
+            )
+            .into());
+        }
+        Ok(())
+    }
+}
+
+impl ClientContext for DeliveryTracker {}
+
+impl ProducerContext for DeliveryTracker {
+    type DeliveryOpaque = ();
+
+    fn delivery(&self, result: &DeliveryResult, _: Self::DeliveryOpaque) {
+        if let Err((e, _)) = result {
+            error!("kafka: delivery failed {:?}", e);
+            self.failed.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+}
+
+/// Publishes the block stream to a Kafka protocol broker.
+///
+/// Forks are published as an `undo` event (see `filter::Event::undo`) on every partition of
+/// every topic. The
+/// checkpoint is only written after the broker has acknowledged every message up to it, so a
+/// restart from the checkpoint can repeat messages but never skip them.
+pub struct KafkaSink {
+    config: KafkaConfig,
+    producer: BaseProducer<DeliveryTracker>,
+    last_block: Option<u32>,
+    uncommitted: u32,
+}
+
+impl KafkaSink {
+    pub fn new(config: KafkaConfig) -> Result<KafkaSink> {
+        let producer: BaseProducer<DeliveryTracker> = ClientConfig::new()
+            .set("bootstrap.servers", &config.brokers)
+            .set("acks", "all")
+            .set("enable.idempotence", "true")
+            .create_with_context(DeliveryTracker::default())?;
+        let last_block = config.checkpoint.load()?;
+        Ok(KafkaSink {
+            config,
+            producer,
+            last_block,
+            uncommitted: 0,
+        })
+    }
+
+    fn send(&self, message: &Message) -> Result<()> {
+        let mut record = BaseRecord::<str, str>::to(&message.topic).payload(&message.payload);
+        if let Some(key) = &message.key {
+            record = record.key(key.as_str());
+        }
+        if let Some(partition) = message.partition {
+            record = record.partition(partition);
+        }
+        loop {
+            match self.producer.send(record) {
+                Ok(()) => return Ok(()),
+                Err((KafkaError::MessageProduction(RDKafkaErrorCode::QueueFull), r)) => {
+                    // let the queue drain then try again
+                    self.producer.poll(Duration::from_millis(100));
+                    record = r;
+                }
+                Err((e, _)) => return Err(e.into()),
+            }
+        }
+    }
+
+    fn topics(&self) -> Vec<&str> {
+        let mut topics: Vec<&str> = self
+            .config
+            .routes
+            .iter()
+            .map(|r| r.topic.as_str())
+            .collect();
+        if let Some(t) = &self.config.default_topic {
+            topics.push(t);
+        }
+        topics.sort();
+        topics.dedup();
+        topics
+    }
+
+    /// (topic, number of partitions) of every topic, from the broker
+    fn partitions(&self) -> Result<Vec<(String, i32)>> {
+        let mut partitions = vec![];
+        for topic in self.topics() {
+            let metadata = self
+                .producer
+                .client()
+                .fetch_metadata(Some(topic), self.config.ack_timeout)?;
+            // a topic that doesn't exist yet has nothing to retract
+            let count = metadata
+                .topics()
+                .iter()
+                .find(|t| t.name() == topic)
+                .map_or(0, |t| t.partitions().len() as i32);
+            partitions.push((String::from(topic), count));
+        }
+        Ok(partitions)
+    }
+
+    /// waits for every outstanding message to be acknowledged, then saves the checkpoint
+    fn commit(&mut self) -> Result<()> {
+        self.producer.flush(self.config.ack_timeout);
+        self.producer
+            .context()
+            .check(self.producer.in_flight_count())?;
+        if let Some(last) = self.last_block {
+            self.config.checkpoint.save(last)?;
+        }
+        self.uncommitted = 0;
+        Ok(())
+    }
+}
+
+impl BlockSink for KafkaSink {
+    fn write_block(&mut self, block: &GetBlocksResultV0Ex) -> Result<()> {
+        let block_num = match &block.this_block {
+            Some(bp) => bp.block_num,
+            None => return Ok(()),
+        };
+        for message in block_messages(&self.config, block)? {
+            self.send(&message)?;
+        }
+        self.producer.poll(Duration::from_millis(0));
+
+        self.last_block = Some(block_num);
+        self.uncommitted += 1;
+        if self.uncommitted >= self.config.commit_interval {
+            self.commit()?;
+        }
+        Ok(())
+    }
+
+    fn undo_to(&mut self, block_num: u32) -> Result<()> {
+        for message in undo_messages(block_num, &self.partitions()?)? {
+            self.send(&message)?;
+        }
+        self.last_block = Some(block_num);
+        self.commit()
+    }
+
+    fn last_committed_block(&mut self) -> Result<Option<u32>> {
+        Ok(self.last_block)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.commit()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filter::{ActionFilter, DeltaFilter, EventKind};
+    use crate::shipper_types::tests::{block, trace};
+    use crate::shipper_types::{
+        ContractRow, ContractRowV0, TableDeltaEx, TableRowEx, TableRowTypes,
+    };
+
+    fn config(unit: MessageUnit, routes: Vec<TopicRoute>) -> KafkaConfig {
+        KafkaConfig {
+            brokers: String::from("localhost:9092"),
+            unit,
+            default_topic: Some(String::from("all")),
+            routes,
+            checkpoint: FileCheckpoint::new("unused"),
+            commit_interval: 1,
+            ack_timeout: Duration::from_secs(1),
+        }
+    }
+
+    fn test_block() -> GetBlocksResultV0Ex {
+        let row = TableRowEx {
+            present: false,
+            data: TableRowTypes::contract_row(ContractRow::contract_row_v0(ContractRowV0 {
+                code: String::from("eosio.token"),
+                scope: String::from("alice"),
+                table: String::from("accounts"),
+                primary_key: String::from("1"),
+                payer: String::from("alice"),
+                value: String::new(),
+            })),
+        };
+        let mut b = block(
+            7,
+            6,
+            vec![TableDeltaEx {
+                name: String::from("contract_row"),
+                rows: vec![row],
+            }],
+        );
+        b.traces = vec![
+            trace(
+                "t1",
+                &[
+                    ("eosio.token", "transfer", Some(1)),
+                    ("alice", "transfer", Some(2)),
+                ],
+            ),
+            trace("t2", &[("bob", "hi", Some(3))]),
+        ];
+        b
+    }
+
+    /// (topic, key) of each message
+    fn keys(messages: &[Message]) -> Vec<(&str, &str)> {
+        messages
+            .iter()
+            .map(|m| (m.topic.as_str(), m.key.as_deref().unwrap_or("")))
+            .collect()
+    }
+
+    #[test]
+    fn actions_are_keyed_by_receiver() {
+        let messages = block_messages(&config(MessageUnit::Action, vec![]), &test_block()).unwrap();
+        assert_eq!(
+            keys(&messages),
+            vec![("all", "eosio.token"), ("all", "alice"), ("all", "bob")]
+        );
+        let event: Event = serde_json::from_str(&messages[0].payload).unwrap();
+        assert_eq!(event.kind, EventKind::Action);
+        assert_eq!(event.block_num, 7);
+        assert_eq!(event.trx_id.as_deref(), Some("t1"));
+        assert_eq!(event.name, "eosio.token:transfer");
+        assert!(messages.iter().all(|m| m.partition.is_none()));
+    }
+
+    #[test]
+    fn transactions_are_keyed_by_their_first_action() {
+        let messages =
+            block_messages(&config(MessageUnit::Transaction, vec![]), &test_block()).unwrap();
+        assert_eq!(
+            keys(&messages),
+            vec![("all", "eosio.token"), ("all", "bob")]
+        );
+        let payload: serde_json::Value = serde_json::from_str(&messages[1].payload).unwrap();
+        assert_eq!(payload["block_num"], 7);
+    }
+
+    #[test]
+    fn routed_deltas_are_keyed_by_contract() {
+        let route = TopicRoute {
+            topic: String::from("tokens"),
+            filter: EventFilter {
+                actions: vec!["bob".parse::<ActionFilter>().unwrap()],
+                deltas: vec!["contract_row:eosio.token".parse::<DeltaFilter>().unwrap()],
+            },
+        };
+        let mut config = config(MessageUnit::Block, vec![route]);
+        config.default_topic = None;
+        let messages = block_messages(&config, &test_block()).unwrap();
+        assert_eq!(
+            keys(&messages),
+            vec![("tokens", "bob"), ("tokens", "eosio.token")]
+        );
+        let event: Event = serde_json::from_str(&messages[1].payload).unwrap();
+        assert_eq!(event.kind, EventKind::DeltaRemoved);
+        assert_eq!(event.name, "contract_row");
+    }
+
+    #[test]
+    fn undo_goes_to_every_partition() {
+        let partitions = vec![
+            (String::from("a"), 3),
+            (String::from("b"), 1),
+            (String::from("c"), 0),
+        ];
+        let messages = undo_messages(5, &partitions).unwrap();
+        let targets: Vec<(&str, Option<i32>)> = messages
+            .iter()
+            .map(|m| (m.topic.as_str(), m.partition))
+            .collect();
+        assert_eq!(
+            targets,
+            vec![
+                ("a", Some(0)),
+                ("a", Some(1)),
+                ("a", Some(2)),
+                ("b", Some(0))
+            ]
+        );
+        let event: Event = serde_json::from_str(&messages[0].payload).unwrap();
+        assert_eq!((event.kind, event.block_num), (EventKind::Undo, 5));
+        assert!(messages.iter().all(|m| m.key.is_none()));
+    }
+
+    #[test]
+    fn a_failed_delivery_stops_every_later_commit() {
+        let tracker = DeliveryTracker::default();
+        tracker.check(0).unwrap();
+        // unacknowledged messages may still arrive, so they only hold this commit back
+        assert!(tracker.check(2).is_err());
+        tracker.check(0).unwrap();
+
+        tracker.failed.fetch_add(1, Ordering::SeqCst);
+        assert!(tracker.check(0).is_err());
+        // retrying the commit doesn't skip past the lost message
+        assert!(tracker.check(0).is_err());
+    }
+}
@@ -0,0 +1,43 @@
+use crate::errors::Result;
+use crate::shipper_types::GetBlocksResultV0Ex;
+
+#[cfg(feature = "kafka")]
+pub mod kafka;
+pub mod ndjson;
+#[cfg(feature = "postgres")]
+pub mod postgres;
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
+#[cfg(feature = "webhook")]
+pub mod webhook;
+
+/// Something that consumes the decoded block stream and persists it.
+///
+/// Sinks remember the last block they committed so a restarted reader can resume from
+/// there, and can drop everything above a block when a fork replaces it.
+pub trait BlockSink {
+    fn write_block(&mut self, block: &GetBlocksResultV0Ex) -> Result<()>;
+
+    /// remove everything written for blocks above `block_num`
+    fn undo_to(&mut self, block_num: u32) -> Result<()>;
+
+    fn last_committed_block(&mut self) -> Result<Option<u32>>;
+
+    /// sinks that batch writes push out whatever they are holding
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// writes the block, first undoing anything at or above it (a fork)
+    fn handle_block(&mut self, block: &GetBlocksResultV0Ex) -> Result<()> {
+        if let Some(bp) = &block.this_block {
+            if let Some(last) = self.last_committed_block()? {
+                if bp.block_num <= last {
+                    self.undo_to(bp.block_num.saturating_sub(1))?;
+                }
+            }
+            self.write_block(block)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,450 @@
+use crate::errors::Result;
+use crate::filter::EventFilter;
+use crate::shipper_types::GetBlocksResultV0Ex;
+use crate::sinks::BlockSink;
+use flate2::write::GzEncoder;
+use log::*;
+use serde_json::json;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::PathBuf;
+
+/// what each line of output holds
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Granularity {
+    /// the whole decoded block
+    Block,
+    /// one line per action trace
+    Action,
+    /// one line per table delta row
+    Delta,
+    /// one line per `filter::Event` matching the sink's filter, see `with_filter`
+    Event,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Compression {
+    None,
+    Gzip,
+    #[cfg(feature = "zstd")]
+    Zstd,
+}
+
+impl Compression {
+    fn extension(&self) -> &'static str {
+        match self {
+            Compression::None => "ndjson",
+            Compression::Gzip => "ndjson.gz",
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => "ndjson.zst",
+        }
+    }
+}
+
+/// when to close the current file and start a new one. `max_bytes` counts uncompressed output.
+#[derive(Debug, Clone, Default)]
+pub struct Rotation {
+    pub max_blocks: Option<u32>,
+    pub max_bytes: Option<u64>,
+}
+
+enum Output {
+    Stdout(BufWriter<io::Stdout>),
+    Plain(BufWriter<File>),
+    Gzip(GzEncoder<BufWriter<File>>),
+    #[cfg(feature = "zstd")]
+    Zstd(zstd::stream::write::Encoder<BufWriter<File>>),
+}
+
+impl Output {
+    fn create(path: &PathBuf, compression: Compression) -> Result<Output> {
+        let f = BufWriter::new(File::create(path)?);
+        Ok(match compression {
+            Compression::None => Output::Plain(f),
+            Compression::Gzip => Output::Gzip(GzEncoder::new(f, flate2::Compression::default())),
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => Output::Zstd(zstd::stream::write::Encoder::new(f, 0)?),
+        })
+    }
+
+    fn write_all(&mut self, buf: &[u8]) -> Result<()> {
+        match self {
+            Output::Stdout(w) => w.write_all(buf)?,
+            Output::Plain(w) => w.write_all(buf)?,
+            Output::Gzip(w) => w.write_all(buf)?,
+            #[cfg(feature = "zstd")]
+            Output::Zstd(w) => w.write_all(buf)?,
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        match self {
+            Output::Stdout(w) => w.flush()?,
+            Output::Plain(w) => w.flush()?,
+            Output::Gzip(w) => w.flush()?,
+            #[cfg(feature = "zstd")]
+            Output::Zstd(w) => w.flush()?,
+        }
+        Ok(())
+    }
+
+    fn finish(self) -> Result<()> {
+        let mut f = match self {
+            Output::Stdout(mut w) => return Ok(w.flush()?),
+            Output::Plain(w) => w,
+            Output::Gzip(w) => w.finish()?,
+            #[cfg(feature = "zstd")]
+            Output::Zstd(w) => w.finish()?,
+        };
+        f.flush()?;
+        Ok(())
+    }
+}
+
+struct OpenFile {
+    output: Output,
+    path: PathBuf,
+    first_block: u32,
+    last_block: u32,
+    blocks: u32,
+    bytes: u64,
+}
+
+/// Writes the block stream as line delimited JSON, using the types' `Serialize` impls.
+///
+/// Files are written as `<prefix>-<first block>-open.<ext>` and renamed to
+/// `<prefix>-<first block>-<last block>.<ext>` when they are rotated or the sink is closed,
+/// so anything without `-open` is complete and can be shipped off.
+///
+/// `NdjsonSink::stdout` writes the same lines to standard output, uncompressed and unrotated.
+pub struct NdjsonSink {
+    /// `None` for standard output
+    dir: Option<PathBuf>,
+    prefix: String,
+    granularity: Granularity,
+    filter: EventFilter,
+    compression: Compression,
+    rotation: Rotation,
+    current: Option<OpenFile>,
+    last_block: Option<u32>,
+    /// an undo marker waiting for the next file, when none was open to take it
+    pending_undo: Option<u32>,
+}
+
+impl NdjsonSink {
+    pub fn new(
+        dir: &str,
+        prefix: &str,
+        granularity: Granularity,
+        compression: Compression,
+        rotation: Rotation,
+    ) -> Result<NdjsonSink> {
+        fs::create_dir_all(dir)?;
+        Ok(NdjsonSink {
+            dir: Some(PathBuf::from(dir)),
+            prefix: String::from(prefix),
+            granularity,
+            filter: EventFilter::default(),
+            compression,
+            rotation,
+            current: None,
+            last_block: None,
+            pending_undo: None,
+        })
+    }
+
+    pub fn stdout(granularity: Granularity) -> NdjsonSink {
+        NdjsonSink {
+            dir: None,
+            prefix: String::new(),
+            granularity,
+            filter: EventFilter::default(),
+            compression: Compression::None,
+            rotation: Rotation::default(),
+            current: None,
+            last_block: None,
+            pending_undo: None,
+        }
+    }
+
+    /// the actions and deltas written with `Granularity::Event`
+    pub fn with_filter(mut self, filter: EventFilter) -> NdjsonSink {
+        self.filter = filter;
+        self
+    }
+
+    fn file_name(&self, dir: &PathBuf, first: u32, last: Option<u32>) -> PathBuf {
+        let name = match last {
+            Some(l) => format!(
+                "{}-{:010}-{:010}.{}",
+                self.prefix,
+                first,
+                l,
+                self.compression.extension()
+            ),
+            None => format!(
+                "{}-{:010}-open.{}",
+                self.prefix,
+                first,
+                self.compression.extension()
+            ),
+        };
+        dir.join(name)
+    }
+
+    /// finishes the current file and gives it its final name
+    pub fn close(&mut self) -> Result<()> {
+        if let Some(of) = self.current.take() {
+            of.output.finish()?;
+            if let Some(dir) = &self.dir {
+                let final_path = self.file_name(dir, of.first_block, Some(of.last_block));
+                fs::rename(&of.path, &final_path)?;
+                info!("ndjson: wrote {:?}", final_path);
+            }
+        }
+        Ok(())
+    }
+
+    fn lines(&self, block: &GetBlocksResultV0Ex, block_num: u32) -> Result<Vec<String>> {
+        let mut lines = vec![];
+        match self.granularity {
+            Granularity::Block => lines.push(serde_json::to_string(block)?),
+            Granularity::Action => {
+                for trace in &block.traces {
+                    let tt = trace.transaction_trace();
+                    for at in &tt.action_traces {
+                        lines.push(serde_json::to_string(&json!({
+                            "block_num": block_num,
+                            "trx_id": tt.id,
+                            "action_trace": at,
+                        }))?);
+                    }
+                }
+            }
+            Granularity::Event => {
+                for event in self.filter.events(block)? {
+                    lines.push(serde_json::to_string(&event)?);
+                }
+            }
+            Granularity::Delta => {
+                for delta in &block.deltas {
+                    for row in &delta.rows {
+                        lines.push(serde_json::to_string(&json!({
+                            "block_num": block_num,
+                            "name": delta.name,
+                            "present": row.present,
+                            "data": row.data,
+                        }))?);
+                    }
+                }
+            }
+        }
+        Ok(lines)
+    }
+
+    fn undo_line(block_num: u32) -> Result<String> {
+        Ok(serde_json::to_string(&json!({ "undo_to": block_num }))?)
+    }
+
+    fn write_lines(&mut self, block_num: u32, mut lines: Vec<String>) -> Result<()> {
+        if let Some(undo) = self.pending_undo.take() {
+            lines.insert(0, NdjsonSink::undo_line(undo)?);
+        }
+        if self.current.is_none() {
+            let (output, path) = match &self.dir {
+                Some(dir) => {
+                    let path = self.file_name(dir, block_num, None);
+                    (Output::create(&path, self.compression)?, path)
+                }
+                None => (Output::Stdout(BufWriter::new(io::stdout())), PathBuf::new()),
+            };
+            self.current = Some(OpenFile {
+                output,
+                path,
+                first_block: block_num,
+                last_block: block_num,
+                blocks: 0,
+                bytes: 0,
+            });
+        }
+        let of = self.current.as_mut().unwrap();
+        for line in lines {
+            of.output.write_all(line.as_bytes())?;
+            of.output.write_all(b"\n")?;
+            of.bytes += line.len() as u64 + 1;
+        }
+        of.last_block = of.last_block.max(block_num);
+        of.blocks += 1;
+
+        let full_blocks = match self.rotation.max_blocks {
+            Some(m) => of.blocks >= m,
+            None => false,
+        };
+        let full_bytes = match self.rotation.max_bytes {
+            Some(m) => of.bytes >= m,
+            None => false,
+        };
+        if full_blocks || full_bytes {
+            self.close()?;
+        }
+        Ok(())
+    }
+}
+
+impl BlockSink for NdjsonSink {
+    fn write_block(&mut self, block: &GetBlocksResultV0Ex) -> Result<()> {
+        if let Some(bp) = &block.this_block {
+            let lines = self.lines(block, bp.block_num)?;
+            self.write_lines(bp.block_num, lines)?;
+            self.last_block = Some(bp.block_num);
+        }
+        Ok(())
+    }
+
+    /// The output is append only, so a fork is recorded as a `{"undo_to": n}` line that readers
+    /// must honour by discarding earlier lines for blocks above `n`. It goes into the current
+    /// file without counting as a block; when no file is open it starts the next one.
+    fn undo_to(&mut self, block_num: u32) -> Result<()> {
+        match self.current.as_mut() {
+            Some(of) => {
+                let line = NdjsonSink::undo_line(block_num)?;
+                of.output.write_all(line.as_bytes())?;
+                of.output.write_all(b"\n")?;
+                of.bytes += line.len() as u64 + 1;
+            }
+            None => self.pending_undo = Some(block_num),
+        }
+        self.last_block = Some(block_num);
+        Ok(())
+    }
+
+    fn last_committed_block(&mut self) -> Result<Option<u32>> {
+        Ok(self.last_block)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        if let Some(of) = self.current.as_mut() {
+            of.output.flush()?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for NdjsonSink {
+    fn drop(&mut self) {
+        if let Err(e) = self.close() {
+            error!("ndjson: unable to close {:?}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shipper_types::tests::block;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("ship-ndjson-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn sink(dir: &PathBuf, max_blocks: u32) -> NdjsonSink {
+        NdjsonSink::new(
+            &dir.to_string_lossy(),
+            "test",
+            Granularity::Block,
+            Compression::None,
+            Rotation {
+                max_blocks: Some(max_blocks),
+                max_bytes: None,
+            },
+        )
+        .unwrap()
+    }
+
+    /// file name and, per line, the block number or `undo_to` marker
+    fn files(dir: &PathBuf) -> Vec<(String, Vec<String>)> {
+        let mut files: Vec<(String, Vec<String>)> = fs::read_dir(dir)
+            .unwrap()
+            .map(|e| {
+                let path = e.unwrap().path();
+                let lines = fs::read_to_string(&path)
+                    .unwrap()
+                    .lines()
+                    .map(|l| {
+                        let v: serde_json::Value = serde_json::from_str(l).unwrap();
+                        match v.get("undo_to") {
+                            Some(n) => format!("undo_to {}", n),
+                            None => v["this_block"]["block_num"].to_string(),
+                        }
+                    })
+                    .collect();
+                (
+                    path.file_name().unwrap().to_string_lossy().into_owned(),
+                    lines,
+                )
+            })
+            .collect();
+        files.sort();
+        files
+    }
+
+    fn strings(v: &[&str]) -> Vec<String> {
+        v.iter().map(|s| String::from(*s)).collect()
+    }
+
+    #[test]
+    fn undo_goes_into_the_current_file() {
+        let dir = temp_dir("current");
+        let mut sink = sink(&dir, 3);
+        for block_num in &[1, 2, 3, 4, 5, 4] {
+            sink.handle_block(&block(*block_num, 0, vec![])).unwrap();
+        }
+        sink.close().unwrap();
+        assert_eq!(
+            files(&dir),
+            vec![
+                (
+                    String::from("test-0000000001-0000000003.ndjson"),
+                    strings(&["1", "2", "3"])
+                ),
+                // the marker neither counts as a block nor moves the file's range
+                (
+                    String::from("test-0000000004-0000000005.ndjson"),
+                    strings(&["4", "5", "undo_to 3", "4"])
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn undo_after_a_rotation_starts_the_next_file() {
+        let dir = temp_dir("rotated");
+        let mut sink = sink(&dir, 2);
+        for block_num in &[1, 2] {
+            sink.handle_block(&block(*block_num, 0, vec![])).unwrap();
+        }
+        sink.undo_to(1).unwrap();
+        // no file was opened for it
+        assert_eq!(files(&dir).len(), 1);
+        assert_eq!(sink.last_committed_block().unwrap(), Some(1));
+
+        sink.handle_block(&block(2, 0, vec![])).unwrap();
+        sink.close().unwrap();
+        assert_eq!(
+            files(&dir),
+            vec![
+                (
+                    String::from("test-0000000001-0000000002.ndjson"),
+                    strings(&["1", "2"])
+                ),
+                (
+                    String::from("test-0000000002-0000000002.ndjson"),
+                    strings(&["undo_to 1", "2"])
+                ),
+            ]
+        );
+    }
+}
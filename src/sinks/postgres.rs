@@ -0,0 +1,566 @@
+use crate::errors::Result;
+use crate::shipper_types::{ContractRow, GetBlocksResultV0Ex, TableRowTypes};
+use crate::sinks::BlockSink;
+use ::postgres::{Client, NoTls};
+use log::*;
+use std::collections::BTreeMap;
+use std::io::Write;
+
+// same layout as history-tools fill-pg, so existing queries keep working
+const SCHEMA: &str = r#"
+CREATE SCHEMA IF NOT EXISTS {schema};
+DO $$ BEGIN
+    CREATE TYPE {schema}.transaction_status_type AS ENUM ('executed', 'soft_fail', 'hard_fail', 'delayed', 'expired');
+EXCEPTION WHEN duplicate_object THEN null;
+END $$;
+CREATE TABLE IF NOT EXISTS {schema}.fill_status (
+    head bigint,
+    head_id varchar(64),
+    irreversible bigint,
+    irreversible_id varchar(64),
+    first bigint
+);
+CREATE TABLE IF NOT EXISTS {schema}.block_info (
+    block_num bigint,
+    block_id varchar(64),
+    timestamp timestamp,
+    producer varchar(13),
+    confirmed integer,
+    previous varchar(64),
+    transaction_mroot varchar(64),
+    action_mroot varchar(64),
+    schedule_version bigint,
+    PRIMARY KEY (block_num)
+);
+CREATE TABLE IF NOT EXISTS {schema}.transaction_trace (
+    block_num bigint,
+    transaction_ordinal integer,
+    failed_dtrx_trace varchar(64),
+    id varchar(64),
+    status {schema}.transaction_status_type,
+    cpu_usage_us bigint,
+    net_usage_words bigint,
+    elapsed bigint,
+    net_usage numeric,
+    scheduled bool,
+    account_ram_delta_present bool,
+    account_ram_delta_account varchar(13),
+    account_ram_delta_delta bigint,
+    "except" varchar,
+    error_code numeric,
+    PRIMARY KEY (block_num, transaction_ordinal)
+);
+CREATE TABLE IF NOT EXISTS {schema}.action_trace (
+    block_num bigint,
+    transaction_id varchar(64),
+    transaction_status {schema}.transaction_status_type,
+    action_ordinal bigint,
+    creator_action_ordinal bigint,
+    receipt_present bool,
+    receipt_receiver varchar(13),
+    receipt_act_digest varchar(64),
+    receipt_global_sequence numeric,
+    receipt_recv_sequence numeric,
+    receipt_code_sequence bigint,
+    receipt_abi_sequence bigint,
+    receiver varchar(13),
+    act_account varchar(13),
+    act_name varchar(13),
+    act_data bytea,
+    context_free bool,
+    elapsed bigint,
+    console varchar,
+    "except" varchar,
+    error_code numeric,
+    PRIMARY KEY (block_num, transaction_id, action_ordinal)
+);
+CREATE TABLE IF NOT EXISTS {schema}.contract_row (
+    block_num bigint,
+    present bool,
+    code varchar(13),
+    scope varchar(13),
+    "table" varchar(13),
+    primary_key numeric,
+    payer varchar(13),
+    value bytea,
+    PRIMARY KEY (code, "table", primary_key, scope, block_num, present)
+);
+"#;
+
+const COPY_TABLES: [(&str, &str); 4] = [
+    (
+        "block_info",
+        "block_num, block_id, timestamp, producer, confirmed, previous, transaction_mroot, action_mroot, schedule_version",
+    ),
+    (
+        "transaction_trace",
+        "block_num, transaction_ordinal, failed_dtrx_trace, id, status, cpu_usage_us, net_usage_words, elapsed, net_usage, scheduled, account_ram_delta_present, account_ram_delta_account, account_ram_delta_delta, \"except\", error_code",
+    ),
+    (
+        "action_trace",
+        "block_num, transaction_id, transaction_status, action_ordinal, creator_action_ordinal, receipt_present, receipt_receiver, receipt_act_digest, receipt_global_sequence, receipt_recv_sequence, receipt_code_sequence, receipt_abi_sequence, receiver, act_account, act_name, act_data, context_free, elapsed, console, \"except\", error_code",
+    ),
+    (
+        "contract_row",
+        "block_num, present, code, scope, \"table\", primary_key, payer, value",
+    ),
+];
+
+const NULL: &str = "\\N";
+
+fn status_name(status: u8) -> &'static str {
+    match status {
+        0 => "executed",
+        1 => "soft_fail",
+        2 => "hard_fail",
+        3 => "delayed",
+        _ => "expired",
+    }
+}
+
+// COPY text format escaping
+fn text(s: &str) -> String {
+    s.replace('\\', "\\\\")
+        .replace('\t', "\\t")
+        .replace('\n', "\\n")
+        .replace('\r', "\\r")
+}
+
+fn opt_text(s: Option<&String>) -> String {
+    match s {
+        Some(s) => text(s),
+        None => String::from(NULL),
+    }
+}
+
+fn bytea(hex: &str) -> String {
+    format!("\\\\x{}", hex)
+}
+
+fn row(fields: Vec<String>) -> String {
+    fields.join("\t") + "\n"
+}
+
+/// Loads blocks into history-tools style tables (`block_info`, `transaction_trace`,
+/// `action_trace`, `contract_row`, `fill_status`) with COPY.
+///
+/// Blocks are buffered and loaded `batch_size` at a time in one database transaction, which
+/// also updates `fill_status`.
+pub struct PostgresSink {
+    client: Client,
+    schema: String,
+    batch_size: u32,
+    buffers: BTreeMap<&'static str, String>,
+    buffered_blocks: u32,
+    first: Option<u32>,
+    head: Option<(u32, String)>,
+    irreversible: Option<(u32, String)>,
+}
+
+impl PostgresSink {
+    /// `params` is a libpq style connection string, eg `host=localhost user=postgres`
+    pub fn connect(params: &str, schema: &str, batch_size: u32) -> Result<PostgresSink> {
+        let client = Client::connect(params, NoTls)?;
+        PostgresSink::new(client, schema, batch_size)
+    }
+
+    pub fn new(mut client: Client, schema: &str, batch_size: u32) -> Result<PostgresSink> {
+        client.batch_execute(&SCHEMA.replace("{schema}", schema))?;
+        Ok(PostgresSink {
+            client,
+            schema: String::from(schema),
+            batch_size: batch_size.max(1),
+            buffers: BTreeMap::new(),
+            buffered_blocks: 0,
+            first: None,
+            head: None,
+            irreversible: None,
+        })
+    }
+
+    fn push(&mut self, table: &'static str, fields: Vec<String>) {
+        self.buffers
+            .entry(table)
+            .or_insert_with(String::new)
+            .push_str(&row(fields));
+    }
+
+    fn fill_status(&mut self) -> Result<Option<u32>> {
+        let rows = self.client.query(
+            format!("SELECT head FROM {}.fill_status", self.schema).as_str(),
+            &[],
+        )?;
+        match rows.first() {
+            Some(r) => {
+                let head: Option<i64> = r.get(0);
+                Ok(head.map(|h| h as u32))
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+impl BlockSink for PostgresSink {
+    fn write_block(&mut self, block: &GetBlocksResultV0Ex) -> Result<()> {
+        let this_block = match &block.this_block {
+            Some(bp) => bp,
+            None => return Ok(()),
+        };
+        let block_num = this_block.block_num.to_string();
+
+        if let Some(sb) = &block.block {
+            let header = &sb.signed_header().header;
+            self.push(
+                "block_info",
+                vec![
+                    block_num.clone(),
+                    text(&this_block.block_id),
+                    text(&header.timestamp),
+                    text(&header.producer),
+                    header.confirmed.to_string(),
+                    text(&header.previous),
+                    text(&header.transaction_mroot),
+                    text(&header.action_mroot),
+                    header.schedule_version.to_string(),
+                ],
+            );
+        }
+
+        for (ordinal, trace) in block.traces.iter().enumerate() {
+            let tt = trace.transaction_trace();
+            let failed_dtrx = tt
+                .failed_dtrx_trace
+                .as_ref()
+                .map(|f| f.transaction_trace().id.clone());
+            self.push(
+                "transaction_trace",
+                vec![
+                    block_num.clone(),
+                    (ordinal + 1).to_string(),
+                    opt_text(failed_dtrx.as_ref()),
+                    text(&tt.id),
+                    String::from(status_name(tt.status)),
+                    tt.cpu_usage_us.to_string(),
+                    tt.net_usage_words.to_string(),
+                    text(&tt.elapsed),
+                    text(&tt.net_usage),
+                    tt.scheduled.to_string(),
+                    tt.account_ram_delta.is_some().to_string(),
+                    opt_text(tt.account_ram_delta.as_ref().map(|d| &d.account)),
+                    opt_text(tt.account_ram_delta.as_ref().map(|d| &d.delta)),
+                    opt_text(tt.except.as_ref()),
+                    opt_text(tt.error_code.map(|e| e.to_string()).as_ref()),
+                ],
+            );
+            for at in &tt.action_traces {
+                let act = at.act();
+                let receipt = at.receipt();
+                self.push(
+                    "action_trace",
+                    vec![
+                        block_num.clone(),
+                        text(&tt.id),
+                        String::from(status_name(tt.status)),
+                        at.action_ordinal().to_string(),
+                        at.creator_action_ordinal().to_string(),
+                        receipt.is_some().to_string(),
+                        opt_text(receipt.map(|r| &r.receiver)),
+                        opt_text(receipt.map(|r| &r.act_digest)),
+                        opt_text(receipt.map(|r| &r.global_sequence)),
+                        opt_text(receipt.map(|r| &r.recv_sequence)),
+                        opt_text(receipt.map(|r| r.code_sequence.to_string()).as_ref()),
+                        opt_text(receipt.map(|r| r.abi_sequence.to_string()).as_ref()),
+                        text(at.receiver()),
+                        text(&act.account),
+                        text(&act.name),
+                        bytea(&act.data),
+                        at.context_free().to_string(),
+                        text(at.elapsed()),
+                        text(at.console()),
+                        opt_text(at.except()),
+                        opt_text(at.error_code().map(|e| e.to_string()).as_ref()),
+                    ],
+                );
+            }
+        }
+
+        for delta in &block.deltas {
+            if delta.name != "contract_row" {
+                continue;
+            }
+            for r in &delta.rows {
+                if let TableRowTypes::contract_row(ContractRow::contract_row_v0(cr)) = &r.data {
+                    self.push(
+                        "contract_row",
+                        vec![
+                            block_num.clone(),
+                            r.present.to_string(),
+                            text(&cr.code),
+                            text(&cr.scope),
+                            text(&cr.table),
+                            text(&cr.primary_key),
+                            text(&cr.payer),
+                            bytea(&cr.value),
+                        ],
+                    );
+                }
+            }
+        }
+
+        if self.first.is_none() {
+            self.first = Some(this_block.block_num);
+        }
+        self.head = Some((this_block.block_num, this_block.block_id.clone()));
+        self.irreversible = Some((
+            block.last_irreversible.block_num,
+            block.last_irreversible.block_id.clone(),
+        ));
+        self.buffered_blocks += 1;
+        if self.buffered_blocks >= self.batch_size {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    fn undo_to(&mut self, block_num: u32) -> Result<()> {
+        // simplest to get the buffered blocks into the database and trim them there
+        self.flush()?;
+        info!("postgres: removing blocks above {}", block_num);
+        let mut tx = self.client.transaction()?;
+        for (table, _) in COPY_TABLES.iter() {
+            tx.execute(
+                format!("DELETE FROM {}.{} WHERE block_num > $1", self.schema, table).as_str(),
+                &[&(block_num as i64)],
+            )?;
+        }
+        tx.execute(
+            format!(
+                "UPDATE {0}.fill_status SET head = $1, head_id = (SELECT block_id FROM {0}.block_info WHERE block_num = $1)",
+                self.schema
+            )
+            .as_str(),
+            &[&(block_num as i64)],
+        )?;
+        tx.commit()?;
+        self.head = Some((block_num, String::new()));
+        Ok(())
+    }
+
+    fn last_committed_block(&mut self) -> Result<Option<u32>> {
+        match &self.head {
+            Some((num, _)) => Ok(Some(*num)),
+            None => self.fill_status(),
+        }
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        let (head, head_id) = match &self.head {
+            Some(h) => h.clone(),
+            None => return Ok(()),
+        };
+        if self.buffered_blocks == 0 {
+            return Ok(());
+        }
+        let (irreversible, irreversible_id) = self.irreversible.clone().unwrap_or_default();
+        let mut tx = self.client.transaction()?;
+        for (table, columns) in COPY_TABLES.iter() {
+            if let Some(data) = self.buffers.get(table) {
+                if data.is_empty() {
+                    continue;
+                }
+                let mut writer = tx.copy_in(
+                    format!("COPY {}.{} ({}) FROM STDIN", self.schema, table, columns).as_str(),
+                )?;
+                writer.write_all(data.as_bytes())?;
+                writer.finish()?;
+            }
+        }
+        let updated = tx.execute(
+            format!(
+                "UPDATE {}.fill_status SET head = $1, head_id = $2, irreversible = $3, irreversible_id = $4",
+                self.schema
+            )
+            .as_str(),
+            &[
+                &(head as i64),
+                &head_id,
+                &(irreversible as i64),
+                &irreversible_id,
+            ],
+        )?;
+        if updated == 0 {
+            let first = self.first.unwrap_or(head);
+            tx.execute(
+                format!(
+                    "INSERT INTO {}.fill_status (head, head_id, irreversible, irreversible_id, first) VALUES ($1, $2, $3, $4, $5)",
+                    self.schema
+                )
+                .as_str(),
+                &[
+                    &(head as i64),
+                    &head_id,
+                    &(irreversible as i64),
+                    &irreversible_id,
+                    &(first as i64),
+                ],
+            )?;
+        }
+        tx.commit()?;
+        debug!(
+            "postgres: loaded {} blocks up to {}",
+            self.buffered_blocks, head
+        );
+        self.buffers.clear();
+        self.buffered_blocks = 0;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shipper_types::tests::{block, trace};
+    use crate::shipper_types::{
+        ActionTraceVariant, ContractRowV0, TableDeltaEx, TableRowEx, Traces,
+    };
+
+    #[test]
+    fn copy_text_escaping() {
+        assert_eq!(text("plain"), "plain");
+        assert_eq!(text("a\tb\nc\rd"), "a\\tb\\nc\\rd");
+        // backslashes first, so the escapes added after aren't doubled
+        assert_eq!(text("C:\\n"), "C:\\\\n");
+        assert_eq!(opt_text(None), "\\N");
+        assert_eq!(opt_text(Some(&String::from("\\N"))), "\\\\N");
+        assert_eq!(bytea("00ff"), "\\\\x00ff");
+        assert_eq!(row(vec![String::from("1"), text("x\ty")]), "1\tx\\ty\n");
+    }
+
+    fn contract_row(block_num: u32, scope: &str) -> TableDeltaEx {
+        TableDeltaEx {
+            name: String::from("contract_row"),
+            rows: vec![TableRowEx {
+                present: true,
+                data: TableRowTypes::contract_row(ContractRow::contract_row_v0(ContractRowV0 {
+                    code: String::from("eosio.token"),
+                    scope: String::from(scope),
+                    table: String::from("accounts"),
+                    primary_key: block_num.to_string(),
+                    payer: String::from("alice"),
+                    value: String::from("0a0b"),
+                })),
+            }],
+        }
+    }
+
+    /// a block with a contract row and one failed action
+    fn test_block(block_num: u32, scope: &str) -> GetBlocksResultV0Ex {
+        let mut b = block(block_num, 1, vec![contract_row(block_num, scope)]);
+        let mut t = trace(&format!("{:064x}", block_num), &[("alice", "fail", None)]);
+        let Traces::transaction_trace_v0(tt) = &mut t;
+        if let ActionTraceVariant::action_trace_v0(at) = &mut tt.action_traces[0] {
+            at.except = Some(String::from("assertion failure\twith\nspecials"));
+            at.error_code = Some(8_000_000_000_000_000_000);
+        }
+        b.traces = vec![t];
+        b
+    }
+
+    /// the database given by `SHIP_TEST_POSTGRES` (a libpq connection string), in a fresh
+    /// `schema`
+    fn connect(schema: &str, batch_size: u32) -> PostgresSink {
+        let params = std::env::var("SHIP_TEST_POSTGRES").expect("SHIP_TEST_POSTGRES is not set");
+        let mut client = Client::connect(&params, NoTls).unwrap();
+        client
+            .batch_execute(&format!("DROP SCHEMA IF EXISTS {} CASCADE", schema))
+            .unwrap();
+        PostgresSink::new(client, schema, batch_size).unwrap()
+    }
+
+    fn query_strings(sink: &mut PostgresSink, sql: &str) -> Vec<String> {
+        sink.client
+            .query(sql, &[])
+            .unwrap()
+            .iter()
+            .map(|r| r.get(0))
+            .collect()
+    }
+
+    #[test]
+    #[ignore = "needs SHIP_TEST_POSTGRES"]
+    fn loads_blocks_and_follows_forks() {
+        let mut sink = connect("ship_test_fork", 2);
+        for block_num in 1..=3 {
+            sink.handle_block(&test_block(block_num, "a\tb\\c"))
+                .unwrap();
+        }
+        // blocks 1 and 2 went out as a batch, 3 is still buffered
+        assert_eq!(
+            query_strings(
+                &mut sink,
+                "SELECT head::text FROM ship_test_fork.fill_status"
+            ),
+            vec!["2"]
+        );
+        sink.flush().unwrap();
+        assert_eq!(
+            query_strings(
+                &mut sink,
+                "SELECT concat_ws(',', head, irreversible, first) FROM ship_test_fork.fill_status"
+            ),
+            vec!["3,1,1"]
+        );
+        // the escaped text comes back as it was
+        assert_eq!(
+            query_strings(
+                &mut sink,
+                "SELECT DISTINCT scope::text FROM ship_test_fork.contract_row"
+            ),
+            vec!["a\tb\\c"]
+        );
+        assert_eq!(
+            query_strings(
+                &mut sink,
+                "SELECT concat_ws('|', \"except\", error_code) FROM ship_test_fork.action_trace \
+                 WHERE block_num = 1"
+            ),
+            vec!["assertion failure\twith\nspecials|8000000000000000000"]
+        );
+
+        // a new sink picks up from fill_status
+        let params = std::env::var("SHIP_TEST_POSTGRES").unwrap();
+        let mut resumed = PostgresSink::new(
+            Client::connect(&params, NoTls).unwrap(),
+            "ship_test_fork",
+            2,
+        )
+        .unwrap();
+        assert_eq!(resumed.last_committed_block().unwrap(), Some(3));
+
+        // another block 2 removes blocks 2 and 3 first
+        resumed.handle_block(&test_block(2, "forked")).unwrap();
+        assert_eq!(
+            query_strings(
+                &mut resumed,
+                "SELECT head::text FROM ship_test_fork.fill_status"
+            ),
+            vec!["1"]
+        );
+        resumed.flush().unwrap();
+        assert_eq!(
+            query_strings(
+                &mut resumed,
+                "SELECT concat_ws(',', block_num, scope) FROM ship_test_fork.contract_row \
+                 ORDER BY block_num"
+            ),
+            vec!["1,a\tb\\c", "2,forked"]
+        );
+        assert_eq!(
+            query_strings(
+                &mut resumed,
+                "SELECT concat_ws(',', head, first) FROM ship_test_fork.fill_status"
+            ),
+            vec!["2,1"]
+        );
+    }
+}
@@ -0,0 +1,350 @@
+use crate::errors::Result;
+use crate::shipper_types::{
+    ContractRow, GetBlocksResultV0Ex, TableRowTypes, DEFAULT_MAX_DECOMPRESSED_SIZE,
+};
+use crate::sinks::BlockSink;
+use log::*;
+use rusqlite::{params, Connection, OptionalExtension, NO_PARAMS};
+
+const SCHEMA: &str = r#"
+CREATE TABLE IF NOT EXISTS block (
+    block_num INTEGER PRIMARY KEY,
+    block_id TEXT NOT NULL,
+    previous TEXT,
+    timestamp TEXT,
+    producer TEXT,
+    confirmed INTEGER,
+    schedule_version INTEGER,
+    transaction_count INTEGER
+);
+CREATE TABLE IF NOT EXISTS block_transaction (
+    block_num INTEGER NOT NULL,
+    seq INTEGER NOT NULL,
+    trx_id TEXT NOT NULL,
+    status INTEGER NOT NULL,
+    cpu_usage_us INTEGER NOT NULL,
+    net_usage_words INTEGER NOT NULL,
+    PRIMARY KEY (block_num, seq)
+);
+CREATE INDEX IF NOT EXISTS block_transaction_trx_id ON block_transaction (trx_id);
+CREATE TABLE IF NOT EXISTS action_trace (
+    block_num INTEGER NOT NULL,
+    trx_id TEXT NOT NULL,
+    action_ordinal INTEGER NOT NULL,
+    creator_action_ordinal INTEGER NOT NULL,
+    receiver TEXT NOT NULL,
+    account TEXT NOT NULL,
+    name TEXT NOT NULL,
+    authorization TEXT NOT NULL,
+    data TEXT NOT NULL,
+    context_free INTEGER NOT NULL,
+    elapsed INTEGER,
+    console TEXT,
+    "except" TEXT,
+    act_digest TEXT,
+    global_sequence INTEGER,
+    recv_sequence INTEGER,
+    code_sequence INTEGER,
+    abi_sequence INTEGER,
+    PRIMARY KEY (block_num, trx_id, action_ordinal)
+);
+CREATE INDEX IF NOT EXISTS action_trace_receiver ON action_trace (receiver, account, name);
+CREATE TABLE IF NOT EXISTS contract_row (
+    block_num INTEGER NOT NULL,
+    present INTEGER NOT NULL,
+    code TEXT NOT NULL,
+    scope TEXT NOT NULL,
+    tbl TEXT NOT NULL,
+    primary_key TEXT NOT NULL,
+    payer TEXT NOT NULL,
+    value TEXT NOT NULL,
+    PRIMARY KEY (code, scope, tbl, primary_key, block_num)
+);
+CREATE INDEX IF NOT EXISTS contract_row_block ON contract_row (block_num);
+CREATE TABLE IF NOT EXISTS sink_status (
+    id INTEGER PRIMARY KEY CHECK (id = 0),
+    last_block INTEGER NOT NULL,
+    last_block_id TEXT NOT NULL
+);
+"#;
+
+const HISTORY_TABLES: [&str; 4] = ["block", "block_transaction", "action_trace", "contract_row"];
+
+fn as_i64(s: &str) -> i64 {
+    s.parse::<i64>().unwrap_or(0)
+}
+
+/// Writes blocks, transactions, action traces and `contract_row` deltas into SQLite.
+///
+/// Each block goes in a single SQLite transaction together with the `sink_status` row,
+/// so the status row always names the last block that was completely written.
+pub struct SqliteSink {
+    conn: Connection,
+    max_decompressed_size: usize,
+}
+
+impl SqliteSink {
+    pub fn open(path: &str) -> Result<SqliteSink> {
+        SqliteSink::new(Connection::open(path)?)
+    }
+
+    pub fn new(conn: Connection) -> Result<SqliteSink> {
+        conn.execute_batch(SCHEMA)?;
+        Ok(SqliteSink {
+            conn,
+            max_decompressed_size: DEFAULT_MAX_DECOMPRESSED_SIZE,
+        })
+    }
+
+    /// how far a packed transaction may inflate while working out its id. A block with one
+    /// that goes past it fails to write.
+    pub fn with_max_decompressed_size(mut self, max_decompressed_size: usize) -> SqliteSink {
+        self.max_decompressed_size = max_decompressed_size;
+        self
+    }
+
+    pub fn connection(&self) -> &Connection {
+        &self.conn
+    }
+}
+
+impl BlockSink for SqliteSink {
+    fn write_block(&mut self, block: &GetBlocksResultV0Ex) -> Result<()> {
+        let this_block = match &block.this_block {
+            Some(bp) => bp,
+            None => return Ok(()),
+        };
+        let block_num = this_block.block_num;
+        let tx = self.conn.transaction()?;
+
+        if let Some(sb) = &block.block {
+            let header = &sb.signed_header().header;
+            let receipts = sb
+                .receipts(self.max_decompressed_size)
+                .into_iter()
+                .map(|(receipt, trx_id)| Ok((receipt, trx_id?)))
+                .collect::<Result<Vec<_>>>()?;
+            tx.execute(
+                "INSERT INTO block (block_num, block_id, previous, timestamp, producer, confirmed, schedule_version, transaction_count) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+                params![
+                    block_num,
+                    this_block.block_id,
+                    header.previous,
+                    header.timestamp,
+                    header.producer,
+                    header.confirmed,
+                    header.schedule_version,
+                    receipts.len() as u32
+                ],
+            )?;
+            for (seq, (receipt, trx_id)) in receipts.iter().enumerate() {
+                tx.execute(
+                    "INSERT INTO block_transaction (block_num, seq, trx_id, status, cpu_usage_us, net_usage_words) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                    params![
+                        block_num,
+                        seq as u32,
+                        trx_id,
+                        receipt.status,
+                        receipt.cpu_usage_us,
+                        receipt.net_usage_words
+                    ],
+                )?;
+            }
+        }
+
+        for trace in &block.traces {
+            let tt = trace.transaction_trace();
+            for at in &tt.action_traces {
+                let act = at.act();
+                let receipt = at.receipt();
+                tx.execute(
+                    "INSERT INTO action_trace (block_num, trx_id, action_ordinal, creator_action_ordinal, receiver, account, name, authorization, data, context_free, elapsed, console, \"except\", act_digest, global_sequence, recv_sequence, code_sequence, abi_sequence) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17, ?18)",
+                    params![
+                        block_num,
+                        tt.id,
+                        at.action_ordinal(),
+                        at.creator_action_ordinal(),
+                        at.receiver(),
+                        act.account,
+                        act.name,
+                        serde_json::to_string(&act.authorization)?,
+                        act.data,
+                        at.context_free(),
+                        as_i64(at.elapsed()),
+                        at.console(),
+                        at.except(),
+                        receipt.map(|r| r.act_digest.clone()),
+                        receipt.map(|r| as_i64(&r.global_sequence)),
+                        receipt.map(|r| as_i64(&r.recv_sequence)),
+                        receipt.map(|r| r.code_sequence),
+                        receipt.map(|r| r.abi_sequence)
+                    ],
+                )?;
+            }
+        }
+
+        for delta in &block.deltas {
+            if delta.name != "contract_row" {
+                continue;
+            }
+            for row in &delta.rows {
+                if let TableRowTypes::contract_row(ContractRow::contract_row_v0(cr)) = &row.data {
+                    tx.execute(
+                        "INSERT OR REPLACE INTO contract_row (block_num, present, code, scope, tbl, primary_key, payer, value) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+                        params![
+                            block_num,
+                            row.present,
+                            cr.code,
+                            cr.scope,
+                            cr.table,
+                            cr.primary_key,
+                            cr.payer,
+                            cr.value
+                        ],
+                    )?;
+                }
+            }
+        }
+
+        tx.execute(
+            "INSERT OR REPLACE INTO sink_status (id, last_block, last_block_id) VALUES (0, ?1, ?2)",
+            params![block_num, this_block.block_id],
+        )?;
+        tx.commit()?;
+        debug!("sqlite: committed block {}", block_num);
+        Ok(())
+    }
+
+    fn undo_to(&mut self, block_num: u32) -> Result<()> {
+        info!("sqlite: removing blocks above {}", block_num);
+        let tx = self.conn.transaction()?;
+        for table in HISTORY_TABLES.iter() {
+            tx.execute(
+                &format!("DELETE FROM {} WHERE block_num > ?1", table),
+                params![block_num],
+            )?;
+        }
+        let block_id: Option<String> = tx
+            .query_row(
+                "SELECT block_id FROM block WHERE block_num = ?1",
+                params![block_num],
+                |r| r.get(0),
+            )
+            .optional()?;
+        tx.execute(
+            "INSERT OR REPLACE INTO sink_status (id, last_block, last_block_id) VALUES (0, ?1, ?2)",
+            params![block_num, block_id.unwrap_or_default()],
+        )?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn last_committed_block(&mut self) -> Result<Option<u32>> {
+        let last: Option<u32> = self
+            .conn
+            .query_row(
+                "SELECT last_block FROM sink_status WHERE id = 0",
+                NO_PARAMS,
+                |r| r.get(0),
+            )
+            .optional()?;
+        Ok(last)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shipper_types::tests::{block, trace};
+    use crate::shipper_types::{ContractRowV0, TableDeltaEx, TableRowEx};
+
+    fn row(primary_key: &str, value: &str) -> TableDeltaEx {
+        TableDeltaEx {
+            name: String::from("contract_row"),
+            rows: vec![TableRowEx {
+                present: true,
+                data: TableRowTypes::contract_row(ContractRow::contract_row_v0(ContractRowV0 {
+                    code: String::from("eosio.token"),
+                    scope: String::from("alice"),
+                    table: String::from("accounts"),
+                    primary_key: String::from(primary_key),
+                    payer: String::from("alice"),
+                    value: String::from(value),
+                })),
+            }],
+        }
+    }
+
+    fn test_block(block_num: u32, trx_id: &str) -> GetBlocksResultV0Ex {
+        let mut b = block(block_num, 0, vec![row(&block_num.to_string(), trx_id)]);
+        b.traces = vec![trace(
+            trx_id,
+            &[("eosio.token", "transfer", Some(block_num as u64))],
+        )];
+        b
+    }
+
+    fn count(sink: &SqliteSink, table: &str) -> u32 {
+        sink.connection()
+            .query_row(&format!("SELECT COUNT(*) FROM {}", table), NO_PARAMS, |r| {
+                r.get(0)
+            })
+            .unwrap()
+    }
+
+    #[test]
+    fn writes_blocks_and_follows_forks() {
+        let mut sink = SqliteSink::new(Connection::open_in_memory().unwrap()).unwrap();
+        assert_eq!(sink.last_committed_block().unwrap(), None);
+        for block_num in 1..=3 {
+            sink.handle_block(&test_block(block_num, &format!("t{}", block_num)))
+                .unwrap();
+        }
+        assert_eq!(sink.last_committed_block().unwrap(), Some(3));
+        assert_eq!(count(&sink, "action_trace"), 3);
+        assert_eq!(count(&sink, "contract_row"), 3);
+        let except: Option<String> = sink
+            .connection()
+            .query_row(
+                "SELECT \"except\" FROM action_trace WHERE block_num = 2",
+                NO_PARAMS,
+                |r| r.get(0),
+            )
+            .unwrap();
+        assert_eq!(except, None);
+
+        // a fork replacing 2 and 3
+        sink.handle_block(&test_block(2, "f2")).unwrap();
+        assert_eq!(sink.last_committed_block().unwrap(), Some(2));
+        assert_eq!(count(&sink, "action_trace"), 2);
+        assert_eq!(count(&sink, "contract_row"), 2);
+        let trx_id: String = sink
+            .connection()
+            .query_row(
+                "SELECT trx_id FROM action_trace WHERE block_num = 2",
+                NO_PARAMS,
+                |r| r.get(0),
+            )
+            .unwrap();
+        assert_eq!(trx_id, "f2");
+    }
+
+    #[test]
+    fn resumes_from_the_last_committed_block() {
+        let path = std::env::temp_dir().join(format!("ship-sqlite-{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let path_str = path.to_str().unwrap();
+        {
+            let mut sink = SqliteSink::open(path_str).unwrap();
+            sink.handle_block(&test_block(7, "t7")).unwrap();
+            sink.handle_block(&test_block(8, "t8")).unwrap();
+            sink.undo_to(7).unwrap();
+        }
+        let mut sink = SqliteSink::open(path_str).unwrap();
+        assert_eq!(sink.last_committed_block().unwrap(), Some(7));
+        assert_eq!(count(&sink, "action_trace"), 1);
+        sink.handle_block(&test_block(8, "r8")).unwrap();
+        assert_eq!(sink.last_committed_block().unwrap(), Some(8));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
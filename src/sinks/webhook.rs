@@ -0,0 +1,592 @@
+use crate::checkpoint::FileCheckpoint;
+use crate::errors::Result;
+use crate::filter::{Event, EventFilter};
+use crate::shipper_types::GetBlocksResultV0Ex;
+use crate::sinks::BlockSink;
+use hmac::{Hmac, Mac, NewMac};
+use log::*;
+use reqwest::blocking::Client;
+use sha2::Sha256;
+use std::fs;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+pub const SIGNATURE_HEADER: &str = "X-Ship-Signature";
+
+/// a URL and the events it wants. with a secret, each body is signed with HMAC-SHA256 and the
+/// hex digest sent as `X-Ship-Signature: sha256=<digest>`
+#[derive(Debug, Clone)]
+pub struct WebhookTarget {
+    pub url: String,
+    pub filter: EventFilter,
+    pub secret: Option<Vec<u8>>,
+}
+
+/// One POST attempt, giving back the HTTP status. `WebhookSink` does the backoff and spooling
+/// around it.
+pub trait Transport {
+    fn post(&self, url: &str, headers: &[(&str, String)], body: &[u8]) -> Result<u16>;
+}
+
+/// the default transport, reqwest's blocking client
+pub struct ReqwestTransport {
+    client: Client,
+}
+
+impl ReqwestTransport {
+    pub fn new(timeout: Duration) -> Result<ReqwestTransport> {
+        Ok(ReqwestTransport {
+            client: Client::builder().timeout(timeout).build()?,
+        })
+    }
+}
+
+impl Transport for ReqwestTransport {
+    fn post(&self, url: &str, headers: &[(&str, String)], body: &[u8]) -> Result<u16> {
+        let mut req = self.client.post(url).body(body.to_vec());
+        for (name, value) in headers {
+            req = req.header(*name, value.as_str());
+        }
+        Ok(req.send()?.status().as_u16())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct WebhookConfig {
+    pub targets: Vec<WebhookTarget>,
+    /// events per POST
+    pub batch_size: usize,
+    /// how long a URL gets no requests after a failed one. doubled after every further failure
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    pub timeout: Duration,
+    pub spool_dir: String,
+    pub checkpoint: FileCheckpoint,
+}
+
+/// POSTs matched action traces and deltas as a JSON array of `Event`s.
+///
+/// Delivery is at-least-once: the checkpoint only moves past a block once every event up to it
+/// has been accepted by its URL (any 2xx) or written to the spool directory. Spooled batches are
+/// resent, in order, before anything newer goes to that URL. A URL that fails is backed off:
+/// its batches go straight to the spool until the backoff is over, so one slow receiver
+/// doesn't hold up the stream. Resuming from the checkpoint can repeat events, so receivers
+/// should be idempotent on (block_num, trx_id, name).
+///
+/// Requests are made with the blocking reqwest client, so keep the sink on its own thread
+/// rather than inside the tokio runtime. `with_transport` sends them some other way.
+pub struct WebhookSink {
+    config: WebhookConfig,
+    transport: Box<dyn Transport + Send>,
+    pending: Vec<Vec<Event>>,
+    targets: Vec<TargetState>,
+    last_block: Option<u32>,
+}
+
+/// what has happened with one target
+#[derive(Debug, Clone, Default)]
+struct TargetState {
+    /// block of the last event posted or spooled, a fork below it has to be retracted
+    delivered: Option<u32>,
+    /// after a failure, the current backoff and when it is over
+    backoff: Option<(Duration, Instant)>,
+}
+
+impl WebhookSink {
+    pub fn new(config: WebhookConfig) -> Result<WebhookSink> {
+        let transport = ReqwestTransport::new(config.timeout)?;
+        WebhookSink::with_transport(config, Box::new(transport))
+    }
+
+    pub fn with_transport(
+        config: WebhookConfig,
+        transport: Box<dyn Transport + Send>,
+    ) -> Result<WebhookSink> {
+        fs::create_dir_all(&config.spool_dir)?;
+        let pending = config.targets.iter().map(|_| vec![]).collect();
+        let last_block = config.checkpoint.load()?;
+        // anything up to the checkpoint may have gone out before a restart
+        let targets = config
+            .targets
+            .iter()
+            .map(|_| TargetState {
+                delivered: last_block,
+                backoff: None,
+            })
+            .collect();
+        Ok(WebhookSink {
+            config,
+            transport,
+            pending,
+            targets,
+            last_block,
+        })
+    }
+
+    fn sign(secret: &[u8], body: &[u8]) -> String {
+        let mut mac = Hmac::<Sha256>::new_varkey(secret).expect("HMAC takes any key size");
+        mac.update(body);
+        let digest = mac.finalize().into_bytes();
+        let hex: Vec<String> = digest.iter().map(|b| format!("{:02x}", b)).collect();
+        format!("sha256={}", hex.join(""))
+    }
+
+    /// one POST to target `idx`, unless it is backing off. false when it wasn't accepted
+    fn post(&mut self, idx: usize, body: &[u8]) -> bool {
+        let state = &mut self.targets[idx];
+        if let Some((_, until)) = state.backoff {
+            if Instant::now() < until {
+                return false;
+            }
+        }
+        let target = &self.config.targets[idx];
+        let mut headers = vec![("Content-Type", String::from("application/json"))];
+        if let Some(secret) = &target.secret {
+            headers.push((SIGNATURE_HEADER, WebhookSink::sign(secret, body)));
+        }
+        match self.transport.post(&target.url, &headers, body) {
+            Ok(status) if (200..300).contains(&status) => {
+                state.backoff = None;
+                return true;
+            }
+            Ok(status) => warn!("webhook: {} returned {}", target.url, status),
+            Err(e) => warn!("webhook: {} failed {:?}", target.url, e),
+        }
+        let delay = match state.backoff {
+            Some((delay, _)) => (delay * 2).min(self.config.max_backoff),
+            None => self.config.initial_backoff,
+        };
+        state.backoff = Some((delay, Instant::now() + delay));
+        false
+    }
+
+    fn spooled(&self, idx: usize) -> Result<Vec<PathBuf>> {
+        let prefix = format!("{:03}-", idx);
+        let mut files: Vec<PathBuf> = fs::read_dir(&self.config.spool_dir)?
+            .filter_map(|e| e.ok())
+            .map(|e| e.path())
+            .filter(|p| match p.file_name().and_then(|f| f.to_str()) {
+                Some(name) => name.starts_with(&prefix) && name.ends_with(".json"),
+                None => false,
+            })
+            .collect();
+        // names carry zero padded sequence numbers so this is delivery order
+        files.sort();
+        Ok(files)
+    }
+
+    fn spool(&self, idx: usize, body: &[u8]) -> Result<()> {
+        let seq = match self.spooled(idx)?.last() {
+            Some(p) => {
+                p.file_stem()
+                    .and_then(|f| f.to_str())
+                    .and_then(|f| f.rsplit('-').next())
+                    .and_then(|n| n.parse::<u64>().ok())
+                    .unwrap_or(0)
+                    + 1
+            }
+            None => 0,
+        };
+        let path =
+            PathBuf::from(&self.config.spool_dir).join(format!("{:03}-{:012}.json", idx, seq));
+        fs::write(&path, body)?;
+        warn!("webhook: spooled undelivered batch to {:?}", path);
+        Ok(())
+    }
+
+    /// resend spooled batches for a target. true once the spool is empty
+    fn drain_spool(&mut self, idx: usize) -> Result<bool> {
+        for path in self.spooled(idx)? {
+            let body = fs::read(&path)?;
+            if !self.post(idx, &body) {
+                return Ok(false);
+            }
+            fs::remove_file(&path)?;
+            info!("webhook: delivered spooled {:?}", path);
+        }
+        Ok(true)
+    }
+
+    fn deliver(&mut self, idx: usize) -> Result<()> {
+        let events = std::mem::replace(&mut self.pending[idx], vec![]);
+        for chunk in events.chunks(self.config.batch_size.max(1)) {
+            let body = serde_json::to_vec(chunk)?;
+            let delivered = self.drain_spool(idx)? && self.post(idx, &body);
+            if !delivered {
+                self.spool(idx, &body)?;
+            }
+            self.targets[idx].delivered = chunk.last().map(|e| e.block_num);
+        }
+        Ok(())
+    }
+
+    fn deliver_all(&mut self) -> Result<()> {
+        for idx in 0..self.pending.len() {
+            if !self.pending[idx].is_empty() {
+                self.deliver(idx)?;
+            }
+        }
+        if let Some(last) = self.last_block {
+            self.config.checkpoint.save(last)?;
+        }
+        Ok(())
+    }
+}
+
+impl BlockSink for WebhookSink {
+    fn write_block(&mut self, block: &GetBlocksResultV0Ex) -> Result<()> {
+        let block_num = match &block.this_block {
+            Some(bp) => bp.block_num,
+            None => return Ok(()),
+        };
+        let mut full = false;
+        for (idx, target) in self.config.targets.iter().enumerate() {
+            let events = target.filter.events(block)?;
+            self.pending[idx].extend(events);
+            full |= self.pending[idx].len() >= self.config.batch_size;
+        }
+        self.last_block = Some(block_num);
+        if full || self.pending.iter().all(|p| p.is_empty()) {
+            self.deliver_all()?;
+        }
+        Ok(())
+    }
+
+    fn undo_to(&mut self, block_num: u32) -> Result<()> {
+        for (pending, target) in self.pending.iter_mut().zip(&self.targets) {
+            pending.retain(|e| e.block_num <= block_num);
+            // events from earlier batches are already out, the receiver has to retract them
+            if target.delivered.map_or(false, |last| last > block_num) {
+                pending.push(Event::undo(block_num));
+            }
+        }
+        self.last_block = Some(block_num);
+        self.deliver_all()
+    }
+
+    fn last_committed_block(&mut self) -> Result<Option<u32>> {
+        Ok(self.last_block)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.deliver_all()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filter::{ActionFilter, EventKind};
+    use crate::shipper_types::tests::{block, trace};
+    use std::collections::VecDeque;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::sync::{mpsc, Arc, Mutex};
+    use std::thread;
+
+    /// (signature header, body) of each POST
+    type Received = Arc<Mutex<Vec<(Option<String>, Vec<u8>)>>>;
+
+    /// answers each POST with the next of `statuses`, failing to connect once they run out
+    struct TestTransport {
+        statuses: Mutex<VecDeque<u16>>,
+        received: Received,
+    }
+
+    impl Transport for TestTransport {
+        fn post(&self, _url: &str, headers: &[(&str, String)], body: &[u8]) -> Result<u16> {
+            let status = self
+                .statuses
+                .lock()
+                .unwrap()
+                .pop_front()
+                .ok_or("connection refused")?;
+            let signature = headers
+                .iter()
+                .find(|(name, _)| *name == SIGNATURE_HEADER)
+                .map(|(_, value)| value.clone());
+            self.received
+                .lock()
+                .unwrap()
+                .push((signature, body.to_vec()));
+            Ok(status)
+        }
+    }
+
+    fn target(action: &str) -> WebhookTarget {
+        WebhookTarget {
+            url: String::from("http://localhost/hook"),
+            filter: EventFilter {
+                actions: vec![action.parse::<ActionFilter>().unwrap()],
+                deltas: vec![],
+            },
+            secret: Some(b"secret".to_vec()),
+        }
+    }
+
+    fn sink(name: &str, batch_size: usize, statuses: Vec<u16>) -> (WebhookSink, Received) {
+        sink_with(
+            name,
+            batch_size,
+            statuses,
+            vec![target("eosio.token:transfer")],
+        )
+    }
+
+    /// a sink that retries failed URLs straight away
+    fn sink_with(
+        name: &str,
+        batch_size: usize,
+        statuses: Vec<u16>,
+        targets: Vec<WebhookTarget>,
+    ) -> (WebhookSink, Received) {
+        let dir =
+            std::env::temp_dir().join(format!("ship-webhook-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        let config = WebhookConfig {
+            targets,
+            batch_size,
+            initial_backoff: Duration::from_secs(0),
+            max_backoff: Duration::from_secs(0),
+            timeout: Duration::from_secs(1),
+            spool_dir: dir.join("spool").to_string_lossy().into_owned(),
+            checkpoint: FileCheckpoint::new(&dir.join("checkpoint").to_string_lossy()),
+        };
+        let received: Received = Arc::new(Mutex::new(vec![]));
+        let transport = TestTransport {
+            statuses: Mutex::new(statuses.into_iter().collect()),
+            received: received.clone(),
+        };
+        let sink = WebhookSink::with_transport(config, Box::new(transport)).unwrap();
+        (sink, received)
+    }
+
+    fn transfer_block(block_num: u32) -> GetBlocksResultV0Ex {
+        let mut b = block(block_num, 0, vec![]);
+        b.traces = vec![trace(
+            &format!("trx{}", block_num),
+            &[("eosio.token", "transfer", Some(block_num as u64))],
+        )];
+        b
+    }
+
+    /// (kind, block_num) of each event in a body
+    fn events(body: &[u8]) -> Vec<(EventKind, u32)> {
+        let events: Vec<Event> = serde_json::from_slice(body).unwrap();
+        events.iter().map(|e| (e.kind, e.block_num)).collect()
+    }
+
+    #[test]
+    fn signs_with_hmac_sha256() {
+        // RFC 4231 test case 2
+        assert_eq!(
+            WebhookSink::sign(b"Jefe", b"what do ya want for nothing?"),
+            "sha256=5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[test]
+    fn posts_signed_batches_and_checkpoints() {
+        let (mut sink, received) = sink("post", 1, vec![200]);
+        sink.handle_block(&transfer_block(5)).unwrap();
+
+        let received = received.lock().unwrap();
+        assert_eq!(received.len(), 1);
+        let (signature, body) = &received[0];
+        assert_eq!(
+            signature.as_deref(),
+            Some(WebhookSink::sign(b"secret", body).as_str())
+        );
+        assert_eq!(events(body), vec![(EventKind::Action, 5)]);
+        assert_eq!(sink.config.checkpoint.load().unwrap(), Some(5));
+    }
+
+    #[test]
+    fn spools_failed_batches_and_replays_them_first() {
+        let (mut sink, received) = sink("spool", 1, vec![500, 200, 200]);
+        sink.handle_block(&transfer_block(5)).unwrap();
+        // refused, so it is in the spool and the checkpoint moved on anyway
+        assert_eq!(sink.spooled(0).unwrap().len(), 1);
+        assert_eq!(sink.config.checkpoint.load().unwrap(), Some(5));
+
+        sink.handle_block(&transfer_block(6)).unwrap();
+        assert_eq!(sink.spooled(0).unwrap().len(), 0);
+        let bodies: Vec<Vec<(EventKind, u32)>> = received
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(_, body)| events(body))
+            .collect();
+        let action = |n| vec![(EventKind::Action, n)];
+        assert_eq!(bodies, vec![action(5), action(5), action(6)]);
+        assert_eq!(sink.config.checkpoint.load().unwrap(), Some(6));
+    }
+
+    #[test]
+    fn spool_keeps_its_order_while_the_url_is_down() {
+        let (mut sink, received) = sink("down", 1, vec![]);
+        for block_num in 5..=7 {
+            sink.handle_block(&transfer_block(block_num)).unwrap();
+        }
+        let spooled: Vec<Vec<(EventKind, u32)>> = sink
+            .spooled(0)
+            .unwrap()
+            .iter()
+            .map(|p| events(&fs::read(p).unwrap()))
+            .collect();
+        assert_eq!(spooled.len(), 3);
+        assert_eq!(spooled[2], vec![(EventKind::Action, 7)]);
+        assert!(received.lock().unwrap().is_empty());
+    }
+
+    /// the events of each body the transport was given
+    fn bodies(received: &Received) -> Vec<Vec<(EventKind, u32)>> {
+        received
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(_, body)| events(body))
+            .collect()
+    }
+
+    #[test]
+    fn undo_drops_pending_events() {
+        let (mut sink, received) = sink("undo", 10, vec![200, 200]);
+        sink.handle_block(&transfer_block(5)).unwrap();
+        sink.handle_block(&transfer_block(6)).unwrap();
+        // a fork replacing block 6 before anything was sent needs no undo
+        sink.handle_block(&transfer_block(6)).unwrap();
+        sink.flush().unwrap();
+
+        let action = |n| vec![(EventKind::Action, n)];
+        assert_eq!(bodies(&received), vec![action(5), action(6)]);
+        assert_eq!(sink.last_committed_block().unwrap(), Some(6));
+    }
+
+    #[test]
+    fn undo_only_goes_to_targets_sent_the_undone_blocks() {
+        let targets = vec![target("eosio.token:transfer"), target("bob")];
+        let (mut sink, received) = sink_with("undo-targets", 1, vec![200; 5], targets);
+        sink.handle_block(&transfer_block(5)).unwrap();
+        sink.handle_block(&transfer_block(6)).unwrap();
+        sink.handle_block(&transfer_block(6)).unwrap();
+        // and only once
+        sink.undo_to(5).unwrap();
+
+        let action = |n| vec![(EventKind::Action, n)];
+        assert_eq!(
+            bodies(&received),
+            vec![
+                action(5),
+                action(6),
+                vec![(EventKind::Undo, 5)],
+                action(6),
+                vec![(EventKind::Undo, 5)],
+            ]
+        );
+        assert_eq!(sink.targets[0].delivered, Some(5));
+        assert_eq!(sink.targets[1].delivered, None);
+    }
+
+    #[test]
+    fn failed_urls_are_backed_off_into_the_spool() {
+        let (mut sink, received) = sink("backoff", 1, vec![500, 503, 200, 200, 200, 200]);
+        sink.config.initial_backoff = Duration::from_secs(3600);
+        sink.config.max_backoff = Duration::from_secs(5400);
+        sink.handle_block(&transfer_block(5)).unwrap();
+        // backing off, so straight to the spool without a request
+        sink.handle_block(&transfer_block(6)).unwrap();
+        assert_eq!(received.lock().unwrap().len(), 1);
+        assert_eq!(sink.spooled(0).unwrap().len(), 2);
+        assert_eq!(sink.config.checkpoint.load().unwrap(), Some(6));
+
+        let over = |sink: &mut WebhookSink| {
+            let (delay, _) = sink.targets[0].backoff.unwrap();
+            sink.targets[0].backoff = Some((delay, Instant::now()));
+            delay
+        };
+        assert_eq!(over(&mut sink), Duration::from_secs(3600));
+        sink.handle_block(&transfer_block(7)).unwrap();
+        // failed again, the backoff doubles up to max_backoff
+        assert_eq!(over(&mut sink), Duration::from_secs(5400));
+        sink.handle_block(&transfer_block(8)).unwrap();
+
+        assert!(sink.targets[0].backoff.is_none());
+        assert_eq!(sink.spooled(0).unwrap().len(), 0);
+        let action = |n| vec![(EventKind::Action, n)];
+        assert_eq!(
+            bodies(&received),
+            vec![
+                action(5),
+                action(5),
+                action(5),
+                action(6),
+                action(7),
+                action(8)
+            ]
+        );
+    }
+
+    /// serves one request per status on a local port, giving back each request's head and
+    /// body. a status of 0 never answers.
+    fn serve(statuses: Vec<u16>) -> (String, mpsc::Receiver<(String, Vec<u8>)>) {
+        let listener = TcpListener::bind("[::1]:0").unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            for status in statuses {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut request = vec![];
+                let mut buf = [0; 4096];
+                let (head, body) = loop {
+                    let n = stream.read(&mut buf).unwrap();
+                    request.extend_from_slice(&buf[..n]);
+                    let text = String::from_utf8_lossy(&request).into_owned();
+                    if let Some(end) = text.find("\r\n\r\n") {
+                        let length = text[..end]
+                            .lines()
+                            .find_map(|l| {
+                                l.to_lowercase()
+                                    .strip_prefix("content-length:")
+                                    .map(|v| v.trim().parse::<usize>().unwrap())
+                            })
+                            .unwrap_or(0);
+                        if request.len() >= end + 4 + length {
+                            break (String::from(&text[..end]), request[end + 4..].to_vec());
+                        }
+                    }
+                };
+                tx.send((head, body)).unwrap();
+                if status == 0 {
+                    thread::sleep(Duration::from_secs(2));
+                    continue;
+                }
+                let response = format!(
+                    "HTTP/1.1 {} X\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                    status
+                );
+                stream.write_all(response.as_bytes()).unwrap();
+            }
+        });
+        (url, rx)
+    }
+
+    #[test]
+    fn reqwest_transport_posts_signed_bodies() {
+        let (url, requests) = serve(vec![200, 503, 0]);
+        let transport = ReqwestTransport::new(Duration::from_millis(500)).unwrap();
+        let body = b"[]";
+        let headers = vec![(SIGNATURE_HEADER, WebhookSink::sign(b"secret", body))];
+
+        assert_eq!(transport.post(&url, &headers, body).unwrap(), 200);
+        let (head, received) = requests.recv().unwrap();
+        assert!(head.starts_with("POST /hook "));
+        let signature = format!("{}: {}", SIGNATURE_HEADER, headers[0].1).to_lowercase();
+        assert!(head.to_lowercase().lines().any(|l| l == signature));
+        assert_eq!(received, body);
+
+        assert_eq!(transport.post(&url, &headers, body).unwrap(), 503);
+        // no answer within the timeout
+        assert!(transport.post(&url, &headers, body).is_err());
+    }
+}
@@ -0,0 +1,625 @@
+use crate::errors::{ErrorKind, Result};
+use crate::shipper_types::{
+    Account, AccountMetadata, AccountMetadataV0, AccountV0, ContractIndex128, ContractIndex128V0,
+    ContractIndex256, ContractIndex256V0, ContractIndex64, ContractIndex64V0, ContractIndexDouble,
+    ContractIndexDoubleV0, ContractRow, ContractRowV0, GetBlocksResultV0Ex, Permission,
+    PermissionLink, PermissionLinkV0, PermissionV0, ResourceLimits, ResourceLimitsConfig,
+    ResourceLimitsConfigV0, ResourceLimitsState, ResourceLimitsStateV0, ResourceLimitsV0,
+    ResourceUsage, ResourceUsageV0, TableDeltaEx, TableRowTypes,
+};
+use crate::undo::UndoLog;
+use crate::ShipAbiFiles;
+use log::*;
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap};
+use std::ops::Bound;
+
+/// one component of a row key. numeric fields (uint64 primary keys, uint128 sender ids..)
+/// compare as numbers so range scans come back in chain order.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum KeyPart {
+    Num(u128),
+    Str(String),
+}
+
+impl KeyPart {
+    fn from_json(v: &Value) -> KeyPart {
+        match v {
+            Value::Number(n) => match n.as_u64() {
+                Some(x) => KeyPart::Num(x as u128),
+                None => KeyPart::Str(n.to_string()),
+            },
+            Value::String(s) => KeyPart::from(s.as_str()),
+            Value::Bool(b) => KeyPart::Num(*b as u128),
+            other => KeyPart::Str(other.to_string()),
+        }
+    }
+}
+
+impl From<&str> for KeyPart {
+    fn from(s: &str) -> Self {
+        match s.parse::<u128>() {
+            Ok(n) => KeyPart::Num(n),
+            Err(_) => KeyPart::Str(String::from(s)),
+        }
+    }
+}
+
+impl From<u64> for KeyPart {
+    fn from(n: u64) -> Self {
+        KeyPart::Num(n as u128)
+    }
+}
+
+pub type RowKey = Vec<KeyPart>;
+
+#[derive(Debug, Deserialize)]
+struct AbiTable {
+    r#type: String,
+    key_names: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct AbiTables {
+    tables: Vec<AbiTable>,
+}
+
+/// Chain state rebuilt in memory from table deltas.
+///
+/// Rows are keyed by the `key_names` the ABI declares for each table type, so a
+/// `contract_row` lives under `code, scope, table, primary_key`. Every block that is
+/// still reversible keeps an undo log, and a block arriving at or below the current head
+/// rolls the state back before it is applied.
+#[derive(Debug)]
+pub struct StateStore {
+    key_names: HashMap<String, Vec<String>>,
+    tables: HashMap<String, BTreeMap<RowKey, TableRowTypes>>,
+    /// keyed by (table, row key)
+    undo: UndoLog<(String, RowKey), TableRowTypes>,
+}
+
+impl StateStore {
+    /// uses the table definitions from the embedded shipper ABI
+    pub fn new() -> Result<StateStore> {
+        let abi_f = ShipAbiFiles::get("shipper.abi.json").unwrap();
+        let abi_js = String::from_utf8(abi_f.as_ref().to_vec())?;
+        StateStore::new_with_abi(&abi_js)
+    }
+
+    /// uses the table definitions from an ABI, usually the one nodeos sends on connect
+    pub fn new_with_abi(abi_json: &str) -> Result<StateStore> {
+        let abi: AbiTables = serde_json::from_str(abi_json)?;
+        let key_names = abi
+            .tables
+            .into_iter()
+            .map(|t| (t.r#type, t.key_names))
+            .collect();
+        Ok(StateStore {
+            key_names,
+            tables: HashMap::new(),
+            undo: UndoLog::new(),
+        })
+    }
+
+    pub fn head_block_num(&self) -> Option<u32> {
+        self.undo.head()
+    }
+
+    pub fn last_irreversible(&self) -> u32 {
+        self.undo.last_irreversible()
+    }
+
+    pub fn apply_block(&mut self, block: &GetBlocksResultV0Ex) -> Result<()> {
+        match &block.this_block {
+            None => Ok(()),
+            Some(bp) => {
+                self.apply_deltas(bp.block_num, &block.deltas)?;
+                self.set_irreversible(block.last_irreversible.block_num);
+                Ok(())
+            }
+        }
+    }
+
+    pub fn apply_deltas(&mut self, block_num: u32, deltas: &[TableDeltaEx]) -> Result<()> {
+        if let Some(fork) = self.undo.fork_point(block_num) {
+            info!("fork at {}, rolling back to {}", block_num, fork);
+            self.rollback_to(fork)?;
+        }
+        // key every row first so an unknown table leaves the tables untouched
+        let mut keyed = vec![];
+        for delta in deltas {
+            for row in &delta.rows {
+                if let TableRowTypes::Other(_) = row.data {
+                    continue;
+                }
+                keyed.push((&delta.name, self.row_key(&delta.name, &row.data)?, row));
+            }
+        }
+        let mut entries = vec![];
+        for (name, key, row) in keyed {
+            let table = self
+                .tables
+                .entry(name.clone())
+                .or_insert_with(BTreeMap::new);
+            let previous = if row.present {
+                table.insert(key.clone(), row.data.clone())
+            } else {
+                table.remove(&key)
+            };
+            entries.push(((name.clone(), key), previous));
+        }
+        self.undo.push(block_num, entries);
+        Ok(())
+    }
+
+    /// undo every block above `block_num`
+    pub fn rollback_to(&mut self, block_num: u32) -> Result<()> {
+        let tables = &mut self.tables;
+        self.undo.rollback_to(block_num, |(table, key), previous| {
+            let table = tables.entry(table).or_insert_with(BTreeMap::new);
+            match previous {
+                Some(row) => table.insert(key, row),
+                None => table.remove(&key),
+            };
+        })
+    }
+
+    /// drops undo logs for blocks that can no longer be forked out
+    pub fn set_irreversible(&mut self, block_num: u32) {
+        self.undo.set_irreversible(block_num);
+    }
+
+    fn row_key(&self, table: &str, row: &TableRowTypes) -> Result<RowKey> {
+        let key_names = self
+            .key_names
+            .get(table)
+            .ok_or_else(|| ErrorKind::UnknownTable(String::from(table)))?;
+        // serializes as [table, [table_vX, {fields}]]
+        let v = serde_json::to_value(row)?;
+        let fields = &v[1][1];
+        Ok(key_names
+            .iter()
+            .map(|k| KeyPart::from_json(&fields[k.as_str()]))
+            .collect())
+    }
+
+    pub fn get(&self, table: &str, key: &[KeyPart]) -> Option<&TableRowTypes> {
+        self.tables.get(table).and_then(|t| t.get(key))
+    }
+
+    /// every row of `table` whose key starts with `prefix`, in key order
+    pub fn scan<'a>(
+        &'a self,
+        table: &str,
+        prefix: &[KeyPart],
+    ) -> impl Iterator<Item = (&'a RowKey, &'a TableRowTypes)> + 'a {
+        self.scan_range(table, prefix, Bound::Unbounded, Bound::Unbounded)
+    }
+
+    /// rows whose key starts with `prefix` and whose next key part is within `lower..upper`
+    pub fn scan_range<'a>(
+        &'a self,
+        table: &str,
+        prefix: &[KeyPart],
+        lower: Bound<KeyPart>,
+        upper: Bound<KeyPart>,
+    ) -> impl Iterator<Item = (&'a RowKey, &'a TableRowTypes)> + 'a {
+        let prefix = prefix.to_vec();
+        let depth = prefix.len();
+        let start = match lower {
+            Bound::Included(k) => Bound::Included([&prefix[..], &[k]].concat()),
+            Bound::Excluded(k) => Bound::Excluded([&prefix[..], &[k]].concat()),
+            Bound::Unbounded => Bound::Included(prefix.clone()),
+        };
+        self.tables
+            .get(table)
+            .into_iter()
+            .flat_map(move |t| t.range((start.clone(), Bound::Unbounded)))
+            .take_while(move |(k, _)| k.starts_with(&prefix))
+            .take_while(move |(k, _)| match (&upper, k.get(depth)) {
+                (Bound::Included(u), Some(p)) => p <= u,
+                (Bound::Excluded(u), Some(p)) => p < u,
+                _ => true,
+            })
+    }
+
+    pub fn account(&self, name: &str) -> Option<&AccountV0> {
+        match self.get("account", &[KeyPart::from(name)]) {
+            Some(TableRowTypes::account(Account::account_v0(a))) => Some(a),
+            _ => None,
+        }
+    }
+
+    pub fn account_metadata(&self, name: &str) -> Option<&AccountMetadataV0> {
+        match self.get("account_metadata", &[KeyPart::from(name)]) {
+            Some(TableRowTypes::account_metadata(AccountMetadata::account_metadata_v0(m))) => {
+                Some(m)
+            }
+            _ => None,
+        }
+    }
+
+    pub fn permission(&self, owner: &str, name: &str) -> Option<&PermissionV0> {
+        match self.get("permission", &[KeyPart::from(owner), KeyPart::from(name)]) {
+            Some(TableRowTypes::permission(Permission::permission_v0(p))) => Some(p),
+            _ => None,
+        }
+    }
+
+    pub fn permissions(&self, owner: &str) -> Vec<&PermissionV0> {
+        let prefix = vec![KeyPart::from(owner)];
+        self.scan("permission", &prefix)
+            .filter_map(|(_, r)| match r {
+                TableRowTypes::permission(Permission::permission_v0(p)) => Some(p),
+                _ => None,
+            })
+            .collect()
+    }
+
+    pub fn permission_links(&self, account: &str) -> Vec<&PermissionLinkV0> {
+        let prefix = vec![KeyPart::from(account)];
+        self.scan("permission_link", &prefix)
+            .filter_map(|(_, r)| match r {
+                TableRowTypes::permission_link(PermissionLink::permission_link_v0(l)) => Some(l),
+                _ => None,
+            })
+            .collect()
+    }
+
+    pub fn resource_usage(&self, owner: &str) -> Option<&ResourceUsageV0> {
+        match self.get("resource_usage", &[KeyPart::from(owner)]) {
+            Some(TableRowTypes::resource_usage(ResourceUsage::resource_usage_v0(u))) => Some(u),
+            _ => None,
+        }
+    }
+
+    pub fn resource_limits(&self, owner: &str) -> Option<&ResourceLimitsV0> {
+        match self.get("resource_limits", &[KeyPart::from(owner)]) {
+            Some(TableRowTypes::resource_limits(ResourceLimits::resource_limits_v0(l))) => Some(l),
+            _ => None,
+        }
+    }
+
+    pub fn resource_limits_state(&self) -> Option<&ResourceLimitsStateV0> {
+        match self.get("resource_limits_state", &[]) {
+            Some(TableRowTypes::resource_limits_state(
+                ResourceLimitsState::resource_limits_state_v0(s),
+            )) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn resource_limits_config(&self) -> Option<&ResourceLimitsConfigV0> {
+        match self.get("resource_limits_config", &[]) {
+            Some(TableRowTypes::resource_limits_config(
+                ResourceLimitsConfig::resource_limits_config_v0(c),
+            )) => Some(c),
+            _ => None,
+        }
+    }
+
+    pub fn contract_row(
+        &self,
+        code: &str,
+        scope: &str,
+        table: &str,
+        primary_key: u64,
+    ) -> Option<&ContractRowV0> {
+        let key = vec![
+            KeyPart::from(code),
+            KeyPart::from(scope),
+            KeyPart::from(table),
+            KeyPart::from(primary_key),
+        ];
+        match self.get("contract_row", &key) {
+            Some(TableRowTypes::contract_row(ContractRow::contract_row_v0(r))) => Some(r),
+            _ => None,
+        }
+    }
+
+    /// rows of a contract table with `lower <= primary_key <= upper`
+    pub fn contract_rows(
+        &self,
+        code: &str,
+        scope: &str,
+        table: &str,
+        lower: u64,
+        upper: u64,
+    ) -> Vec<&ContractRowV0> {
+        let prefix = vec![
+            KeyPart::from(code),
+            KeyPart::from(scope),
+            KeyPart::from(table),
+        ];
+        self.scan_range(
+            "contract_row",
+            &prefix,
+            Bound::Included(KeyPart::from(lower)),
+            Bound::Included(KeyPart::from(upper)),
+        )
+        .filter_map(|(_, r)| match r {
+            TableRowTypes::contract_row(ContractRow::contract_row_v0(r)) => Some(r),
+            _ => None,
+        })
+        .collect()
+    }
+
+    fn index_rows<'a, T, F>(
+        &'a self,
+        index: &str,
+        code: &str,
+        scope: &str,
+        table: &str,
+        f: F,
+    ) -> Vec<&'a T>
+    where
+        F: Fn(&'a TableRowTypes) -> Option<&'a T>,
+    {
+        let prefix = vec![
+            KeyPart::from(code),
+            KeyPart::from(scope),
+            KeyPart::from(table),
+        ];
+        self.scan(index, &prefix)
+            .filter_map(|(_, r)| f(r))
+            .collect()
+    }
+
+    /// secondary index rows ordered by (secondary_key, primary_key), limited to
+    /// `lower <= secondary_key <= upper`
+    pub fn contract_index64(
+        &self,
+        code: &str,
+        scope: &str,
+        table: &str,
+        lower: u64,
+        upper: u64,
+    ) -> Vec<&ContractIndex64V0> {
+        let mut rows = self.index_rows("contract_index64", code, scope, table, |r| match r {
+            TableRowTypes::contract_index64(ContractIndex64::contract_index64_v0(i)) => Some(i),
+            _ => None,
+        });
+        rows.retain(|i| match i.secondary_key.parse::<u64>() {
+            Ok(k) => k >= lower && k <= upper,
+            Err(_) => false,
+        });
+        rows.sort_by_key(|i| {
+            (
+                i.secondary_key.parse::<u64>().unwrap_or(0),
+                i.primary_key.parse::<u64>().unwrap_or(0),
+            )
+        });
+        rows
+    }
+
+    pub fn contract_index128(
+        &self,
+        code: &str,
+        scope: &str,
+        table: &str,
+        lower: u128,
+        upper: u128,
+    ) -> Vec<&ContractIndex128V0> {
+        let mut rows = self.index_rows("contract_index128", code, scope, table, |r| match r {
+            TableRowTypes::contract_index128(ContractIndex128::contract_index128_v0(i)) => Some(i),
+            _ => None,
+        });
+        rows.retain(|i| match i.secondary_key.parse::<u128>() {
+            Ok(k) => k >= lower && k <= upper,
+            Err(_) => false,
+        });
+        rows.sort_by_key(|i| {
+            (
+                i.secondary_key.parse::<u128>().unwrap_or(0),
+                i.primary_key.parse::<u64>().unwrap_or(0),
+            )
+        });
+        rows
+    }
+
+    /// checksum256 keys compare as their hex strings
+    pub fn contract_index256(
+        &self,
+        code: &str,
+        scope: &str,
+        table: &str,
+        lower: &str,
+        upper: &str,
+    ) -> Vec<&ContractIndex256V0> {
+        let mut rows = self.index_rows("contract_index256", code, scope, table, |r| match r {
+            TableRowTypes::contract_index256(ContractIndex256::contract_index256_v0(i)) => Some(i),
+            _ => None,
+        });
+        rows.retain(|i| i.secondary_key.as_str() >= lower && i.secondary_key.as_str() <= upper);
+        rows.sort_by(|a, b| {
+            a.secondary_key.cmp(&b.secondary_key).then(
+                a.primary_key
+                    .parse::<u64>()
+                    .unwrap_or(0)
+                    .cmp(&b.primary_key.parse::<u64>().unwrap_or(0)),
+            )
+        });
+        rows
+    }
+
+    pub fn contract_index_double(
+        &self,
+        code: &str,
+        scope: &str,
+        table: &str,
+        lower: f64,
+        upper: f64,
+    ) -> Vec<&ContractIndexDoubleV0> {
+        let mut rows = self.index_rows("contract_index_double", code, scope, table, |r| match r {
+            TableRowTypes::contract_index_double(
+                ContractIndexDouble::contract_index_double_v0(i),
+            ) => Some(i),
+            _ => None,
+        });
+        rows.retain(|i| match i.secondary_key.parse::<f64>() {
+            Ok(k) => k >= lower && k <= upper,
+            Err(_) => false,
+        });
+        rows.sort_by(|a, b| {
+            let ka = a.secondary_key.parse::<f64>().unwrap_or(0.0);
+            let kb = b.secondary_key.parse::<f64>().unwrap_or(0.0);
+            ka.partial_cmp(&kb).unwrap_or(std::cmp::Ordering::Equal)
+        });
+        rows
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shipper_types::tests::block;
+    use crate::shipper_types::TableRowEx;
+
+    fn contract_row(primary_key: u64, value: &str, present: bool) -> TableRowEx {
+        TableRowEx {
+            present,
+            data: TableRowTypes::contract_row(ContractRow::contract_row_v0(ContractRowV0 {
+                code: String::from("eosio.token"),
+                scope: String::from("alice"),
+                table: String::from("accounts"),
+                primary_key: primary_key.to_string(),
+                payer: String::from("alice"),
+                value: String::from(value),
+            })),
+        }
+    }
+
+    fn deltas(rows: Vec<TableRowEx>) -> Vec<TableDeltaEx> {
+        vec![TableDeltaEx {
+            name: String::from("contract_row"),
+            rows,
+        }]
+    }
+
+    fn values(state: &StateStore) -> Vec<(u64, String)> {
+        state
+            .contract_rows("eosio.token", "alice", "accounts", 0, u64::MAX)
+            .into_iter()
+            .map(|r| (r.primary_key.parse().unwrap(), r.value.clone()))
+            .collect()
+    }
+
+    fn rows(v: &[(u64, &str)]) -> Vec<(u64, String)> {
+        v.iter().map(|(k, v)| (*k, String::from(*v))).collect()
+    }
+
+    #[test]
+    fn fork_rolls_back_before_applying() {
+        let mut state = StateStore::new().unwrap();
+        state
+            .apply_block(&block(1, 0, deltas(vec![contract_row(1, "a1", true)])))
+            .unwrap();
+        state
+            .apply_block(&block(
+                2,
+                0,
+                deltas(vec![
+                    contract_row(1, "a2", true),
+                    contract_row(2, "b2", true),
+                ]),
+            ))
+            .unwrap();
+        state
+            .apply_block(&block(3, 0, deltas(vec![contract_row(1, "", false)])))
+            .unwrap();
+        assert_eq!(values(&state), rows(&[(2, "b2")]));
+        assert_eq!(state.head_block_num(), Some(3));
+
+        // another block 2 replaces blocks 2 and 3
+        state
+            .apply_block(&block(2, 0, deltas(vec![contract_row(3, "c2", true)])))
+            .unwrap();
+        assert_eq!(values(&state), rows(&[(1, "a1"), (3, "c2")]));
+        assert_eq!(state.head_block_num(), Some(2));
+
+        state.rollback_to(0).unwrap();
+        assert_eq!(values(&state), vec![]);
+        assert_eq!(state.head_block_num(), Some(0));
+    }
+
+    #[test]
+    fn irreversible_blocks_stay() {
+        let mut state = StateStore::new().unwrap();
+        for block_num in 1..=3 {
+            let value = format!("a{}", block_num);
+            state
+                .apply_block(&block(
+                    block_num,
+                    2,
+                    deltas(vec![contract_row(1, &value, true)]),
+                ))
+                .unwrap();
+        }
+        assert_eq!(state.last_irreversible(), 2);
+        assert!(state.rollback_to(1).is_err());
+        assert_eq!(values(&state), rows(&[(1, "a3")]));
+
+        state.rollback_to(2).unwrap();
+        assert_eq!(values(&state), rows(&[(1, "a2")]));
+        // a fork at the irreversible block itself can't be followed
+        assert!(state
+            .apply_block(&block(2, 2, deltas(vec![contract_row(1, "x", true)])))
+            .is_err());
+    }
+
+    #[test]
+    fn rows_are_keyed_by_the_abi_key_names() {
+        let mut state = StateStore::new().unwrap();
+        state
+            .apply_block(&block(
+                1,
+                0,
+                deltas(vec![
+                    contract_row(10, "ten", true),
+                    contract_row(9, "nine", true),
+                    contract_row(100, "hundred", true),
+                ]),
+            ))
+            .unwrap();
+        // primary keys compare as numbers, not strings
+        assert_eq!(
+            values(&state),
+            rows(&[(9, "nine"), (10, "ten"), (100, "hundred")])
+        );
+        let range = state.contract_rows("eosio.token", "alice", "accounts", 10, 99);
+        assert_eq!(range.len(), 1);
+        assert_eq!(range[0].value, "ten");
+        assert!(state
+            .contract_row("eosio.token", "alice", "accounts", 100)
+            .is_some());
+        assert!(state
+            .contract_row("eosio.token", "bob", "accounts", 100)
+            .is_none());
+    }
+
+    #[test]
+    fn an_unknown_table_leaves_the_block_unapplied() {
+        let mut state = StateStore::new().unwrap();
+        state
+            .apply_block(&block(1, 0, deltas(vec![contract_row(1, "a1", true)])))
+            .unwrap();
+        let mut bad = deltas(vec![
+            contract_row(1, "a2", true),
+            contract_row(2, "b2", true),
+        ]);
+        bad.push(TableDeltaEx {
+            name: String::from("no_such_table"),
+            rows: vec![contract_row(3, "c2", true)],
+        });
+        assert!(state.apply_block(&block(2, 0, bad)).is_err());
+        assert_eq!(values(&state), rows(&[(1, "a1")]));
+        assert_eq!(state.head_block_num(), Some(1));
+
+        state
+            .apply_block(&block(2, 0, deltas(vec![contract_row(2, "b2", true)])))
+            .unwrap();
+        state.rollback_to(1).unwrap();
+        assert_eq!(values(&state), rows(&[(1, "a1")]));
+    }
+}
@@ -0,0 +1,512 @@
+use crate::abi::EmbeddedAbi;
+use crate::errors::Result;
+use crate::sequence;
+use crate::shipper_types::{ContractRow, GetBlocksResultV0Ex, TableRowTypes};
+use crate::undo::{self, UndoLog};
+use log::*;
+use serde::de::Error as _;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+
+/// the built-in token ABI is loaded under this name, and decodes every contract's data
+const TOKEN_ABI_CONTRACT: &str = "eosio.token";
+
+const TOKEN_ACTIONS: [&str; 5] = ["transfer", "issue", "retire", "open", "close"];
+
+/// a symbol code such as `EOS` packed into a u64, as used for `accounts`/`stat` primary keys
+pub fn symbol_code_raw(code: &str) -> u64 {
+    code.bytes()
+        .take(7)
+        .enumerate()
+        .fold(0u64, |raw, (i, c)| raw | (c as u64) << (8 * i))
+}
+
+/// `4,EOS`
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Symbol {
+    pub precision: u8,
+    pub code: String,
+}
+
+impl FromStr for Symbol {
+    type Err = crate::errors::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut parts = s.splitn(2, ',');
+        let precision = parts.next().and_then(|p| p.parse::<u8>().ok());
+        match (precision, parts.next()) {
+            (Some(precision), Some(code)) => Ok(Symbol {
+                precision,
+                code: String::from(code),
+            }),
+            _ => Err(format!("invalid symbol '{}'", s).into()),
+        }
+    }
+}
+
+impl fmt::Display for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{},{}", self.precision, self.code)
+    }
+}
+
+/// `1.0000 EOS`, held as an integer amount of the smallest unit
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Asset {
+    pub amount: i64,
+    pub symbol: Symbol,
+}
+
+impl FromStr for Asset {
+    type Err = crate::errors::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || format!("invalid asset '{}'", s);
+        let mut parts = s.trim().splitn(2, ' ');
+        let number = parts.next().ok_or_else(invalid)?;
+        let code = parts.next().ok_or_else(invalid)?;
+        let (whole, fraction) = match number.find('.') {
+            Some(i) => (&number[..i], &number[i + 1..]),
+            None => (number, ""),
+        };
+        let digits = format!("{}{}", whole, fraction);
+        let amount = digits.parse::<i64>().map_err(|_| invalid())?;
+        Ok(Asset {
+            amount,
+            symbol: Symbol {
+                precision: fraction.len() as u8,
+                code: String::from(code),
+            },
+        })
+    }
+}
+
+impl fmt::Display for Asset {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let sign = if self.amount < 0 { "-" } else { "" };
+        let abs = self.amount.wrapping_abs() as u64;
+        if self.symbol.precision == 0 {
+            return write!(f, "{}{} {}", sign, abs, self.symbol.code);
+        }
+        let scale = 10u64.pow(self.symbol.precision as u32);
+        write!(
+            f,
+            "{}{}.{:0width$} {}",
+            sign,
+            abs / scale,
+            abs % scale,
+            self.symbol.code,
+            width = self.symbol.precision as usize
+        )
+    }
+}
+
+impl Serialize for Asset {
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Asset {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        Asset::from_str(&s).map_err(|e| D::Error::custom(e.to_string()))
+    }
+}
+
+impl Serialize for Symbol {
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Symbol {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        Symbol::from_str(&s).map_err(|e| D::Error::custom(e.to_string()))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum TokenAction {
+    Transfer {
+        from: String,
+        to: String,
+        quantity: Asset,
+        memo: String,
+    },
+    Issue {
+        to: String,
+        quantity: Asset,
+        memo: String,
+    },
+    Retire {
+        quantity: Asset,
+        memo: String,
+    },
+    Open {
+        owner: String,
+        symbol: Symbol,
+        ram_payer: String,
+    },
+    Close {
+        owner: String,
+        symbol: Symbol,
+    },
+}
+
+/// a token action as executed by the token contract itself (notifications are left out)
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TokenTransfer {
+    pub block_num: u32,
+    pub trx_id: String,
+    pub global_sequence: u64,
+    /// the token contract, eg `eosio.token`
+    pub contract: String,
+    #[serde(flatten)]
+    pub action: TokenAction,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CurrencyStats {
+    pub supply: Asset,
+    pub max_supply: Asset,
+    pub issuer: String,
+}
+
+#[derive(Deserialize)]
+struct AccountRow {
+    balance: Asset,
+}
+
+/// Picks the `transfer`, `issue`, `retire`, `open` and `close` actions of eosio.token
+/// compatible contracts out of a block's traces.
+///
+/// Any contract counts as long as the action data decodes with the standard token ABI. Only
+/// the execution by the contract itself (receiver == act.account) is reported, so a transfer
+/// shows up once however many accounts were notified.
+pub struct TokenTracker {
+    abi: EmbeddedAbi,
+}
+
+impl TokenTracker {
+    pub fn new() -> Result<TokenTracker> {
+        Ok(TokenTracker {
+            abi: EmbeddedAbi::load("token.abi.json", TOKEN_ABI_CONTRACT)?,
+        })
+    }
+
+    pub fn transfers(&self, block: &GetBlocksResultV0Ex) -> Result<Vec<TokenTransfer>> {
+        let mut transfers = vec![];
+        for e in sequence::actions(block)? {
+            if e.receiver != e.act.account {
+                continue;
+            }
+            let name = e.act.name.as_str();
+            if !TOKEN_ACTIONS.contains(&name) {
+                continue;
+            }
+            // the action name doubles as the type name in the token ABI, and as the serde tag
+            let action = self
+                .abi
+                .decode::<serde_json::Value>(name, &e.act.data)
+                .and_then(|mut fields| {
+                    fields["action"] = serde_json::Value::from(name);
+                    Ok(serde_json::from_value::<TokenAction>(fields)?)
+                });
+            match action {
+                Ok(action) => transfers.push(TokenTransfer {
+                    block_num: e.block_num,
+                    trx_id: String::from(e.trx_id),
+                    global_sequence: e.global_sequence,
+                    contract: e.act.account.clone(),
+                    action,
+                }),
+                // same action name, different contract
+                _ => debug!("{}:{} is not a token action", e.act.account, e.act.name),
+            }
+        }
+        Ok(transfers)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum BalanceKey {
+    /// (contract, owner, symbol code)
+    Account(String, String, u64),
+    /// (contract, symbol code)
+    Stat(String, u64),
+}
+
+#[derive(Debug, Clone)]
+enum BalanceValue {
+    Account(Asset),
+    Stat(CurrencyStats),
+}
+
+/// Token balances and supplies kept up to date from the `accounts` and `stat` contract rows
+/// of every token contract (needs `fetch_deltas`).
+///
+/// Like `state_store::StateStore`, reversible blocks keep an undo log and a block at or below
+/// the head rolls the balances back first.
+pub struct TokenBalances {
+    abi: EmbeddedAbi,
+    rows: HashMap<BalanceKey, BalanceValue>,
+    undo: UndoLog<BalanceKey, BalanceValue>,
+}
+
+impl TokenBalances {
+    pub fn new() -> Result<TokenBalances> {
+        Ok(TokenBalances {
+            abi: EmbeddedAbi::load("token.abi.json", TOKEN_ABI_CONTRACT)?,
+            rows: HashMap::new(),
+            undo: UndoLog::new(),
+        })
+    }
+
+    pub fn apply_block(&mut self, block: &GetBlocksResultV0Ex) -> Result<()> {
+        let block_num = match &block.this_block {
+            Some(bp) => bp.block_num,
+            None => return Ok(()),
+        };
+        if let Some(fork) = self.undo.fork_point(block_num) {
+            info!("tokens: fork at {}, rolling back to {}", block_num, fork);
+            self.rollback_to(fork)?;
+        }
+        let mut entries = vec![];
+        for delta in &block.deltas {
+            if delta.name != "contract_row" {
+                continue;
+            }
+            for row in &delta.rows {
+                let cr = match &row.data {
+                    TableRowTypes::contract_row(ContractRow::contract_row_v0(cr)) => cr,
+                    _ => continue,
+                };
+                let pk = match cr.primary_key.parse::<u64>() {
+                    Ok(pk) => pk,
+                    Err(_) => continue,
+                };
+                let (key, value) = match cr.table.as_str() {
+                    "accounts" => {
+                        let key = BalanceKey::Account(cr.code.clone(), cr.scope.clone(), pk);
+                        let value = if row.present {
+                            match self.abi.decode::<AccountRow>("account", &cr.value) {
+                                Ok(r) => Some(BalanceValue::Account(r.balance)),
+                                Err(_) => continue,
+                            }
+                        } else {
+                            None
+                        };
+                        (key, value)
+                    }
+                    "stat" => {
+                        let key = BalanceKey::Stat(cr.code.clone(), pk);
+                        let value = if row.present {
+                            match self
+                                .abi
+                                .decode::<CurrencyStats>("currency_stats", &cr.value)
+                            {
+                                Ok(r) => Some(BalanceValue::Stat(r)),
+                                Err(_) => continue,
+                            }
+                        } else {
+                            None
+                        };
+                        (key, value)
+                    }
+                    _ => continue,
+                };
+                let previous = match value {
+                    Some(v) => self.rows.insert(key.clone(), v),
+                    None => self.rows.remove(&key),
+                };
+                entries.push((key, previous));
+            }
+        }
+        self.undo.push(block_num, entries);
+        self.undo
+            .set_irreversible(block.last_irreversible.block_num);
+        Ok(())
+    }
+
+    /// undo every block above `block_num`
+    pub fn rollback_to(&mut self, block_num: u32) -> Result<()> {
+        let rows = &mut self.rows;
+        self.undo.rollback_to(block_num, |key, previous| {
+            undo::restore(rows, key, previous)
+        })
+    }
+
+    /// eg `balance("eosio.token", "alice", "EOS")`
+    pub fn balance(&self, contract: &str, owner: &str, symbol_code: &str) -> Option<&Asset> {
+        let key = BalanceKey::Account(
+            String::from(contract),
+            String::from(owner),
+            symbol_code_raw(symbol_code),
+        );
+        match self.rows.get(&key) {
+            Some(BalanceValue::Account(a)) => Some(a),
+            _ => None,
+        }
+    }
+
+    /// (contract, balance) for every token `owner` holds
+    pub fn balances_of(&self, owner: &str) -> Vec<(&str, &Asset)> {
+        let mut balances: Vec<(&str, &Asset)> = self
+            .rows
+            .iter()
+            .filter_map(|(k, v)| match (k, v) {
+                (BalanceKey::Account(contract, o, _), BalanceValue::Account(a)) if o == owner => {
+                    Some((contract.as_str(), a))
+                }
+                _ => None,
+            })
+            .collect();
+        balances.sort_by(|a, b| (a.0, &a.1.symbol.code).cmp(&(b.0, &b.1.symbol.code)));
+        balances
+    }
+
+    pub fn supply(&self, contract: &str, symbol_code: &str) -> Option<&CurrencyStats> {
+        let key = BalanceKey::Stat(String::from(contract), symbol_code_raw(symbol_code));
+        match self.rows.get(&key) {
+            Some(BalanceValue::Stat(s)) => Some(s),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shipper_types::tests::block;
+    use crate::shipper_types::{ContractRowV0, TableDeltaEx, TableRowEx};
+
+    fn asset(s: &str) -> Asset {
+        s.parse().unwrap()
+    }
+
+    /// an `accounts` row of `owner` holding `balance`, in the packed form of the contract
+    fn account_row(owner: &str, balance: &str, present: bool) -> TableDeltaEx {
+        let balance = asset(balance);
+        let mut symbol = vec![balance.symbol.precision];
+        symbol.extend(balance.symbol.code.bytes());
+        symbol.resize(8, 0);
+        let value: String = balance
+            .amount
+            .to_le_bytes()
+            .iter()
+            .chain(symbol.iter())
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        TableDeltaEx {
+            name: String::from("contract_row"),
+            rows: vec![TableRowEx {
+                present,
+                data: TableRowTypes::contract_row(ContractRow::contract_row_v0(ContractRowV0 {
+                    code: String::from("eosio.token"),
+                    scope: String::from(owner),
+                    table: String::from("accounts"),
+                    primary_key: symbol_code_raw(&balance.symbol.code).to_string(),
+                    payer: String::from(owner),
+                    value,
+                })),
+            }],
+        }
+    }
+
+    #[test]
+    fn asset_from_str() {
+        let a = asset("1.0000 EOS");
+        assert_eq!(a.amount, 10000);
+        assert_eq!(
+            a.symbol,
+            Symbol {
+                precision: 4,
+                code: String::from("EOS"),
+            }
+        );
+        assert_eq!(asset("0.0001 EOS").amount, 1);
+        assert_eq!(asset("-2.50 XYZ").amount, -250);
+        let whole = asset("42 WAX");
+        assert_eq!((whole.amount, whole.symbol.precision), (42, 0));
+
+        for s in &["1.0000", "EOS", "1.0.0 EOS", "abc EOS", ""] {
+            assert!(s.parse::<Asset>().is_err(), "'{}' parsed", s);
+        }
+    }
+
+    #[test]
+    fn asset_display_round_trips() {
+        for s in &[
+            "1.0000 EOS",
+            "0.0001 EOS",
+            "-2.50 XYZ",
+            "42 WAX",
+            "0.000 TKN",
+        ] {
+            assert_eq!(asset(s).to_string(), *s);
+        }
+        assert_eq!(serde_json::to_string(&asset("1.5 A")).unwrap(), "\"1.5 A\"");
+        assert_eq!("4,EOS".parse::<Symbol>().unwrap().to_string(), "4,EOS");
+        assert!("EOS".parse::<Symbol>().is_err());
+    }
+
+    #[test]
+    fn symbol_code_raw_packs_little_endian() {
+        assert_eq!(symbol_code_raw("EOS"), 0x53_4f_45);
+        assert_eq!(symbol_code_raw(""), 0);
+    }
+
+    #[test]
+    fn balances_roll_back_on_a_fork() {
+        let mut balances = TokenBalances::new().unwrap();
+        balances
+            .apply_block(&block(1, 0, vec![account_row("alice", "1.0000 EOS", true)]))
+            .unwrap();
+        balances
+            .apply_block(&block(2, 1, vec![account_row("alice", "3.0000 EOS", true)]))
+            .unwrap();
+        balances
+            .apply_block(&block(3, 1, vec![account_row("bob", "0.5000 EOS", true)]))
+            .unwrap();
+        assert_eq!(
+            balances.balance("eosio.token", "alice", "EOS"),
+            Some(&asset("3.0000 EOS"))
+        );
+
+        // another block 2 closes alice's account instead
+        balances
+            .apply_block(&block(
+                2,
+                1,
+                vec![account_row("alice", "1.0000 EOS", false)],
+            ))
+            .unwrap();
+        assert_eq!(balances.balance("eosio.token", "alice", "EOS"), None);
+        assert_eq!(balances.balance("eosio.token", "bob", "EOS"), None);
+
+        // block 1 is irreversible
+        assert!(balances.rollback_to(0).is_err());
+        balances.rollback_to(1).unwrap();
+        assert_eq!(
+            balances.balances_of("alice"),
+            vec![("eosio.token", &asset("1.0000 EOS"))]
+        );
+    }
+}
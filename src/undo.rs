@@ -0,0 +1,180 @@
+use crate::errors::{ErrorKind, Result};
+use std::collections::{HashMap, VecDeque};
+use std::hash::Hash;
+
+/// The undo log behind the trackers' fork handling.
+///
+/// Every block that is still reversible records the previous value of each key it changed,
+/// `None` when the key wasn't there. Rolling back restores them newest first, and blocks at
+/// or below the last irreversible block are forgotten since they can't be forked out.
+#[derive(Debug)]
+pub struct UndoLog<K, V> {
+    blocks: VecDeque<(u32, Vec<(K, Option<V>)>)>,
+    head: Option<u32>,
+    last_irreversible: u32,
+}
+
+impl<K, V> Default for UndoLog<K, V> {
+    fn default() -> Self {
+        UndoLog {
+            blocks: VecDeque::new(),
+            head: None,
+            last_irreversible: 0,
+        }
+    }
+}
+
+impl<K, V> UndoLog<K, V> {
+    pub fn new() -> UndoLog<K, V> {
+        UndoLog::default()
+    }
+
+    /// starts at `head` with nothing to undo, for state loaded from elsewhere
+    pub fn starting_at(head: Option<u32>) -> UndoLog<K, V> {
+        UndoLog {
+            blocks: VecDeque::new(),
+            head,
+            last_irreversible: head.unwrap_or(0),
+        }
+    }
+
+    pub fn head(&self) -> Option<u32> {
+        self.head
+    }
+
+    pub fn last_irreversible(&self) -> u32 {
+        self.last_irreversible
+    }
+
+    /// the block to roll back to before applying `block_num`, `None` unless it is a fork
+    pub fn fork_point(&self, block_num: u32) -> Option<u32> {
+        match self.head {
+            Some(head) if block_num <= head => Some(block_num.saturating_sub(1)),
+            _ => None,
+        }
+    }
+
+    /// records what `block_num` changed and makes it the head
+    pub fn push(&mut self, block_num: u32, entries: Vec<(K, Option<V>)>) {
+        self.blocks.push_back((block_num, entries));
+        self.head = Some(block_num);
+    }
+
+    /// Hands every change above `block_num` to `restore`, newest first, as the key and the
+    /// value it had before. Fails without undoing anything when `block_num` is below the last
+    /// irreversible block.
+    pub fn rollback_to<F>(&mut self, block_num: u32, mut restore: F) -> Result<()>
+    where
+        F: FnMut(K, Option<V>),
+    {
+        if block_num < self.last_irreversible {
+            return Err(ErrorKind::RollbackUnavailable(block_num).into());
+        }
+        while self.blocks.back().map_or(false, |(b, _)| *b > block_num) {
+            if let Some((_, entries)) = self.blocks.pop_back() {
+                for (key, previous) in entries.into_iter().rev() {
+                    restore(key, previous);
+                }
+            }
+        }
+        self.head = match self.head {
+            Some(h) if h > block_num => Some(block_num),
+            h => h,
+        };
+        Ok(())
+    }
+
+    /// drops the undo information of blocks that can no longer be forked out
+    pub fn set_irreversible(&mut self, block_num: u32) {
+        if block_num > self.last_irreversible {
+            self.last_irreversible = block_num;
+        }
+        while self
+            .blocks
+            .front()
+            .map_or(false, |(b, _)| *b <= self.last_irreversible)
+        {
+            self.blocks.pop_front();
+        }
+    }
+}
+
+/// puts `previous` back into `map`, the usual `restore` for `UndoLog::rollback_to`
+pub fn restore<K: Eq + Hash, V>(map: &mut HashMap<K, V>, key: K, previous: Option<V>) {
+    match previous {
+        Some(v) => map.insert(key, v),
+        None => map.remove(&key),
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn set(
+        map: &mut HashMap<&'static str, u32>,
+        entries: &mut Vec<(&'static str, Option<u32>)>,
+        key: &'static str,
+        value: Option<u32>,
+    ) {
+        let previous = match value {
+            Some(v) => map.insert(key, v),
+            None => map.remove(key),
+        };
+        entries.push((key, previous));
+    }
+
+    #[test]
+    fn rolls_back_newest_first() {
+        let mut map = HashMap::new();
+        let mut log = UndoLog::new();
+        for (block_num, value) in vec![(1, 10), (2, 20), (3, 30)] {
+            let mut entries = vec![];
+            set(&mut map, &mut entries, "a", Some(value));
+            if block_num == 3 {
+                set(&mut map, &mut entries, "b", Some(1));
+                set(&mut map, &mut entries, "a", None);
+            }
+            log.push(block_num, entries);
+        }
+        assert_eq!(log.head(), Some(3));
+        assert_eq!(log.fork_point(2), Some(1));
+        assert_eq!(log.fork_point(4), None);
+
+        log.rollback_to(1, |k, v| restore(&mut map, k, v)).unwrap();
+        assert_eq!(map.get("a"), Some(&10));
+        assert_eq!(map.get("b"), None);
+        assert_eq!(log.head(), Some(1));
+    }
+
+    #[test]
+    fn irreversible_blocks_cant_be_undone() {
+        let mut map = HashMap::new();
+        let mut log = UndoLog::new();
+        for block_num in 1..=4 {
+            let mut entries = vec![];
+            set(&mut map, &mut entries, "a", Some(block_num));
+            log.push(block_num, entries);
+        }
+        log.set_irreversible(2);
+        assert_eq!(log.last_irreversible(), 2);
+        // doesn't go backwards
+        log.set_irreversible(1);
+        assert_eq!(log.last_irreversible(), 2);
+
+        assert!(log.rollback_to(1, |k, v| restore(&mut map, k, v)).is_err());
+        assert_eq!(map.get("a"), Some(&4));
+
+        log.rollback_to(2, |k, v| restore(&mut map, k, v)).unwrap();
+        assert_eq!(map.get("a"), Some(&2));
+    }
+
+    #[test]
+    fn starting_at_has_nothing_to_undo() {
+        let mut log: UndoLog<&str, u32> = UndoLog::starting_at(Some(7));
+        assert_eq!(log.fork_point(7), Some(6));
+        assert!(log.rollback_to(6, |_, _| {}).is_err());
+        log.rollback_to(7, |_, _| panic!("nothing to restore"))
+            .unwrap();
+    }
+}